@@ -1,58 +1,1708 @@
 //! This module contains the configuration structures used for deserializing
 //! TOML configuration files, along with custom deserialization logic.
 
-use crate::threading::{self, Scheduler};
+use crate::{
+    service::{
+        ApiKeys, ClientLimiters, Countries, FileStore, Latency, Offenders, Provider, RateLimiter,
+        RuleCounters, Traffic, Users, Warm,
+    },
+    threading::{self, Picks, Scheduler, Splitter},
+};
+use ipnet::IpNet;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::{net::SocketAddr, os::unix::thread};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    os::unix::thread,
+    path::PathBuf,
+    sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
+};
+
+/// Main configuration structs based on TOML config file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    /// List of all servers.
+    #[serde(rename = "server")]
+    pub servers: Vec<Server>,
+    /// Logging settings.
+    #[serde(default)]
+    pub log: Log,
+    /// Status endpoint, disabled unless present.
+    #[serde(default)]
+    pub admin: Option<Admin>,
+    /// Metrics exporters.
+    #[serde(default)]
+    pub metrics: Metrics,
+    /// Overload detection, disabled unless present.
+    #[serde(default)]
+    pub load_shedding: Option<LoadShedding>,
+    /// Named policies inherited by the patterns that reference them.
+    #[serde(default, rename = "upstream")]
+    pub upstreams: HashMap<String, Upstream>,
+    /// Country lookups of client addresses, disabled unless present.
+    #[serde(default)]
+    pub geoip: Option<GeoIp>,
+}
+
+impl Config {
+    /// Fills the timeout, retry, buffering and hedging settings that
+    /// patterns leave unset from the upstream they reference. Fails when an
+    /// upstream doesn't exist.
+    pub fn inherit_upstreams(&mut self) -> Result<(), String> {
+        for pattern in self
+            .servers
+            .iter_mut()
+            .flat_map(|server| &mut server.patterns)
+        {
+            let Some(name) = &pattern.upstream else {
+                continue;
+            };
+            let Some(upstream) = self.upstreams.get(name) else {
+                return Err(format!(
+                    "pattern {:?} references unknown upstream {name:?}",
+                    pattern.uri
+                ));
+            };
+
+            let upstream = upstream.clone();
+            pattern.retries = pattern.retries.or(upstream.retries);
+            pattern.retry_budget = pattern.retry_budget.or(upstream.retry_budget);
+            pattern.retry_on = pattern.retry_on.take().or(upstream.retry_on);
+            pattern.retry_methods = pattern.retry_methods.take().or(upstream.retry_methods);
+            pattern.retry_buffer = pattern.retry_buffer.or(upstream.retry_buffer);
+            pattern.request_buffering = pattern
+                .request_buffering
+                .take()
+                .or(upstream.request_buffering);
+            pattern.first_byte_timeout = pattern.first_byte_timeout.or(upstream.first_byte_timeout);
+            pattern.response_timeout = pattern.response_timeout.or(upstream.response_timeout);
+            pattern.deadline = pattern.deadline.or(upstream.deadline);
+            pattern.hedge_after = pattern.hedge_after.or(upstream.hedge_after);
+            pattern.max_backoff = pattern.max_backoff.or(upstream.max_backoff);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the credentials files of the patterns that require auth, and
+    /// checks the settings of those logging users in with OIDC.
+    pub fn load_credentials(&mut self) -> Result<(), String> {
+        for oidc in self
+            .servers
+            .iter()
+            .flat_map(|server| &server.patterns)
+            .filter_map(|pattern| pattern.oidc.as_ref())
+        {
+            crate::service::validate_oidc(oidc)?;
+        }
+
+        let auths = self
+            .servers
+            .iter_mut()
+            .flat_map(|server| &mut server.patterns)
+            .filter_map(|pattern| pattern.auth.as_mut());
+
+        for auth in auths {
+            if let Some(basic) = &mut auth.basic {
+                basic.users = Arc::new(Users::load(&basic.users_file)?);
+            }
+            if let Some(api_key) = &mut auth.api_key {
+                api_key.keys = Arc::new(ApiKeys::load(&api_key.keys_file)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Opens the GeoIP database and hands it to every server. Fails when
+    /// patterns filter countries without a database.
+    pub fn load_geoip(&mut self) -> Result<(), String> {
+        let Some(geoip) = &mut self.geoip else {
+            let filtered = self
+                .servers
+                .iter()
+                .flat_map(|server| &server.patterns)
+                .find(|pattern| {
+                    !pattern.allow_countries.is_empty() || !pattern.deny_countries.is_empty()
+                });
+
+            return match filtered {
+                Some(pattern) => Err(format!(
+                    "pattern {:?} filters countries without a [geoip] database",
+                    pattern.uri
+                )),
+                None => Ok(()),
+            };
+        };
+
+        if http::HeaderName::try_from(geoip.header.as_str()).is_err() {
+            return Err(format!("invalid GeoIP header name {:?}", geoip.header));
+        }
+        geoip.countries = Arc::new(Countries::open(&geoip.database)?);
+        for server in &mut self.servers {
+            server.geoip = Some(geoip.clone());
+        }
+
+        Ok(())
+    }
+}
+
+/// MaxMind GeoLite2 or GeoIP2 Country database giving the country of the
+/// clients, which is logged, sent to backends and filtered by patterns.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeoIp {
+    pub database: PathBuf,
+    /// Header carrying the ISO code of the country to backends, replacing
+    /// the one sent by the client.
+    #[serde(default = "default::country_header")]
+    pub header: String,
+    #[serde(skip)]
+    pub countries: Arc<Countries>,
+}
+
+/// Timeout, retry, buffering and hedging policy defined once and shared by
+/// every pattern whose `upstream` names it. Settings of the pattern itself
+/// take precedence, see [`Pattern`] for their meaning.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Upstream {
+    pub retries: Option<u32>,
+    #[serde(with = "humantime_serde")]
+    pub retry_budget: Option<Duration>,
+    pub retry_on: Option<Vec<RetryOn>>,
+    pub retry_methods: Option<Vec<String>>,
+    pub retry_buffer: Option<u64>,
+    pub request_buffering: Option<RequestBuffering>,
+    #[serde(with = "humantime_serde")]
+    pub first_byte_timeout: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub response_timeout: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub deadline: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub hedge_after: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Option<Duration>,
+}
+
+/// Thresholds past which the proxy counts as overloaded and rejects the
+/// requests of low priority patterns with `503 Service Unavailable`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoadShedding {
+    /// Delay of the runtime timers, as in `"50ms"`. Grows when the workers
+    /// can't keep up with the tasks.
+    #[serde(default, with = "humantime_serde")]
+    pub max_lag: Option<Duration>,
+    /// Resident memory of the process in bytes. Only measured on Linux.
+    #[serde(default)]
+    pub max_memory: Option<u64>,
+    /// Time between measurements.
+    #[serde(default = "default::load_interval", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+/// Metrics exporters, see [`crate::metrics`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Metrics {
+    /// Pushes request metrics to a StatsD agent.
+    pub statsd: Option<Statsd>,
+}
+
+/// StatsD agent, tags are sent in the DogStatsD format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Statsd {
+    /// UDP address of the agent, as in `"127.0.0.1:8125"`.
+    pub address: String,
+    /// Prepended to all the metric names.
+    #[serde(default = "default::statsd_prefix")]
+    pub prefix: String,
+    /// Tags added to every metric.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Listener that reports the state of all servers as JSON.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Admin {
+    pub listen: SocketAddr,
+    /// Path of the status endpoint, everything else gets a 404.
+    #[serde(default = "default::status_path")]
+    pub path: String,
+    /// Debug capture of sampled requests, disabled unless present.
+    #[serde(default)]
+    pub capture: Option<Capture>,
+    /// Path where the `split` of a pattern is changed with a `PUT` request.
+    #[serde(default = "default::split_path")]
+    pub split_path: String,
+    /// Path where cached files are evicted with a `POST` request.
+    #[serde(default = "default::purge_path")]
+    pub purge_path: String,
+    /// Token that requests reading captures, changing splits or purging
+    /// files must send as a bearer token in their `Authorization` header. Only clients on the
+    /// loopback interface can make them unless set.
+    #[serde(default)]
+    pub token: Option<Secret>,
+    /// Networks whose connections are accepted, such as internal ones.
+    /// Every network is allowed unless set.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Networks whose connections are closed as soon as they're accepted.
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
+/// Captures full headers and body prefixes of some requests into a ring
+/// buffer served by the admin listener.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Capture {
+    /// Path where the captured requests are served.
+    #[serde(default = "default::captures_path")]
+    pub path: String,
+    /// Fraction of requests captured, between 0 and 1.
+    #[serde(default)]
+    pub sample_rate: f64,
+    /// Requests carrying this header are always captured.
+    pub header: Option<String>,
+    /// Number of body bytes kept for each request and response.
+    #[serde(default)]
+    pub body_bytes: usize,
+    /// Number of requests kept, older ones are discarded.
+    #[serde(default = "default::capture_entries")]
+    pub entries: usize,
+}
+
+/// Logging configuration, see [`crate::logging`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Log {
+    /// Default level for all modules, `"info"` if not specified.
+    pub level: String,
+    /// Levels for specific modules such as `"xnav::service" = "debug"`.
+    pub modules: BTreeMap<String, String>,
+    /// Output format of the log lines.
+    pub format: LogFormat,
+    /// File for access logs, stdout if not specified.
+    pub access: Option<LogFile>,
+    /// Requests left out of the access log.
+    pub exclude: Vec<Exclusion>,
+    /// File recording every request made to the admin listener, stdout if
+    /// not specified. Entries are only ever appended.
+    pub audit: Option<LogFile>,
+    /// Warnings, errors and panics, kept apart from the request logs.
+    pub error: ErrorLog,
+    /// Exports request spans to an OpenTelemetry collector.
+    pub otlp: Option<Otlp>,
+    /// Sends logs to a syslog daemon as well.
+    pub syslog: Option<Syslog>,
+    /// Sends logs to Graylog or any other GELF input as well.
+    pub gelf: Option<Gelf>,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: String::from("info"),
+            modules: BTreeMap::new(),
+            format: LogFormat::Text,
+            access: None,
+            exclude: Vec::new(),
+            audit: None,
+            error: ErrorLog::default(),
+            otlp: None,
+            syslog: None,
+            gelf: None,
+        }
+    }
+}
+
+/// Rule leaving requests out of the access log. A request is excluded when
+/// it matches every criteria given in the rule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Exclusion {
+    /// Prefix of the request path.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Response status, either exact like `"404"` or a class like `"2xx"`.
+    #[serde(default)]
+    pub status: Option<StatusMatch>,
+    /// Request header that must be present.
+    #[serde(default)]
+    pub header: Option<String>,
+    /// Value the header must have, any value if not specified.
+    #[serde(default)]
+    pub header_value: Option<String>,
+}
+
+/// Response status criteria of an [`Exclusion`] or an error page.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum StatusMatch {
+    Exact(u16),
+    /// First digit of the status code.
+    Class(u8),
+}
+
+impl StatusMatch {
+    pub fn matches(&self, status: u16) -> bool {
+        match *self {
+            StatusMatch::Exact(exact) => status == exact,
+            StatusMatch::Class(class) => status / 100 == u16::from(class),
+        }
+    }
+}
+
+impl TryFrom<String> for StatusMatch {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid =
+            || format!("invalid status {value:?}, expected a code or a class like \"2xx\"");
+
+        match value.to_ascii_lowercase().as_bytes() {
+            [class @ b'1'..=b'5', b'x', b'x'] => Ok(StatusMatch::Class(class - b'0')),
+            _ => match value.parse() {
+                Ok(status @ 100..=599) => Ok(StatusMatch::Exact(status)),
+                _ => Err(invalid()),
+            },
+        }
+    }
+}
+
+impl From<StatusMatch> for String {
+    fn from(value: StatusMatch) -> Self {
+        match value {
+            StatusMatch::Exact(status) => status.to_string(),
+            StatusMatch::Class(class) => format!("{class}xx"),
+        }
+    }
+}
+
+/// Secret kept out of the config file. Either the secret itself, or a table
+/// reading it from a file, as in `{ file = "/run/secrets/oidc" }`, or from
+/// an environment variable, as in `{ env = "OIDC_CLIENT_SECRET" }`. Sources
+/// are read along with the configuration at startup. Printed redacted.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "SecretSource")]
+pub struct Secret(String);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretSource {
+    Value(String),
+    File { file: PathBuf },
+    Env { env: String },
+}
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<SecretSource> for Secret {
+    type Error = String;
+
+    fn try_from(source: SecretSource) -> Result<Self, Self::Error> {
+        let secret = match source {
+            SecretSource::Value(secret) => secret,
+            // Files made by editors and orchestrators end with a newline.
+            SecretSource::File { file } => std::fs::read_to_string(&file)
+                .map_err(|err| format!("failed to read secret file {file:?}: {err}"))?
+                .trim_end_matches(['\r', '\n'])
+                .to_owned(),
+            SecretSource::Env { env } => std::env::var(&env)
+                .map_err(|err| format!("failed to read secret variable {env}: {err}"))?,
+        };
+
+        Ok(Self(secret))
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Log file with optional size and time based rotation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    /// Rotates the file once it would grow beyond this number of bytes.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Rotates the file every time period.
+    #[serde(default = "default::rotation")]
+    pub rotation: Rotation,
+    /// Number of rotated files kept around besides the current one.
+    #[serde(default = "default::max_log_files")]
+    pub max_files: usize,
+}
+
+/// Destination of the error log stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorLog {
+    /// File for error logs, stderr if not specified.
+    #[serde(flatten)]
+    pub file: Option<LogFile>,
+    /// Least severe level sent to this stream instead of stdout.
+    #[serde(default = "default::error_level")]
+    pub level: String,
+    /// Output format, same as the rest of the logs if not specified.
+    #[serde(default)]
+    pub format: Option<LogFormat>,
+}
+
+impl Default for ErrorLog {
+    fn default() -> Self {
+        Self {
+            file: None,
+            level: default::error_level(),
+            format: None,
+        }
+    }
+}
+
+/// Syslog endpoint, messages are sent in RFC 5424 format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Syslog {
+    /// `"udp://host:port"`, `"tcp://host:port"` or `"unix:///dev/log"`.
+    pub address: String,
+    #[serde(default = "default::facility")]
+    pub facility: Facility,
+    /// Sends access logs.
+    #[serde(default = "default::enabled")]
+    pub access: bool,
+    /// Sends the error log stream.
+    #[serde(default = "default::enabled")]
+    pub error: bool,
+}
+
+/// GELF input, each event is sent as a JSON object with its fields.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Gelf {
+    /// `"udp://host:port"` or `"tcp://host:port"`.
+    pub address: String,
+    /// Name of this host in the messages, the system hostname by default.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Largest UDP datagram sent, bigger messages are split into chunks.
+    #[serde(default = "default::gelf_chunk_size")]
+    pub chunk_size: usize,
+    /// Sends access logs.
+    #[serde(default = "default::enabled")]
+    pub access: bool,
+    /// Sends the error log stream.
+    #[serde(default = "default::enabled")]
+    pub error: bool,
+}
+
+/// Syslog facilities as defined in RFC 5424 section 6.2.1.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// OTLP over HTTP trace exporter settings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Otlp {
+    /// Collector URL, as in `"http://localhost:4318/v1/traces"`.
+    pub endpoint: String,
+    /// Value of the `service.name` resource attribute.
+    #[serde(default = "default::service_name")]
+    pub service_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Never,
+    Minutely,
+    Hourly,
+    Daily,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Server {
+    pub listen: Vec<SocketAddr>,
+    #[serde(rename = "match")]
+    pub patterns: Vec<Pattern>,
+    #[serde(default = "default::max_connections")]
+    pub max_connections: usize,
+    /// Requests handled at once by all the listeners of this server, however
+    /// many connections carry them. Requests over it are answered with `503
+    /// Service Unavailable`.
+    pub max_requests: Option<usize>,
+    /// Requests in flight, shared by all the replicas of the server.
+    #[serde(skip)]
+    pub requests: Arc<AtomicUsize>,
+    /// Time allowed for a new connection to send its first request head.
+    #[serde(with = "humantime_serde")]
+    pub header_timeout: Duration,
+    /// Time allowed between the end of a response and the head of the next
+    /// request on a kept-alive connection.
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+    /// Time given to open connections and tunnels to finish on shutdown,
+    /// after which they are aborted. Connections are waited for as long as
+    /// they take by default, and tunnels are closed once they are done.
+    #[serde(with = "humantime_serde")]
+    pub drain_timeout: Option<Duration>,
+    /// Default of `max_body_size` for the patterns that don't set one.
+    pub max_body_size: Option<u64>,
+    /// Requests per second handled by all the listeners of this server.
+    pub rate_limit: Option<RateLimit>,
+    /// Bandwidth of the responses sent over each connection.
+    pub bandwidth: Option<Bandwidth>,
+    /// Connections of clients sending request bodies slower than this are
+    /// closed. Request heads are bounded by `header_timeout` and
+    /// `idle_timeout` instead.
+    pub min_data_rate: Option<MinDataRate>,
+    /// Refuses for a while the clients that get too many error responses.
+    pub ban: Option<Ban>,
+    /// Proxies in front of this server, as in `["10.0.0.0/8"]`. The client
+    /// address of their requests is taken from `client_ip_header`.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Header that `trusted_proxies` append the client address to. The
+    /// other one is ignored, clients could send it with any address.
+    pub client_ip_header: ClientIpHeader,
+    /// Networks whose connections are accepted, as in `["10.0.0.0/8"]`.
+    /// Checked against the peer address before reading any request, unlike
+    /// the `allow` of patterns. Every network is allowed unless set.
+    pub allow: Vec<IpNet>,
+    /// Networks whose connections are closed as soon as they're accepted.
+    pub deny: Vec<IpNet>,
+    /// Set from the `[geoip]` table of the config.
+    pub geoip: Option<GeoIp>,
+    /// Headers such as HSTS and CSP added to every response.
+    pub security_headers: Option<SecurityHeaders>,
+    /// Refuse requests matching their conditions before routing, the first
+    /// matching rule applies.
+    pub rules: Vec<Rule>,
+    /// Refuses with `400 Bad Request` the requests whose framing backends
+    /// could read differently, such as those with both `Content-Length`
+    /// and `Transfer-Encoding`, and strips hop-by-hop headers from the
+    /// others, to prevent request smuggling.
+    pub strict_http: bool,
+    /// Adds a `Via` entry to forwarded requests and to the responses of
+    /// backends. Disabled unless present.
+    pub via: Option<Via>,
+    /// Sets `X-Real-IP` on forwarded requests to the address of the client,
+    /// traced through `trusted_proxies`, replacing the one sent by clients.
+    pub real_ip: bool,
+    /// Adds `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    /// to forwarded requests besides `Forwarded`, since most frameworks only
+    /// read those. Proto and Host sent by `trusted_proxies` are kept.
+    pub x_forwarded: bool,
+    pub name: Option<String>,
+    #[serde(skip)]
+    pub log_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pattern {
+    #[serde(default = "default::uri")]
+    pub uri: String,
+    #[serde(flatten)]
+    pub action: Action,
+    /// On-the-fly compression of static files and backend responses,
+    /// disabled unless present.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Decompresses the responses of backends encoded with gzip, br or zstd
+    /// when the client doesn't accept their encoding, or when
+    /// `response.substitute` has to read them.
+    #[serde(default)]
+    pub decompress: bool,
+    /// MIME type overrides for the serve action, keyed by lowercase file
+    /// extension without the leading dot.
+    #[serde(default)]
+    pub content_types: HashMap<String, String>,
+    /// File served instead of a 404 when the requested path doesn't exist,
+    /// relative to the serve directory. Useful for single page applications.
+    #[serde(default)]
+    pub fallback: Option<String>,
+    /// Allows serving dotfiles and paths containing dot-segments.
+    #[serde(default)]
+    pub serve_hidden: bool,
+    /// Follows symbolic links when serving files. Links are still required
+    /// to resolve inside the serve directory. Disabled by default, any path
+    /// that goes through a link is then refused.
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// In-memory cache for small files of the serve action.
+    #[serde(default)]
+    pub file_cache: Option<FileCache>,
+    /// Memory allowed for each response read in full before being sent,
+    /// such as served files being compressed.
+    #[serde(default)]
+    pub response_buffering: ResponseBuffering,
+    /// Accepts PUT and DELETE requests that modify the serve directory.
+    /// Requires a `token`, or `auth`, `oidc` or `forward_auth` on the
+    /// pattern.
+    #[serde(default)]
+    pub serve_writable: Option<Writable>,
+    /// Strips the matched `uri` prefix from the request path before looking
+    /// up files, so `/static/app.js` maps to `app.js` inside the directory.
+    #[serde(default)]
+    pub alias: bool,
+    /// Sends matching files with `Content-Disposition: attachment`.
+    #[serde(default)]
+    pub download: Option<Download>,
+    /// Name of the `[upstream.NAME]` table whose timeout, retry and hedging
+    /// settings apply to this pattern, unless set here as well.
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// Number of times a forwarded request is sent again to the next backend
+    /// when the conditions in `retry_on` are met. None by default.
+    #[serde(default)]
+    pub retries: Option<u32>,
+    /// Total time allowed for all the attempts, as in `"2s"`. No more retries
+    /// are made past it.
+    #[serde(default, with = "humantime_serde")]
+    pub retry_budget: Option<Duration>,
+    /// Failures that trigger a retry. Requests that never reached the backend
+    /// are always safe to send again, the others depend on `retry_methods`.
+    /// Only `connect-failure` by default.
+    #[serde(default)]
+    pub retry_on: Option<Vec<RetryOn>>,
+    /// Methods whose requests are sent again after a backend answered with a
+    /// status in `retry_on`. Only idempotent methods by default. Requests
+    /// without a body, or whose body `request_buffering` read in full, are
+    /// sent again whatever their method.
+    #[serde(default)]
+    pub retry_methods: Option<Vec<String>>,
+    /// Methods handled by this pattern, GET allowing HEAD as well. Requests
+    /// with others get `405 Method Not Allowed` without reaching backends.
+    /// All methods are allowed unless set.
+    #[serde(default)]
+    pub allowed_methods: Option<Vec<String>>,
+    /// Request bodies up to this number of bytes are buffered so that they
+    /// can be sent again. Larger bodies are streamed and never retried once
+    /// sent. None by default.
+    #[serde(default)]
+    pub retry_buffer: Option<u64>,
+    /// Reads whole request bodies before sending them to a backend instead
+    /// of streaming them, so that slow clients don't hold backend
+    /// connections. Buffered bodies can be retried.
+    #[serde(default)]
+    pub request_buffering: Option<RequestBuffering>,
+    /// Time allowed for a backend to send the response headers once the
+    /// request is sent, as in `"10s"`. Answers `504 Gateway Timeout` when
+    /// exceeded.
+    #[serde(default, with = "humantime_serde")]
+    pub first_byte_timeout: Option<Duration>,
+    /// Time allowed for the whole backend response, from the first attempt
+    /// until the last body byte. Answers `504 Gateway Timeout` when the
+    /// headers haven't arrived by then, otherwise the body is cut short.
+    #[serde(default, with = "humantime_serde")]
+    pub response_timeout: Option<Duration>,
+    /// Time allowed for the whole request, from routing until the last
+    /// response byte is sent. Answers `504 Gateway Timeout` and abandons the
+    /// backend request when the response hasn't started by then, otherwise
+    /// the body is cut short.
+    #[serde(default, with = "humantime_serde")]
+    pub deadline: Option<Duration>,
+    /// Sends a copy of the requests that may be retried to another backend
+    /// when the first one hasn't answered after this long, as in `"50ms"`.
+    /// Whichever answers first is used, the other request is cancelled.
+    #[serde(default, with = "humantime_serde")]
+    pub hedge_after: Option<Duration>,
+    /// Idle connections kept open to each backend of the forward action, so
+    /// that requests don't wait for new ones. Each is used for one request
+    /// and replaced right after.
+    #[serde(default)]
+    pub warm_connections: usize,
+    /// Backends answering `429 Too Many Requests` or `503 Service
+    /// Unavailable` with a `Retry-After` header get no requests for the time
+    /// they ask, up to this long, as in `"30s"`. `Retry-After` is ignored
+    /// unless set.
+    #[serde(default, with = "humantime_serde")]
+    pub max_backoff: Option<Duration>,
+    /// Replaces the error responses of backends with the statuses in
+    /// `intercepted_statuses` by `error_pages`, so that their bodies don't
+    /// reach clients. Pages include the ID of the request.
+    #[serde(default)]
+    pub intercept_errors: bool,
+    /// Statuses replaced by `intercept_errors`, either exact like `"502"` or
+    /// a class like `"5xx"`. Only 502 and 504 by default.
+    #[serde(default = "default::intercepted_statuses")]
+    pub intercepted_statuses: Vec<StatusMatch>,
+    /// Templates of the pages of intercepted errors keyed by status, exact
+    /// like `"502"` or a class like `"5xx"`. Exact statuses take precedence.
+    /// `{status}` and `{request_id}` are replaced in the file content, and
+    /// statuses without a page get a plain text one.
+    #[serde(default)]
+    pub error_pages: HashMap<StatusMatch, PathBuf>,
+    /// Credentials required before serving files or forwarding.
+    #[serde(default)]
+    pub auth: Option<Auth>,
+    /// External service asked whether each request is allowed, once the
+    /// credentials of `auth` are checked.
+    #[serde(default)]
+    pub forward_auth: Option<ForwardAuth>,
+    /// Identity provider users log in with before their requests get
+    /// through, after the credentials of `auth` are checked.
+    #[serde(default)]
+    pub oidc: Option<Oidc>,
+    /// HMAC signature required on forwarded requests, such as those of
+    /// webhooks. Their bodies are read in full to check it.
+    #[serde(default)]
+    pub signature: Option<Signature>,
+    /// Changes made to requests before forwarding them.
+    #[serde(default)]
+    pub request: RequestRewrite,
+    /// Replaces the `Host` header of forwarded requests, as in
+    /// `"internal.svc"`, for backends that only answer to their own name.
+    /// `"$backend"` is the address of the backend each attempt goes to.
+    #[serde(default, deserialize_with = "header_value")]
+    pub upstream_host: Option<String>,
+    /// Changes made to the responses of backends.
+    #[serde(default)]
+    pub response: ResponseRewrite,
+    /// Requests with larger bodies are rejected with `413 Payload Too Large`,
+    /// before they reach the backend or the disk when they declare their
+    /// length. Defaults to the `max_body_size` of the server.
+    #[serde(default)]
+    pub max_body_size: Option<u64>,
+    /// Requests per second handled by this pattern, on top of the limit of
+    /// the server.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Bandwidth of the responses of this pattern, shared by all clients.
+    #[serde(default)]
+    pub bandwidth: Option<Bandwidth>,
+    /// Lets requests wait for a backend when all of them have their
+    /// `max_requests` in flight. Without it they are answered with
+    /// `503 Service Unavailable` right away.
+    #[serde(default)]
+    pub queue: Option<Queue>,
+    /// Answer of the forward action when none of its backends can take
+    /// requests, instead of `502 Bad Gateway`. Disabled unless present.
+    #[serde(default)]
+    pub unavailable: Option<Unavailable>,
+    /// Holds the requests of this pattern for this long before handling
+    /// them, as in `"10s"`, to slow down scanners of routes like
+    /// `/wp-login.php`. Only the client connection is held meanwhile.
+    #[serde(default, with = "humantime_serde")]
+    pub tarpit: Option<Duration>,
+    /// Networks whose clients are let in, as in `["10.0.0.0/8"]`. Everyone
+    /// is unless set.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Networks whose clients get `403 Forbidden`, even if allowed.
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// Countries whose clients are let in, as ISO codes like `"FR"`.
+    /// Everyone is unless set, clients of unknown countries are refused
+    /// otherwise. Requires the `[geoip]` database.
+    #[serde(default)]
+    pub allow_countries: Vec<String>,
+    /// Countries whose clients get `403 Forbidden`.
+    #[serde(default)]
+    pub deny_countries: Vec<String>,
+    /// Cross-site request forgery protection, disabled unless present.
+    #[serde(default)]
+    pub csrf: Option<Csrf>,
+    /// Signed cookie that clients have to get before their requests are
+    /// handled, keeping simple scrapers away.
+    #[serde(default)]
+    pub challenge: Option<Challenge>,
+    /// Requests of `low` priority patterns are the first to be rejected
+    /// when the proxy is overloaded, see `load_shedding`.
+    #[serde(default)]
+    pub priority: Priority,
+    /// Share of the requests sent to each group of backends, as in
+    /// `{ stable = 95, canary = 5 }`. Backups are shared by all groups.
+    #[serde(default)]
+    pub split: Option<Split>,
+    /// Group receiving all the requests, for blue-green deployments. Turned
+    /// into a `split` where the other groups have no weight, so the admin
+    /// listener can flip it.
+    #[serde(default)]
+    pub active: Option<String>,
+    /// Writes access log entries for the requests matched by this pattern.
+    #[serde(default = "default::enabled")]
+    pub access_log: bool,
+    /// Level of the access log entries of this pattern, lowering it to
+    /// `debug` keeps noisy routes out of the logs unless debugging.
+    #[serde(default = "default::access_log_level")]
+    pub access_log_level: LogLevel,
+    /// Latencies of the requests matched by this pattern.
+    #[serde(skip)]
+    pub latency: Arc<Latency>,
+    /// Body bytes transferred by the requests matched by this pattern.
+    #[serde(skip)]
+    pub traffic: Arc<Traffic>,
+}
+
+impl Pattern {
+    /// Creates a pattern with all the optional settings left as default.
+    fn new(uri: String, action: Action) -> Self {
+        Self {
+            uri,
+            action,
+            compression: None,
+            decompress: false,
+            content_types: HashMap::new(),
+            fallback: None,
+            serve_hidden: false,
+            follow_symlinks: false,
+            file_cache: None,
+            response_buffering: ResponseBuffering::default(),
+            serve_writable: None,
+            alias: false,
+            download: None,
+            upstream: None,
+            retries: None,
+            retry_budget: None,
+            retry_on: None,
+            retry_methods: None,
+            allowed_methods: None,
+            retry_buffer: None,
+            request_buffering: None,
+            first_byte_timeout: None,
+            response_timeout: None,
+            deadline: None,
+            hedge_after: None,
+            warm_connections: 0,
+            max_backoff: None,
+            intercept_errors: false,
+            intercepted_statuses: default::intercepted_statuses(),
+            error_pages: HashMap::new(),
+            auth: None,
+            forward_auth: None,
+            oidc: None,
+            signature: None,
+            request: RequestRewrite::default(),
+            upstream_host: None,
+            response: ResponseRewrite::default(),
+            max_body_size: None,
+            rate_limit: None,
+            bandwidth: None,
+            queue: None,
+            unavailable: None,
+            tarpit: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            allow_countries: Vec::new(),
+            deny_countries: Vec::new(),
+            csrf: None,
+            challenge: None,
+            priority: Priority::default(),
+            split: None,
+            active: None,
+            access_log: default::enabled(),
+            access_log_level: default::access_log_level(),
+            latency: Arc::default(),
+            traffic: Arc::default(),
+        }
+    }
+
+    /// Number of retries, see `retries`.
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(0)
+    }
+
+    /// Largest request body buffered for retries, see `retry_buffer`.
+    pub fn retry_buffer(&self) -> u64 {
+        self.retry_buffer.unwrap_or(0)
+    }
+
+    /// Failures that trigger a retry, see `retry_on`.
+    pub fn retry_on(&self) -> &[RetryOn] {
+        self.retry_on
+            .as_deref()
+            .unwrap_or(&[RetryOn::ConnectFailure])
+    }
+
+    /// Whether requests with this method are sent again after a retryable
+    /// status, see `retry_methods`.
+    pub fn retries_method(&self, method: &str) -> bool {
+        match &self.retry_methods {
+            Some(methods) => methods
+                .iter()
+                .any(|retried| retried.eq_ignore_ascii_case(method)),
+            None => ["GET", "HEAD", "OPTIONS"].contains(&method),
+        }
+    }
+}
+
+/// Credentials required by a pattern. Requests without them get
+/// `401 Unauthorized`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Auth {
+    #[serde(default)]
+    pub basic: Option<BasicAuth>,
+    #[serde(default)]
+    pub api_key: Option<ApiKeyAuth>,
+}
+
+/// HTTP Basic auth against the users of an htpasswd file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BasicAuth {
+    /// htpasswd file with bcrypt (`htpasswd -B`) or SHA-1 (`htpasswd -s`)
+    /// passwords, read at startup.
+    pub users_file: PathBuf,
+    /// Protection space sent to clients in `WWW-Authenticate`.
+    #[serde(default = "default::realm")]
+    pub realm: String,
+    #[serde(skip)]
+    pub users: Arc<Users>,
+}
+
+/// API keys identifying their owners by a label, which is logged along with
+/// the requests that present them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKeyAuth {
+    /// File with one `label:key` line per key, read at startup.
+    pub keys_file: PathBuf,
+    /// Header carrying the key.
+    #[serde(default = "default::api_key_header")]
+    pub header: String,
+    /// Query parameter carrying the key when the header is missing, not
+    /// looked at unless set.
+    #[serde(default)]
+    pub query: Option<String>,
+    #[serde(skip)]
+    pub keys: Arc<ApiKeys>,
+}
+
+/// Subrequest sent to an auth service such as oauth2-proxy before handling
+/// a request. The method, URI, host and client of the original request go
+/// in the `X-Forwarded-*` headers. A 2xx answer allows the request, any
+/// other is sent to the client instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForwardAuth {
+    pub address: SocketAddr,
+    /// Path of the subrequests, sent with the method of the original one.
+    #[serde(default = "default::uri")]
+    pub path: String,
+    /// Headers of the original request sent along with the subrequest.
+    #[serde(default = "default::forward_auth_request_headers")]
+    pub request_headers: Vec<String>,
+    /// Headers of a 2xx answer copied onto the original request, such as
+    /// `X-Auth-Request-User`, replacing those sent by the client.
+    #[serde(default)]
+    pub response_headers: Vec<String>,
+    /// Requests fail with `500 Internal Server Error` when the service
+    /// takes longer to answer.
+    #[serde(default = "default::forward_auth_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+/// OpenID Connect provider with which users log in, through the
+/// authorization code flow. Sessions are kept in an encrypted cookie, and
+/// the claims of the user are sent to backends in headers, replacing those
+/// sent by clients. Requests without a session other than GET and HEAD get
+/// `401 Unauthorized`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Oidc {
+    /// URL of the provider, as in `"https://accounts.google.com"`, where its
+    /// discovery document is found under `/.well-known`.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Secret,
+    /// Absolute URL of the callback, whose path must be routed to this
+    /// pattern, as in `"https://app.example.com/oauth2/callback"`.
+    pub redirect_uri: String,
+    #[serde(default = "default::oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// Headers sent to backends keyed by the claim they hold, the subject
+    /// and email of the user by default.
+    #[serde(default = "default::oidc_claims")]
+    pub claims: HashMap<String, String>,
+    /// Name of the session cookie.
+    #[serde(default = "default::oidc_cookie")]
+    pub cookie: String,
+    /// Key encrypting the cookies. Generated at startup unless set, which
+    /// logs users out on restarts.
+    #[serde(default)]
+    pub secret: Option<Secret>,
+    /// How long sessions last, as in `"8h"`.
+    #[serde(default = "default::oidc_session_ttl", with = "humantime_serde")]
+    pub session_ttl: Duration,
+    /// Requests to the provider fail after this long.
+    #[serde(default = "default::oidc_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    #[serde(skip)]
+    pub provider: Arc<Provider>,
+}
+
+/// Shared secret with which clients sign their requests, as webhook senders
+/// do. Requests with a missing or wrong signature get `401 Unauthorized`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Signature {
+    pub secret: Secret,
+    /// Header holding the signature, as in `"X-Hub-Signature-256"`.
+    #[serde(default = "default::signature_header")]
+    pub header: String,
+    /// Text before the signature in the header, as in `"sha256="`.
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub algorithm: SignatureAlgorithm,
+    #[serde(default)]
+    pub encoding: SignatureEncoding,
+    /// Signed content, where `{body}`, `{method}`, `{path}` and
+    /// `{timestamp}` are replaced by those of the request. Only the body by
+    /// default.
+    #[serde(default = "default::signature_canonical")]
+    pub canonical: String,
+    /// Header holding the time of signing in seconds since the epoch,
+    /// required when set. Replayed requests are refused once it's off by
+    /// more than `tolerance`.
+    #[serde(default)]
+    pub timestamp_header: Option<String>,
+    #[serde(default = "default::signature_tolerance", with = "humantime_serde")]
+    pub tolerance: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureAlgorithm {
+    #[default]
+    Sha256,
+    Sha1,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+/// Origins allowed to send state-changing requests, those with a method
+/// other than GET, HEAD, OPTIONS and TRACE. Their origin is taken from the
+/// `Origin` header, or the `Referer` one without it, and requests from other
+/// origins get `403 Forbidden`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Csrf {
+    /// Allowed origins, as in `"https://example.com"`. Only the origin of
+    /// the request itself, according to its `Host`, unless set.
+    #[serde(default)]
+    pub origins: Vec<String>,
+    /// Lets through requests with neither `Origin` nor `Referer`, such as
+    /// those of scripts rather than browsers.
+    #[serde(default)]
+    pub allow_missing: bool,
+}
+
+/// Challenge sent to clients without a valid cookie. The cookie is signed
+/// for the address and user agent of the client, and requests other than
+/// GET and HEAD get `403 Forbidden` without it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Challenge {
+    #[serde(default)]
+    pub mode: ChallengeMode,
+    /// Key signing the cookies. Generated at startup unless set, so that
+    /// clients are challenged again after restarts.
+    #[serde(default)]
+    pub secret: Option<Secret>,
+    /// How long clients keep their cookie, as in `"1d"`.
+    #[serde(default = "default::challenge_ttl", with = "humantime_serde")]
+    pub ttl: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeMode {
+    /// Redirects to the same URL while setting the cookie, which stops
+    /// clients that don't keep cookies.
+    #[default]
+    Redirect,
+    /// Page setting the cookie with a script and reloading, which stops
+    /// clients that don't run scripts either.
+    Interstitial,
+}
+
+/// Page sent with `503 Service Unavailable` when all the backends of a
+/// forward action are unreachable or paused, such as a maintenance page.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Unavailable {
+    /// Directory whose files are sent instead, as with the serve action.
+    /// The file settings of the pattern apply, such as `fallback`.
+    #[serde(default)]
+    pub serve: Option<String>,
+    /// Body sent when there's no directory to serve.
+    #[serde(default)]
+    pub body: String,
+    #[serde(default = "default::unavailable_content_type")]
+    pub content_type: String,
+}
+
+/// Failure of a forwarded request that can be retried.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum RetryOn {
+    /// The backend could not be connected to, the request was not sent.
+    ConnectFailure,
+    /// The backend responded with this status, such as 503.
+    Status(u16),
+}
+
+impl TryFrom<String> for RetryOn {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.parse() {
+            _ if value == "connect-failure" => Ok(RetryOn::ConnectFailure),
+            Ok(status @ 500..=599) => Ok(RetryOn::Status(status)),
+            _ => Err(format!(
+                "invalid retry condition {value:?}, expected \"connect-failure\" or a 5xx status"
+            )),
+        }
+    }
+}
+
+impl From<RetryOn> for String {
+    fn from(value: RetryOn) -> Self {
+        match value {
+            RetryOn::ConnectFailure => String::from("connect-failure"),
+            RetryOn::Status(status) => status.to_string(),
+        }
+    }
+}
+
+/// Verbosity of a log event.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "BackendOption")]
+pub struct Backend {
+    pub address: SocketAddr,
+    pub weight: usize,
+    /// Requests sent to this backend at the same time, across all the
+    /// replicas of the server. The scheduler skips it while at the limit.
+    pub max_requests: Option<usize>,
+    /// Only receives the requests that failed on the other backends, once
+    /// they are out of retries.
+    pub backup: bool,
+    /// Group of the backend in the `split` of the pattern.
+    pub group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum Algorithm {
+    #[serde(rename = "WRR")]
+    Wrr,
+}
+
+/// Content encodings supported for compressed responses.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "zstd")]
+    Zstd,
+}
+
+/// Compression settings for the files of the serve action and for the
+/// responses of backends that aren't compressed yet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Compression {
+    /// Allowed encodings in order of preference.
+    #[serde(default = "default::compression_algorithms")]
+    pub algorithms: Vec<Encoding>,
+    /// Responses smaller than this number of bytes are sent uncompressed.
+    /// Backend responses without `Content-Length` are always compressed.
+    #[serde(default = "default::compression_min_size")]
+    pub min_size: usize,
+    /// Compressible MIME types, `text/*` style wildcards are allowed.
+    #[serde(default = "default::compressible_types")]
+    pub mime_types: Vec<String>,
+}
+
+/// Settings of the in-memory static file cache. Entries are evicted when the
+/// underlying file changes on disk or when their TTL expires.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileCache {
+    /// Files larger than this number of bytes are always read from disk.
+    #[serde(default = "default::file_cache_max_file_size")]
+    pub max_file_size: u64,
+    /// Maximum number of cached files, the oldest entry is evicted first.
+    #[serde(default = "default::file_cache_max_entries")]
+    pub max_entries: usize,
+    /// Time after which entries are read from disk again, as in `"5m"`.
+    #[serde(default = "default::file_cache_ttl", with = "humantime_serde")]
+    pub ttl: Duration,
+    /// Cached content shared by all the replicas of the server.
+    #[serde(skip)]
+    pub store: Arc<FileStore>,
+}
+
+/// Settings for uploading and deleting files in a serve directory.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Writable {
+    /// Uploads larger than this number of bytes are rejected with 413.
+    #[serde(default = "default::writable_max_size")]
+    pub max_size: u64,
+    /// Bearer token required in the `Authorization` header, optional when
+    /// the pattern authenticates its clients otherwise.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Ceiling on the number of requests handled per second, regardless of the
+/// client. Requests over it are answered with `429 Too Many Requests`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimit {
+    #[serde(deserialize_with = "positive_rate")]
+    pub requests_per_second: f64,
+    /// Requests allowed at once after a quiet period, one second worth of
+    /// requests by default.
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// Shared by all the replicas of the server.
+    #[serde(skip)]
+    pub limiter: Arc<RateLimiter>,
+}
+
+impl RateLimit {
+    /// Counts a request against the limit, returning `false` if it's over.
+    pub fn allows(&self) -> bool {
+        self.limiter.acquire(self.requests_per_second, self.burst())
+    }
+
+    /// Same as [`RateLimit::allows`] with the bucket `client` has among
+    /// `clients`.
+    pub fn allows_client(&self, clients: &ClientLimiters, client: IpAddr) -> bool {
+        clients.acquire(client, self.requests_per_second, self.burst())
+    }
+
+    fn burst(&self) -> f64 {
+        self.burst
+            .map_or(self.requests_per_second.ceil().max(1.0), f64::from)
+    }
+}
+
+/// Floor on the rate at which clients send request bodies, against those
+/// trickling bytes to hold connections. Only the time spent waiting for the
+/// client counts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MinDataRate {
+    pub bytes_per_second: u64,
+    /// Time the client may wait before it has to keep up with the rate, as
+    /// in `"5s"`.
+    #[serde(default = "default::min_data_rate_grace", with = "humantime_serde")]
+    pub grace: Duration,
+}
+
+/// Header giving the client address of the requests of trusted proxies.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIpHeader {
+    /// `Forwarded`, the one other instances of xnav send.
+    #[default]
+    Forwarded,
+    XForwardedFor,
+}
+
+/// Bans clients that get too many error responses, such as scanners and
+/// password guessers, as traced through `trusted_proxies`. Their connections
+/// are refused until the ban ends, and the requests left on open connections
+/// or sent through proxies get `403 Forbidden`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ban {
+    /// Responses counted against the client, either exact like `"401"` or a
+    /// class like `"4xx"`. 401, 403 and 404 by default.
+    #[serde(default = "default::ban_statuses")]
+    pub statuses: Vec<StatusMatch>,
+    /// Counted responses allowed within `window` before the client is
+    /// banned.
+    pub max_errors: u32,
+    #[serde(default = "default::ban_window", with = "humantime_serde")]
+    pub window: Duration,
+    /// How long clients stay banned, as in `"10m"`.
+    #[serde(default = "default::ban_duration", with = "humantime_serde")]
+    pub duration: Duration,
+    /// Shared by all the replicas of the server.
+    #[serde(skip)]
+    pub offenders: Arc<Offenders>,
+}
+
+impl Ban {
+    /// Counts a response with `status` against `client` if it's an error,
+    /// banning it once over the limit.
+    pub fn record(&self, client: IpAddr, status: u16) {
+        if self.statuses.iter().any(|counted| counted.matches(status)) {
+            self.offenders
+                .offend(client, self.max_errors, self.window, self.duration);
+        }
+    }
+
+    /// Whether `client` is banned.
+    pub fn bans(&self, client: IpAddr) -> bool {
+        self.offenders.banned(client)
+    }
+}
+
+/// Changes made to forwarded requests.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RequestRewrite {
+    /// Values may hold `$client_ip`, `$client_port`, `$host`, `$method`,
+    /// `$path`, `$query` and `$server_addr`, which are replaced by those of
+    /// the request as received. The client is the one traced through
+    /// `trusted_proxies`, its port is the one of the peer.
+    #[serde(default)]
+    pub headers: HeaderRules,
+}
+
+/// Changes made to the responses of backends, such as removing
+/// `X-Powered-By`. `Server` is always the one of xnav.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResponseRewrite {
+    #[serde(default, deserialize_with = "response_headers")]
+    pub headers: HeaderRules,
+    /// Find and replace in the bodies of responses, like `sub_filter` of
+    /// nginx.
+    #[serde(default)]
+    pub substitute: Option<Substitution>,
+    /// Domains of the cookies set by backends replaced with public ones, as
+    /// in `{ "internal.svc" = "example.com" }`. Leading dots are ignored.
+    #[serde(default)]
+    pub cookie_domains: HashMap<String, String>,
+    /// Path prefixes of the cookies set by backends replaced with public
+    /// ones, as in `{ "/" = "/app/" }` for an app served under `/app/`.
+    /// Prefixes match whole segments and the longest one applies.
+    #[serde(default)]
+    pub cookie_paths: HashMap<String, String>,
+}
+
+/// Strings replaced in response bodies as they stream through, as in
+/// `replace = { "http://internal.svc" = "https://example.com" }`. Where
+/// several strings match at the same position the longest is replaced.
+/// Compressed and partial responses are left alone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Substitution {
+    pub replace: HashMap<String, String>,
+    /// Content types of the rewritten responses, `text/*` style wildcards
+    /// are allowed.
+    #[serde(default = "default::substitution_types")]
+    pub mime_types: Vec<String>,
+}
+
+/// Headers removed, then set and then added to a message.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HeaderRules {
+    /// Headers appended to those of the same name.
+    #[serde(default)]
+    pub add: HashMap<String, String>,
+    /// Headers replacing those of the same name.
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+impl HeaderRules {
+    /// Applies the rules to `headers`, the values of `set` and `add` going
+    /// through `expand` before anything changes. Invalid headers are
+    /// skipped.
+    pub fn apply(&self, headers: &mut http::HeaderMap, expand: impl Fn(&str) -> String) {
+        let set = self.set.iter().map(|rule| (rule, false));
+        let add = self.add.iter().map(|rule| (rule, true));
+        let changes: Vec<_> = set
+            .chain(add)
+            .map(|((name, template), append)| (name, expand(template), append))
+            .collect();
+
+        for name in &self.remove {
+            headers.remove(name.as_str());
+        }
+
+        for (name, value, append) in changes {
+            let header = http::HeaderName::try_from(name.as_str())
+                .ok()
+                .zip(http::HeaderValue::from_str(&value).ok());
+            let Some((name, value)) = header else {
+                tracing::warn!(name, value, "Invalid header in header rules");
+                continue;
+            };
+
+            if append {
+                headers.append(name, value);
+            } else {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+/// `Via` entry identifying xnav to backends and clients, as in `1.1 xnav`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Via {
+    /// Name of this proxy in the entry.
+    #[serde(default = "default::via_pseudonym")]
+    pub pseudonym: String,
+    /// Replaces the entries of previous hops instead of extending them,
+    /// which hides them from the other side.
+    #[serde(default)]
+    pub replace: bool,
+}
+
+/// Security headers added to the responses of a server that don't have
+/// them yet. Each header defaults to the one of the preset, an empty value
+/// leaves it out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityHeaders {
+    #[serde(default)]
+    pub preset: SecurityPreset,
+    /// `Strict-Transport-Security`, only sent over HTTPS by browsers.
+    #[serde(default, deserialize_with = "header_value")]
+    pub strict_transport_security: Option<String>,
+    /// `X-Content-Type-Options`.
+    #[serde(default, deserialize_with = "header_value")]
+    pub content_type_options: Option<String>,
+    /// `X-Frame-Options`.
+    #[serde(default, deserialize_with = "header_value")]
+    pub frame_options: Option<String>,
+    /// `Referrer-Policy`.
+    #[serde(default, deserialize_with = "header_value")]
+    pub referrer_policy: Option<String>,
+    /// `Content-Security-Policy`.
+    #[serde(default, deserialize_with = "header_value")]
+    pub content_security_policy: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityPreset {
+    /// Only the headers that are set.
+    None,
+    /// `nosniff`, same origin frames and `strict-origin-when-cross-origin`
+    /// referrers, which hardly ever break anything.
+    #[default]
+    Basic,
+    /// Basic with one year of HSTS including subdomains, no frames, no
+    /// referrers and a `default-src 'self'` CSP.
+    Strict,
+}
+
+/// Conditions on requests that get them refused, such as blocking path
+/// traversal attempts like `..%2f` or overly long URIs. A request matches
+/// when it meets all the conditions that are set, ignoring case.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    /// Reported along with the counters of the rule.
+    pub name: String,
+    /// Matched against the normalized path, so that encodings such as
+    /// `%2e` for `.` can't get around it.
+    #[serde(default)]
+    pub path_contains: Option<String>,
+    #[serde(default)]
+    pub query_contains: Option<String>,
+    /// Headers containing a value, an empty one only requires the header.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Length of the whole URI, query included.
+    #[serde(default)]
+    pub uri_longer_than: Option<usize>,
+    /// Size declared by the request for its body.
+    #[serde(default)]
+    pub body_larger_than: Option<u64>,
+    pub action: RuleAction,
+    /// Shared by all the replicas of the server.
+    #[serde(skip)]
+    pub counters: Arc<RuleCounters>,
+    /// Buckets of the clients for the `rate_limit` action, shared as well.
+    #[serde(skip)]
+    pub clients: Arc<ClientLimiters>,
+}
+
+/// What happens to the requests matching a rule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Refused with `403 Forbidden`.
+    Block,
+    /// Refused with `429 Too Many Requests` once over the limit, which
+    /// applies to each client apart, as traced through `trusted_proxies`.
+    RateLimit(RateLimit),
+}
+
+/// Ceiling on the rate at which response bodies are sent, for fair sharing
+/// of constrained links.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bandwidth {
+    pub bytes_per_second: u64,
+    /// Bytes sent at full speed after a quiet period, one second worth of
+    /// bytes by default.
+    #[serde(default)]
+    pub burst: Option<u64>,
+    #[serde(skip)]
+    pub limiter: Arc<RateLimiter>,
+}
+
+impl Bandwidth {
+    /// Counts `bytes` as sent, returning how long to wait before sending
+    /// more.
+    pub fn consume(&self, bytes: usize) -> Duration {
+        let rate = self.bytes_per_second.max(1) as f64;
+        let burst = self.burst.unwrap_or(self.bytes_per_second).max(1) as f64;
+
+        self.limiter.borrow(bytes as f64, rate, burst)
+    }
 
-/// Main configuration structs based on TOML config file.
+    /// Same ceiling with a bucket of its own.
+    pub fn unshared(&self) -> Self {
+        Self {
+            limiter: Arc::default(),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+}
+
+/// Weights of the groups of backends of a pattern, which can be changed at
+/// runtime through the admin listener.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Config {
-    /// List of all servers.
-    #[serde(rename = "server")]
-    pub servers: Vec<Server>,
+#[serde(from = "BTreeMap<String, u32>", into = "BTreeMap<String, u32>")]
+pub struct Split {
+    /// Shared by all the replicas of the server.
+    pub splitter: Arc<Splitter>,
 }
 
-#[derive(Serialize, Debug, Clone)]
-pub struct Server {
-    pub listen: Vec<SocketAddr>,
-    #[serde(rename = "match")]
-    pub patterns: Vec<Pattern>,
-    #[serde(default = "default::max_connections")]
-    pub max_connections: usize,
-    pub name: Option<String>,
-    #[serde(skip)]
-    pub log_name: String,
+impl From<BTreeMap<String, u32>> for Split {
+    fn from(weights: BTreeMap<String, u32>) -> Self {
+        Self {
+            splitter: Arc::new(Splitter::new(&weights)),
+        }
+    }
+}
+
+impl From<Split> for BTreeMap<String, u32> {
+    fn from(split: Split) -> Self {
+        split.splitter.weights()
+    }
 }
 
+/// Where buffered request bodies are kept.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Pattern {
-    #[serde(default = "default::uri")]
-    pub uri: String,
-    #[serde(flatten)]
-    pub action: Action,
+pub struct RequestBuffering {
+    /// Bodies larger than this number of bytes are written to a temporary
+    /// file instead of being kept in memory.
+    #[serde(default = "default::request_buffering_memory")]
+    pub memory: u64,
+    /// Directory of the temporary files, the one of the system by default.
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
 }
 
+/// Limits of the responses read in full before being sent.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(from = "BackendOption")]
-pub struct Backend {
-    pub address: SocketAddr,
-    pub weight: usize,
+pub struct ResponseBuffering {
+    /// Responses larger than this number of bytes are streamed from disk,
+    /// going through a temporary file when they have to be transformed.
+    #[serde(default = "default::response_buffering_memory")]
+    pub memory: u64,
+    /// Directory of the temporary files, the one of the system by default.
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub enum Algorithm {
-    #[serde(rename = "WRR")]
-    Wrr,
+impl Default for ResponseBuffering {
+    fn default() -> Self {
+        Self {
+            memory: default::response_buffering_memory(),
+            directory: None,
+        }
+    }
+}
+
+/// Bounds of the queue of requests waiting for a backend. Requests that
+/// find it full or wait for too long get a `503 Service Unavailable`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Queue {
+    /// Requests waiting at the same time, across all the replicas.
+    pub depth: usize,
+    /// Time a request may wait, as in `"500ms"`.
+    #[serde(default = "default::queue_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+/// Selects files that browsers should download instead of displaying. When
+/// both lists are empty all the files of the pattern are downloads.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Download {
+    /// File extensions such as `"zip"` or `"tar.gz"`.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Path prefixes relative to the serve directory such as `"releases/"`.
+    #[serde(default)]
+    pub paths: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
-#[serde(from = "ForwardOption")]
+#[serde(try_from = "ForwardOption")]
 pub struct Forward {
     pub backends: Vec<Backend>,
     pub algorithm: Algorithm,
     #[serde(skip)]
     pub scheduler: Box<dyn Scheduler + Sync + Send>,
+    /// Scheduler of the backup backends, if there are any.
+    #[serde(skip)]
+    pub backup_scheduler: Option<Box<dyn Scheduler + Sync + Send>>,
+    /// Requests routed to each backend.
+    #[serde(skip)]
+    pub picks: Arc<Picks>,
+    /// Connections opened ahead of time, see `warm_connections`.
+    #[serde(skip)]
+    pub warm: Arc<Warm>,
 }
 
 impl std::fmt::Debug for Forward {
@@ -66,10 +1716,14 @@ impl std::fmt::Debug for Forward {
 
 impl Clone for Forward {
     fn clone(&self) -> Self {
+        let (scheduler, backup_scheduler) = threading::make_tiers(self.algorithm, &self.backends);
         Self {
             backends: self.backends.clone(),
             algorithm: self.algorithm.clone(),
-            scheduler: threading::make(self.algorithm, &self.backends),
+            scheduler,
+            backup_scheduler,
+            picks: self.picks.clone(),
+            warm: self.warm.clone(),
         }
     }
 }
@@ -84,13 +1738,231 @@ pub enum Action {
 mod default {
     //! Default values for some configuration options.
 
+    use std::{collections::HashMap, time::Duration};
+
     pub fn uri() -> String {
         String::from("/")
     }
 
+    pub fn weight() -> usize {
+        1
+    }
+
+    pub fn queue_timeout() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    pub fn load_interval() -> Duration {
+        Duration::from_millis(100)
+    }
+
     pub fn max_connections() -> usize {
         1024
     }
+
+    pub fn header_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    pub fn idle_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub fn realm() -> String {
+        String::from("xnav")
+    }
+
+    pub fn signature_header() -> String {
+        String::from("x-signature")
+    }
+
+    pub fn signature_canonical() -> String {
+        String::from("{body}")
+    }
+
+    pub fn signature_tolerance() -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+
+    pub fn oidc_scopes() -> Vec<String> {
+        ["openid", "email", "profile"].map(String::from).to_vec()
+    }
+
+    pub fn oidc_claims() -> HashMap<String, String> {
+        HashMap::from([
+            (String::from("sub"), String::from("x-forwarded-user")),
+            (String::from("email"), String::from("x-forwarded-email")),
+        ])
+    }
+
+    pub fn oidc_cookie() -> String {
+        String::from("xnav_session")
+    }
+
+    pub fn oidc_session_ttl() -> Duration {
+        Duration::from_secs(8 * 60 * 60)
+    }
+
+    pub fn oidc_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn challenge_ttl() -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+
+    pub fn substitution_types() -> Vec<String> {
+        vec![String::from("text/html")]
+    }
+
+    pub fn via_pseudonym() -> String {
+        String::from("xnav")
+    }
+
+    pub fn country_header() -> String {
+        String::from("x-country-code")
+    }
+
+    pub fn api_key_header() -> String {
+        String::from("x-api-key")
+    }
+
+    pub fn forward_auth_request_headers() -> Vec<String> {
+        vec![String::from("cookie"), String::from("authorization")]
+    }
+
+    pub fn forward_auth_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    pub fn ban_statuses() -> Vec<super::StatusMatch> {
+        [401, 403, 404].map(super::StatusMatch::Exact).to_vec()
+    }
+
+    pub fn ban_window() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub fn ban_duration() -> Duration {
+        Duration::from_secs(600)
+    }
+
+    pub fn min_data_rate_grace() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    pub fn statsd_prefix() -> String {
+        String::from("xnav")
+    }
+
+    pub fn status_path() -> String {
+        String::from("/status")
+    }
+
+    pub fn captures_path() -> String {
+        String::from("/captures")
+    }
+
+    pub fn request_buffering_memory() -> u64 {
+        1024 * 1024
+    }
+
+    pub fn response_buffering_memory() -> u64 {
+        8 * 1024 * 1024
+    }
+
+    pub fn split_path() -> String {
+        String::from("/split")
+    }
+
+    pub fn purge_path() -> String {
+        String::from("/purge")
+    }
+
+    pub fn capture_entries() -> usize {
+        100
+    }
+
+    pub fn facility() -> super::Facility {
+        super::Facility::Daemon
+    }
+
+    pub fn enabled() -> bool {
+        true
+    }
+
+    pub fn gelf_chunk_size() -> usize {
+        1420
+    }
+
+    pub fn unavailable_content_type() -> String {
+        String::from("text/html")
+    }
+
+    pub fn intercepted_statuses() -> Vec<super::StatusMatch> {
+        vec![
+            super::StatusMatch::Exact(502),
+            super::StatusMatch::Exact(504),
+        ]
+    }
+
+    pub fn access_log_level() -> super::LogLevel {
+        super::LogLevel::Info
+    }
+
+    pub fn error_level() -> String {
+        String::from("warn")
+    }
+
+    pub fn service_name() -> String {
+        String::from("xnav")
+    }
+
+    pub fn rotation() -> super::Rotation {
+        super::Rotation::Never
+    }
+
+    pub fn max_log_files() -> usize {
+        7
+    }
+
+    pub fn file_cache_max_file_size() -> u64 {
+        64 * 1024
+    }
+
+    pub fn file_cache_max_entries() -> usize {
+        1024
+    }
+
+    pub fn file_cache_ttl() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub fn writable_max_size() -> u64 {
+        10 * 1024 * 1024
+    }
+
+    pub fn compression_algorithms() -> Vec<super::Encoding> {
+        vec![super::Encoding::Brotli, super::Encoding::Gzip]
+    }
+
+    pub fn compression_min_size() -> usize {
+        1024
+    }
+
+    pub fn compressible_types() -> Vec<String> {
+        [
+            "text/*",
+            "application/javascript",
+            "application/json",
+            "application/xml",
+            "application/wasm",
+            "image/svg+xml",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -117,20 +1989,104 @@ where
     Ok(OneOrMany::deserialize(deserializer)?.into())
 }
 
+/// Rate that is refused at load time unless above zero, as nothing would
+/// ever be allowed.
+fn positive_rate<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let rate = f64::deserialize(deserializer)?;
+    if !(rate > 0.0 && rate.is_finite()) {
+        return Err(serde::de::Error::custom(format!(
+            "rate must be a positive number, got {rate}"
+        )));
+    }
+
+    Ok(rate)
+}
+
+/// Header rules of responses, refused when they touch `Server`, which xnav
+/// always replaces.
+fn response_headers<'de, D>(deserializer: D) -> Result<HeaderRules, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let rules = HeaderRules::deserialize(deserializer)?;
+    let server = rules
+        .add
+        .keys()
+        .chain(rules.set.keys())
+        .chain(&rules.remove)
+        .any(|name| name.eq_ignore_ascii_case("server"));
+    if server {
+        return Err(serde::de::Error::custom(
+            "the 'server' header of responses can't be changed",
+        ));
+    }
+
+    Ok(rules)
+}
+
+/// Header value that is refused at load time rather than on every response
+/// when it can't be sent.
+fn header_value<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    let invalid = value
+        .as_ref()
+        .filter(|value| http::HeaderValue::from_str(value).is_err());
+    if let Some(value) = invalid {
+        return Err(serde::de::Error::custom(format!(
+            "invalid header value {value:?}"
+        )));
+    }
+
+    Ok(value)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 enum BackendOption {
     Simple(SocketAddr),
-    Weighted { address: SocketAddr, weight: usize },
+    Weighted {
+        address: SocketAddr,
+        #[serde(default = "default::weight")]
+        weight: usize,
+        #[serde(default)]
+        max_requests: Option<usize>,
+        #[serde(default)]
+        backup: bool,
+        #[serde(default)]
+        group: Option<String>,
+    },
 }
 
 impl From<BackendOption> for Backend {
     fn from(value: BackendOption) -> Self {
-        let (address, weight) = match value {
-            BackendOption::Simple(address) => (address, 1),
-            BackendOption::Weighted { address, weight } => (address, weight),
-        };
-        Self { address, weight }
+        match value {
+            BackendOption::Simple(address) => Self {
+                address,
+                weight: default::weight(),
+                max_requests: None,
+                backup: false,
+                group: None,
+            },
+            BackendOption::Weighted {
+                address,
+                weight,
+                max_requests,
+                backup,
+                group,
+            } => Self {
+                address,
+                weight,
+                max_requests,
+                backup,
+                group,
+            },
+        }
     }
 }
 
@@ -145,8 +2101,10 @@ enum ForwardOption {
     },
 }
 
-impl From<ForwardOption> for Forward {
-    fn from(value: ForwardOption) -> Self {
+impl TryFrom<ForwardOption> for Forward {
+    type Error = &'static str;
+
+    fn try_from(value: ForwardOption) -> Result<Self, Self::Error> {
         let (backends, algorithm) = match value {
             ForwardOption::Simple(backends) => (backends, Algorithm::Wrr),
             ForwardOption::WithAlgorithm {
@@ -154,12 +2112,27 @@ impl From<ForwardOption> for Forward {
                 backends,
             } => (backends, algorithm),
         };
-        let scheduler = threading::make(algorithm, &backends);
-        Self {
+
+        // Schedulers of tiers without weights would have nothing to pick.
+        let (backups, primaries): (Vec<_>, Vec<_>) =
+            backends.iter().partition(|backend| backend.backup);
+        let unweighted =
+            |tier: &[&Backend]| !tier.is_empty() && tier.iter().all(|backend| backend.weight == 0);
+        if unweighted(&primaries) || unweighted(&backups) {
+            return Err("at least one backend, and one backup if any, needs a weight");
+        }
+
+        let (scheduler, backup_scheduler) = threading::make_tiers(algorithm, &backends);
+        let picks = Arc::new(Picks::new(&backends));
+        let warm = Arc::new(Warm::new(&backends));
+        Ok(Self {
             backends,
             algorithm,
             scheduler,
-        }
+            backup_scheduler,
+            picks,
+            warm,
+        })
     }
 }
 
@@ -184,12 +2157,50 @@ enum Field {
     Uri,
     Name,
     Connections,
+    #[serde(rename = "max_requests")]
+    MaxRequests,
+    #[serde(rename = "header_timeout")]
+    HeaderTimeout,
+    #[serde(rename = "idle_timeout")]
+    IdleTimeout,
+    #[serde(rename = "drain_timeout")]
+    DrainTimeout,
+    #[serde(rename = "max_body_size")]
+    MaxBodySize,
+    #[serde(rename = "rate_limit")]
+    RateLimit,
+    Bandwidth,
+    #[serde(rename = "min_data_rate")]
+    MinDataRate,
+    Ban,
+    #[serde(rename = "trusted_proxies")]
+    TrustedProxies,
+    Allow,
+    Deny,
+    #[serde(rename = "security_headers")]
+    SecurityHeaders,
+    Rule,
+    #[serde(rename = "strict_http")]
+    StrictHttp,
+    #[serde(rename = "client_ip_header")]
+    ClientIpHeader,
+    #[serde(rename = "via")]
+    Via,
+    #[serde(rename = "real_ip")]
+    RealIp,
+    #[serde(rename = "x_forwarded")]
+    XForwarded,
 }
 
 enum Error {
     MixedSimpleAndMatch,
     MixedActions,
     MissingConfig,
+    MixedSplitAndActive,
+    OpenWritable,
+    ServedSignature,
+    UnknownActiveGroup,
+    ZeroSplit,
 }
 
 impl std::fmt::Display for Error {
@@ -202,6 +2213,13 @@ impl std::fmt::Display for Error {
                 "use either 'forward' or 'serve', if you need multiple patterns use 'match'"
             }
             Error::MissingConfig => "missing 'match' or simple configuration",
+            Error::MixedSplitAndActive => "use either 'split' or 'active' to pick a group",
+            Error::OpenWritable => {
+                "'serve_writable' needs a 'token', or 'auth', 'oidc' or 'forward_auth'"
+            }
+            Error::ServedSignature => "'signature' only applies to 'forward' patterns",
+            Error::UnknownActiveGroup => "no backend belongs to the 'active' group",
+            Error::ZeroSplit => "at least one group of 'split' needs a weight",
         };
         f.write_str(message)
     }
@@ -223,6 +2241,25 @@ impl<'de> serde::de::Visitor<'de> for ServerVisitor {
         let mut simple_pattern: Option<Pattern> = None;
         let mut name = None;
         let mut max_connections = default::max_connections();
+        let mut max_requests = None;
+        let mut header_timeout = default::header_timeout();
+        let mut idle_timeout = default::idle_timeout();
+        let mut drain_timeout = None;
+        let mut max_body_size = None;
+        let mut rate_limit = None;
+        let mut bandwidth = None;
+        let mut min_data_rate = None;
+        let mut ban = None;
+        let mut trusted_proxies = Vec::new();
+        let mut allow = Vec::new();
+        let mut deny = Vec::new();
+        let mut security_headers = None;
+        let mut rules = Vec::new();
+        let mut strict_http = false;
+        let mut client_ip_header = ClientIpHeader::default();
+        let mut via = None;
+        let mut real_ip = false;
+        let mut x_forwarded = false;
         let mut uri = default::uri();
 
         while let Some(key) = map.next_key()? {
@@ -256,10 +2293,10 @@ impl<'de> serde::de::Visitor<'de> for ServerVisitor {
                             }
                         }
                     }
-                    simple_pattern = Some(Pattern {
-                        uri: default::uri(),
-                        action: Action::Forward(map.next_value()?),
-                    });
+                    simple_pattern = Some(Pattern::new(
+                        default::uri(),
+                        Action::Forward(map.next_value()?),
+                    ));
                 }
                 Field::Serve => {
                     if !patterns.is_empty() {
@@ -275,10 +2312,10 @@ impl<'de> serde::de::Visitor<'de> for ServerVisitor {
                             }
                         }
                     }
-                    simple_pattern = Some(Pattern {
-                        uri: default::uri(),
-                        action: Action::Serve(map.next_value()?),
-                    });
+                    simple_pattern = Some(Pattern::new(
+                        default::uri(),
+                        Action::Serve(map.next_value()?),
+                    ));
                 }
                 Field::Uri => {
                     if !patterns.is_empty() {
@@ -295,6 +2332,70 @@ impl<'de> serde::de::Visitor<'de> for ServerVisitor {
                 Field::Connections => {
                     max_connections = map.next_value()?;
                 }
+                Field::MaxRequests => {
+                    max_requests = Some(map.next_value()?);
+                }
+                Field::HeaderTimeout => {
+                    header_timeout = map
+                        .next_value::<humantime_serde::Serde<Duration>>()?
+                        .into_inner();
+                }
+                Field::RateLimit => {
+                    rate_limit = Some(map.next_value()?);
+                }
+                Field::Bandwidth => {
+                    bandwidth = Some(map.next_value()?);
+                }
+                Field::MinDataRate => {
+                    min_data_rate = Some(map.next_value()?);
+                }
+                Field::Ban => {
+                    ban = Some(map.next_value()?);
+                }
+                Field::TrustedProxies => {
+                    trusted_proxies = map.next_value()?;
+                }
+                Field::Allow => {
+                    allow = map.next_value()?;
+                }
+                Field::Deny => {
+                    deny = map.next_value()?;
+                }
+                Field::SecurityHeaders => {
+                    security_headers = Some(map.next_value()?);
+                }
+                Field::Rule => {
+                    rules = map.next_value()?;
+                }
+                Field::StrictHttp => {
+                    strict_http = map.next_value()?;
+                }
+                Field::ClientIpHeader => {
+                    client_ip_header = map.next_value()?;
+                }
+                Field::Via => {
+                    via = Some(map.next_value()?);
+                }
+                Field::RealIp => {
+                    real_ip = map.next_value()?;
+                }
+                Field::XForwarded => {
+                    x_forwarded = map.next_value()?;
+                }
+                Field::MaxBodySize => {
+                    max_body_size = Some(map.next_value()?);
+                }
+                Field::IdleTimeout => {
+                    idle_timeout = map
+                        .next_value::<humantime_serde::Serde<Duration>>()?
+                        .into_inner();
+                }
+                Field::DrainTimeout => {
+                    drain_timeout = Some(
+                        map.next_value::<humantime_serde::Serde<Duration>>()?
+                            .into_inner(),
+                    );
+                }
             }
         }
 
@@ -311,12 +2412,234 @@ impl<'de> serde::de::Visitor<'de> for ServerVisitor {
             return Err(serde::de::Error::missing_field("listen"));
         }
 
+        for pattern in &mut patterns {
+            pattern.max_body_size = pattern.max_body_size.or(max_body_size);
+
+            // Anyone could change the files otherwise.
+            let open = pattern
+                .serve_writable
+                .as_ref()
+                .is_some_and(|writable| writable.token.is_none());
+            let authenticated =
+                pattern.auth.is_some() || pattern.oidc.is_some() || pattern.forward_auth.is_some();
+            if open && !authenticated {
+                return Err(serde::de::Error::custom(Error::OpenWritable));
+            }
+
+            // Served files are never checked, the signature would do nothing.
+            if pattern.signature.is_some() && matches!(pattern.action, Action::Serve(_)) {
+                return Err(serde::de::Error::custom(Error::ServedSignature));
+            }
+
+            if let (Some(active), Action::Forward(forward)) = (&pattern.active, &pattern.action) {
+                if pattern.split.is_some() {
+                    return Err(serde::de::Error::custom(Error::MixedSplitAndActive));
+                }
+                let weights: BTreeMap<_, _> = forward
+                    .backends
+                    .iter()
+                    .filter_map(|backend| backend.group.clone())
+                    .map(|group| {
+                        let weight = u32::from(&group == active);
+                        (group, weight)
+                    })
+                    .collect();
+                if !weights.contains_key(active) {
+                    return Err(serde::de::Error::custom(Error::UnknownActiveGroup));
+                }
+                pattern.split = Some(Split::from(weights));
+            }
+
+            // Nothing could be picked, every request would fail.
+            let zero = pattern
+                .split
+                .as_ref()
+                .is_some_and(|split| split.splitter.weights().values().all(|&weight| weight == 0));
+            if zero {
+                return Err(serde::de::Error::custom(Error::ZeroSplit));
+            }
+        }
+
         Ok(Server {
             listen,
             patterns,
             max_connections,
+            max_requests,
+            requests: Arc::default(),
+            header_timeout,
+            idle_timeout,
+            drain_timeout,
+            max_body_size,
+            rate_limit,
+            bandwidth,
+            min_data_rate,
+            ban,
+            trusted_proxies,
+            allow,
+            deny,
+            geoip: None,
+            security_headers,
+            rules,
+            strict_http,
+            client_ip_header,
+            via,
+            real_ip,
+            x_forwarded,
             name,
             log_name: String::from("unnamed"),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unusable_splits() {
+        let server = |pattern: &str| {
+            toml::from_str::<Server>(&format!(
+                r#"
+                    listen = ["127.0.0.1:8080"]
+
+                    [[match]]
+                    uri = "/"
+                    forward = [
+                        {{ address = "127.0.0.1:8081", group = "stable" }},
+                        {{ address = "127.0.0.1:8082", group = "canary" }},
+                    ]
+                    {pattern}
+                "#
+            ))
+        };
+
+        assert!(server(r#"active = "canary""#).is_ok());
+        assert!(server(r#"split = { stable = 1, canary = 0 }"#).is_ok());
+        assert!(server(r#"active = "beta""#).is_err());
+        assert!(server(r#"split = { stable = 0, canary = 0 }"#).is_err());
+    }
+
+    #[test]
+    fn rejects_unweighted_backends() {
+        let server = |backends: &str| {
+            toml::from_str::<Server>(&format!(
+                r#"
+                    listen = ["127.0.0.1:8080"]
+                    forward = [{backends}]
+                "#
+            ))
+        };
+
+        assert!(server(r#"{ address = "127.0.0.1:8081", weight = 0 }, "127.0.0.1:8082""#).is_ok());
+        assert!(server(r#"{ address = "127.0.0.1:8081", weight = 0 }"#).is_err());
+        assert!(server(
+            r#""127.0.0.1:8081", { address = "127.0.0.1:8082", weight = 0, backup = true }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_open_writable_patterns() {
+        let server = |extra: &str| {
+            toml::from_str::<Server>(&format!(
+                r#"
+                    listen = ["127.0.0.1:8080"]
+
+                    [[match]]
+                    uri = "/"
+                    serve = "/var/www"
+                    {extra}
+                "#
+            ))
+        };
+
+        assert!(server(r#"serve_writable = { token = "secret" }"#).is_ok());
+        assert!(
+            server("serve_writable = {}\nforward_auth = { address = \"127.0.0.1:4180\" }").is_ok()
+        );
+        assert!(server("serve_writable = {}").is_err());
+    }
+
+    #[test]
+    fn rejects_signatures_on_served_patterns() {
+        let server = |action: &str| {
+            toml::from_str::<Server>(&format!(
+                r#"
+                    listen = ["127.0.0.1:8080"]
+
+                    [[match]]
+                    uri = "/"
+                    {action}
+                    signature = {{ secret = "secret" }}
+                "#
+            ))
+        };
+
+        assert!(server(r#"forward = "127.0.0.1:8081""#).is_ok());
+        assert!(server(r#"serve = "/var/www""#).is_err());
+    }
+
+    #[test]
+    fn inherits_upstreams() {
+        let mut config = toml::from_str::<Config>(
+            r#"
+                [upstream.api]
+                retries = 2
+                retry_buffer = 4096
+                request_buffering = { memory = 1024 }
+                deadline = "30s"
+
+                [[server]]
+                listen = ["127.0.0.1:8080"]
+
+                [[server.match]]
+                uri = "/api"
+                forward = "127.0.0.1:8081"
+                upstream = "api"
+                retries = 1
+
+                [[server.match]]
+                uri = "/"
+                forward = "127.0.0.1:8082"
+            "#,
+        )
+        .unwrap();
+        config.inherit_upstreams().unwrap();
+
+        let [api, other] = &config.servers[0].patterns[..] else {
+            panic!("expected two patterns");
+        };
+        assert_eq!(api.retries(), 1);
+        assert_eq!(api.retry_buffer(), 4096);
+        assert_eq!(api.request_buffering.as_ref().unwrap().memory, 1024);
+        assert_eq!(api.deadline, Some(Duration::from_secs(30)));
+        assert_eq!(other.retry_buffer(), 0);
+        assert!(other.request_buffering.is_none());
+
+        config.servers[0].patterns[1].upstream = Some(String::from("web"));
+        assert!(config.inherit_upstreams().is_err());
+    }
+
+    #[test]
+    fn rejects_unusable_rates() {
+        let rate =
+            |rate: &str| toml::from_str::<RateLimit>(&format!("requests_per_second = {rate}"));
+
+        assert!(rate("0.5").is_ok());
+        assert!(rate("10").is_ok());
+        assert!(rate("0").is_err());
+        assert!(rate("-1.0").is_err());
+        assert!(rate("nan").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_security_headers() {
+        let security =
+            |value: &str| toml::from_str::<SecurityHeaders>(&format!("frame_options = {value:?}"));
+
+        assert!(security("SAMEORIGIN").is_ok());
+        assert!(security("").is_ok());
+        assert!(security("DENY\n").is_err());
+        assert!(toml::from_str::<SecurityHeaders>("preset = \"strict\"").is_ok());
+    }
+}
@@ -1,3 +1,12 @@
 //! Structs and enums derived from the config file using [`serde`].
 mod config;
-pub use config::{Action, Algorithm, Backend, Config, Forward, Pattern, Server};
+pub use config::{
+    Action, Admin, Algorithm, ApiKeyAuth, Auth, Backend, Ban, Bandwidth, BasicAuth, Capture,
+    Challenge, ChallengeMode, ClientIpHeader, Compression, Config, Csrf, Download, Encoding,
+    ErrorLog, Exclusion, Facility, FileCache, Forward, ForwardAuth, Gelf, HeaderRules,
+    LoadShedding, Log, LogFile, LogFormat, LogLevel, Metrics, MinDataRate, Oidc, Otlp, Pattern,
+    Priority, Queue, RateLimit, RequestBuffering, RequestRewrite, ResponseBuffering,
+    ResponseRewrite, RetryOn, Rotation, Rule, RuleAction, Secret, SecurityHeaders, SecurityPreset,
+    Server, Signature, SignatureAlgorithm, SignatureEncoding, Split, Statsd, StatusMatch,
+    Substitution, Syslog, Unavailable, Upstream, Via, Writable,
+};
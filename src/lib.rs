@@ -4,6 +4,8 @@
 #![feature(is_some_and)]
 
 pub mod config;
+pub mod logging;
+pub mod metrics;
 pub mod server;
 pub mod service;
 pub mod sync;
@@ -12,10 +14,12 @@ pub mod threading;
 use std::io;
 
 pub use config::{Action, Algorithm, Backend, Config, Forward, Pattern, Server};
-pub use server::{Master, Server as ServerInstance, ShutdownState, State};
-pub use service::{BoxBodyResponse, LocalResponse, ProxyResponse};
+pub use server::{
+    Master, Server as ServerInstance, ShutdownEvent, ShutdownEvents, ShutdownState, Snapshot, State,
+};
+pub use service::{BoxBodyResponse, LocalResponse, ProxyResponse, RequestHook, RequestInfo};
 pub use sync::{Notification, Notifier, Subscription};
-pub use threading::{make as make_scheduler, Scheduler, WeightedRoundRobin};
+pub use threading::{make as make_scheduler, Decision, Scheduler, WeightedRoundRobin};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -30,6 +34,12 @@ pub enum Error {
 
     /// Error while processing HTTP requests.
     Http(hyper::Error),
+
+    /// The logging subsystem could not be initialized.
+    Logging(String),
+
+    /// The config file parsed but refers to something it doesn't define.
+    Config(String),
 }
 
 impl std::error::Error for Error {}
@@ -40,6 +50,8 @@ impl std::fmt::Display for Error {
             Error::Io(err) => write!(f, "IO error: {err}"),
             Error::Toml(err) => write!(f, "TOML parse error: {err}"),
             Error::Http(err) => write!(f, "HTTP error: {err}"),
+            Error::Logging(err) => write!(f, "Logging error: {err}"),
+            Error::Config(err) => write!(f, "Config error: {err}"),
         }
     }
 }
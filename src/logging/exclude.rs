@@ -0,0 +1,127 @@
+//! Rules leaving requests out of the access log.
+
+use std::sync::OnceLock;
+
+use hyper::HeaderMap;
+
+use crate::config::Exclusion;
+
+/// Rules configured at startup.
+static RULES: OnceLock<Vec<Exclusion>> = OnceLock::new();
+
+pub(super) fn init(rules: &[Exclusion]) {
+    let _ = RULES.set(rules.to_vec());
+}
+
+/// Rules whose request criteria matched, the status is only known once the
+/// response is ready.
+pub struct Exclusions<'a>(Vec<&'a Exclusion>);
+
+/// Checks the request criteria of every configured rule against a request.
+pub fn exclusions(path: &str, headers: &HeaderMap) -> Exclusions<'static> {
+    matching(
+        RULES.get().map(Vec::as_slice).unwrap_or_default(),
+        path,
+        headers,
+    )
+}
+
+impl Exclusions<'_> {
+    /// Whether the request is left out of the access log given its status.
+    pub fn excludes(&self, status: u16) -> bool {
+        self.0.iter().any(|rule| {
+            rule.status
+                .as_ref()
+                .is_none_or(|expected| expected.matches(status))
+        })
+    }
+}
+
+fn matching<'a>(rules: &'a [Exclusion], path: &str, headers: &HeaderMap) -> Exclusions<'a> {
+    Exclusions(
+        rules
+            .iter()
+            .filter(|rule| matches_request(rule, path, headers))
+            .collect(),
+    )
+}
+
+fn matches_request(rule: &Exclusion, path: &str, headers: &HeaderMap) -> bool {
+    let path_matches = rule
+        .path
+        .as_ref()
+        .is_none_or(|prefix| path.starts_with(prefix.as_str()));
+
+    let header_matches = rule.header.as_ref().is_none_or(|name| {
+        let value = headers.get(name.as_str());
+        match &rule.header_value {
+            Some(expected) => value.is_some_and(|value| value == expected.as_str()),
+            None => value.is_some(),
+        }
+    });
+
+    path_matches && header_matches
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+    use crate::config::StatusMatch;
+
+    fn rule(path: Option<&str>, status: Option<&str>, header: Option<(&str, &str)>) -> Exclusion {
+        Exclusion {
+            path: path.map(String::from),
+            status: status.map(|status| StatusMatch::try_from(status.to_owned()).unwrap()),
+            header: header.map(|(name, _)| String::from(name)),
+            header_value: header
+                .map(|(_, value)| String::from(value))
+                .filter(|value| !value.is_empty()),
+        }
+    }
+
+    #[test]
+    fn status_match() {
+        assert_eq!(
+            StatusMatch::try_from(String::from("2XX")),
+            Ok(StatusMatch::Class(2))
+        );
+        assert_eq!(
+            StatusMatch::try_from(String::from("404")),
+            Ok(StatusMatch::Exact(404))
+        );
+        assert!(StatusMatch::try_from(String::from("7xx")).is_err());
+        assert!(StatusMatch::try_from(String::from("42")).is_err());
+        assert!(StatusMatch::Class(2).matches(204));
+        assert!(!StatusMatch::Class(2).matches(304));
+    }
+
+    #[test]
+    fn all_criteria_must_match() {
+        let rules = [
+            rule(Some("/healthz"), Some("2xx"), None),
+            rule(None, None, Some(("user-agent", "kube-probe"))),
+        ];
+        let [healthz, probe] = &rules;
+        let headers = HeaderMap::new();
+
+        let exclusions = matching(&rules, "/healthz/live", &headers);
+
+        assert!(exclusions.excludes(200));
+        assert!(!exclusions.excludes(503));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("user-agent", HeaderValue::from_static("kube-probe"));
+        assert!(matches_request(probe, "/", &headers));
+        assert!(!matches_request(healthz, "/", &headers));
+
+        headers.insert("user-agent", HeaderValue::from_static("curl"));
+        assert!(!matches_request(probe, "/", &headers));
+        assert!(matches_request(
+            &rule(None, None, Some(("user-agent", ""))),
+            "/",
+            &headers
+        ));
+    }
+}
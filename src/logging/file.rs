@@ -0,0 +1,134 @@
+//! Log files rotated by size and/or time.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::{LogFile, Rotation};
+
+/// Append-only log file. When the file is rotated it's renamed to `path.1`,
+/// previous rotations are shifted to `path.2`, `path.3` and so on, dropping
+/// everything above the configured number of retained files.
+pub struct RollingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    rotation: Rotation,
+    max_files: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    period: u64,
+}
+
+impl RollingFile {
+    /// Opens or creates the log file described by `config`.
+    pub fn open(config: &LogFile) -> Result<Self, io::Error> {
+        if let Some(parent) = config.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = append(&config.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: config.path.clone(),
+            max_size: config.max_size,
+            rotation: config.rotation,
+            max_files: config.max_files,
+            state: Mutex::new(State {
+                file,
+                size,
+                period: period(config.rotation),
+            }),
+        })
+    }
+
+    fn write_line(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        let current_period = period(self.rotation);
+        let exceeds_size = self
+            .max_size
+            .is_some_and(|max_size| state.size > 0 && state.size + buf.len() as u64 > max_size);
+
+        if exceeds_size || current_period != state.period {
+            state.file.flush()?;
+            self.rotate()?;
+            state.file = append(&self.path)?;
+            state.size = 0;
+            state.period = current_period;
+        }
+
+        let written = state.file.write(buf)?;
+        state.size += written as u64;
+
+        Ok(written)
+    }
+
+    /// Shifts all the rotated files by one position.
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+
+        let rotated = |index: usize| PathBuf::from(format!("{}.{index}", self.path.display()));
+
+        let _ = fs::remove_file(rotated(self.max_files));
+
+        for index in (1..self.max_files).rev() {
+            let from = rotated(index);
+            if from.exists() {
+                fs::rename(from, rotated(index + 1))?;
+            }
+        }
+
+        fs::rename(&self.path, rotated(1))
+    }
+}
+
+impl io::Write for &RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_line(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RollingFile {
+    type Writer = &'a RollingFile;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Index of the current rotation period, files are rotated when it changes.
+fn period(rotation: Rotation) -> u64 {
+    let seconds = match rotation {
+        Rotation::Never => return 0,
+        Rotation::Minutely => 60,
+        Rotation::Hourly => 60 * 60,
+        Rotation::Daily => 24 * 60 * 60,
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+
+    now / seconds
+}
@@ -0,0 +1,342 @@
+//! Log forwarding in the Graylog Extended Log Format over UDP or TCP.
+
+use std::{
+    io,
+    sync::mpsc::{self, SyncSender},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use super::{parse_address, severity, Address, Transport};
+use crate::config::Gelf;
+
+/// Magic bytes at the start of every chunk.
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+
+/// Magic bytes, message id, sequence number and sequence count.
+const CHUNK_HEADER: usize = 12;
+
+/// Receivers discard messages split into more chunks than this.
+const MAX_CHUNKS: usize = 128;
+
+/// Messages waiting to be sent. Further ones are dropped rather than holding
+/// up the threads that log while the GELF input is slow or unreachable.
+const QUEUED: usize = 1024;
+
+/// Layer sending each event as a GELF message. Fields of the event and its
+/// spans become additional fields of the message.
+///
+/// Messages are handed to a background thread that does the network I/O.
+pub struct GelfLayer {
+    host: String,
+    messages: SyncSender<Vec<u8>>,
+}
+
+/// Sending end of the background thread.
+struct Connection {
+    chunk_size: usize,
+    transport: Transport,
+    /// Counter used to tell the chunks of different messages apart.
+    messages: u64,
+}
+
+/// Fields recorded on a span, stored in its extensions.
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl GelfLayer {
+    /// Connects to the GELF input described by `config`.
+    pub fn connect(config: &Gelf) -> Result<Self, crate::Error> {
+        let address = parse_address(&config.address)
+            .filter(|address| !matches!(address, Address::Unix(_)))
+            .ok_or_else(|| {
+                crate::Error::Logging(format!("invalid GELF address {}", config.address))
+            })?;
+
+        if config.chunk_size <= CHUNK_HEADER {
+            return Err(crate::Error::Logging(format!(
+                "GELF chunk size must be larger than {CHUNK_HEADER} bytes"
+            )));
+        }
+
+        let transport = Transport::connect(address)?;
+
+        let host = config.host.clone().unwrap_or_else(|| {
+            std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|host| host.trim().to_owned())
+                .unwrap_or_else(|_| String::from("xnav"))
+        });
+
+        let mut connection = Connection {
+            chunk_size: config.chunk_size,
+            transport,
+            messages: 0,
+        };
+
+        let (messages, queued) = mpsc::sync_channel::<Vec<u8>>(QUEUED);
+        std::thread::Builder::new()
+            .name(String::from("xnav-gelf"))
+            .spawn(move || {
+                for message in queued {
+                    // There's nowhere else to report the failure.
+                    let _ = connection.send(&message);
+                }
+            })?;
+
+        Ok(Self { host, messages })
+    }
+}
+
+impl Connection {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let chunk_size = self.chunk_size;
+        let id = self.message_id();
+
+        match &mut self.transport {
+            Transport::Udp(socket) if message.len() > chunk_size => {
+                let chunks = chunks(id, message, chunk_size)
+                    .ok_or_else(|| io::Error::other("GELF message too large"))?;
+
+                chunks
+                    .iter()
+                    .try_for_each(|chunk| socket.send(chunk).map(drop))
+            }
+            // Messages are delimited by a null byte over TCP.
+            transport => transport.send(message, b'\0'),
+        }
+    }
+
+    /// Message ids only need to be unique among the messages in flight.
+    fn message_id(&mut self) -> [u8; 8] {
+        let count = self.messages;
+        self.messages += 1;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        (nanos ^ count.rotate_left(32) ^ u64::from(std::process::id())).to_be_bytes()
+    }
+}
+
+impl<S> Layer<S> for GelfLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+
+        // Outermost spans first, so inner fields win on name clashes.
+        for span in ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            if let Some(span_fields) = span.extensions().get::<Fields>() {
+                fields.0.extend(span_fields.0.clone());
+            }
+        }
+
+        event.record(&mut fields);
+
+        let metadata = event.metadata();
+        let message = message(&self.host, metadata.level(), metadata.target(), fields);
+
+        // Dropped when the queue is full or the thread is gone.
+        let _ = self.messages.try_send(message.to_string().into_bytes());
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), Value::from(format!("{value:?}")));
+    }
+}
+
+/// Builds a GELF 1.1 message. The `message` field becomes the short message
+/// and every other field an additional `_` prefixed one.
+fn message(host: &str, level: &Level, target: &str, fields: Fields) -> Value {
+    let mut message = Map::new();
+    let mut fields = fields.0;
+
+    // Receivers reject empty messages, access events have none.
+    let short_message = match fields.remove("message") {
+        Some(Value::String(text)) if !text.is_empty() => text,
+        Some(Value::String(_)) | None => target.to_owned(),
+        Some(value) => value.to_string(),
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+
+    message.insert(String::from("version"), Value::from("1.1"));
+    message.insert(String::from("host"), Value::from(host));
+    message.insert(String::from("short_message"), Value::from(short_message));
+    message.insert(String::from("timestamp"), Value::from(timestamp));
+    message.insert(String::from("level"), Value::from(severity(level)));
+    message.insert(String::from("_target"), Value::from(target));
+
+    for (name, value) in fields {
+        // `_id` is reserved by the format.
+        let name = if name == "id" {
+            String::from("_id_")
+        } else {
+            format!("_{name}")
+        };
+        message.insert(name, value);
+    }
+
+    Value::Object(message)
+}
+
+/// Splits `message` into chunks of at most `size` bytes, or `None` if that
+/// takes more than the receivers accept.
+fn chunks(id: [u8; 8], message: &[u8], size: usize) -> Option<Vec<Vec<u8>>> {
+    let pieces: Vec<_> = message.chunks(size - CHUNK_HEADER).collect();
+
+    if pieces.len() > MAX_CHUNKS {
+        return None;
+    }
+
+    let count = pieces.len() as u8;
+
+    let chunks = pieces
+        .into_iter()
+        .enumerate()
+        .map(|(sequence, piece)| {
+            let mut chunk = Vec::with_capacity(CHUNK_HEADER + piece.len());
+            chunk.extend_from_slice(&CHUNK_MAGIC);
+            chunk.extend_from_slice(&id);
+            chunk.push(sequence as u8);
+            chunk.push(count);
+            chunk.extend_from_slice(piece);
+            chunk
+        })
+        .collect();
+
+    Some(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn additional_fields() {
+        let mut fields = Fields::default();
+        fields
+            .0
+            .insert(String::from("message"), Value::from("Served"));
+        fields.0.insert(String::from("status"), Value::from(200));
+        fields.0.insert(String::from("id"), Value::from(7));
+
+        let message = message("web-1", &Level::WARN, "xnav::access", fields);
+
+        assert_eq!(message["version"], "1.1");
+        assert_eq!(message["host"], "web-1");
+        assert_eq!(message["short_message"], "Served");
+        assert_eq!(message["level"], 4);
+        assert_eq!(message["_target"], "xnav::access");
+        assert_eq!(message["_status"], 200);
+        assert_eq!(message["_id_"], 7);
+        assert!(message.get("_message").is_none());
+
+        let access = super::message("web-1", &Level::INFO, "xnav::access", Fields::default());
+        assert_eq!(access["short_message"], "xnav::access");
+    }
+
+    #[test]
+    fn chunking() {
+        let id = [1, 2, 3, 4, 5, 6, 7, 8];
+        let message = [b'x'; 25];
+
+        let chunks = chunks(id, &message, CHUNK_HEADER + 10).unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(&chunks[0][..2], &CHUNK_MAGIC);
+        assert_eq!(&chunks[0][2..10], &id);
+        assert_eq!((chunks[2][10], chunks[2][11]), (2, 3));
+        assert_eq!(chunks[2].len(), CHUNK_HEADER + 5);
+
+        assert!(super::chunks(id, &[0; 200], CHUNK_HEADER + 1).is_none());
+    }
+
+    #[test]
+    fn sends_in_background() {
+        let input = UdpSocket::bind("[::1]:0").unwrap();
+        input
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+
+        let layer = GelfLayer::connect(&Gelf {
+            address: format!("udp://{}", input.local_addr().unwrap()),
+            host: Some(String::from("web-1")),
+            chunk_size: 1420,
+            access: true,
+            error: true,
+        })
+        .unwrap();
+
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || tracing::warn!("Backend down"));
+
+        let mut datagram = [0; 1420];
+        let len = input.recv(&mut datagram).unwrap();
+        let message: Value = serde_json::from_slice(&datagram[..len]).unwrap();
+        assert_eq!(message["short_message"], "Backend down");
+        assert_eq!(message["host"], "web-1");
+    }
+}
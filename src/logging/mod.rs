@@ -0,0 +1,368 @@
+//! Logging setup based on [`tracing`]. Access logs are emitted with the
+//! [`ACCESS`] target so they can be filtered independently of the rest, and
+//! control-plane operations with the [`AUDIT`] target.
+//! Warnings, errors and panics form a separate error stream.
+
+mod exclude;
+mod file;
+mod gelf;
+mod otel;
+mod syslog;
+
+pub use exclude::{exclusions, Exclusions};
+pub use file::RollingFile;
+pub use gelf::GelfLayer;
+pub use otel::{inject, set_parent, shutdown};
+pub use syslog::SyslogWriter;
+
+use std::{
+    io::{self, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    os::unix::net::UnixDatagram,
+    str::FromStr,
+    time::Duration,
+};
+
+use tracing::{error, Level, Metadata};
+use tracing_subscriber::{
+    field::RecordFields,
+    filter::{filter_fn, LevelFilter},
+    fmt::{
+        self,
+        format::{DefaultFields, Writer},
+        FormatFields, MakeWriter,
+    },
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::config::{Log, LogFormat};
+
+/// Target used for access log events, one per request.
+pub const ACCESS: &str = "xnav::access";
+
+/// Target used for audit log events, one per admin operation.
+pub const AUDIT: &str = "xnav::audit";
+
+/// Time given to syslog and GELF inputs to accept TCP connections.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Where syslog and GELF messages are delivered.
+#[derive(Debug, PartialEq, Eq)]
+enum Address {
+    Udp(String),
+    Tcp(String),
+    Unix(String),
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    /// TCP connections are reopened lazily after write failures.
+    Tcp {
+        to: String,
+        stream: Option<TcpStream>,
+    },
+    Unix(UnixDatagram),
+}
+
+/// Installs the global [`tracing`] subscriber described by the `[log]`
+/// section of the configuration. The `RUST_LOG` environment variable takes
+/// precedence over the configured filter when set.
+///
+/// Access and audit logs go to their configured files or stdout. Events at least as
+/// severe as the error stream level go to the error file or stderr, anything
+/// else goes to stdout. Panics are logged to the error stream as well.
+/// Access and error streams can also be copied to syslog and GELF inputs.
+pub fn init(config: &Log) -> Result<(), crate::Error> {
+    let directives = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_else(|_| directives(config));
+
+    let filter = EnvFilter::try_new(directives)
+        .map_err(|err| crate::Error::Logging(format!("invalid log filter: {err}")))?;
+
+    let error_level = LevelFilter::from_str(&config.error.level)
+        .map_err(|err| crate::Error::Logging(format!("invalid error log level: {err}")))?;
+
+    let is_error = move |metadata: &Metadata<'_>| {
+        !is_access(metadata) && !is_audit(metadata) && *metadata.level() <= error_level
+    };
+
+    exclude::init(&config.exclude);
+
+    let access_file = config.access.as_ref().map(RollingFile::open).transpose()?;
+    let audit_file = config.audit.as_ref().map(RollingFile::open).transpose()?;
+    let error_file = config
+        .error
+        .file
+        .as_ref()
+        .map(RollingFile::open)
+        .transpose()?;
+
+    let mut layers = Vec::new();
+
+    let access_to_stdout = access_file.is_none();
+    let audit_to_stdout = audit_file.is_none();
+
+    let stdout =
+        layer(config.format, std::io::stdout, true).with_filter(filter_fn(move |metadata| {
+            if is_access(metadata) {
+                access_to_stdout
+            } else if is_audit(metadata) {
+                audit_to_stdout
+            } else {
+                !is_error(metadata)
+            }
+        }));
+    layers.push(stdout.boxed());
+
+    if let Some(file) = access_file {
+        layers.push(
+            layer(config.format, file, false)
+                .with_filter(filter_fn(is_access))
+                .boxed(),
+        );
+    }
+
+    if let Some(file) = audit_file {
+        layers.push(
+            layer(config.format, file, false)
+                .with_filter(filter_fn(is_audit))
+                .boxed(),
+        );
+    }
+
+    let error_format = config.error.format.unwrap_or(config.format);
+
+    let errors = match error_file {
+        Some(file) => layer(error_format, file, false),
+        None => layer(error_format, std::io::stderr, true),
+    };
+    // Spans are let through so errors keep the context of the request.
+    let with_spans = filter_fn(move |metadata| metadata.is_span() || is_error(metadata));
+    layers.push(errors.with_filter(with_spans).boxed());
+
+    // Sinks copying the access and/or error streams. Spans are let through
+    // for the same reason as above.
+    let copied = move |access: bool, error: bool| {
+        filter_fn(move |metadata| {
+            metadata.is_span() || (access && is_access(metadata)) || (error && is_error(metadata))
+        })
+    };
+
+    if let Some(syslog) = &config.syslog {
+        layers.push(
+            syslog::layer(SyslogWriter::connect(syslog)?, config.format)
+                .with_filter(copied(syslog.access, syslog.error))
+                .boxed(),
+        );
+    }
+
+    if let Some(gelf) = &config.gelf {
+        layers.push(
+            GelfLayer::connect(gelf)?
+                .with_filter(copied(gelf.access, gelf.error))
+                .boxed(),
+        );
+    }
+
+    if let Some(otlp) = &config.otlp {
+        layers.push(otel::layer(otlp)?);
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .try_init()
+        .map_err(|err| crate::Error::Logging(err.to_string()))?;
+
+    std::panic::set_hook(Box::new(|info| {
+        let location = info.location().map(ToString::to_string);
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+
+        error!(target: "xnav::panic", location, "Panicked: {payload}");
+    }));
+
+    Ok(())
+}
+
+/// Formatting layer writing to `writer`.
+fn layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+
+    match (format, ansi) {
+        (LogFormat::Text, true) => layer.boxed(),
+        (LogFormat::Text, false) => layer.fmt_fields(PlainFields::default()).boxed(),
+        (LogFormat::Json, _) => layer.json().boxed(),
+    }
+}
+
+/// Field formatter for text layers without colors. Span fields are formatted
+/// once and cached per formatter type, so sharing [`DefaultFields`] with the
+/// stdout layer would leak its escape codes into files and syslog.
+#[derive(Default)]
+struct PlainFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for PlainFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        writer: Writer<'writer>,
+        fields: R,
+    ) -> std::fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+impl Transport {
+    fn connect(address: Address) -> io::Result<Self> {
+        let transport = match address {
+            Address::Udp(to) => Transport::Udp(connect_udp(&to)?),
+            Address::Tcp(to) => Transport::Tcp {
+                stream: Some(connect_tcp(&to)?),
+                to,
+            },
+            Address::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Transport::Unix(socket)
+            }
+        };
+
+        Ok(transport)
+    }
+
+    /// Sends `message` as one datagram, or followed by `delimiter` over TCP.
+    fn send(&mut self, message: &[u8], delimiter: u8) -> io::Result<()> {
+        match self {
+            Transport::Udp(socket) => socket.send(message).map(drop),
+            Transport::Unix(socket) => socket.send(message).map(drop),
+            Transport::Tcp { to, stream } => {
+                if stream.is_none() {
+                    *stream = Some(connect_tcp(to)?);
+                }
+
+                let connection = stream.as_mut().unwrap();
+                let result = connection
+                    .write_all(message)
+                    .and_then(|()| connection.write_all(&[delimiter]));
+                if result.is_err() {
+                    *stream = None;
+                }
+
+                result
+            }
+        }
+    }
+}
+
+fn parse_address(address: &str) -> Option<Address> {
+    let (scheme, rest) = address.split_once("://")?;
+
+    if rest.is_empty() {
+        return None;
+    }
+
+    match scheme {
+        "udp" => Some(Address::Udp(rest.to_owned())),
+        "tcp" => Some(Address::Tcp(rest.to_owned())),
+        "unix" => Some(Address::Unix(rest.to_owned())),
+        _ => None,
+    }
+}
+
+/// RFC 5424 severity of a [`tracing`] level, which GELF uses as well.
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// UDP socket connected to `to`, bound to the address family of the first
+/// address it resolves to.
+fn connect_udp(to: &str) -> io::Result<UdpSocket> {
+    let address = to.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{to} has no address"))
+    })?;
+
+    let local = match address {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(address)?;
+    Ok(socket)
+}
+
+/// TCP connection to the first address of `to` that accepts one within
+/// `CONNECT_TIMEOUT`.
+fn connect_tcp(to: &str) -> io::Result<TcpStream> {
+    let mut failure = None;
+
+    for address in to.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => failure = Some(err),
+        }
+    }
+
+    Err(failure.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{to} has no address"))
+    }))
+}
+
+fn is_access(metadata: &Metadata<'_>) -> bool {
+    metadata.target() == ACCESS
+}
+
+fn is_audit(metadata: &Metadata<'_>) -> bool {
+    metadata.target() == AUDIT
+}
+
+/// Builds [`EnvFilter`] directives out of the default level and the per
+/// module levels. Audit events are kept whatever the default level is.
+fn directives(config: &Log) -> String {
+    let mut directives = vec![config.level.clone(), format!("{AUDIT}=info")];
+
+    for (module, level) in &config.modules {
+        directives.push(format!("{module}={level}"));
+    }
+
+    directives.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        assert_eq!(
+            parse_address("udp://127.0.0.1:514"),
+            Some(Address::Udp(String::from("127.0.0.1:514")))
+        );
+        assert_eq!(
+            parse_address("tcp://logs.internal:601"),
+            Some(Address::Tcp(String::from("logs.internal:601")))
+        );
+        assert_eq!(
+            parse_address("unix:///dev/log"),
+            Some(Address::Unix(String::from("/dev/log")))
+        );
+        assert_eq!(parse_address("127.0.0.1:514"), None);
+        assert_eq!(parse_address("udp://"), None);
+        assert_eq!(parse_address("http://127.0.0.1"), None);
+    }
+}
@@ -0,0 +1,100 @@
+//! OpenTelemetry export of request spans over OTLP and W3C trace context
+//! propagation between clients and backends.
+
+use std::sync::OnceLock;
+
+use hyper::{header::HeaderName, HeaderMap};
+use opentelemetry::{
+    propagation::{Extractor, Injector},
+    trace::TracerProvider,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::Layer;
+
+use super::BoxedLayer;
+use crate::config::Otlp;
+
+/// Provider kept around to flush pending spans on shutdown.
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Builds the layer that exports [`tracing`] spans to an OTLP collector.
+pub(super) fn layer(config: &Otlp) -> Result<BoxedLayer, crate::Error> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .build()
+        .map_err(|err| crate::Error::Logging(format!("OTLP exporter error: {err}")))?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+
+    let tracer = provider.tracer("xnav");
+    let _ = PROVIDER.set(provider);
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+}
+
+/// Flushes and stops the OTLP exporter, if any.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        let _ = provider.shutdown();
+    }
+}
+
+/// Makes `span` a child of the trace context found in `headers`, if any.
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+
+    let _ = span.set_parent(context);
+}
+
+/// Writes the trace context of `span` into `headers` so that the backend
+/// continues the same trace.
+pub fn inject(span: &Span, headers: &mut HeaderMap) {
+    let context = span.context();
+
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if value.is_empty() {
+            return;
+        }
+
+        let (Ok(name), Ok(value)) = (HeaderName::try_from(key), value.try_into()) else {
+            return;
+        };
+
+        self.0.insert(name, value);
+    }
+}
@@ -0,0 +1,174 @@
+//! Log forwarding to a syslog daemon over UDP, TCP or a unix socket.
+
+use std::{
+    io,
+    sync::mpsc::{self, SyncSender},
+};
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::{
+    fmt::{self, MakeWriter},
+    Layer,
+};
+
+use super::{parse_address, severity, BoxedLayer, PlainFields, Transport};
+use crate::config::{Facility, LogFormat, Syslog};
+
+/// Messages waiting to be sent. Further ones are dropped rather than holding
+/// up the threads that log while the daemon is slow or unreachable.
+const QUEUED: usize = 1024;
+
+/// Sink sending each log event as a single RFC 5424 message.
+///
+/// Messages are handed to a background thread that does the I/O.
+pub struct SyslogWriter {
+    facility: Facility,
+    messages: SyncSender<Vec<u8>>,
+}
+
+impl SyslogWriter {
+    /// Connects to the syslog endpoint described by `config`.
+    pub fn connect(config: &Syslog) -> Result<Self, crate::Error> {
+        let address = parse_address(&config.address).ok_or_else(|| {
+            crate::Error::Logging(format!("invalid syslog address {}", config.address))
+        })?;
+
+        let mut transport = Transport::connect(address)?;
+
+        let (messages, queued) = mpsc::sync_channel::<Vec<u8>>(QUEUED);
+        std::thread::Builder::new()
+            .name(String::from("xnav-syslog"))
+            .spawn(move || {
+                for packet in queued {
+                    // Non-transparent framing over TCP, as described in
+                    // RFC 6587. There's nowhere else to report failures.
+                    let _ = transport.send(&packet, b'\n');
+                }
+            })?;
+
+        Ok(Self {
+            facility: config.facility,
+            messages,
+        })
+    }
+
+    fn send(&self, severity: u8, message: &[u8]) {
+        let message = message.strip_suffix(b"\n").unwrap_or(message);
+        let mut packet = header(self.facility, severity).into_bytes();
+        packet.extend_from_slice(message);
+
+        // Dropped when the queue is full or the thread is gone.
+        let _ = self.messages.try_send(packet);
+    }
+
+    fn message(&self, severity: u8) -> Message<'_> {
+        Message {
+            writer: self,
+            severity,
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// Buffers one formatted event and sends it when dropped.
+pub struct Message<'a> {
+    writer: &'a SyslogWriter,
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+impl io::Write for Message<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            self.writer.send(self.severity, &self.buf);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = Message<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.message(severity(&Level::INFO))
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.message(severity(meta.level()))
+    }
+}
+
+/// Formatting layer for syslog messages. The daemon timestamps messages on
+/// its own, so the time is left out.
+pub(super) fn layer(writer: SyslogWriter, format: LogFormat) -> BoxedLayer {
+    let layer = fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false)
+        .without_time();
+
+    match format {
+        LogFormat::Text => layer.fmt_fields(PlainFields::default()).boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// Message header up to the start of the message itself. Timestamp and
+/// hostname are left for the daemon to fill in.
+fn header(facility: Facility, severity: u8) -> String {
+    let priority = facility as u8 * 8 + severity;
+
+    format!("<{priority}>1 - - xnav {} - - ", std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn priority() {
+        let pid = std::process::id();
+
+        assert_eq!(
+            header(Facility::Local0, severity(&Level::ERROR)),
+            format!("<131>1 - - xnav {pid} - - ")
+        );
+        assert_eq!(
+            header(Facility::Daemon, severity(&Level::INFO)),
+            format!("<30>1 - - xnav {pid} - - ")
+        );
+    }
+
+    #[test]
+    fn ipv6_collectors() {
+        let daemon = UdpSocket::bind("[::1]:0").unwrap();
+        daemon
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let writer = SyslogWriter::connect(&Syslog {
+            address: format!("udp://{}", daemon.local_addr().unwrap()),
+            facility: Facility::Daemon,
+            access: true,
+            error: true,
+        })
+        .unwrap();
+
+        writer.send(6, b"Started\n");
+
+        let mut packet = [0; 128];
+        let len = daemon.recv(&mut packet).unwrap();
+        assert!(packet[..len].starts_with(b"<30>1 "));
+        assert!(packet[..len].ends_with(b" Started"));
+    }
+}
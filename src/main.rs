@@ -1,8 +1,12 @@
 #[tokio::main]
 async fn main() -> Result<(), xnav::Error> {
-    let config = toml::from_str(&tokio::fs::read_to_string("config.toml").await?)?;
-    xnav::Master::init(config)?
+    let config: xnav::Config = toml::from_str(&tokio::fs::read_to_string("config.toml").await?)?;
+    xnav::logging::init(&config.log)?;
+    xnav::metrics::init(&config.metrics)?;
+    let result = xnav::Master::init(config)?
         .shutdown_on(tokio::signal::ctrl_c())
         .run()
-        .await
+        .await;
+    xnav::logging::shutdown();
+    result
 }
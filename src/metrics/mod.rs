@@ -0,0 +1,69 @@
+//! Request metrics pushed to external collectors. Percentiles and totals
+//! per pattern are also available on the admin status endpoint.
+
+mod statsd;
+
+use std::{net::SocketAddr, sync::OnceLock, time::Duration};
+
+pub use statsd::Statsd;
+
+use crate::{config::Metrics, service::Lookup};
+
+/// Sink configured at startup, if any.
+static STATSD: OnceLock<Statsd> = OnceLock::new();
+
+/// Measurements of a single completed request.
+pub struct Sample<'a> {
+    pub server: &'a str,
+    pub route: &'a str,
+    pub method: &'a str,
+    pub status: u16,
+    pub latency: Duration,
+    pub received: u64,
+    pub sent: u64,
+    /// Backend the request was forwarded to, if any.
+    pub backend: Option<SocketAddr>,
+    /// Whether the served file was found in the file cache, if it's enabled.
+    pub cache: Option<Lookup>,
+}
+
+/// Change in the connections of a listener.
+pub struct ConnectionSample<'a> {
+    pub server: &'a str,
+    pub listener: SocketAddr,
+    pub event: ConnectionEvent,
+    /// Connections being served after the event.
+    pub active: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ConnectionEvent {
+    Accepted,
+    Closed,
+    /// The listener stopped accepting connections at `max_connections`.
+    Limited,
+}
+
+/// Sets up the sinks described in the `[metrics]` section of the
+/// configuration.
+pub fn init(config: &Metrics) -> Result<(), crate::Error> {
+    if let Some(statsd) = &config.statsd {
+        let _ = STATSD.set(Statsd::connect(statsd)?);
+    }
+
+    Ok(())
+}
+
+/// Reports a completed request to all the configured sinks.
+pub fn record(sample: &Sample<'_>) {
+    if let Some(statsd) = STATSD.get() {
+        statsd.record(sample);
+    }
+}
+
+/// Reports a connection event to all the configured sinks.
+pub fn record_connection(sample: &ConnectionSample<'_>) {
+    if let Some(statsd) = STATSD.get() {
+        statsd.record_connection(sample);
+    }
+}
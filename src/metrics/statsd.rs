@@ -0,0 +1,244 @@
+//! StatsD sink with DogStatsD tags.
+
+use std::{
+    fmt::Write,
+    io,
+    net::{ToSocketAddrs, UdpSocket},
+};
+
+use tracing::debug;
+
+use super::{ConnectionEvent, ConnectionSample, Sample};
+
+/// Client sending one UDP packet with all the metrics of each request.
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    /// Tags added to every metric, already formatted.
+    tags: String,
+}
+
+impl Statsd {
+    /// Creates a client for the agent described in `config`. The address is
+    /// resolved once, at startup.
+    pub fn connect(config: &crate::config::Statsd) -> Result<Self, crate::Error> {
+        let address =
+            config.address.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "StatsD address not found")
+            })?;
+
+        let socket = if address.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0")?
+        } else {
+            UdpSocket::bind("[::]:0")?
+        };
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+
+        let tags = config
+            .tags
+            .iter()
+            .map(|(key, value)| tag(key, value))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        Ok(Self {
+            socket,
+            prefix: config.prefix.clone(),
+            tags,
+        })
+    }
+
+    pub fn record(&self, sample: &Sample<'_>) {
+        self.send(&self.format(sample));
+    }
+
+    pub fn record_connection(&self, sample: &ConnectionSample<'_>) {
+        self.send(&self.format_connection(sample));
+    }
+
+    fn send(&self, packet: &str) {
+        // Metrics are best effort, dropping them is better than blocking.
+        if let Err(err) = self.socket.send(packet.as_bytes()) {
+            debug!(%err, "Failed to send StatsD metrics");
+        }
+    }
+
+    fn format(&self, sample: &Sample<'_>) -> String {
+        let mut tags = vec![
+            tag("server", sample.server),
+            tag("route", sample.route),
+            tag("method", method(sample.method)),
+            tag("status", &sample.status.to_string()),
+        ];
+
+        if let Some(backend) = sample.backend {
+            tags.push(tag("backend", &backend.to_string()));
+        }
+
+        let tags = self.join(tags);
+        let latency = sample.latency.as_secs_f64() * 1000.0;
+
+        let mut packet = String::new();
+        let mut metric = |name: &str, value: &dyn std::fmt::Display, kind: &str| {
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            let _ = write!(packet, "{}.{name}:{value}|{kind}|#{tags}", self.prefix);
+        };
+
+        metric("requests", &1, "c");
+        metric("request.latency", &format_args!("{latency:.3}"), "ms");
+        metric("bytes.received", &sample.received, "c");
+        metric("bytes.sent", &sample.sent, "c");
+
+        if let Some(lookup) = sample.cache {
+            metric(&format!("cache.{}", lookup.as_str()), &1, "c");
+        }
+
+        packet
+    }
+
+    fn format_connection(&self, sample: &ConnectionSample<'_>) -> String {
+        let tags = self.join(vec![
+            tag("server", sample.server),
+            tag("listener", &sample.listener.to_string()),
+        ]);
+
+        let counter = match sample.event {
+            ConnectionEvent::Accepted => Some("connections.accepted"),
+            ConnectionEvent::Limited => Some("connections.limited"),
+            ConnectionEvent::Closed => None,
+        };
+
+        let mut packet = String::new();
+        if let Some(counter) = counter {
+            let _ = writeln!(packet, "{}.{counter}:1|c|#{tags}", self.prefix);
+        }
+        let _ = write!(
+            packet,
+            "{}.connections.active:{}|g|#{tags}",
+            self.prefix, sample.active
+        );
+
+        packet
+    }
+
+    /// Joins per metric tags with the configured ones.
+    fn join(&self, mut tags: Vec<String>) -> String {
+        if !self.tags.is_empty() {
+            tags.push(self.tags.clone());
+        }
+
+        tags.join(",")
+    }
+}
+
+/// Method tag of a request. Clients can send any method, others are all
+/// tagged `other` so that they can't create new series at will.
+fn method(method: &str) -> &str {
+    const KNOWN: [&str; 9] = [
+        "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+    ];
+
+    if KNOWN.contains(&method) {
+        method
+    } else {
+        "other"
+    }
+}
+
+/// Formats a tag, replacing the characters that are part of the protocol.
+/// Colons are only allowed in the value.
+fn tag(key: &str, value: &str) -> String {
+    let clean = |text: &str| text.replace([',', '|', '#', '\n'], "_");
+
+    format!("{}:{}", clean(key).replace(':', "_"), clean(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn packet() {
+        let statsd = Statsd::connect(&config::Statsd {
+            address: String::from("127.0.0.1:8125"),
+            prefix: String::from("xnav"),
+            tags: BTreeMap::from([(String::from("env"), String::from("prod"))]),
+        })
+        .unwrap();
+
+        let sample = Sample {
+            server: "web",
+            route: "/api",
+            method: "GET",
+            status: 200,
+            latency: Duration::from_micros(1500),
+            received: 0,
+            sent: 42,
+            backend: Some("127.0.0.1:8080".parse().unwrap()),
+            cache: None,
+        };
+
+        let tags = "#server:web,route:/api,method:GET,status:200,backend:127.0.0.1:8080,env:prod";
+
+        assert_eq!(
+            statsd.format(&sample),
+            [
+                format!("xnav.requests:1|c|{tags}"),
+                format!("xnav.request.latency:1.500|ms|{tags}"),
+                format!("xnav.bytes.received:0|c|{tags}"),
+                format!("xnav.bytes.sent:42|c|{tags}"),
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn connections() {
+        let statsd = Statsd::connect(&config::Statsd {
+            address: String::from("127.0.0.1:8125"),
+            prefix: String::from("xnav"),
+            tags: BTreeMap::new(),
+        })
+        .unwrap();
+
+        let mut sample = ConnectionSample {
+            server: "web",
+            listener: "127.0.0.1:8080".parse().unwrap(),
+            event: ConnectionEvent::Accepted,
+            active: 3,
+        };
+
+        let tags = "#server:web,listener:127.0.0.1:8080";
+
+        assert_eq!(
+            statsd.format_connection(&sample),
+            format!("xnav.connections.accepted:1|c|{tags}\nxnav.connections.active:3|g|{tags}")
+        );
+
+        sample.event = ConnectionEvent::Closed;
+        assert_eq!(
+            statsd.format_connection(&sample),
+            format!("xnav.connections.active:3|g|{tags}")
+        );
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(tag("route", "/a,b|c"), "route:/a_b_c");
+        assert_eq!(tag("server", "127.0.0.1:80"), "server:127.0.0.1:80");
+        assert_eq!(tag("a:b", "c"), "a_b:c");
+    }
+
+    #[test]
+    fn methods() {
+        assert_eq!(method("PATCH"), "PATCH");
+        assert_eq!(method("PROPFIND"), "other");
+        assert_eq!(method("get"), "other");
+    }
+}
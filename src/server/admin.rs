@@ -0,0 +1,718 @@
+//! Admin listener exposing the state of every server as JSON, meant for
+//! dashboards and readiness probes, along with debug captures of requests,
+//! control over traffic splits and purges of cached files.
+
+use std::{collections::BTreeMap, convert::Infallible, net::IpAddr, path::Path, sync::Arc};
+
+use http_body_util::BodyExt;
+use hyper::{
+    body::Incoming, header, server::conn::http1::Builder, service::service_fn, Method, Request,
+    StatusCode,
+};
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tracing::{debug, error, info};
+
+use crate::{
+    config, logging,
+    server::snapshot::Inventory,
+    service::{allows, full, secret_matches, BoxBodyResponse, Capturer, LocalResponse},
+};
+
+pub(super) struct Admin {
+    listener: std::net::TcpListener,
+    path: String,
+    split_path: String,
+    purge_path: String,
+    token: Option<Arc<str>>,
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    inventory: Arc<Inventory>,
+    capturer: Option<Arc<Capturer>>,
+}
+
+/// Body of a request changing the split of a pattern. Groups left out keep
+/// their weight, unless an `active` group takes all the requests.
+#[derive(Deserialize)]
+struct SplitChange {
+    #[serde(default)]
+    server: Option<String>,
+    uri: String,
+    #[serde(default)]
+    split: BTreeMap<String, u32>,
+    #[serde(default)]
+    active: Option<String>,
+}
+
+/// Body of a request purging the cached files served at a URL, or at all
+/// the URLs starting with a prefix. Files of every server are purged unless
+/// one is named.
+#[derive(Deserialize)]
+struct Purge {
+    #[serde(default)]
+    server: Option<String>,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    prefix: Option<String>,
+}
+
+impl Admin {
+    /// Binds the admin listener described in the configuration.
+    pub fn init(
+        config: &config::Admin,
+        inventory: Arc<Inventory>,
+        capturer: Option<Arc<Capturer>>,
+    ) -> Result<Self, std::io::Error> {
+        let listener = std::net::TcpListener::bind(config.listen)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            path: config.path.clone(),
+            split_path: config.split_path.clone(),
+            purge_path: config.purge_path.clone(),
+            token: config.token.as_ref().map(|token| token.expose().into()),
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+            inventory,
+            capturer,
+        })
+    }
+
+    /// Serves status requests until the task is aborted.
+    pub async fn run(self) -> Result<(), crate::Error> {
+        let listener = TcpListener::from_std(self.listener)?;
+        info!(admin = %listener.local_addr()?, path = %self.path, "Serving status");
+
+        let path: Arc<str> = self.path.into();
+        let split_path: Arc<str> = self.split_path.into();
+        let purge_path: Arc<str> = self.purge_path.into();
+
+        loop {
+            let (stream, client_addr) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    error!(%err, "Admin listener failed to accept connection");
+                    continue;
+                }
+            };
+            if !allows(&self.allow, &self.deny, client_addr.ip()) {
+                debug!(client = %client_addr, "Refusing client address on admin listener");
+                continue;
+            }
+
+            let inventory = self.inventory.clone();
+            let capturer = self.capturer.clone();
+            let path = path.clone();
+            let split_path = split_path.clone();
+            let purge_path = purge_path.clone();
+            let token = self.token.clone();
+
+            tokio::task::spawn(async move {
+                let service = service_fn(move |request: Request<Incoming>| {
+                    let inventory = inventory.clone();
+                    let capturer = capturer.clone();
+                    let path = path.clone();
+                    let split_path = split_path.clone();
+                    let purge_path = purge_path.clone();
+                    let token = token.clone();
+
+                    async move {
+                        let method = request.method().clone();
+                        let requested = request.uri().path().to_owned();
+                        let response = match &capturer {
+                            _ if requested == *path => status(&inventory),
+                            _ if (requested == *split_path
+                                || requested == *purge_path
+                                || capturer.as_ref().is_some_and(|c| requested == c.path()))
+                                && !authorized(&request, token.as_deref(), client_addr.ip()) =>
+                            {
+                                unauthorized()
+                            }
+                            Some(capturer) if requested == capturer.path() => captures(capturer),
+                            _ if requested == *split_path => split(request, &inventory).await,
+                            _ if requested == *purge_path => purge(request, &inventory).await,
+                            _ => LocalResponse::not_found(),
+                        };
+
+                        info!(
+                            target: logging::AUDIT,
+                            client = %client_addr,
+                            %method,
+                            path = %requested,
+                            status = response.status().as_u16(),
+                            "Admin request",
+                        );
+
+                        Ok::<_, Infallible>(response)
+                    }
+                });
+
+                if let Err(err) = Builder::new().serve_connection(stream, service).await {
+                    error!(client = %client_addr, %err, "Failed to serve admin connection");
+                }
+            });
+        }
+    }
+}
+
+/// Builds the status response. The status code is 200 only when all servers
+/// are accepting connections, 503 otherwise.
+fn status(inventory: &Inventory) -> BoxBodyResponse {
+    let snapshot = inventory.snapshot();
+
+    let status = if snapshot.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let body = serde_json::to_vec(&snapshot).unwrap();
+
+    LocalResponse::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(full(body))
+        .unwrap()
+}
+
+/// Serves the captured requests, oldest first.
+fn captures(capturer: &Capturer) -> BoxBodyResponse {
+    let body = serde_json::to_vec(&capturer.records()).unwrap();
+
+    LocalResponse::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(full(body))
+        .unwrap()
+}
+
+/// Whether `request` may read captures or change the state of the servers.
+/// It needs `token`
+/// if there's one, otherwise it has to come from the loopback interface.
+fn authorized<T>(request: &Request<T>, token: Option<&str>, client: IpAddr) -> bool {
+    let Some(token) = token else {
+        return client.is_loopback();
+    };
+
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+        .is_some_and(|given| secret_matches(token.as_bytes(), given))
+}
+
+/// Changes the weights of the groups of a pattern, answering with all of
+/// them. Only groups already in the split of the pattern are accepted.
+/// Requests in flight finish on the backends they were sent to.
+async fn split(request: Request<Incoming>, inventory: &Inventory) -> BoxBodyResponse {
+    if request.method() != Method::PUT {
+        return rejected(StatusCode::METHOD_NOT_ALLOWED, "Use PUT to change a split");
+    }
+
+    let body = match request.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return rejected(StatusCode::BAD_REQUEST, "Failed to read the body"),
+    };
+
+    let change: SplitChange = match serde_json::from_slice(&body) {
+        Ok(change) => change,
+        Err(err) => return rejected(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+
+    match change_split(inventory, &change) {
+        Ok(weights) => {
+            info!(uri = %change.uri, ?weights, "Changed traffic split");
+            LocalResponse::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(full(serde_json::to_vec(&weights).unwrap()))
+                .unwrap()
+        }
+        Err((status, reason)) => rejected(status, &reason),
+    }
+}
+
+/// Applies `change` to every matching route, all or none of them.
+fn change_split(
+    inventory: &Inventory,
+    change: &SplitChange,
+) -> Result<BTreeMap<String, u32>, (StatusCode, String)> {
+    let splitters: Vec<_> = inventory
+        .routes
+        .iter()
+        .filter(|route| route.server == change.server && route.uri == change.uri)
+        .map(|route| route.split.as_ref())
+        .collect();
+
+    if splitters.is_empty() {
+        return Err((StatusCode::NOT_FOUND, format!("No pattern {}", change.uri)));
+    }
+
+    let Some(splitters) = splitters.into_iter().collect::<Option<Vec<_>>>() else {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Pattern {} has no split", change.uri),
+        ));
+    };
+
+    let mut updated = Vec::with_capacity(splitters.len());
+
+    for splitter in splitters {
+        let mut weights = splitter.weights();
+
+        if let Some(active) = &change.active {
+            if !weights.contains_key(active) {
+                return Err((StatusCode::BAD_REQUEST, format!("Unknown group {active}")));
+            }
+            for (group, weight) in &mut weights {
+                *weight = u32::from(group == active);
+            }
+        }
+
+        for (group, weight) in &change.split {
+            match weights.get_mut(group) {
+                Some(current) => *current = *weight,
+                None => return Err((StatusCode::BAD_REQUEST, format!("Unknown group {group}"))),
+            }
+        }
+
+        if weights.values().all(|weight| *weight == 0) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                String::from("At least one group needs a weight"),
+            ));
+        }
+
+        updated.push((splitter, weights));
+    }
+
+    for (splitter, weights) in &updated {
+        splitter.set(weights);
+    }
+
+    Ok(updated.swap_remove(0).1)
+}
+
+/// Evicts cached files from memory, answering with how many were. Files are
+/// read from disk again the next time they are requested.
+async fn purge(request: Request<Incoming>, inventory: &Inventory) -> BoxBodyResponse {
+    if request.method() != Method::POST {
+        return rejected(StatusCode::METHOD_NOT_ALLOWED, "Use POST to purge files");
+    }
+
+    let body = match request.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return rejected(StatusCode::BAD_REQUEST, "Failed to read the body"),
+    };
+
+    let purge: Purge = match serde_json::from_slice(&body) {
+        Ok(purge) => purge,
+        Err(err) => return rejected(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+
+    match purge_files(inventory, &purge) {
+        Ok(purged) => {
+            info!(
+                url = purge.url,
+                prefix = purge.prefix,
+                purged,
+                "Purged cached files"
+            );
+            LocalResponse::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(full(serde_json::json!({ "purged": purged }).to_string()))
+                .unwrap()
+        }
+        Err((status, reason)) => rejected(status, &reason),
+    }
+}
+
+/// Maps the URL or prefix of `purge` to files in the directories of the
+/// matching routes, the way the serve action does, and evicts them.
+fn purge_files(inventory: &Inventory, purge: &Purge) -> Result<usize, (StatusCode, String)> {
+    let (url, exact) = match (&purge.url, &purge.prefix) {
+        (Some(url), None) => (url, true),
+        (None, Some(prefix)) => (prefix, false),
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                String::from("Expected either a url or a prefix"),
+            ))
+        }
+    };
+
+    let cached: Vec<_> = inventory
+        .routes
+        .iter()
+        .filter(|route| purge.server.is_none() || route.server == purge.server)
+        .filter_map(|route| Some((route, route.files.as_ref()?)))
+        .collect();
+
+    if cached.is_empty() {
+        return Err((StatusCode::NOT_FOUND, String::from("No cached files")));
+    }
+
+    let mut purged = 0;
+
+    for (route, files) in cached {
+        let Ok(directory) = Path::new(&files.directory).canonicalize() else {
+            continue;
+        };
+
+        // Prefixes shorter than the pattern cover all of its files.
+        let path = match url.strip_prefix(route.uri.as_str()) {
+            _ if !exact && route.uri.starts_with(url.as_str()) => "",
+            Some(rest) if files.alias => rest,
+            Some(_) => url,
+            None => continue,
+        };
+        let file = directory.join(path.strip_prefix('/').unwrap_or(path));
+
+        purged += if exact {
+            let file = file.canonicalize().unwrap_or(file);
+            files.cache.store.purge(|cached| cached == file)
+        } else {
+            let prefix = file.to_string_lossy();
+            files
+                .cache
+                .store
+                .purge(|cached| cached.to_string_lossy().starts_with(&*prefix))
+        };
+    }
+
+    Ok(purged)
+}
+
+fn unauthorized() -> BoxBodyResponse {
+    let mut response = rejected(StatusCode::UNAUTHORIZED, "Missing or wrong admin token");
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        header::HeaderValue::from_static("Bearer"),
+    );
+    response
+}
+
+fn rejected(status: StatusCode, reason: &str) -> BoxBodyResponse {
+    LocalResponse::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(full(reason.to_owned()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::watch,
+    };
+
+    use super::*;
+    use crate::{
+        server::{
+            snapshot::{CachedFiles, Replica, Route},
+            ShutdownState, State,
+        },
+        threading::{Picks, Splitter},
+    };
+
+    fn replica(state: State, active: usize) -> (watch::Sender<State>, Replica) {
+        let (sender, receiver) = watch::channel(state);
+        let (_, connections) = watch::channel(active);
+
+        let replica = Replica {
+            address: "127.0.0.1:8080".parse().unwrap(),
+            name: Some(String::from("web")),
+            state: receiver,
+            connections,
+            counters: Arc::default(),
+            max_connections: 8,
+            requests: Arc::default(),
+            max_requests: None,
+            rules: Vec::new(),
+        };
+
+        (sender, replica)
+    }
+
+    async fn json(response: BoxBodyResponse) -> serde_json::Value {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_from_other_networks() {
+        let answer = |networks: &'static str| async move {
+            let config = toml::from_str::<config::Admin>(&format!(
+                r#"
+                    listen = "127.0.0.1:0"
+                    {networks}
+                "#
+            ))
+            .unwrap();
+            let inventory = Arc::new(Inventory {
+                replicas: Vec::new(),
+                routes: Vec::new(),
+            });
+            let admin = Admin::init(&config, inventory, None).unwrap();
+            let address = admin.listener.local_addr().unwrap();
+            let task = tokio::spawn(admin.run());
+
+            // Refused connections are closed without a word, writing or
+            // reading might fail as well.
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let request = b"GET /status HTTP/1.1\r\nhost: admin\r\nconnection: close\r\n\r\n";
+            let _ = stream.write_all(request).await;
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response).await;
+
+            task.abort();
+            String::from_utf8_lossy(&response).into_owned()
+        };
+
+        assert!(answer("").await.starts_with("HTTP/1.1 200"));
+        assert!(answer(r#"allow = ["127.0.0.0/8"]"#)
+            .await
+            .starts_with("HTTP/1.1 200"));
+        assert_eq!(answer(r#"allow = ["10.0.0.0/8"]"#).await, "");
+        let denied = "allow = [\"127.0.0.0/8\"]\ndeny = [\"127.0.0.1/32\"]";
+        assert_eq!(answer(denied).await, "");
+    }
+
+    #[tokio::test]
+    async fn ready_when_all_listening() {
+        let (_sender, replica) = replica(State::Listening, 3);
+        let response = status(&Inventory {
+            replicas: vec![replica],
+            routes: Vec::new(),
+        });
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json(response).await,
+            serde_json::json!({
+                "ready": true,
+                "servers": [{
+                    "address": "127.0.0.1:8080",
+                    "name": "web",
+                    "state": "listening",
+                    "connections": 3,
+                    "max_connections": 8,
+                    "accepted": 0,
+                    "limited": 0,
+                    "requests": 0,
+                }],
+                "routes": [],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn unavailable_while_shutting_down() {
+        let (_listening, first) = replica(State::Listening, 0);
+        let (_shutting_down, second) =
+            replica(State::ShuttingDown(ShutdownState::PendingConnections(2)), 2);
+        let response = status(&Inventory {
+            replicas: vec![first, second],
+            routes: Vec::new(),
+        });
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            json(response).await["servers"][1]["state"],
+            serde_json::json!({ "shutting_down": { "pending_connections": 2 } })
+        );
+    }
+
+    #[tokio::test]
+    async fn route_latencies() {
+        let route = Route {
+            server: Some(String::from("web")),
+            listen: vec!["127.0.0.1:8080".parse().unwrap()],
+            uri: String::from("/api"),
+            latency: Arc::default(),
+            traffic: Arc::default(),
+            picks: Some(Arc::new(Picks::new(&[config::Backend {
+                address: "127.0.0.1:9000".parse().unwrap(),
+                weight: 2,
+                max_requests: Some(1),
+                backup: false,
+                group: None,
+            }]))),
+            split: None,
+            files: None,
+        };
+        route.latency.record(std::time::Duration::from_millis(5));
+        route.traffic.add_sent(512);
+        let picks = route.picks.as_ref().unwrap();
+        let backend = "127.0.0.1:9000".parse().unwrap();
+        let _lease = picks.lease(backend).unwrap();
+        assert!(picks.lease(backend).is_none());
+
+        let json = json(status(&Inventory {
+            replicas: Vec::new(),
+            routes: vec![route],
+        }))
+        .await;
+        let route = &json["routes"][0];
+
+        assert_eq!(route["uri"], "/api");
+        assert_eq!(route["listen"], serde_json::json!(["127.0.0.1:8080"]));
+        assert_eq!(route["latency"]["count"], 1);
+        assert!(route["latency"]["p99"].as_u64().unwrap() >= 5000);
+        assert_eq!(
+            (route["received"].as_u64(), route["sent"].as_u64()),
+            (Some(0), Some(512))
+        );
+        assert_eq!(
+            route["backends"],
+            serde_json::json!([{
+                "address": "127.0.0.1:9000",
+                "weight": 2,
+                "requests": 1,
+                "in_flight": 1,
+                "max_requests": 1,
+                "backup": false,
+            }])
+        );
+    }
+
+    #[test]
+    fn split_changes() {
+        let weights = BTreeMap::from([(String::from("stable"), 95), (String::from("canary"), 5)]);
+        let splitter = Arc::new(Splitter::new(&weights));
+        let inventory = Inventory {
+            replicas: Vec::new(),
+            routes: vec![Route {
+                server: Some(String::from("web")),
+                listen: vec!["127.0.0.1:8080".parse().unwrap()],
+                uri: String::from("/api"),
+                latency: Arc::default(),
+                traffic: Arc::default(),
+                picks: None,
+                split: Some(splitter.clone()),
+                files: None,
+            }],
+        };
+        let change = |uri: &str, split: &[(&str, u32)]| SplitChange {
+            server: Some(String::from("web")),
+            uri: String::from(uri),
+            split: split
+                .iter()
+                .map(|(group, weight)| (String::from(*group), *weight))
+                .collect(),
+            active: None,
+        };
+
+        let changed = change_split(&inventory, &change("/api", &[("canary", 50)])).unwrap();
+        assert_eq!(
+            changed,
+            BTreeMap::from([(String::from("stable"), 95), (String::from("canary"), 50)])
+        );
+        assert_eq!(splitter.weights(), changed);
+
+        let rejected = [
+            change_split(&inventory, &change("/web", &[("canary", 50)])),
+            change_split(&inventory, &change("/api", &[("beta", 50)])),
+            change_split(&inventory, &change("/api", &[("stable", 0), ("canary", 0)])),
+        ];
+        let statuses: Vec<_> = rejected
+            .into_iter()
+            .map(|result| result.unwrap_err().0)
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::NOT_FOUND,
+                StatusCode::BAD_REQUEST,
+                StatusCode::BAD_REQUEST
+            ]
+        );
+        assert_eq!(splitter.weights(), changed);
+
+        let flip = SplitChange {
+            active: Some(String::from("canary")),
+            ..change("/api", &[])
+        };
+        let flipped = change_split(&inventory, &flip).unwrap();
+        assert_eq!(
+            flipped,
+            BTreeMap::from([(String::from("stable"), 0), (String::from("canary"), 1)])
+        );
+
+        let loopback = IpAddr::from([127, 0, 0, 1]);
+        let remote = IpAddr::from([192, 0, 2, 1]);
+        let request = |token: &str| {
+            let request = Request::builder();
+            match token {
+                "" => request,
+                token => request.header(header::AUTHORIZATION, format!("Bearer {token}")),
+            }
+            .body(())
+            .unwrap()
+        };
+        assert!(authorized(&request(""), None, loopback));
+        assert!(!authorized(&request(""), None, remote));
+        assert!(authorized(&request("s3cret"), Some("s3cret"), remote));
+        assert!(!authorized(&request("s3cre"), Some("s3cret"), remote));
+        assert!(!authorized(&request(""), Some("s3cret"), loopback));
+    }
+
+    #[tokio::test]
+    async fn purges_cached_files() {
+        let directory = std::env::temp_dir().join(format!("xnav-purge-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("css")).unwrap();
+        for file in ["index.html", "css/app.css", "css/print.css"] {
+            std::fs::write(directory.join(file), file).unwrap();
+        }
+        let directory = directory.canonicalize().unwrap();
+
+        let cache: config::FileCache = toml::from_str("").unwrap();
+        for file in ["index.html", "css/app.css", "css/print.css"] {
+            cache.read(&directory, &directory.join(file)).await.unwrap();
+        }
+        let inventory = Inventory {
+            replicas: Vec::new(),
+            routes: vec![Route {
+                server: Some(String::from("web")),
+                listen: vec!["127.0.0.1:8080".parse().unwrap()],
+                uri: String::from("/static"),
+                latency: Arc::default(),
+                traffic: Arc::default(),
+                picks: None,
+                split: None,
+                files: Some(CachedFiles {
+                    directory: directory.to_string_lossy().into_owned(),
+                    alias: true,
+                    cache,
+                }),
+            }],
+        };
+        let purge = |url: Option<&str>, prefix: Option<&str>| Purge {
+            server: None,
+            url: url.map(String::from),
+            prefix: prefix.map(String::from),
+        };
+
+        let usage = json(status(&inventory)).await["routes"][0]["file_cache"].clone();
+        assert_eq!(
+            (&usage["misses"], &usage["entries"], &usage["bytes"]),
+            (&3.into(), &3.into(), &34.into())
+        );
+
+        let purged = |url, prefix| purge_files(&inventory, &purge(url, prefix)).unwrap();
+        assert_eq!(purged(Some("/static/css/app.css"), None), 1);
+        assert_eq!(purged(Some("/static/css/app.css"), None), 0);
+        assert_eq!(purged(None, Some("/static/css/")), 1);
+        assert_eq!(purged(None, Some("/")), 1);
+        assert!(purge_files(&inventory, &purge(None, None)).is_err());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
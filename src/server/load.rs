@@ -0,0 +1,67 @@
+//! Detection of overload, during which low priority requests are shed.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::config::LoadShedding;
+
+/// Set while any of the thresholds of the monitor is exceeded.
+static OVERLOADED: AtomicBool = AtomicBool::new(false);
+
+/// Whether requests of low priority patterns should be rejected.
+pub fn overloaded() -> bool {
+    OVERLOADED.load(Ordering::Relaxed)
+}
+
+/// Measures the lag of the runtime timers and the memory of the process every
+/// `interval`, until the task is aborted.
+pub(super) async fn monitor(config: LoadShedding) {
+    loop {
+        let started = Instant::now();
+        tokio::time::sleep(config.interval).await;
+        let lag = started.elapsed().saturating_sub(config.interval);
+
+        let lagging = config.max_lag.is_some_and(|max_lag| lag > max_lag);
+        let memory = config.max_memory.and_then(|_| resident_memory());
+        let swollen = config
+            .max_memory
+            .zip(memory)
+            .is_some_and(|(max_memory, memory)| memory > max_memory);
+
+        let overloaded = lagging || swollen;
+        if OVERLOADED.swap(overloaded, Ordering::Relaxed) != overloaded {
+            if overloaded {
+                warn!(?lag, memory, "Overloaded, shedding low priority requests");
+            } else {
+                info!(?lag, memory, "No longer overloaded");
+            }
+        }
+    }
+}
+
+/// Resident set size of the process in bytes, only known on Linux.
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_resident_memory(&status)
+}
+
+fn parse_resident_memory(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resident_memory_from_status() {
+        let status = "Name:\txnav\nVmPeak:\t  20000 kB\nVmRSS:\t   5120 kB\nThreads:\t4\n";
+        assert_eq!(parse_resident_memory(status), Some(5 * 1024 * 1024));
+        assert_eq!(parse_resident_memory("Name:\txnav\n"), None);
+    }
+}
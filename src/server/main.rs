@@ -1,43 +1,145 @@
 use std::future::{self, Future};
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 
-use tokio::sync::{broadcast, watch};
+use hyper::header::HeaderName;
+use tokio::sync::broadcast;
+use tracing::error;
 
 use crate::{
-    config::Config,
-    server::{Server, State},
+    config::{Action, Config, LoadShedding},
+    server::{
+        admin::Admin,
+        load,
+        snapshot::{CachedFiles, Inventory, Replica, Route, Snapshot},
+        Server, ShutdownEvent, ShutdownEvents,
+    },
+    service::{Capturer, RequestHook, Warm},
 };
 
 /// The master task is responsible for creating, spawning, and shutting down all the server instances described in the configuration file.
 pub struct Master {
     servers: Vec<Server>,
-    states: Vec<(SocketAddr, watch::Receiver<State>)>,
+    sockets: Vec<SocketAddr>,
+    inventory: Arc<Inventory>,
+    admin: Option<Admin>,
+    /// Pools of connections to keep open, with their size.
+    warm: Vec<(Arc<Warm>, usize)>,
+    load_shedding: Option<LoadShedding>,
     shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
     shutdown_notify: broadcast::Sender<()>,
+    shutdown_events: ShutdownEvents,
 }
 
 impl Master {
     /// Attempts to initialize all the servers specified in the configuration file.
-    pub fn init(config: Config) -> Result<Self, crate::Error> {
+    pub fn init(mut config: Config) -> Result<Self, crate::Error> {
+        config.inherit_upstreams().map_err(crate::Error::Config)?;
+        config.load_credentials().map_err(crate::Error::Config)?;
+        config.load_geoip().map_err(crate::Error::Config)?;
+
         let mut servers = Vec::new();
-        let mut states = Vec::new();
+        let mut replicas = Vec::new();
+        let mut routes = Vec::new();
+        let mut warm = Vec::new();
         let shutdown = Box::pin(future::pending());
         let (shutdown_notify, _) = broadcast::channel(1);
+        let shutdown_events = ShutdownEvents::new();
+        let capturer = config
+            .admin
+            .as_ref()
+            .and_then(|admin| admin.capture.clone())
+            .map(|capture| {
+                let secrets = config
+                    .servers
+                    .iter()
+                    .flat_map(|server| &server.patterns)
+                    .flat_map(|pattern| {
+                        let api_key = pattern.auth.as_ref().and_then(|auth| auth.api_key.as_ref());
+                        api_key.map(|api_key| &api_key.header).into_iter().chain(
+                            pattern
+                                .signature
+                                .as_ref()
+                                .map(|signature| &signature.header),
+                        )
+                    })
+                    .filter_map(|name| HeaderName::try_from(name.as_str()).ok());
+
+                Arc::new(Capturer::new(capture).redacting(secrets))
+            });
 
         for server_config in config.servers {
+            for pattern in &server_config.patterns {
+                if let (Action::Forward(forward), 1..) = (&pattern.action, pattern.warm_connections)
+                {
+                    warm.push((forward.warm.clone(), pattern.warm_connections));
+                }
+                routes.push(Route {
+                    server: server_config.name.clone(),
+                    listen: server_config.listen.clone(),
+                    uri: pattern.uri.clone(),
+                    latency: pattern.latency.clone(),
+                    traffic: pattern.traffic.clone(),
+                    picks: match &pattern.action {
+                        Action::Forward(forward) => Some(forward.picks.clone()),
+                        Action::Serve(_) => None,
+                    },
+                    split: pattern.split.as_ref().map(|split| split.splitter.clone()),
+                    files: match (&pattern.action, &pattern.file_cache) {
+                        (Action::Serve(directory), Some(cache)) => Some(CachedFiles {
+                            directory: directory.clone(),
+                            alias: pattern.alias,
+                            cache: cache.clone(),
+                        }),
+                        _ => None,
+                    },
+                });
+            }
+
             for replica in 0..server_config.listen.len() {
-                let server = Server::init(server_config.clone(), replica)?;
-                states.push((server.socket_address(), server.subscribe()));
+                let mut server = Server::init(server_config.clone(), replica)?
+                    .shutdown_events(shutdown_events.clone());
+                if let Some(capturer) = &capturer {
+                    server = server.capturer(capturer.clone());
+                }
+                replicas.push(Replica {
+                    address: server.socket_address(),
+                    name: server_config.name.clone(),
+                    state: server.subscribe(),
+                    connections: server.subscribe_connections(),
+                    counters: server.connection_counters(),
+                    max_connections: server_config.max_connections,
+                    requests: server_config.requests.clone(),
+                    max_requests: server_config.max_requests,
+                    rules: server_config
+                        .rules
+                        .iter()
+                        .map(|rule| (rule.name.clone(), rule.counters.clone()))
+                        .collect(),
+                });
                 servers.push(server);
             }
         }
 
+        let sockets = replicas.iter().map(|replica| replica.address).collect();
+        let inventory = Arc::new(Inventory { replicas, routes });
+
+        let admin = match &config.admin {
+            Some(admin) => Some(Admin::init(admin, inventory.clone(), capturer)?),
+            None => None,
+        };
+
         Ok(Self {
             servers,
-            states,
+            sockets,
+            inventory,
+            admin,
+            warm,
+            load_shedding: config.load_shedding,
             shutdown,
             shutdown_notify,
+            shutdown_events,
         })
     }
 
@@ -59,6 +161,22 @@ impl Master {
         self
     }
 
+    /// Registers a hook that observes every request handled by all servers.
+    pub fn hook(mut self, hook: Arc<dyn RequestHook>) -> Self {
+        self.servers = self
+            .servers
+            .into_iter()
+            .map(|server| server.hook(hook.clone()))
+            .collect();
+
+        self
+    }
+
+    /// Subscribes to the progress of the graceful shutdown of all servers.
+    pub fn subscribe_shutdown(&self) -> broadcast::Receiver<ShutdownEvent> {
+        self.shutdown_events.subscribe()
+    }
+
     /// Runs all servers and initiates termination when the shutdown future completes.
     pub async fn run(self) -> Result<(), crate::Error> {
         let mut set = tokio::task::JoinSet::new();
@@ -67,19 +185,40 @@ impl Master {
             set.spawn(server.run());
         }
 
+        // The status endpoint keeps running during graceful shutdown so that
+        // pending connections can be observed.
+        let admin = self.admin.map(|admin| {
+            tokio::spawn(async move {
+                if let Err(err) = admin.run().await {
+                    error!(%err, "Admin listener stopped");
+                }
+            })
+        });
+
+        let monitor = self
+            .load_shedding
+            .map(|config| tokio::spawn(load::monitor(config)));
+
+        let warming: Vec<_> = self
+            .warm
+            .into_iter()
+            .map(|(warm, connections)| {
+                tokio::spawn(async move { warm.maintain(connections).await })
+            })
+            .collect();
+
         let mut first_error = None;
 
         tokio::select! {
             Some(Ok(Err(err))) = set.join_next() => {
                 first_error = Some(err);
-                println!("Master => Received error while waiting for shutdown");
+                error!("Master received error while waiting for shutdown");
             }
 
-            _ = self.shutdown => {
-                println!("Master => Sending shutdown signal to all servers");
-            }
+            _ = self.shutdown => {}
         }
 
+        self.shutdown_events.send(ShutdownEvent::Started);
         self.shutdown_notify.send(()).unwrap();
 
         while let Some(result) = set.join_next().await {
@@ -88,14 +227,33 @@ impl Master {
             }
         }
 
+        if let Some(admin) = admin {
+            admin.abort();
+        }
+
+        if let Some(monitor) = monitor {
+            monitor.abort();
+        }
+
+        for task in warming {
+            task.abort();
+        }
+
+        self.shutdown_events.send(ShutdownEvent::Done);
+
         match first_error {
             None => Ok(()),
             Some(err) => Err(crate::Error::from(err)),
         }
     }
 
+    /// Captures the current state and counters of all servers.
+    pub fn snapshot(&self) -> Snapshot {
+        self.inventory.snapshot()
+    }
+
     /// Returns the addresses of all listening sockets.
     pub fn sockets(&self) -> Vec<SocketAddr> {
-        self.states.iter().map(|(addr, _)| *addr).collect()
+        self.sockets.clone()
     }
 }
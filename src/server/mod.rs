@@ -1,7 +1,14 @@
 //! This module defines the main server architecture, organizing tasks and handling requests.
 
+mod admin;
+mod load;
 mod main;
 mod server;
+mod shutdown;
+mod snapshot;
 
+pub use load::overloaded;
 pub use main::Master;
-pub use server::{Server, ShutdownState, State};
+pub use server::{ConnectionCounters, Server, ShutdownState, State};
+pub use shutdown::{ShutdownEvent, ShutdownEvents};
+pub use snapshot::{BackendSnapshot, RouteSnapshot, ServerSnapshot, Snapshot};
@@ -1,16 +1,32 @@
-use std::{future::Future, io, net::SocketAddr, pin::Pin, ptr, sync::Arc};
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use hyper::server::conn::http1::Builder;
+use serde::Serialize;
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::{watch, Semaphore},
+    sync::{broadcast, watch, Semaphore},
     TcpSocket,
 };
 use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
 use crate::{
     config,
-    service::Xnav,
+    metrics::{self, ConnectionEvent},
+    server::{ShutdownEvent, ShutdownEvents},
+    service::{Capturer, Hooks, RequestHook, Tunnels, Xnav},
     sync::{Notification, Notifier},
 };
 pub struct Server {
@@ -21,10 +37,36 @@ pub struct Server {
     notifier: Notifier,
     shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
     connections: Arc<Semaphore>,
+    active_connections: watch::Sender<usize>,
+    counters: Arc<ConnectionCounters>,
+    shutdown_events: ShutdownEvents,
+    hooks: Vec<Arc<dyn RequestHook>>,
+    capturer: Option<Arc<Capturer>>,
+}
+
+/// Totals of the connections handled by a listener.
+#[derive(Debug, Default)]
+pub struct ConnectionCounters {
+    accepted: AtomicU64,
+    limited: AtomicU64,
+}
+
+impl ConnectionCounters {
+    /// Connections accepted since the server started.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the listener stopped accepting connections because
+    /// `max_connections` was reached. Clients wait in the backlog meanwhile.
+    pub fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
 }
 
 /// Represents the current state of the server.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum State {
     Starting,
     Listening,
@@ -33,7 +75,8 @@ pub enum State {
 }
 
 /// Represents a state in the graceful shutdown process.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ShutdownState {
     PendingConnections(usize),
     Done,
@@ -59,6 +102,7 @@ impl Server {
         let notifier = Notifier::new();
         let shutdown = Box::pin(std::future::pending());
         let connections = Arc::new(Semaphore::new(config.max_connections));
+        let (active_connections, _) = watch::channel(0);
 
         Ok(Self {
             state,
@@ -68,6 +112,11 @@ impl Server {
             notifier,
             shutdown,
             connections,
+            active_connections,
+            counters: Arc::default(),
+            shutdown_events: ShutdownEvents::new(),
+            hooks: Vec::new(),
+            capturer: None,
         })
     }
 
@@ -79,6 +128,29 @@ impl Server {
         self
     }
 
+    /// Sends shutdown progress to `events` instead of a stream of its own.
+    pub fn shutdown_events(mut self, events: ShutdownEvents) -> Self {
+        self.shutdown_events = events;
+        self
+    }
+
+    /// Registers a hook that observes every request handled by this server.
+    pub fn hook(mut self, hook: Arc<dyn RequestHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Records sampled requests of this server into `capturer`.
+    pub fn capturer(mut self, capturer: Arc<Capturer>) -> Self {
+        self.capturer = Some(capturer);
+        self
+    }
+
+    /// Subscribes to the shutdown progress events of this server.
+    pub fn subscribe_shutdown(&self) -> broadcast::Receiver<ShutdownEvent> {
+        self.shutdown_events.subscribe()
+    }
+
     /// Gets the socket address of the listener.
     pub fn socket_address(&self) -> SocketAddr {
         self.address
@@ -89,6 +161,22 @@ impl Server {
         self.state.subscribe()
     }
 
+    /// Number of connections currently being served, each of them holds one
+    /// of the `max_connections` permits.
+    pub fn active_connections(&self) -> usize {
+        *self.active_connections.borrow()
+    }
+
+    /// Subscribes to changes in the number of active connections.
+    pub fn subscribe_connections(&self) -> watch::Receiver<usize> {
+        self.active_connections.subscribe()
+    }
+
+    /// Connection totals of this server, updated while it runs.
+    pub fn connection_counters(&self) -> Arc<ConnectionCounters> {
+        self.counters.clone()
+    }
+
     /// Begins accepting connections and running the server.
     pub async fn run(self) -> Result<(), crate::Error> {
         let Self {
@@ -99,6 +187,11 @@ impl Server {
             shutdown,
             address,
             connections,
+            active_connections,
+            counters,
+            shutdown_events,
+            hooks,
+            capturer,
         } = self;
 
         let log_name = if let Some(ref id) = config.name {
@@ -110,45 +203,89 @@ impl Server {
         config.log_name = log_name.clone();
 
         state.send_replace(State::Listening);
-        println!("{log_name} => Listening for requests");
+        info!(server = %log_name, "Listening for requests");
 
         let config = Box::leak(Box::new(config));
+        let abort = CancellationToken::new();
+        let tunnels = Tunnels::new(abort.clone());
 
         let listener = Listener {
             config,
+            address,
             connections,
+            active_connections: active_connections.clone(),
+            counters,
+            hooks: hooks.into(),
+            capturer,
             listener,
             notifier: &notifier,
             state: &state,
+            tunnels: tunnels.clone(),
+            abort: abort.clone(),
         };
 
         tokio::select! {
             result = listener.listen() => {
                 if let Err(err) = result {
-                    println!("{log_name} => Error while accepting connections: {err}");
+                    error!(server = %log_name, %err, "Error while accepting connections");
                 }
             }
-            _ = shutdown => {
-                println!("{log_name} => Received shutdown signal");
-            }
+            _ = shutdown => {}
         }
 
         drop(listener);
 
-        if let Ok(num_tasks) = notifier.send(Notification::Shutdown) {
-            println!("{log_name} => Can't shutdown yet, {num_tasks} pending connections");
+        let pending = notifier.send(Notification::Shutdown).unwrap_or(0);
+        shutdown_events.send(ShutdownEvent::Draining {
+            server: address,
+            pending,
+        });
+
+        if pending > 0 {
             state.send_replace(State::ShuttingDown(ShutdownState::PendingConnections(
-                num_tasks,
+                pending,
             )));
+        }
+
+        let drain_timeout = config.drain_timeout;
+        let drained = async {
             notifier.collect_acknowledgements().await;
+            if drain_timeout.is_some() {
+                tunnels.closed().await;
+            }
+        };
+        tokio::pin!(drained);
+
+        let in_time = match drain_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, &mut drained).await.is_ok(),
+            None => {
+                (&mut drained).await;
+                true
+            }
+        };
+
+        if !in_time {
+            warn!(
+                server = %log_name,
+                connections = *active_connections.borrow(),
+                tunnels = tunnels.len(),
+                "Drain timeout passed, aborting what is left"
+            );
+            abort.cancel();
+            drained.await;
         }
 
+        // Connection tasks are gone, so tunnels are the only ones that could
+        // still be around.
+        abort.cancel();
+        tunnels.closed().await;
+
         unsafe {
             drop(Box::from_raw(ptr::from_ref(config).cast_mut()));
         }
 
         state.send_replace(State::ShuttingDown(ShutdownState::Done));
-        println!("{log_name} => Shutdown complete");
+        shutdown_events.send(ShutdownEvent::ServerDone { server: address });
 
         Ok(())
     }
@@ -157,9 +294,17 @@ impl Server {
 struct Listener<'a> {
     listener: TcpListener,
     config: &'static config::Server,
+    address: SocketAddr,
     notifier: &'a Notifier,
     state: &'a watch::Sender<State>,
     connections: Arc<Semaphore>,
+    active_connections: watch::Sender<usize>,
+    counters: Arc<ConnectionCounters>,
+    hooks: Hooks,
+    capturer: Option<Arc<Capturer>>,
+    tunnels: Tunnels,
+    /// Cancelled when the connections left are aborted on shutdown.
+    abort: CancellationToken,
 }
 
 impl<'a> Listener<'a> {
@@ -169,43 +314,341 @@ impl<'a> Listener<'a> {
             let mut notify_listening_again = false;
 
             if self.connections.available_permits() == 0 {
-                println!(
-                    "{} => Reached max connections: {}",
-                    config.log_name, config.max_connections
+                warn!(
+                    server = %config.log_name,
+                    max_connections = config.max_connections,
+                    "Reached max connections"
                 );
                 self.state
                     .send_replace(State::MaxConnectionsReached(config.max_connections));
+                self.counters.limited.fetch_add(1, Ordering::Relaxed);
+                record_connection(
+                    config,
+                    self.address,
+                    ConnectionEvent::Limited,
+                    config.max_connections,
+                );
                 notify_listening_again = true;
             }
 
             let permit = self.connections.clone().acquire_owned().await.unwrap();
 
             if notify_listening_again {
-                println!("{} => Accepting connections again", config.log_name);
+                info!(server = %config.log_name, "Accepting connections again");
                 self.state.send_replace(State::Listening);
             }
 
             let (stream, client_addr) = self.listener.accept().await?;
+            if config
+                .ban
+                .as_ref()
+                .is_some_and(|ban| ban.bans(client_addr.ip()))
+            {
+                debug!(server = %config.log_name, client = %client_addr, "Refusing banned client");
+                continue;
+            }
+            if !crate::service::allows(&config.allow, &config.deny, client_addr.ip()) {
+                debug!(server = %config.log_name, client = %client_addr, "Refusing client address");
+                continue;
+            }
             let mut subscription = self.notifier.subscribe();
             let server_addr = stream.local_addr()?;
+            let active_connections = self.active_connections.clone();
+            active_connections.send_modify(|active| *active += 1);
+            self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+            let active = *active_connections.borrow();
+            record_connection(config, self.address, ConnectionEvent::Accepted, active);
+            let address = self.address;
+            let (requests, in_flight) = watch::channel(0);
+            let starved = CancellationToken::new();
+            let service = Xnav::new(config, client_addr, server_addr)
+                .with_hooks(self.hooks.clone())
+                .with_capturer(self.capturer.clone())
+                .with_requests(requests)
+                .with_tunnels(self.tunnels.clone())
+                .with_starved(starved.clone());
+            let abort = self.abort.clone();
 
             tokio::task::spawn(async move {
-                if let Err(err) = Builder::new()
+                let connection = Builder::new()
                     .preserve_header_case(true)
                     .title_case_headers(true)
-                    .serve_connection(stream, Xnav::new(config, client_addr, server_addr))
-                    .with_upgrades()
-                    .await
-                {
-                    println!("Failed to serve connection: {:?}", err);
+                    .serve_connection(stream, service)
+                    .with_upgrades();
+
+                // Clients that never send a request only get to hold a permit
+                // for so long.
+                let result = tokio::select! {
+                    result = connection => result,
+                    () = idle(in_flight, config.header_timeout, config.idle_timeout) => {
+                        debug!(server = %config.log_name, client = %client_addr, "Closing idle connection");
+                        Ok(())
+                    }
+                    () = starved.cancelled() => {
+                        debug!(server = %config.log_name, client = %client_addr, "Closing slow connection");
+                        Ok(())
+                    }
+                    () = abort.cancelled() => {
+                        debug!(server = %config.log_name, client = %client_addr, "Aborting connection");
+                        Ok(())
+                    }
+                };
+
+                if let Err(err) = result {
+                    error!(server = %config.log_name, client = %client_addr, %err, "Failed to serve connection");
                 }
 
                 if let Some(Notification::Shutdown) = subscription.receive_notification() {
                     subscription.acknowledge_notification().await;
                 }
 
+                active_connections.send_modify(|active| *active -= 1);
+                record_connection(
+                    config,
+                    address,
+                    ConnectionEvent::Closed,
+                    *active_connections.borrow(),
+                );
                 drop(permit);
             });
         }
     }
 }
+
+/// Resolves once a connection goes without a request in flight for longer
+/// than `header_timeout` before its first request, or `idle_timeout` after
+/// any of the following ones.
+async fn idle(
+    mut requests: watch::Receiver<usize>,
+    header_timeout: Duration,
+    idle_timeout: Duration,
+) {
+    let mut timeout = header_timeout;
+
+    loop {
+        match tokio::time::timeout(timeout, requests.changed()).await {
+            Err(_) => return,
+            // The service is gone along with the connection.
+            Ok(Err(_)) => return std::future::pending().await,
+            Ok(Ok(())) => {}
+        }
+
+        if requests.wait_for(|requests| *requests == 0).await.is_err() {
+            return std::future::pending().await;
+        }
+
+        timeout = idle_timeout;
+    }
+}
+
+/// Reports a connection event of the listener bound to `listener`.
+fn record_connection(
+    config: &config::Server,
+    listener: SocketAddr,
+    event: ConnectionEvent,
+    active: usize,
+) {
+    metrics::record_connection(&metrics::ConnectionSample {
+        server: config.name.as_deref().unwrap_or(&config.log_name),
+        listener,
+        event,
+        active,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use hyper::http::response;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tracing::{
+        field::{Field, Visit},
+        Event, Level, Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        Layer,
+    };
+
+    use super::*;
+    use crate::{logging, service::RequestInfo};
+
+    /// Sends a GET request for `path` to `address`, returning the raw
+    /// response.
+    async fn get(address: SocketAddr, path: &str) -> Vec<u8> {
+        let mut client = TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nhost: xnav\r\nconnection: close\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    /// Writes down the callbacks it gets.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl RequestHook for Recorder {
+        fn on_request(&self, request: &RequestInfo) {
+            let call = format!("request {} {}", request.method, request.uri.path());
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn on_upstream_selected(&self, _request: &RequestInfo, backend: SocketAddr) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("upstream {backend}"));
+        }
+
+        fn on_response(&self, _request: &RequestInfo, response: &response::Parts) {
+            let call = format!("response {}", response.status.as_u16());
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    /// Writes down the level and route of access log entries.
+    #[derive(Clone, Default)]
+    struct AccessLog {
+        entries: Arc<Mutex<Vec<(Level, String)>>>,
+    }
+
+    struct Route(String);
+
+    impl Visit for Route {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "route" {
+                self.0 = format!("{value:?}");
+            }
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for AccessLog {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            if event.metadata().target() != logging::ACCESS {
+                return;
+            }
+            let mut route = Route(String::new());
+            event.record(&mut route);
+            let level = *event.metadata().level();
+            self.entries.lock().unwrap().push((level, route.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_access_per_pattern() {
+        let config = toml::from_str::<config::Server>(
+            r#"
+                listen = ["127.0.0.1:0"]
+
+                [[match]]
+                uri = "/healthz"
+                forward = "127.0.0.1:1"
+                access_log = false
+
+                [[match]]
+                uri = "/debug"
+                forward = "127.0.0.1:1"
+                access_log_level = "debug"
+
+                [[match]]
+                uri = "/"
+                forward = "127.0.0.1:1"
+            "#,
+        )
+        .unwrap();
+        let log = AccessLog::default();
+        let _subscriber =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(log.clone()));
+        let server = Server::init(config, 0).unwrap();
+        let address = server.socket_address();
+        let task = tokio::spawn(server.run());
+
+        // Entries are written once the exchange is over, which might be
+        // right after the client got the whole response.
+        let logged = |count| {
+            let entries = log.entries.clone();
+            async move {
+                for _ in 0..100 {
+                    if entries.lock().unwrap().len() >= count {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+        for (path, count) in [("/healthz", 0), ("/debug", 1), ("/", 2)] {
+            get(address, path).await;
+            logged(count).await;
+        }
+        task.abort();
+
+        assert_eq!(
+            *log.entries.lock().unwrap(),
+            [
+                (Level::DEBUG, String::from("/debug")),
+                (Level::INFO, String::from("/"))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_request_hooks() {
+        let config = toml::from_str::<config::Server>(
+            r#"
+                listen = ["127.0.0.1:0"]
+                forward = "127.0.0.1:1"
+            "#,
+        )
+        .unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let server = Server::init(config, 0).unwrap().hook(recorder.clone());
+        let address = server.socket_address();
+        let task = tokio::spawn(server.run());
+
+        let response = get(address, "/api").await;
+        task.abort();
+
+        // Nothing listens on the backend, which answers with a 502.
+        assert!(response.starts_with(b"HTTP/1.1 502"));
+        assert_eq!(
+            *recorder.calls.lock().unwrap(),
+            ["request GET /api", "upstream 127.0.0.1:1", "response 502"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_shutdown_progress() {
+        let config = toml::from_str::<config::Server>(
+            r#"
+                listen = ["127.0.0.1:0"]
+                forward = "127.0.0.1:9000"
+            "#,
+        )
+        .unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = Server::init(config, 0).unwrap().shutdown_on(stopped);
+        let address = server.socket_address();
+        let mut events = server.subscribe_shutdown();
+        let mut connections = server.subscribe_connections();
+        let task = tokio::spawn(server.run());
+
+        let client = TcpStream::connect(address).await.unwrap();
+        connections.wait_for(|active| *active == 1).await.unwrap();
+        stop.send(()).unwrap();
+
+        let draining = ShutdownEvent::Draining {
+            server: address,
+            pending: 1,
+        };
+        assert_eq!(events.recv().await.unwrap(), draining);
+
+        drop(client);
+        let done = ShutdownEvent::ServerDone { server: address };
+        assert_eq!(events.recv().await.unwrap(), done);
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+//! Progress events of the graceful shutdown.
+
+use std::net::SocketAddr;
+
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// Step of the graceful shutdown of the master or one of its servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownEvent {
+    /// Servers were told to stop accepting connections.
+    Started,
+    /// A server stopped accepting connections and waits for the pending ones.
+    Draining { server: SocketAddr, pending: usize },
+    /// A server closed all its connections.
+    ServerDone { server: SocketAddr },
+    /// All the servers are done.
+    Done,
+}
+
+/// Sender side of the shutdown event stream. Every event is also logged, so
+/// that shutdown looks the same in the logs no matter who triggered it.
+#[derive(Clone)]
+pub struct ShutdownEvents {
+    sender: broadcast::Sender<ShutdownEvent>,
+}
+
+impl ShutdownEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self { sender }
+    }
+
+    /// Subscribes to events sent from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ShutdownEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Logs `event` and sends it to all the subscribers.
+    pub fn send(&self, event: ShutdownEvent) {
+        match event {
+            ShutdownEvent::Started => info!("Shutdown started"),
+            ShutdownEvent::Draining { server, pending } => {
+                info!(%server, pending, "Draining connections")
+            }
+            ShutdownEvent::ServerDone { server } => info!(%server, "Server shutdown complete"),
+            ShutdownEvent::Done => info!("Shutdown complete"),
+        }
+
+        // Nobody listening is fine.
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for ShutdownEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_to_subscribers() {
+        let events = ShutdownEvents::new();
+        events.send(ShutdownEvent::Started);
+
+        let mut first = events.subscribe();
+        let mut second = events.clone().subscribe();
+        events.send(ShutdownEvent::Done);
+
+        assert_eq!(first.recv().await.unwrap(), ShutdownEvent::Done);
+        assert_eq!(second.recv().await.unwrap(), ShutdownEvent::Done);
+        assert!(first.try_recv().is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Point in time view of all the servers run by the [`Master`], shared by
+//! the admin listener and embedders.
+//!
+//! [`Master`]: super::Master
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{
+    config::FileCache,
+    server::{ConnectionCounters, State},
+    service::{CacheUsage, Latency, Percentiles, RuleCounters, Traffic},
+    threading::{Picks, Splitter},
+};
+
+/// Handles needed to report the status of a single server replica.
+pub(super) struct Replica {
+    pub address: SocketAddr,
+    pub name: Option<String>,
+    pub state: watch::Receiver<State>,
+    pub connections: watch::Receiver<usize>,
+    pub counters: Arc<ConnectionCounters>,
+    pub max_connections: usize,
+    /// Requests in flight on all the replicas of the server.
+    pub requests: Arc<AtomicUsize>,
+    pub max_requests: Option<usize>,
+    /// Rules of the server with their counters, shared by its replicas.
+    pub rules: Vec<(String, Arc<RuleCounters>)>,
+}
+
+/// Pattern of a server whose latencies are reported.
+pub(super) struct Route {
+    pub server: Option<String>,
+    pub listen: Vec<SocketAddr>,
+    pub uri: String,
+    pub latency: Arc<Latency>,
+    pub traffic: Arc<Traffic>,
+    /// Requests routed to each backend, for forward actions.
+    pub picks: Option<Arc<Picks>>,
+    /// Weights of the groups of backends, changed by the admin listener.
+    pub split: Option<Arc<Splitter>>,
+    /// Files cached in memory, purged by the admin listener.
+    pub files: Option<CachedFiles>,
+}
+
+/// Serve directory of a pattern whose files are cached in memory.
+pub(super) struct CachedFiles {
+    pub directory: String,
+    pub alias: bool,
+    pub cache: FileCache,
+}
+
+/// Everything that can be reported about the running servers.
+pub(super) struct Inventory {
+    pub replicas: Vec<Replica>,
+    pub routes: Vec<Route>,
+}
+
+/// State and counters of all servers, see [`Master::snapshot`].
+///
+/// [`Master::snapshot`]: super::Master::snapshot
+#[derive(Serialize, Debug, Clone)]
+pub struct Snapshot {
+    /// Whether all servers are accepting connections.
+    pub ready: bool,
+    pub servers: Vec<ServerSnapshot>,
+    pub routes: Vec<RouteSnapshot>,
+}
+
+/// A single listening replica of a server.
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerSnapshot {
+    pub address: SocketAddr,
+    pub name: Option<String>,
+    pub state: State,
+    pub connections: usize,
+    pub max_connections: usize,
+    /// Connections accepted since startup.
+    pub accepted: u64,
+    /// Times `max_connections` stopped the listener from accepting.
+    pub limited: u64,
+    /// Requests in flight on all the listeners of the server.
+    pub requests: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests: Option<usize>,
+    /// Totals of the rules of the server, over all its replicas.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleSnapshot>,
+}
+
+/// A rule of a server.
+#[derive(Serialize, Debug, Clone)]
+pub struct RuleSnapshot {
+    pub name: String,
+    /// Requests meeting the conditions of the rule.
+    pub matched: u64,
+    /// Matching requests that were blocked or over the rate limit.
+    pub refused: u64,
+}
+
+/// A pattern of a server, with totals over all its replicas.
+#[derive(Serialize, Debug, Clone)]
+pub struct RouteSnapshot {
+    pub server: Option<String>,
+    pub listen: Vec<SocketAddr>,
+    pub uri: String,
+    /// Request latencies in microseconds.
+    pub latency: Percentiles,
+    /// Body bytes received from clients.
+    pub received: u64,
+    /// Body bytes sent to clients.
+    pub sent: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub backends: Vec<BackendSnapshot>,
+    /// Weights of the groups of backends.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub split: Option<BTreeMap<String, u32>>,
+    /// Totals of the in-memory cache of served files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_cache: Option<CacheUsage>,
+}
+
+/// A backend of a forward action.
+#[derive(Serialize, Debug, Clone)]
+pub struct BackendSnapshot {
+    pub address: SocketAddr,
+    pub weight: usize,
+    /// Requests routed to this backend.
+    pub requests: u64,
+    /// Requests waiting for or receiving a response from this backend.
+    pub in_flight: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests: Option<usize>,
+    pub backup: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Milliseconds left before this backend gets requests again, after it
+    /// asked to back off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paused_ms: Option<u64>,
+}
+
+impl Inventory {
+    pub fn snapshot(&self) -> Snapshot {
+        let servers: Vec<_> = self
+            .replicas
+            .iter()
+            .map(|replica| ServerSnapshot {
+                address: replica.address,
+                name: replica.name.clone(),
+                state: *replica.state.borrow(),
+                connections: *replica.connections.borrow(),
+                max_connections: replica.max_connections,
+                accepted: replica.counters.accepted(),
+                limited: replica.counters.limited(),
+                requests: replica.requests.load(Ordering::Acquire),
+                max_requests: replica.max_requests,
+                rules: replica
+                    .rules
+                    .iter()
+                    .map(|(name, counters)| RuleSnapshot {
+                        name: name.clone(),
+                        matched: counters.matched(),
+                        refused: counters.refused(),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let routes = self
+            .routes
+            .iter()
+            .map(|route| RouteSnapshot {
+                server: route.server.clone(),
+                listen: route.listen.clone(),
+                uri: route.uri.clone(),
+                latency: route.latency.percentiles(),
+                received: route.traffic.received(),
+                sent: route.traffic.sent(),
+                backends: route
+                    .picks
+                    .iter()
+                    .flat_map(|picks| picks.counts())
+                    .map(|(backend, usage)| BackendSnapshot {
+                        address: backend.address,
+                        weight: backend.weight,
+                        requests: usage.requests,
+                        in_flight: usage.in_flight,
+                        max_requests: backend.max_requests,
+                        backup: backend.backup,
+                        group: backend.group.clone(),
+                        paused_ms: usage.paused.map(|paused| paused.as_millis() as u64),
+                    })
+                    .collect(),
+                split: route.split.as_ref().map(|splitter| splitter.weights()),
+                file_cache: route.files.as_ref().map(|files| files.cache.store.usage()),
+            })
+            .collect();
+
+        let ready = servers
+            .iter()
+            .all(|server| server.state == State::Listening);
+
+        Snapshot {
+            ready,
+            servers,
+            routes,
+        }
+    }
+}
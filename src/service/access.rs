@@ -0,0 +1,307 @@
+//! Access control by client address, see `allow` and `deny` in [`Pattern`],
+//! and by method, origin and country.
+//!
+//! [`Pattern`]: crate::config::Pattern
+
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::{header, HeaderMap, Method};
+use ipnet::IpNet;
+
+use crate::config::{ClientIpHeader, Csrf};
+
+/// Address of the client behind `peer`. Requests from trusted proxies are
+/// traced back through the `source` header, from the closest hop, up to the
+/// first address that is not a trusted proxy.
+pub fn client_ip(
+    peer: IpAddr,
+    headers: &HeaderMap,
+    source: ClientIpHeader,
+    trusted: &[IpNet],
+) -> IpAddr {
+    if !is_trusted(trusted, peer) {
+        return peer;
+    }
+
+    let mut client = peer;
+    for hop in hops(headers, source).into_iter().rev() {
+        // Past an address that can't be read nothing can be told apart.
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop;
+        if !is_trusted(trusted, hop) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Whether `ip` is one of the `trusted` proxies.
+pub fn is_trusted(trusted: &[IpNet], ip: IpAddr) -> bool {
+    trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// Whether the client of `peer` reached the proxy in front of xnav over
+/// HTTPS, as told by a trusted one in `X-Forwarded-Proto`.
+pub fn is_https(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> bool {
+    is_trusted(trusted, peer)
+        && headers
+            .get("x-forwarded-proto")
+            .and_then(|proto| proto.to_str().ok())
+            .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+/// Whether `given` is the `expected` secret, taking as long to tell for any
+/// `given` of the same length so that secrets can't be guessed by timing.
+pub fn secret_matches(expected: &[u8], given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Whether `client` gets through `allow` and `deny`. Denied networks win,
+/// and a non-empty allow list refuses everyone else.
+pub fn allows(allow: &[IpNet], deny: &[IpNet], client: IpAddr) -> bool {
+    if deny.iter().any(|net| net.contains(&client)) {
+        return false;
+    }
+
+    allow.is_empty() || allow.iter().any(|net| net.contains(&client))
+}
+
+/// Whether a client from `country` gets through `allow` and `deny`, the
+/// country lists of a pattern. Clients of unknown countries only get
+/// through when nothing is allowed in particular.
+pub fn allows_country(allow: &[String], deny: &[String], country: Option<&str>) -> bool {
+    let listed = |list: &[String]| {
+        country.is_some_and(|country| list.iter().any(|code| code.eq_ignore_ascii_case(country)))
+    };
+
+    !listed(deny) && (allow.is_empty() || listed(allow))
+}
+
+/// Whether `method` is one of the `allowed_methods` of a pattern, which are
+/// written in any case. Allowing GET allows HEAD as well.
+pub fn allows_method(allowed: &[String], method: &Method) -> bool {
+    let listed = |method: &str| {
+        allowed
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    };
+
+    // Methods themselves are case-sensitive, `get` is not GET.
+    let uppercase = method
+        .as_str()
+        .bytes()
+        .all(|byte| !byte.is_ascii_lowercase());
+
+    uppercase && (listed(method.as_str()) || *method == Method::HEAD && listed("GET"))
+}
+
+/// Value of the `Allow` header of the responses refusing other methods than
+/// `allowed`.
+pub fn allow_header(allowed: &[String]) -> String {
+    let mut methods: Vec<String> = allowed
+        .iter()
+        .map(|method| method.to_ascii_uppercase())
+        .collect();
+    if methods.iter().any(|method| method == "GET")
+        && !methods.iter().any(|method| method == "HEAD")
+    {
+        methods.push(String::from("HEAD"));
+    }
+
+    methods.join(", ")
+}
+
+/// Whether a request with `method` and `headers` passes the `csrf` check.
+pub fn allows_origin(csrf: &Csrf, method: &Method, headers: &HeaderMap) -> bool {
+    if method.is_safe() {
+        return true;
+    }
+
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let origin = match (header(header::ORIGIN), header(header::REFERER)) {
+        (Some(origin), _) => origin,
+        // The origin is what comes before the path.
+        (None, Some(referer)) => match referer.match_indices('/').nth(2) {
+            Some((end, _)) => &referer[..end],
+            None => referer,
+        },
+        (None, None) => return csrf.allow_missing,
+    };
+
+    if csrf.origins.is_empty() {
+        let host = origin.split_once("://").map(|(_, host)| host);
+        return host.is_some_and(|host| Some(host) == header(header::HOST));
+    }
+
+    csrf.origins
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+}
+
+/// Addresses the request went through according to the `source` header,
+/// starting with the client.
+fn hops(headers: &HeaderMap, source: ClientIpHeader) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+
+    match source {
+        ClientIpHeader::Forwarded => values(header::FORWARDED.as_str())
+            .into_iter()
+            .map(|element| {
+                let node = element.split(';').find_map(|pair| {
+                    let (name, value) = pair.trim().split_once('=')?;
+                    name.eq_ignore_ascii_case("for").then_some(value)
+                });
+                node.and_then(|node| parse(node.trim_matches('"')))
+            })
+            .collect(),
+        ClientIpHeader::XForwardedFor => values("x-forwarded-for").into_iter().map(parse).collect(),
+    }
+}
+
+/// Reads an address with an optional port, IPv6 ones in brackets if they
+/// have a port.
+fn parse(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traces_client_through_trusted_proxies() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let client = IpAddr::from([203, 0, 113, 7]);
+        let xff = ClientIpHeader::XForwardedFor;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.1, 203.0.113.7, 10.0.0.2".parse().unwrap(),
+        );
+        assert_eq!(client_ip(proxy, &headers, xff, &trusted), client);
+        // Only trusted proxies can tell who the client is.
+        assert_eq!(client_ip(client, &headers, xff, &trusted), client);
+        assert_eq!(
+            client_ip(proxy, &headers, ClientIpHeader::Forwarded, &trusted),
+            proxy
+        );
+
+        // Sent by the client, the proxy only appends to X-Forwarded-For.
+        headers.insert(header::FORWARDED, "for=10.0.0.9".parse().unwrap());
+        assert_eq!(client_ip(proxy, &headers, xff, &trusted), client);
+
+        headers.insert(
+            header::FORWARDED,
+            "for=\"[2001:db8::1]:4711\";by=10.0.0.2".parse().unwrap(),
+        );
+        assert_eq!(
+            client_ip(proxy, &headers, ClientIpHeader::Forwarded, &trusted),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        headers.insert(
+            header::FORWARDED,
+            "for=unknown, for=10.0.0.3:80".parse().unwrap(),
+        );
+        assert_eq!(
+            client_ip(proxy, &headers, ClientIpHeader::Forwarded, &trusted),
+            IpAddr::from([10, 0, 0, 3])
+        );
+
+        let allow = ["203.0.113.0/24".parse().unwrap()];
+        let deny = ["203.0.113.7/32".parse().unwrap()];
+        assert!(allows(&allow, &[], client));
+        assert!(!allows(&allow, &deny, client));
+        assert!(!allows(&allow, &[], proxy));
+        assert!(allows(&[], &deny, proxy));
+    }
+
+    #[test]
+    fn tells_https_from_trusted_proxies() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-proto", "HTTPS".parse().unwrap());
+
+        assert!(is_https(IpAddr::from([10, 0, 0, 1]), &headers, &trusted));
+        assert!(!is_https(IpAddr::from([192, 0, 2, 1]), &headers, &trusted));
+        assert!(!is_https(
+            IpAddr::from([10, 0, 0, 1]),
+            &HeaderMap::new(),
+            &trusted
+        ));
+    }
+
+    #[test]
+    fn checks_origin_of_unsafe_requests() {
+        let mut csrf = Csrf {
+            origins: Vec::new(),
+            allow_missing: false,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "example.com".parse().unwrap());
+
+        assert!(allows_origin(&csrf, &Method::GET, &headers));
+        assert!(!allows_origin(&csrf, &Method::POST, &headers));
+
+        headers.insert(
+            header::REFERER,
+            "https://example.com/form?a=b".parse().unwrap(),
+        );
+        assert!(allows_origin(&csrf, &Method::POST, &headers));
+
+        headers.insert(header::ORIGIN, "https://evil.example".parse().unwrap());
+        assert!(!allows_origin(&csrf, &Method::DELETE, &headers));
+
+        csrf.origins = vec![String::from("https://evil.example")];
+        assert!(allows_origin(&csrf, &Method::DELETE, &headers));
+    }
+
+    #[test]
+    fn restricts_methods() {
+        let allowed = vec![String::from("get"), String::from("POST")];
+
+        assert!(allows_method(&allowed, &Method::GET));
+        assert!(allows_method(&allowed, &Method::HEAD));
+        assert!(allows_method(&allowed, &Method::POST));
+        assert!(!allows_method(&allowed, &Method::TRACE));
+        assert!(!allows_method(
+            &allowed,
+            &Method::from_bytes(b"get").unwrap()
+        ));
+        assert!(!allows_method(&[], &Method::GET));
+        assert_eq!(allow_header(&allowed), "GET, POST, HEAD");
+    }
+
+    #[test]
+    fn filters_countries() {
+        let (allow, deny) = (vec![String::from("fr")], vec![String::from("RU")]);
+
+        assert!(allows_country(&allow, &[], Some("FR")));
+        assert!(!allows_country(&allow, &[], Some("DE")));
+        assert!(!allows_country(&allow, &[], None));
+        assert!(!allows_country(&[], &deny, Some("RU")));
+        assert!(allows_country(&[], &deny, None));
+    }
+}
@@ -0,0 +1,514 @@
+//! Credentials required by patterns before serving files or forwarding, see
+//! [`Auth`].
+//!
+//! [`Auth`]: crate::config::Auth
+
+use std::{collections::HashMap, net::SocketAddr, path::Path};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http_body_util::BodyExt;
+use hyper::{
+    header::{self, HeaderName},
+    HeaderMap, Request, StatusCode, Uri,
+};
+use sha1::{Digest, Sha1};
+use tracing::{debug, error};
+
+use crate::{
+    config::{Auth, BasicAuth, ForwardAuth, Pattern},
+    service::{
+        body, oidc, proxy,
+        response::{BoxBodyResponse, LocalResponse},
+        secret_matches,
+    },
+};
+
+/// Users of HTTP Basic auth and their hashed passwords, as found in an
+/// htpasswd file.
+#[derive(Debug, Default)]
+pub struct Users {
+    passwords: HashMap<String, Hash>,
+    /// Bcrypt hash checked for unknown users, with the cost of the others so
+    /// that the time taken doesn't tell which users exist.
+    dummy: Option<String>,
+}
+
+#[derive(Debug)]
+enum Hash {
+    /// `$2y$` and the other bcrypt variants, as made by `htpasswd -B`.
+    Bcrypt(String),
+    /// `{SHA}` followed by the base64 SHA-1 digest, as made by `htpasswd -s`.
+    Sha1(Vec<u8>),
+}
+
+impl Users {
+    /// Reads the htpasswd file at `path`. Fails on lines that are not
+    /// `user:hash` or whose hash is neither bcrypt nor SHA-1.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read users file {path:?}: {err}"))?;
+
+        Self::parse(&content).map_err(|err| format!("invalid users file {path:?}: {err}"))
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut passwords = HashMap::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((user, hash)) = line.split_once(':') else {
+                return Err(format!("line {} is not user:hash", number + 1));
+            };
+
+            let hash = if hash.starts_with("$2") {
+                Hash::Bcrypt(hash.to_owned())
+            } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+                let digest = STANDARD
+                    .decode(digest)
+                    .map_err(|_| format!("line {} has an invalid SHA-1 digest", number + 1))?;
+                Hash::Sha1(digest)
+            } else {
+                return Err(format!("line {} uses an unsupported hash", number + 1));
+            };
+
+            passwords.insert(user.to_owned(), hash);
+        }
+
+        let cost = passwords.values().find_map(|hash| match hash {
+            Hash::Bcrypt(hash) => hash.get(4..6)?.parse().ok(),
+            Hash::Sha1(_) => None,
+        });
+        let dummy = cost
+            .map(|cost| bcrypt::hash("", cost))
+            .transpose()
+            .map_err(|err| format!("unusable bcrypt cost: {err}"))?;
+
+        Ok(Self { passwords, dummy })
+    }
+
+    /// Whether `password` is the one of `user`.
+    fn verify(&self, user: &str, password: &str) -> bool {
+        match self.passwords.get(user) {
+            Some(Hash::Bcrypt(hash)) => bcrypt::verify(password, hash).unwrap_or(false),
+            Some(Hash::Sha1(digest)) => secret_matches(digest, &Sha1::digest(password)),
+            None => {
+                if let Some(dummy) = &self.dummy {
+                    let _ = bcrypt::verify(password, dummy);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// API keys and the labels identifying their owners, as found in a keys
+/// file.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    labels: HashMap<String, String>,
+}
+
+impl ApiKeys {
+    /// Reads the keys file at `path`, made of `label:key` lines.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read keys file {path:?}: {err}"))?;
+
+        Self::parse(&content).map_err(|err| format!("invalid keys file {path:?}: {err}"))
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut labels = HashMap::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(':') {
+                Some((label, key)) if !key.is_empty() => {
+                    labels.insert(key.to_owned(), label.to_owned());
+                }
+                _ => return Err(format!("line {} is not label:key", number + 1)),
+            }
+        }
+
+        Ok(Self { labels })
+    }
+
+    /// Label of `key`, if it's a known key.
+    fn label(&self, key: &str) -> Option<&str> {
+        self.labels.get(key).map(String::as_str)
+    }
+}
+
+/// Outcome of checking the credentials of a request.
+pub enum Verdict {
+    /// The request goes on, along with the label of its API key if the
+    /// pattern requires one.
+    Allowed(Option<String>),
+    Rejected(BoxBodyResponse),
+}
+
+/// Checks the credentials required by `pattern`. Every configured scheme
+/// must succeed for the request to be allowed. Users then need an OIDC
+/// session, and the `forward_auth` service is asked last, both may add
+/// headers to the request.
+pub async fn authenticate<T>(
+    pattern: &Pattern,
+    request: &mut Request<T>,
+    client: SocketAddr,
+) -> Verdict {
+    let label = match &pattern.auth {
+        Some(auth) => match check(auth, request).await {
+            Verdict::Allowed(label) => label,
+            rejected => return rejected,
+        },
+        None => None,
+    };
+
+    let rejected = match &pattern.oidc {
+        Some(settings) => oidc::authenticate(settings, request).await,
+        None => None,
+    };
+    if let Some(rejected) = rejected {
+        return Verdict::Rejected(rejected);
+    }
+
+    let rejected = match &pattern.forward_auth {
+        Some(forward_auth) => ask(forward_auth, request, client).await,
+        None => None,
+    };
+
+    match rejected {
+        Some(rejected) => Verdict::Rejected(rejected),
+        None => Verdict::Allowed(label),
+    }
+}
+
+async fn check<T>(auth: &Auth, request: &mut Request<T>) -> Verdict {
+    let rejected = match &auth.basic {
+        Some(basic) => reject_basic(basic, request.headers()).await,
+        None => None,
+    };
+    if let Some(rejected) = rejected {
+        return Verdict::Rejected(rejected);
+    }
+
+    let Some(api_key) = &auth.api_key else {
+        return Verdict::Allowed(None);
+    };
+
+    // Keys don't go any further than here, backends and logs never see them.
+    let from_query = api_key.query.as_deref().and_then(|name| {
+        let (uri, key) = strip_query(request.uri(), &[name]);
+        *request.uri_mut() = uri;
+        key
+    });
+    let from_header = request
+        .headers()
+        .get(&api_key.header)
+        .and_then(|value| value.to_str().ok());
+
+    let label = from_header
+        .or(from_query.as_deref())
+        .and_then(|key| api_key.keys.label(key))
+        .map(str::to_owned);
+
+    match label {
+        Some(label) => {
+            debug!(label, "Authenticated API key");
+            request.headers_mut().remove(&api_key.header);
+            Verdict::Allowed(Some(label))
+        }
+        None => Verdict::Rejected(unauthorized(String::from("ApiKey"))),
+    }
+}
+
+/// `uri` without the API keys that `patterns` take from the query, so that
+/// they can be logged before knowing the pattern of the request.
+pub fn redact(patterns: &[Pattern], uri: &Uri) -> Uri {
+    let names = patterns
+        .iter()
+        .filter_map(|pattern| pattern.auth.as_ref()?.api_key.as_ref()?.query.as_deref())
+        .collect::<Vec<_>>();
+
+    if names.is_empty() {
+        return uri.clone();
+    }
+
+    strip_query(uri, &names).0
+}
+
+/// `uri` without the query parameters called any of `names`, along with the
+/// percent-decoded value of the first of them.
+fn strip_query(uri: &Uri, names: &[&str]) -> (Uri, Option<String>) {
+    let Some(query) = uri.query() else {
+        return (uri.clone(), None);
+    };
+
+    let mut value = None;
+    let kept = query
+        .split('&')
+        .filter(|pair| {
+            let (param, found) = pair.split_once('=').unwrap_or((pair, ""));
+            if !names.contains(&param) {
+                return true;
+            }
+            if value.is_none() {
+                value = decode(found);
+            }
+            false
+        })
+        .collect::<Vec<_>>();
+
+    if kept.len() == query.split('&').count() {
+        return (uri.clone(), None);
+    }
+
+    let path_and_query = if kept.is_empty() {
+        uri.path().to_owned()
+    } else {
+        format!("{}?{}", uri.path(), kept.join("&"))
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+
+    (
+        Uri::from_parts(parts).unwrap_or_else(|_| uri.clone()),
+        value,
+    )
+}
+
+/// Percent-decoded query value, `+` standing for a space as in forms.
+fn decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut at = 0;
+
+    while at < bytes.len() {
+        match bytes[at] {
+            b'%' => {
+                let hex = value.get(at + 1..at + 3)?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                at += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                at += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                at += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Response rejecting a request without the credentials of a user.
+async fn reject_basic(basic: &BasicAuth, headers: &HeaderMap) -> Option<BoxBodyResponse> {
+    let credentials = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+
+    let Some((user, password)) = credentials
+        .as_deref()
+        .and_then(|credentials| credentials.split_once(':'))
+    else {
+        return Some(challenge(basic));
+    };
+
+    // Bcrypt is deliberately slow, it would hold up the other connections.
+    let users = basic.users.clone();
+    let (user, password) = (user.to_owned(), password.to_owned());
+    let verified =
+        tokio::task::spawn_blocking(move || users.verify(&user, &password).then_some(user))
+            .await
+            .unwrap();
+
+    match verified {
+        Some(user) => {
+            debug!(user, "Authenticated user");
+            None
+        }
+        None => Some(challenge(basic)),
+    }
+}
+
+/// Sends a subrequest with the method, URI and selected headers of
+/// `request` to the auth service, which allows the request by answering
+/// 2xx. Otherwise its answer is sent to the client instead, such as a
+/// redirect to a login page.
+async fn ask<T>(
+    forward_auth: &ForwardAuth,
+    request: &mut Request<T>,
+    client: SocketAddr,
+) -> Option<BoxBodyResponse> {
+    let mut subrequest = Request::builder()
+        .method(request.method())
+        .uri(&forward_auth.path)
+        .header(header::HOST, forward_auth.address.to_string())
+        .header("x-forwarded-method", request.method().as_str())
+        .header("x-forwarded-uri", request.uri().to_string())
+        .header("x-forwarded-for", client.ip().to_string());
+
+    if let Some(host) = request.headers().get(header::HOST) {
+        subrequest = subrequest.header("x-forwarded-host", host);
+    }
+    for name in &forward_auth.request_headers {
+        for value in request.headers().get_all(name.as_str()) {
+            subrequest = subrequest.header(name.as_str(), value);
+        }
+    }
+
+    let Ok(subrequest) = subrequest.body(body::empty().map_err(Into::into).boxed()) else {
+        error!("Invalid forward auth subrequest");
+        return Some(LocalResponse::internal_server_error());
+    };
+
+    let asked = async {
+        let (mut sender, _) = proxy::connect(forward_auth.address).await?;
+        sender
+            .send_request(subrequest)
+            .await
+            .inspect_err(|err| error!(%err, "Forward auth service failed to respond"))
+            .ok()
+    };
+
+    let response = match tokio::time::timeout(forward_auth.timeout, asked).await {
+        Ok(Some(response)) => response,
+        Ok(None) => return Some(LocalResponse::internal_server_error()),
+        Err(_) => {
+            error!(timeout = ?forward_auth.timeout, "Forward auth service timed out");
+            return Some(LocalResponse::internal_server_error());
+        }
+    };
+
+    if !response.status().is_success() {
+        debug!(status = %response.status(), "Forward auth service denied request");
+        return Some(response.map(|body| body.map_err(Into::into).boxed()));
+    }
+
+    for name in &forward_auth.response_headers {
+        let Ok(name) = HeaderName::try_from(name.as_str()) else {
+            continue;
+        };
+        request.headers_mut().remove(&name);
+        for value in response.headers().get_all(&name) {
+            request.headers_mut().append(&name, value.clone());
+        }
+    }
+
+    None
+}
+
+fn challenge(basic: &BasicAuth) -> BoxBodyResponse {
+    unauthorized(format!(
+        "Basic realm=\"{}\", charset=\"UTF-8\"",
+        basic.realm
+    ))
+}
+
+fn unauthorized(challenge: String) -> BoxBodyResponse {
+    LocalResponse::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::WWW_AUTHENTICATE, challenge)
+        .body(body::full("HTTP 401 UNAUTHORIZED"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn verifies_htpasswd_hashes() {
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        let users = Users::parse(&format!(
+            "# Comment\nalice:{bcrypt}\nbob:{{SHA}}5en6G6MezRroT3XKqkdPOmY/BfQ=\n"
+        ))
+        .unwrap();
+
+        assert!(users.verify("alice", "secret"));
+        assert!(!users.verify("alice", "wrong"));
+        assert!(users.verify("bob", "secret"));
+        assert!(!users.verify("bob", "Secret"));
+        assert!(!users.verify("carol", "secret"));
+        assert!(users
+            .dummy
+            .as_ref()
+            .is_some_and(|dummy| dummy.starts_with("$2b$04$")));
+
+        assert!(Users::parse("alice:$apr1$salt$hash").is_err());
+        assert!(Users::parse("alice").is_err());
+    }
+
+    async fn label(pattern: &Pattern, mut request: Request<()>) -> Option<String> {
+        let client = SocketAddr::from(([127, 0, 0, 1], 50000));
+        match authenticate(pattern, &mut request, client).await {
+            Verdict::Allowed(label) => label,
+            Verdict::Rejected(response) => {
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+                None
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn labels_api_keys() {
+        let mut pattern: Pattern = toml::from_str(
+            r#"
+                forward = ["127.0.0.1:8080"]
+                auth.api_key = { keys_file = "keys", query = "api_key" }
+            "#,
+        )
+        .unwrap();
+        let api_key = pattern.auth.as_mut().unwrap().api_key.as_mut().unwrap();
+        api_key.keys = Arc::new(ApiKeys::parse("# Comment\nbilling:k1\nmobile:k2:x\n").unwrap());
+
+        let request = Request::builder()
+            .header("x-api-key", "k1")
+            .body(())
+            .unwrap();
+        assert_eq!(label(&pattern, request).await.as_deref(), Some("billing"));
+        let mut request = Request::builder()
+            .header("x-api-key", "k1")
+            .body(())
+            .unwrap();
+        let verdict = authenticate(&pattern, &mut request, ([127, 0, 0, 1], 50000).into()).await;
+        assert!(matches!(verdict, Verdict::Allowed(Some(label)) if label == "billing"));
+        assert!(!request.headers().contains_key("x-api-key"));
+        let mut request = Request::builder()
+            .uri("/?a=b&api_key=k2%3Ax")
+            .body(())
+            .unwrap();
+        let verdict = authenticate(&pattern, &mut request, ([127, 0, 0, 1], 50000).into()).await;
+        assert!(matches!(verdict, Verdict::Allowed(Some(label)) if label == "mobile"));
+        assert_eq!(request.uri(), "/?a=b");
+
+        let patterns = [pattern.clone()];
+        let uri = "/p?api_key=k2:x&c=d".parse().unwrap();
+        assert_eq!(redact(&patterns, &uri), "/p?c=d");
+        assert_eq!(redact(&patterns, &"/p?api_key=k1".parse().unwrap()), "/p");
+        let request = Request::builder()
+            .header("x-api-key", "k3")
+            .body(())
+            .unwrap();
+        assert_eq!(label(&pattern, request).await, None);
+
+        assert!(ApiKeys::parse("billing:").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Clients banned for getting too many error responses, see [`Ban`].
+//!
+//! [`Ban`]: crate::config::Ban
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+/// Clients tracked at once. Past it, those that are not banned are
+/// forgotten, and new ones aren't counted while the bans fill it.
+const MAX_OFFENDERS: usize = 100_000;
+
+/// Error counts and bans of the clients of a server.
+#[derive(Debug, Default)]
+pub struct Offenders {
+    clients: Mutex<Clients>,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    offenders: HashMap<IpAddr, Offender>,
+    /// Last time clients that are neither counted nor banned were dropped.
+    swept: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Offender {
+    /// Start of the current counting window.
+    since: Instant,
+    errors: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Offenders {
+    /// Counts an error of `client`, banning it for `duration` when it gets
+    /// more than `max_errors` of them within `window`. IPv6 clients are
+    /// counted and banned along with the rest of their /64.
+    pub fn offend(&self, client: IpAddr, max_errors: u32, window: Duration, duration: Duration) {
+        self.offend_at(Instant::now(), client, max_errors, window, duration);
+    }
+
+    fn offend_at(
+        &self,
+        now: Instant,
+        client: IpAddr,
+        max_errors: u32,
+        window: Duration,
+        duration: Duration,
+    ) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.sweep(now, window);
+
+        let key = key(client);
+        if !clients.offenders.contains_key(&key) && !clients.make_room(now) {
+            return;
+        }
+
+        let offender = clients.offenders.entry(key).or_insert(Offender {
+            since: now,
+            errors: 0,
+            banned_until: None,
+        });
+
+        if offender.is_banned(now) {
+            return;
+        }
+        if now.duration_since(offender.since) >= window {
+            offender.since = now;
+            offender.errors = 0;
+        }
+
+        offender.errors += 1;
+        if offender.errors > max_errors {
+            warn!(%client, errors = offender.errors, ?duration, "Banning client");
+            offender.banned_until = Some(now + duration);
+            offender.errors = 0;
+        }
+    }
+
+    /// Whether `client` is banned.
+    pub fn banned(&self, client: IpAddr) -> bool {
+        self.banned_at(Instant::now(), client)
+    }
+
+    fn banned_at(&self, now: Instant, client: IpAddr) -> bool {
+        let clients = self.clients.lock().unwrap();
+        clients
+            .offenders
+            .get(&key(client))
+            .is_some_and(|offender| offender.is_banned(now))
+    }
+}
+
+/// Entry of `client` among the offenders. IPv6 networks usually get a whole
+/// /64, any address of which the client can switch to.
+pub(super) fn key(client: IpAddr) -> IpAddr {
+    match client.to_canonical() {
+        IpAddr::V6(address) => IpAddr::V6(Ipv6Addr::from_bits(
+            address.to_bits() & !u128::from(u64::MAX),
+        )),
+        address => address,
+    }
+}
+
+impl Clients {
+    /// Forgets the clients whose window and ban are over, once per window.
+    fn sweep(&mut self, now: Instant, window: Duration) {
+        if self
+            .swept
+            .is_some_and(|swept| now.duration_since(swept) < window)
+        {
+            return;
+        }
+
+        self.offenders.retain(|_, offender| {
+            offender.is_banned(now) || now.duration_since(offender.since) < window
+        });
+        self.swept = Some(now);
+    }
+
+    /// Whether another client fits, forgetting the clients only counted
+    /// when there are too many.
+    fn make_room(&mut self, now: Instant) -> bool {
+        if self.offenders.len() >= MAX_OFFENDERS {
+            self.offenders.retain(|_, offender| offender.is_banned(now));
+        }
+
+        self.offenders.len() < MAX_OFFENDERS
+    }
+}
+
+impl Offender {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| now < until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_repeat_offenders() {
+        let offenders = Offenders::default();
+        let client = IpAddr::from([10, 0, 0, 1]);
+        let (window, duration) = (Duration::from_secs(60), Duration::from_secs(600));
+        let start = Instant::now();
+
+        // Errors spread over more than a window are forgiven.
+        offenders.offend_at(start, client, 2, window, duration);
+        offenders.offend_at(start + window, client, 2, window, duration);
+        offenders.offend_at(start + window, client, 2, window, duration);
+        assert!(!offenders.banned_at(start + window, client));
+
+        offenders.offend_at(start + window, client, 2, window, duration);
+        assert!(offenders.banned_at(start + window, client));
+        assert!(!offenders.banned_at(start + window, IpAddr::from([10, 0, 0, 2])));
+
+        assert!(offenders.banned_at(start + window + duration - Duration::from_secs(1), client));
+        assert!(!offenders.banned_at(start + window + duration, client));
+    }
+
+    #[test]
+    fn bans_ipv6_networks() {
+        let offenders = Offenders::default();
+        let (window, duration) = (Duration::from_secs(60), Duration::from_secs(600));
+        let now = Instant::now();
+
+        for host in 1..=3u16 {
+            let client = IpAddr::from([0x2001, 0xdb8, 0, 1, 0, 0, 0, host]);
+            offenders.offend_at(now, client, 2, window, duration);
+        }
+
+        assert!(offenders.banned_at(now, "2001:db8:0:1::ffff".parse().unwrap()));
+        assert!(!offenders.banned_at(now, "2001:db8:0:2::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn caps_offenders() {
+        let offenders = Offenders::default();
+        let (window, duration) = (Duration::from_secs(60), Duration::from_secs(600));
+        let now = Instant::now();
+
+        let banned = IpAddr::from([10, 0, 0, 1]);
+        offenders.offend_at(now, banned, 0, window, duration);
+        for n in 0..MAX_OFFENDERS as u32 {
+            offenders.offend_at(
+                now,
+                IpAddr::from(std::net::Ipv4Addr::from_bits((1 << 24) + n)),
+                2,
+                window,
+                duration,
+            );
+        }
+
+        let clients = offenders.clients.lock().unwrap();
+        assert!(clients.offenders.len() <= MAX_OFFENDERS);
+        assert!(clients.offenders[&banned].is_banned(now));
+    }
+}
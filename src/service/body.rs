@@ -1,18 +1,173 @@
 //! Utilities for creating common request and response bodies.
 
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
 use bytes::Bytes;
-use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::time::{Instant, Sleep};
+use tracing::{error, Span};
+
+use crate::{config::Bandwidth, service::response::ResponseBody};
 
 /// Single chunk body.
-pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
+pub fn full<T: Into<Bytes>>(chunk: T) -> ResponseBody {
     Full::new(chunk.into())
         .map_err(|never| match never {})
         .boxed()
 }
 
-#[allow(dead_code)]
-pub fn empty() -> BoxBody<Bytes, hyper::Error> {
+/// Body without any content.
+pub fn empty() -> ResponseBody {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
         .boxed()
 }
+
+/// Response body that ends early once `deadline` passes. Clients can only
+/// tell the response is incomplete by its length, the status is already
+/// sent.
+pub struct Deadline<B> {
+    inner: B,
+    sleep: Pin<Box<Sleep>>,
+    expired: bool,
+    span: Span,
+}
+
+impl<B> Deadline<B> {
+    pub fn new(inner: B, deadline: Instant, span: Span) -> Self {
+        Self {
+            inner,
+            sleep: Box::pin(tokio::time::sleep_until(deadline)),
+            expired: false,
+            span,
+        }
+    }
+}
+
+impl<B: Body + Unpin> Body for Deadline<B> {
+    type Data = B::Data;
+
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.expired {
+            return Poll::Ready(None);
+        }
+
+        if let Poll::Ready(frame) = Pin::new(&mut self.inner).poll_frame(cx) {
+            return Poll::Ready(frame);
+        }
+
+        if self.sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        self.expired = true;
+        self.span
+            .in_scope(|| error!("Backend response timed out, body cut short"));
+
+        Poll::Ready(None)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.expired || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Largest piece of data [`Throttled`] sends at once, so that big frames
+/// don't go out in a single burst.
+const THROTTLED_CHUNK: usize = 16 * 1024;
+
+/// Response body sent no faster than any of its `bandwidths` allow. Data is
+/// sent as it comes, then the next piece waits until the buckets refill.
+pub struct Throttled<B> {
+    inner: B,
+    bandwidths: Vec<Bandwidth>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    /// Rest of a frame larger than [`THROTTLED_CHUNK`].
+    pending: Bytes,
+}
+
+impl<B> Throttled<B> {
+    pub fn new(inner: B, bandwidths: Vec<Bandwidth>) -> Self {
+        Self {
+            inner,
+            bandwidths,
+            sleep: None,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl<B: Body<Data = Bytes> + Unpin> Body for Throttled<B> {
+    type Data = Bytes;
+
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(sleep) = &mut self.sleep {
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+        }
+
+        let mut data = if self.pending.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => data,
+                    Err(trailers) => return Poll::Ready(Some(Ok(trailers))),
+                },
+                other => return Poll::Ready(other),
+            }
+        } else {
+            std::mem::take(&mut self.pending)
+        };
+
+        if data.len() > THROTTLED_CHUNK {
+            self.pending = data.split_off(THROTTLED_CHUNK);
+        }
+
+        let wait = self
+            .bandwidths
+            .iter()
+            .map(|bandwidth| bandwidth.consume(data.len()))
+            .max()
+            .unwrap_or_default();
+
+        if !wait.is_zero() {
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+
+        Poll::Ready(Some(Ok(Frame::data(data))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.pending.is_empty() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let mut hint = self.inner.size_hint();
+        let pending = self.pending.len() as u64;
+
+        if let Some(upper) = hint.upper() {
+            hint.set_upper(upper + pending);
+        }
+        hint.set_lower(hint.lower() + pending);
+
+        hint
+    }
+}
@@ -0,0 +1,380 @@
+//! Bodies read in full before they are sent, kept in memory up to a limit
+//! and in files past it. Requests are buffered so that slow clients don't
+//! hold backend connections and bodies can be sent again, responses so that
+//! they can be compressed.
+
+use std::{
+    error::Error,
+    io::{self, Seek, SeekFrom},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    HeaderMap,
+};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::poll_read_buf;
+
+use crate::{config::RequestBuffering, service::response::ResponseBody};
+
+/// Bytes read from a spilled file at once.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Body read ahead of sending it.
+#[derive(Clone)]
+pub enum Buffered {
+    Memory(Bytes),
+    /// Body larger than the memory allowance.
+    File(Arc<Spilled>),
+}
+
+/// File holding a body, removed once dropped unless it existed before.
+pub struct Spilled {
+    path: PathBuf,
+    length: u64,
+    temporary: bool,
+}
+
+/// Reasons why a body could not be buffered.
+pub enum Buffering<E> {
+    TooLarge,
+    Body(E),
+    Io(io::Error),
+}
+
+impl Buffered {
+    /// Reads `body` to the end along with its trailers, giving up once more
+    /// than `limit` bytes have been received. Bodies past the `memory` of
+    /// `config` go to a file.
+    pub async fn read<B>(
+        mut body: B,
+        limit: Option<u64>,
+        config: &RequestBuffering,
+    ) -> Result<(Self, Option<HeaderMap>), Buffering<B::Error>>
+    where
+        B: Body<Data = Bytes> + Unpin,
+    {
+        let mut memory = BytesMut::new();
+        let mut file = None;
+        let mut length = 0;
+        let mut trailers = None;
+
+        while let Some(frame) = body.frame().await {
+            let chunk = match frame.map_err(Buffering::Body)?.into_data() {
+                Ok(chunk) => chunk,
+                Err(frame) => {
+                    trailers = frame.into_trailers().ok().or(trailers);
+                    continue;
+                }
+            };
+
+            length += chunk.len() as u64;
+            if limit.is_some_and(|limit| length > limit) {
+                return Err(Buffering::TooLarge);
+            }
+
+            match &mut file {
+                Some((_, output)) => write(output, &chunk).await?,
+                None if length <= config.memory => memory.extend_from_slice(&chunk),
+                None => {
+                    let (spilled, output) =
+                        Spilled::create(config.directory.as_deref()).map_err(Buffering::Io)?;
+                    let mut output = tokio::fs::File::from_std(output);
+                    write(&mut output, &memory).await?;
+                    write(&mut output, &chunk).await?;
+                    memory = BytesMut::new();
+                    file = Some((spilled, output));
+                }
+            }
+        }
+
+        let Some((mut spilled, mut output)) = file else {
+            return Ok((Buffered::Memory(memory.freeze()), trailers));
+        };
+
+        output.flush().await.map_err(Buffering::Io)?;
+        spilled.length = length;
+
+        Ok((Buffered::File(Arc::new(spilled)), trailers))
+    }
+
+    /// Runs `write` on a new temporary file of `directory` and keeps the
+    /// bytes written to it. `write` returns the length of the file.
+    pub async fn spill<F>(directory: Option<&Path>, write: F) -> io::Result<Self>
+    where
+        F: FnOnce(std::fs::File) -> io::Result<u64> + Send + 'static,
+    {
+        let (mut spilled, output) = Spilled::create(directory)?;
+
+        spilled.length = tokio::task::spawn_blocking(move || write(output))
+            .await
+            .map_err(io::Error::other)??;
+
+        Ok(Buffered::File(Arc::new(spilled)))
+    }
+
+    /// Number of bytes of the body.
+    pub fn len(&self) -> u64 {
+        match self {
+            Buffered::Memory(bytes) => bytes.len() as u64,
+            Buffered::File(spilled) => spilled.length,
+        }
+    }
+
+    /// Body to send to a backend, as many times as needed.
+    pub fn body(&self) -> BoxBody<Bytes, Box<dyn Error + Send + Sync>> {
+        match self {
+            Buffered::Memory(bytes) => crate::service::body::full(bytes.clone())
+                .map_err(Into::into)
+                .boxed(),
+            Buffered::File(spilled) => {
+                FileBody::open(spilled, 0..=spilled.length.saturating_sub(1))
+                    .map_err(Into::into)
+                    .boxed()
+            }
+        }
+    }
+
+    /// Body of a response sending all the content, or only `range` of it.
+    pub fn response_body(&self, range: Option<&RangeInclusive<u64>>) -> ResponseBody {
+        let range = range.cloned().unwrap_or(0..=self.len().saturating_sub(1));
+
+        match self {
+            Buffered::Memory(bytes) if bytes.is_empty() => crate::service::body::empty(),
+            Buffered::Memory(bytes) => crate::service::body::full(
+                bytes.slice(*range.start() as usize..=*range.end() as usize),
+            ),
+            Buffered::File(spilled) => FileBody::open(spilled, range).map_err(Into::into).boxed(),
+        }
+    }
+}
+
+async fn write<E>(output: &mut tokio::fs::File, chunk: &[u8]) -> Result<(), Buffering<E>> {
+    output.write_all(chunk).await.map_err(Buffering::Io)
+}
+
+impl Spilled {
+    /// File that already holds a body of `length` bytes, such as a served
+    /// file.
+    pub fn existing(path: PathBuf, length: u64) -> Self {
+        Self {
+            path,
+            length,
+            temporary: false,
+        }
+    }
+
+    /// Creates a new file in `directory`, the temporary one of the system by
+    /// default. Names are random and files that already exist are never
+    /// opened, others sharing the directory can't guess them or plant links.
+    fn create(directory: Option<&Path>) -> io::Result<(Self, std::fs::File)> {
+        let directory = directory.map_or_else(std::env::temp_dir, Path::to_path_buf);
+
+        loop {
+            let mut random = [0; 8];
+            getrandom::getrandom(&mut random).expect("no source of randomness");
+            let name = random
+                .iter()
+                .fold(String::from("xnav-body-"), |mut name, byte| {
+                    name.push_str(&format!("{byte:02x}"));
+                    name
+                });
+            let path = directory.join(name);
+
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => {
+                    let spilled = Self {
+                        path,
+                        length: 0,
+                        temporary: true,
+                    };
+                    return Ok((spilled, file));
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for Spilled {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Body streamed from a range of a file.
+enum FileBody {
+    Reading {
+        file: tokio::fs::File,
+        buffer: BytesMut,
+        remaining: u64,
+        /// Keeps temporary files around until the body is sent.
+        _spilled: Arc<Spilled>,
+    },
+    Failed(Option<io::Error>),
+}
+
+impl FileBody {
+    fn open(spilled: &Arc<Spilled>, range: RangeInclusive<u64>) -> Self {
+        if spilled.length == 0 {
+            return FileBody::Failed(None);
+        }
+
+        let opened = std::fs::File::open(&spilled.path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(*range.start()))?;
+            Ok(file)
+        });
+
+        match opened {
+            Ok(file) => FileBody::Reading {
+                file: tokio::fs::File::from_std(file),
+                buffer: BytesMut::new(),
+                remaining: range.end() - range.start() + 1,
+                _spilled: spilled.clone(),
+            },
+            Err(err) => FileBody::Failed(Some(err)),
+        }
+    }
+}
+
+impl Body for FileBody {
+    type Data = Bytes;
+
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let (file, buffer, remaining) = match self.get_mut() {
+            FileBody::Reading {
+                file,
+                buffer,
+                remaining,
+                ..
+            } => (file, buffer, remaining),
+            FileBody::Failed(err) => return Poll::Ready(err.take().map(Err)),
+        };
+
+        if *remaining == 0 {
+            return Poll::Ready(None);
+        }
+
+        buffer.reserve(READ_CHUNK);
+
+        match ready!(poll_read_buf(Pin::new(file), cx, buffer)) {
+            Ok(0) => Poll::Ready(Some(Err(io::ErrorKind::UnexpectedEof.into()))),
+            Ok(_) => {
+                // Reads can go past the end of a range.
+                buffer.truncate(buffer.len().min(*remaining as usize));
+                *remaining -= buffer.len() as u64;
+                Poll::Ready(Some(Ok(Frame::data(buffer.split().freeze()))))
+            }
+            Err(err) => Poll::Ready(Some(Err(err))),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            FileBody::Reading { remaining, .. } => *remaining == 0,
+            FileBody::Failed(err) => err.is_none(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            FileBody::Reading { remaining, .. } => SizeHint::with_exact(*remaining),
+            FileBody::Failed(_) => SizeHint::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::Full;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn spills_to_file() {
+        let config = RequestBuffering {
+            memory: 4,
+            directory: None,
+        };
+        let content = Bytes::from_static(b"larger than memory");
+
+        let small = Buffered::read(Full::new(Bytes::from_static(b"tiny")), None, &config).await;
+        assert!(matches!(small, Ok((Buffered::Memory(_), None))));
+
+        let Ok((buffered, _)) = Buffered::read(Full::new(content.clone()), None, &config).await
+        else {
+            panic!("body not buffered");
+        };
+        let Buffered::File(spilled) = &buffered else {
+            panic!("body kept in memory");
+        };
+        let path = spilled.path.clone();
+
+        // Every attempt reads the whole body again.
+        for _ in 0..2 {
+            let body = buffered.body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, content);
+        }
+
+        drop(buffered);
+        assert!(!path.exists());
+
+        let limited = Buffered::read(Full::new(content), Some(8), &config).await;
+        assert!(matches!(limited, Err(Buffering::TooLarge)));
+    }
+
+    #[tokio::test]
+    async fn keeps_trailers() {
+        let config = RequestBuffering {
+            memory: 4,
+            directory: None,
+        };
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "abc".parse().unwrap());
+        let body = Full::new(Bytes::from_static(b"larger than memory"))
+            .with_trailers(std::future::ready(Some(Ok(trailers.clone()))));
+
+        let Ok((buffered, kept)) = Buffered::read(body, None, &config).await else {
+            panic!("body not buffered");
+        };
+        assert!(matches!(buffered, Buffered::File(_)));
+        assert_eq!(kept, Some(trailers));
+    }
+
+    #[tokio::test]
+    async fn sends_file_ranges() {
+        let directory = std::env::temp_dir();
+        let buffered = Buffered::spill(Some(&directory), |mut output| {
+            io::Write::write_all(&mut output, b"0123456789")?;
+            Ok(10)
+        })
+        .await
+        .unwrap();
+
+        let body = buffered.response_body(None).collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "0123456789");
+
+        let range = buffered.response_body(Some(&(3..=5)));
+        assert_eq!(range.size_hint().exact(), Some(3));
+        assert_eq!(range.collect().await.unwrap().to_bytes(), "345");
+    }
+}
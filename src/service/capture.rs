@@ -0,0 +1,274 @@
+//! Debug capture of sampled requests and responses, kept in memory and
+//! retrievable through the admin listener.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use hyper::{header, header::HeaderName, HeaderMap};
+use serde::Serialize;
+
+use crate::config::Capture;
+
+/// Headers whose values are never stored.
+const REDACTED: [HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+
+/// Ring buffer of the most recent captured exchanges.
+pub struct Capturer {
+    config: Capture,
+    /// Configured headers kept out of the records along with `REDACTED`.
+    redacted: Vec<HeaderName>,
+    requests: AtomicU64,
+    records: Mutex<VecDeque<Record>>,
+}
+
+/// A captured request and its response.
+#[derive(Serialize, Debug, Clone)]
+pub struct Record {
+    pub time: String,
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub method: String,
+    pub uri: String,
+    pub request_headers: Vec<(String, String)>,
+    /// Prefix of the request body, at most `body_bytes` long.
+    pub request_body: String,
+    pub status: Option<u16>,
+    pub response_headers: Vec<(String, String)>,
+    /// Prefix of the response body, at most `body_bytes` long.
+    pub response_body: String,
+    #[serde(skip)]
+    request_body_bytes: Vec<u8>,
+    #[serde(skip)]
+    response_body_bytes: Vec<u8>,
+}
+
+impl Capturer {
+    pub fn new(config: Capture) -> Self {
+        Self {
+            records: Mutex::new(VecDeque::with_capacity(config.entries)),
+            requests: AtomicU64::new(0),
+            redacted: Vec::new(),
+            config,
+        }
+    }
+
+    /// Also stores `headers` as redacted, such as those carrying API keys or
+    /// signatures.
+    pub fn redacting(mut self, headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        self.redacted.extend(headers);
+        self
+    }
+
+    /// Path where the records are served by the admin listener.
+    pub fn path(&self) -> &str {
+        &self.config.path
+    }
+
+    /// Maximum number of body bytes stored per direction.
+    pub fn body_bytes(&self) -> usize {
+        self.config.body_bytes
+    }
+
+    /// Decides whether a request is captured. Requests carrying the trigger
+    /// header always are, the rest are sampled evenly at the configured
+    /// rate.
+    pub fn should_capture(&self, headers: &HeaderMap) -> bool {
+        let triggered = self
+            .config
+            .header
+            .as_ref()
+            .is_some_and(|name| headers.contains_key(name.as_str()));
+
+        if triggered {
+            return true;
+        }
+
+        let rate = self.config.sample_rate.clamp(0.0, 1.0);
+        let request = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+
+        (request * rate).floor() != ((request + 1.0) * rate).floor()
+    }
+
+    /// Stores a finished record, dropping the oldest one if full.
+    pub fn push(&self, mut record: Record) {
+        record.request_body = String::from_utf8_lossy(&record.request_body_bytes).into_owned();
+        record.response_body = String::from_utf8_lossy(&record.response_body_bytes).into_owned();
+
+        for (name, value) in record
+            .request_headers
+            .iter_mut()
+            .chain(record.response_headers.iter_mut())
+        {
+            if self
+                .redacted
+                .iter()
+                .any(|redacted| redacted == name.as_str())
+            {
+                *value = String::from("<redacted>");
+            }
+        }
+
+        let mut records = self.records.lock().unwrap();
+
+        if records.len() >= self.config.entries {
+            records.pop_front();
+        }
+
+        if self.config.entries > 0 {
+            records.push_back(record);
+        }
+    }
+
+    /// Captured records, oldest first.
+    pub fn records(&self) -> Vec<Record> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Record {
+    pub fn new(
+        client: SocketAddr,
+        server: SocketAddr,
+        method: String,
+        uri: String,
+        headers: &HeaderMap,
+    ) -> Self {
+        Self {
+            time: httpdate::fmt_http_date(SystemTime::now()),
+            client,
+            server,
+            method,
+            uri,
+            request_headers: headers_to_vec(headers),
+            request_body: String::new(),
+            status: None,
+            response_headers: Vec::new(),
+            response_body: String::new(),
+            request_body_bytes: Vec::new(),
+            response_body_bytes: Vec::new(),
+        }
+    }
+
+    pub fn set_response(&mut self, status: u16, headers: &HeaderMap) {
+        self.status = Some(status);
+        self.response_headers = headers_to_vec(headers);
+    }
+
+    /// Appends `data` to the request or response body prefix until `limit`
+    /// bytes are stored.
+    pub fn append_body(&mut self, response: bool, data: &[u8], limit: usize) {
+        let body = if response {
+            &mut self.response_body_bytes
+        } else {
+            &mut self.request_body_bytes
+        };
+
+        let remaining = limit.saturating_sub(body.len());
+        body.extend_from_slice(&data[..remaining.min(data.len())]);
+    }
+}
+
+fn headers_to_vec(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED.contains(name) {
+                String::from("<redacted>")
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn capturer(sample_rate: f64, entries: usize) -> Capturer {
+        Capturer::new(Capture {
+            path: String::from("/captures"),
+            sample_rate,
+            header: Some(String::from("x-debug")),
+            body_bytes: 4,
+            entries,
+        })
+    }
+
+    #[test]
+    fn sampling() {
+        let sampled = capturer(0.25, 10);
+        let headers = HeaderMap::new();
+
+        let captured = (0..100)
+            .filter(|_| sampled.should_capture(&headers))
+            .count();
+        assert_eq!(captured, 25);
+
+        let mut triggered = HeaderMap::new();
+        triggered.insert("x-debug", HeaderValue::from_static("1"));
+        assert!(capturer(0.0, 10).should_capture(&triggered));
+        assert!(!capturer(0.0, 10).should_capture(&headers));
+    }
+
+    #[test]
+    fn ring_and_redaction() {
+        let ring = capturer(1.0, 2);
+        let address = "127.0.0.1:80".parse().unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer x"));
+
+        for uri in ["/a", "/b", "/c"] {
+            let mut record = Record::new(address, address, "GET".into(), uri.into(), &headers);
+            record.append_body(true, b"hello", ring.body_bytes());
+            ring.push(record);
+        }
+
+        let records = ring.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].uri.as_str(), records[1].uri.as_str()),
+            ("/b", "/c")
+        );
+        assert_eq!(records[1].response_body, "hell");
+        assert_eq!(
+            records[1].request_headers,
+            vec![(String::from("authorization"), String::from("<redacted>"))]
+        );
+
+        let ring = capturer(1.0, 2).redacting([HeaderName::from_static("x-api-key")]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("k1"));
+        headers.insert("x-request", HeaderValue::from_static("1"));
+        ring.push(Record::new(
+            address,
+            address,
+            "GET".into(),
+            "/".into(),
+            &headers,
+        ));
+        assert_eq!(
+            ring.records()[0].request_headers,
+            vec![
+                (String::from("x-api-key"), String::from("<redacted>")),
+                (String::from("x-request"), String::from("1")),
+            ]
+        );
+    }
+}
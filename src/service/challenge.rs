@@ -0,0 +1,210 @@
+//! Signed cookie challenges keeping simple scrapers away from a pattern,
+//! see [`Challenge`].
+//!
+//! [`Challenge`]: crate::config::Challenge
+
+use std::{
+    net::IpAddr,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use hyper::{header, HeaderMap, Method, Request, StatusCode, Uri};
+use sha2::Sha256;
+use tracing::debug;
+
+use crate::{
+    config::{Challenge, ChallengeMode},
+    service::{
+        body,
+        response::{BoxBodyResponse, LocalResponse},
+    },
+};
+
+/// Cookie proving that the client went through the challenge.
+const COOKIE: &str = "xnav_challenge";
+
+/// Response challenging the client of `request`, unless it already holds a
+/// valid cookie. Cookies are only sent back over HTTPS if that's how the
+/// client came.
+pub fn challenge<T>(
+    challenge: &Challenge,
+    request: &Request<T>,
+    client: IpAddr,
+    https: bool,
+) -> Option<BoxBodyResponse> {
+    let now = unix_time();
+    let agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .map_or(&b""[..], |agent| agent.as_bytes());
+
+    if cookie(request.headers(), COOKIE)
+        .is_some_and(|token| verify(challenge, token, client, agent, now))
+    {
+        return None;
+    }
+
+    debug!(mode = ?challenge.mode, "Challenging client");
+
+    let expires = now + challenge.ttl.as_secs();
+    let token = format!("{expires}-{}", sign(challenge, client, agent, expires));
+    let max_age = challenge.ttl.as_secs();
+    let secure = if https { "; Secure" } else { "" };
+
+    let response = match challenge.mode {
+        // Bodies would be lost on the way, and scripts don't follow anyway.
+        _ if !matches!(*request.method(), Method::GET | Method::HEAD) => LocalResponse::forbidden(),
+        ChallengeMode::Redirect => LocalResponse::builder()
+            .status(StatusCode::TEMPORARY_REDIRECT)
+            .header(header::LOCATION, location(request.uri()))
+            .header(
+                header::SET_COOKIE,
+                format!(
+                    "{COOKIE}={token}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}"
+                ),
+            )
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(body::empty())
+            .unwrap(),
+        ChallengeMode::Interstitial => LocalResponse::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(body::full(interstitial(&token, max_age, secure)))
+            .unwrap(),
+    };
+
+    Some(response)
+}
+
+/// Value of the cookie called `name` sent along with a request.
+pub(super) fn cookie<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (cookie, value) = pair.trim().split_once('=')?;
+            (name == cookie).then_some(value)
+        })
+}
+
+/// Whether `token` was signed for this client and hasn't expired yet.
+fn verify(challenge: &Challenge, token: &str, client: IpAddr, agent: &[u8], now: u64) -> bool {
+    let Some((expires, signature)) = token.split_once('-') else {
+        return false;
+    };
+    let (Ok(expires), Ok(signature)) = (expires.parse::<u64>(), URL_SAFE_NO_PAD.decode(signature))
+    else {
+        return false;
+    };
+
+    expires > now
+        && mac(challenge, client, agent, expires)
+            .verify_slice(&signature)
+            .is_ok()
+}
+
+fn sign(challenge: &Challenge, client: IpAddr, agent: &[u8], expires: u64) -> String {
+    URL_SAFE_NO_PAD.encode(
+        mac(challenge, client, agent, expires)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// Tokens are bound to the address and user agent of the client, so that
+/// a fleet of scrapers can't share one.
+fn mac(challenge: &Challenge, client: IpAddr, agent: &[u8], expires: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key(challenge)).unwrap();
+    mac.update(client.to_string().as_bytes());
+    mac.update(b"\n");
+    mac.update(agent);
+    mac.update(b"\n");
+    mac.update(expires.to_string().as_bytes());
+    mac
+}
+
+/// Secret of the challenge, or one generated at startup when it has none,
+/// which makes cookies invalid after restarts.
+fn key(challenge: &Challenge) -> &[u8] {
+    static GENERATED: OnceLock<[u8; 32]> = OnceLock::new();
+
+    match &challenge.secret {
+        Some(secret) => secret.expose().as_bytes(),
+        None => GENERATED.get_or_init(|| {
+            let mut key = [0; 32];
+            getrandom::getrandom(&mut key).expect("no source of randomness");
+            key
+        }),
+    }
+}
+
+fn location(uri: &Uri) -> String {
+    uri.path_and_query()
+        .map_or_else(|| String::from("/"), ToString::to_string)
+}
+
+fn interstitial(token: &str, max_age: u64, secure: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n\
+         <html><head><meta charset=\"utf-8\"><title>Checking your browser</title></head>\n\
+         <body><noscript>Please enable JavaScript to continue.</noscript>\n\
+         <script>document.cookie = \"{COOKIE}={token}; path=/; max-age={max_age}; SameSite=Lax{secure}\"; \
+         location.reload();</script></body></html>\n"
+    )
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_signed_cookies() {
+        let challenge: Challenge = toml::from_str(r#"secret = "s3cret""#).unwrap();
+        let client = IpAddr::from([192, 0, 2, 1]);
+        let request = |cookie: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("/page?a=b")
+                .header("user-agent", "browser");
+            if let Some(cookie) = cookie {
+                request = request.header("cookie", format!("theme=dark; {COOKIE}={cookie}"));
+            }
+            request.body(()).unwrap()
+        };
+
+        let response = super::challenge(&challenge, &request(None), client, false).unwrap();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "/page?a=b");
+        let set_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.ends_with("; HttpOnly; SameSite=Lax"));
+        let token = set_cookie
+            .split(';')
+            .next()
+            .unwrap()
+            .split_once('=')
+            .unwrap()
+            .1;
+
+        assert!(super::challenge(&challenge, &request(Some(token)), client, false).is_none());
+        let elsewhere = IpAddr::from([192, 0, 2, 2]);
+        assert!(super::challenge(&challenge, &request(Some(token)), elsewhere, false).is_some());
+
+        let expired = format!("1-{}", sign(&challenge, client, b"browser", 1));
+        assert!(super::challenge(&challenge, &request(Some(&expired)), client, false).is_some());
+
+        let secure = super::challenge(&challenge, &request(None), client, true).unwrap();
+        let set_cookie = secure.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.ends_with("; HttpOnly; SameSite=Lax; Secure"));
+    }
+}
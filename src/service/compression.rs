@@ -0,0 +1,720 @@
+//! Content negotiation and encoders for compressed responses.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    header::{self, HeaderValue},
+    HeaderMap, StatusCode,
+};
+use tracing::error;
+
+use crate::{
+    config::{Compression, Encoding},
+    service::response::{self, BoxBodyResponse, LocalResponse},
+};
+
+impl Encoding {
+    /// Token used in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Picks the encoding that should be used for a response based on the
+/// client's `Accept-Encoding` header. Encodings with a higher quality value
+/// win, ties are resolved using the order of `supported`.
+pub fn negotiate(
+    accept_encoding: Option<&HeaderValue>,
+    supported: &[Encoding],
+) -> Option<Encoding> {
+    let accept_encoding = accept_encoding?.to_str().ok()?;
+
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for encoding in supported {
+        let quality = accept_encoding
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let token = parts.next()?;
+                if token != encoding.as_str() && token != "*" {
+                    return None;
+                }
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+                Some((token == "*", quality))
+            })
+            // An explicit token takes precedence over the wildcard.
+            .min_by(|(wildcard, _), (other, _)| wildcard.cmp(other))
+            .map(|(_, quality)| quality)
+            .unwrap_or(0.0);
+
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((*encoding, quality));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Returns `true` if the given content type matches any of the configured
+/// compressible MIME types.
+pub fn is_compressible(content_type: &str, config: &Compression) -> bool {
+    matches_mime(content_type, &config.mime_types)
+}
+
+/// Returns `true` if the given content type matches any of `mime_types`,
+/// which may contain `text/*` style wildcards.
+pub fn matches_mime(content_type: &str, mime_types: &[String]) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+
+    mime_types.iter().any(|mime| match mime.strip_suffix("/*") {
+        Some(prefix) => essence
+            .split_once('/')
+            .is_some_and(|(kind, _)| kind.eq_ignore_ascii_case(prefix)),
+        None => essence.eq_ignore_ascii_case(mime),
+    })
+}
+
+/// Compresses a complete response body.
+pub fn compress(encoding: Encoding, mut content: &[u8]) -> Result<Vec<u8>, io::Error> {
+    encode(encoding, &mut content, Vec::new())
+}
+
+/// Compresses the file at `input` into the new file `output` without
+/// holding either in memory. Returns the length of the compressed file.
+pub fn compress_file(encoding: Encoding, input: &Path, output: File) -> Result<u64, io::Error> {
+    let mut input = File::open(input)?;
+    let output = encode(encoding, &mut input, BufWriter::new(output))?;
+
+    output
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .metadata()
+        .map(|metadata| metadata.len())
+}
+
+fn encode<W: Write>(encoding: Encoding, input: &mut impl Read, output: W) -> Result<W, io::Error> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+            io::copy(input, &mut encoder)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(output, 4096, 5, 22);
+            io::copy(input, &mut encoder)?;
+            encoder.flush()?;
+            Ok(encoder.into_inner())
+        }
+        Encoding::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(output, 0)?;
+            io::copy(input, &mut encoder)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Backend `response` compressed with the best encoding of `config` that
+/// the client accepts, according to `accept_encoding`, unless the backend
+/// already compressed it or it's too small to bother.
+pub fn filter(
+    config: &Compression,
+    accept_encoding: Option<&HeaderValue>,
+    mut response: BoxBodyResponse,
+) -> BoxBodyResponse {
+    let headers = response.headers();
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+    let status = response.status();
+
+    if headers.contains_key(header::CONTENT_ENCODING)
+        || !is_compressible(content_type, config)
+        || status.is_informational()
+        || matches!(
+            status,
+            StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+        )
+    {
+        return response;
+    }
+
+    let no_transform = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if no_transform {
+        return response;
+    }
+
+    // The representation depends on the client from now on, whether this
+    // one gets it compressed or not.
+    vary_on_accept_encoding(response.headers_mut());
+
+    let small = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|length| length < config.min_size as u64);
+    // Such as those to HEAD requests, encoders would still send a header.
+    let empty = response.body().is_end_stream();
+    let encoding = negotiate(accept_encoding, &config.algorithms).filter(|_| !small && !empty);
+    let Some(encoding) = encoding else {
+        return response;
+    };
+
+    let encoder = match Encoder::new(encoding) {
+        Ok(encoder) => encoder,
+        Err(err) => {
+            error!(%err, encoding = encoding.as_str(), "Failed to create encoder");
+            return response;
+        }
+    };
+
+    let (mut head, body) = response.into_parts();
+    head.headers.remove(header::CONTENT_LENGTH);
+    head.headers.remove(header::ACCEPT_RANGES);
+    head.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    response::weaken_etag(&mut head.headers);
+
+    let body = Coded {
+        inner: body,
+        coder: Some(encoder),
+        trailers: None,
+    };
+
+    BoxBodyResponse::from_parts(head, body.boxed())
+}
+
+/// Backend `response` decompressed, when its encoding is one of ours and
+/// either the client doesn't accept it, according to `accept_encoding`, or
+/// the filters that follow need the `plaintext`.
+pub fn decompress(
+    accept_encoding: Option<&HeaderValue>,
+    plaintext: bool,
+    mut response: BoxBodyResponse,
+) -> BoxBodyResponse {
+    let Some(encoding) = content_encoding(response.headers()) else {
+        return response;
+    };
+
+    let accepted = negotiate(accept_encoding, &[encoding]).is_some();
+    if response.status() == StatusCode::PARTIAL_CONTENT || (accepted && !plaintext) {
+        return response;
+    }
+    if !accepted {
+        vary_on_accept_encoding(response.headers_mut());
+    }
+
+    let (mut head, body) = response.into_parts();
+    head.headers.remove(header::CONTENT_ENCODING);
+    head.headers.remove(header::CONTENT_LENGTH);
+    head.headers.remove(header::ACCEPT_RANGES);
+    response::weaken_etag(&mut head.headers);
+
+    // Such as those to HEAD requests, there's nothing to decode.
+    if body.is_end_stream() {
+        return BoxBodyResponse::from_parts(head, body);
+    }
+
+    let decoder = match Decoder::new(encoding) {
+        Ok(decoder) => decoder,
+        Err(err) => {
+            error!(%err, encoding = encoding.as_str(), "Failed to create decoder");
+            return LocalResponse::bad_gateway();
+        }
+    };
+
+    let body = Coded {
+        inner: body,
+        coder: Some(decoder),
+        trailers: None,
+    };
+
+    BoxBodyResponse::from_parts(head, body.boxed())
+}
+
+/// Single encoding of a response, if it's one of ours.
+fn content_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    let mut values = headers.get_all(header::CONTENT_ENCODING).iter();
+    let (Some(value), None) = (values.next(), values.next()) else {
+        return None;
+    };
+
+    match value.to_str().ok()?.trim().to_ascii_lowercase().as_str() {
+        "gzip" | "x-gzip" => Some(Encoding::Gzip),
+        "br" => Some(Encoding::Brotli),
+        "zstd" => Some(Encoding::Zstd),
+        _ => None,
+    }
+}
+
+fn vary_on_accept_encoding(headers: &mut HeaderMap) {
+    let varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case(header::ACCEPT_ENCODING.as_str())
+        });
+
+    if !varies {
+        headers.append(
+            header::VARY,
+            HeaderValue::from_static(header::ACCEPT_ENCODING.as_str()),
+        );
+    }
+}
+
+/// Encoder or decoder writing into memory, its output taken out after each
+/// write.
+trait Coder {
+    fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, io::Error>;
+
+    fn finish(self) -> Result<Vec<u8>, io::Error>;
+}
+
+enum Encoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Result<Self, io::Error> {
+        Ok(match encoding {
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            )),
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                5,
+                22,
+            ))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 0)?),
+        })
+    }
+}
+
+impl Coder for Encoder {
+    /// Compresses `data`, flushing it so that streamed responses aren't held
+    /// back.
+    fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let output = match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+
+        Ok(std::mem::take(output))
+    }
+
+    fn finish(self) -> Result<Vec<u8>, io::Error> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Decompressed bytes produced by a single frame of a response, past which
+/// it fails. Memory stays bounded whatever the compression ratio.
+const MAX_DECODED_FRAME: usize = 16 << 20;
+
+/// Decompressed bytes of a whole response, past which it fails.
+const MAX_DECODED_BODY: u64 = 1 << 30;
+
+enum Decoder {
+    Gzip(flate2::write::GzDecoder<Capped>),
+    Brotli(Box<brotli::DecompressorWriter<Capped>>),
+    /// Not the `write` decoder, which can't tell a truncated stream apart.
+    Zstd(zstd::stream::zio::Writer<Capped, zstd::stream::raw::Decoder<'static>>),
+}
+
+/// Output of a decoder, refusing to grow past the limits of decompressed
+/// bodies.
+#[derive(Default)]
+struct Capped {
+    output: Vec<u8>,
+    total: u64,
+}
+
+impl Write for Capped {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.total += data.len() as u64;
+        if self.output.len() + data.len() > MAX_DECODED_FRAME || self.total > MAX_DECODED_BODY {
+            return Err(io::Error::other("decompressed body is too large"));
+        }
+
+        self.output.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Decoder {
+    fn new(encoding: Encoding) -> Result<Self, io::Error> {
+        Ok(match encoding {
+            Encoding::Gzip => Decoder::Gzip(flate2::write::GzDecoder::new(Capped::default())),
+            Encoding::Brotli => Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(
+                Capped::default(),
+                4096,
+            ))),
+            Encoding::Zstd => Decoder::Zstd(zstd::stream::zio::Writer::new(
+                Capped::default(),
+                zstd::stream::raw::Decoder::new()?,
+            )),
+        })
+    }
+}
+
+impl Coder for Decoder {
+    fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let output = match self {
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                decoder.get_mut()
+            }
+            Decoder::Brotli(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                decoder.get_mut()
+            }
+            Decoder::Zstd(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                decoder.writer_mut()
+            }
+        };
+
+        Ok(std::mem::take(&mut output.output))
+    }
+
+    /// Fails on truncated streams, whose end never came.
+    fn finish(self) -> Result<Vec<u8>, io::Error> {
+        let output = match self {
+            Decoder::Gzip(decoder) => decoder.finish()?,
+            Decoder::Brotli(mut decoder) => {
+                decoder.close()?;
+                decoder.into_inner().unwrap_or_else(|output| output)
+            }
+            Decoder::Zstd(mut decoder) => {
+                decoder.finish()?;
+                decoder.into_inner().0
+            }
+        };
+
+        Ok(output.output)
+    }
+}
+
+/// Body compressed or decompressed by `C` as data goes through. Coding
+/// errors end it with an error, so that the response is aborted instead
+/// of looking complete.
+struct Coded<B, C> {
+    inner: B,
+    /// Taken once the body ends.
+    coder: Option<C>,
+    /// Sent after the end of the data.
+    trailers: Option<HeaderMap>,
+}
+
+impl<B, C> Body for Coded<B, C>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: From<io::Error>,
+    C: Coder + Unpin,
+{
+    type Data = Bytes;
+
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+
+        loop {
+            let Some(coder) = &mut this.coder else {
+                let trailers = this.trailers.take();
+                return Poll::Ready(trailers.map(|trailers| Ok(Frame::trailers(trailers))));
+            };
+
+            let compressed = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => coder.write(&data),
+                    Err(frame) => {
+                        this.trailers = frame.into_trailers().ok();
+                        this.coder.take().unwrap().finish()
+                    }
+                },
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => this.coder.take().unwrap().finish(),
+            };
+
+            match compressed {
+                Ok(compressed) if compressed.is_empty() => continue,
+                Ok(compressed) => {
+                    return Poll::Ready(Some(Ok(Frame::data(Bytes::from(compressed)))))
+                }
+                Err(err) => {
+                    error!(%err, "Failed to code response body, aborting it");
+                    this.coder = None;
+                    this.trailers = None;
+                    return Poll::Ready(Some(Err(err.into())));
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.coder.is_none() && self.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPPORTED: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    fn negotiate_str(accept_encoding: &str) -> Option<Encoding> {
+        negotiate(
+            Some(&HeaderValue::from_str(accept_encoding).unwrap()),
+            &SUPPORTED,
+        )
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate(None, &SUPPORTED), None);
+        assert_eq!(negotiate_str("identity"), None);
+        assert_eq!(negotiate_str("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_str("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate_str("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_str("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate_str("br;q=0, *"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn compressible_types() {
+        let config = Compression {
+            algorithms: SUPPORTED.to_vec(),
+            min_size: 0,
+            mime_types: vec![String::from("text/*"), String::from("application/json")],
+        };
+
+        assert!(is_compressible("text/html", &config));
+        assert!(is_compressible("text/css; charset=utf-8", &config));
+        assert!(is_compressible("application/json", &config));
+        assert!(!is_compressible("image/png", &config));
+    }
+
+    #[test]
+    fn streaming_coders() {
+        let chunks = [&b"<html>"[..], &b"hello "[..], &b"world</html>"[..]];
+
+        for encoding in [Encoding::Gzip, Encoding::Brotli, Encoding::Zstd] {
+            let mut encoder = Encoder::new(encoding).unwrap();
+            let mut compressed = Vec::new();
+            for chunk in chunks {
+                compressed.extend(encoder.write(chunk).unwrap());
+            }
+            compressed.extend(encoder.finish().unwrap());
+
+            let mut decompressed = Vec::new();
+            match encoding {
+                Encoding::Gzip => flate2::read::GzDecoder::new(compressed.as_slice())
+                    .read_to_end(&mut decompressed)
+                    .unwrap(),
+                Encoding::Brotli => brotli::Decompressor::new(compressed.as_slice(), 4096)
+                    .read_to_end(&mut decompressed)
+                    .unwrap(),
+                Encoding::Zstd => zstd::stream::read::Decoder::new(compressed.as_slice())
+                    .unwrap()
+                    .read_to_end(&mut decompressed)
+                    .unwrap(),
+            };
+            assert_eq!(decompressed, chunks.concat());
+
+            let mut decoder = Decoder::new(encoding).unwrap();
+            let mut decoded = Vec::new();
+            for chunk in compressed.chunks(7) {
+                decoded.extend(decoder.write(chunk).unwrap());
+            }
+            decoded.extend(decoder.finish().unwrap());
+            assert_eq!(decoded, chunks.concat());
+        }
+    }
+
+    fn html(headers: &[(&str, &str)], content: &'static str) -> BoxBodyResponse {
+        let mut response = LocalResponse::builder().header(header::CONTENT_TYPE, "text/html");
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        response.body(crate::service::body::full(content)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn filters_responses() {
+        let config = Compression {
+            algorithms: vec![Encoding::Gzip],
+            min_size: 10,
+            mime_types: vec![String::from("text/html")],
+        };
+        let gzip = HeaderValue::from_static("gzip");
+        let content = "<html>hello world</html>";
+
+        let compressed = filter(
+            &config,
+            Some(&gzip),
+            html(&[("content-length", "24")], content),
+        );
+        assert_eq!(compressed.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(compressed.headers()[header::VARY], "accept-encoding");
+        assert!(!compressed.headers().contains_key(header::CONTENT_LENGTH));
+        let body = compressed.into_body().collect().await.unwrap().to_bytes();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, content);
+
+        let no_transform = html(&[("cache-control", "public, No-Transform")], content);
+        let kept = filter(&config, Some(&gzip), no_transform);
+        assert!(!kept.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(!kept.headers().contains_key(header::VARY));
+
+        // Others could still get them compressed, caches must tell apart.
+        let small = html(
+            &[("content-length", "5"), ("vary", "Accept-Encoding")],
+            "<p/>!",
+        );
+        let head = LocalResponse::builder()
+            .header(header::CONTENT_TYPE, "text/html")
+            .header(header::CONTENT_LENGTH, "100")
+            .body(crate::service::body::empty())
+            .unwrap();
+        let identity = html(&[], content);
+        for (accept_encoding, response) in
+            [(Some(&gzip), small), (Some(&gzip), head), (None, identity)]
+        {
+            let kept = filter(&config, accept_encoding, response);
+            assert!(!kept.headers().contains_key(header::CONTENT_ENCODING));
+            let vary: Vec<_> = kept.headers().get_all(header::VARY).iter().collect();
+            assert_eq!(vary.len(), 1);
+            assert!(vary[0].as_bytes().eq_ignore_ascii_case(b"accept-encoding"));
+        }
+
+        let corrupt = Coded {
+            inner: crate::service::body::full("not gzip at all"),
+            coder: Some(Decoder::new(Encoding::Gzip).unwrap()),
+            trailers: None,
+        };
+        assert!(corrupt.collect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn decompresses_responses() {
+        let content = "<html>hello world</html>";
+        let gzipped = compress(Encoding::Gzip, content.as_bytes()).unwrap();
+        let response = |status: StatusCode, encoding: &str| {
+            LocalResponse::builder()
+                .status(status)
+                .header(header::CONTENT_ENCODING, encoding)
+                .header(header::CONTENT_LENGTH, gzipped.len())
+                .header(header::ETAG, "\"v1\"")
+                .body(crate::service::body::full(gzipped.clone()))
+                .unwrap()
+        };
+        let gzip = HeaderValue::from_static("gzip, br");
+        let identity = HeaderValue::from_static("identity");
+
+        // Left alone for clients that can read it.
+        let kept = decompress(Some(&gzip), false, response(StatusCode::OK, "gzip"));
+        assert_eq!(kept.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(!kept.headers().contains_key(header::VARY));
+        for kept in [
+            decompress(
+                Some(&identity),
+                true,
+                response(StatusCode::PARTIAL_CONTENT, "gzip"),
+            ),
+            decompress(Some(&identity), true, response(StatusCode::OK, "deflate")),
+        ] {
+            assert!(kept.headers().contains_key(header::CONTENT_ENCODING));
+        }
+
+        for (accept_encoding, plaintext, vary) in [
+            (Some(&gzip), true, false),
+            (Some(&identity), false, true),
+            (None, false, true),
+        ] {
+            let decoded = decompress(accept_encoding, plaintext, response(StatusCode::OK, "GZip"));
+            let headers = decoded.headers();
+            assert!(!headers.contains_key(header::CONTENT_ENCODING));
+            assert!(!headers.contains_key(header::CONTENT_LENGTH));
+            assert_eq!(headers[header::ETAG], "W/\"v1\"");
+            assert_eq!(headers.contains_key(header::VARY), vary);
+            let body = decoded.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, content);
+        }
+
+        for encoding in [Encoding::Gzip, Encoding::Brotli, Encoding::Zstd] {
+            let compressed = compress(encoding, content.as_bytes()).unwrap();
+            let mut decoder = Decoder::new(encoding).unwrap();
+            decoder.write(&compressed[..compressed.len() - 4]).unwrap();
+            assert!(decoder.finish().is_err(), "{}", encoding.as_str());
+        }
+
+        let bomb = compress(Encoding::Gzip, &vec![0; MAX_DECODED_FRAME + 1]).unwrap();
+        assert!(Decoder::new(Encoding::Gzip).unwrap().write(&bomb).is_err());
+    }
+}
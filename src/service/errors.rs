@@ -0,0 +1,212 @@
+//! Error pages sent instead of the error responses of backends, see
+//! `intercept_errors` in [`Pattern`].
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{header, HeaderMap, StatusCode};
+use tracing::{error, info};
+
+use crate::{
+    config::Pattern,
+    service::{
+        body,
+        response::{BoxBodyResponse, LocalResponse},
+    },
+};
+
+/// Header carrying the ID of a request, as sent by clients or the proxies in
+/// front of this one.
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// ID of the request with these headers, the one sent by the client if it
+/// is made of up to 128 letters, digits, `.`, `_` and `-`. Otherwise
+/// generated from the startup time and a counter, so that it stays unique
+/// across restarts.
+pub fn request_id(headers: &HeaderMap) -> String {
+    static STARTED: OnceLock<u64> = OnceLock::new();
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+
+    let sent = headers
+        .get(REQUEST_ID)
+        .map(|id| id.as_bytes())
+        .filter(|id| is_valid_id(id));
+    if let Some(id) = sent {
+        return String::from_utf8_lossy(id).into_owned();
+    }
+
+    let started = STARTED.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs())
+    });
+
+    format!("{started:x}-{:x}", COUNT.fetch_add(1, Ordering::Relaxed))
+}
+
+fn is_valid_id(id: &[u8]) -> bool {
+    (1..=128).contains(&id.len())
+        && id
+            .iter()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"._-".contains(byte))
+}
+
+/// Whether `pattern` replaces responses with this status.
+pub fn intercepts(pattern: &Pattern, status: StatusCode) -> bool {
+    pattern.intercept_errors
+        && pattern
+            .intercepted_statuses
+            .iter()
+            .any(|intercepted| intercepted.matches(status.as_u16()))
+}
+
+/// Page replacing an error response with this status. Only `Retry-After` is
+/// kept from the original response. The ID is logged along with the status,
+/// since the page is all the client gets to report.
+pub async fn page(
+    pattern: &Pattern,
+    status: StatusCode,
+    original: &HeaderMap,
+    request_id: &str,
+) -> BoxBodyResponse {
+    info!(%status, request_id, "Replaced backend error with an error page");
+
+    let template = pattern
+        .error_pages
+        .iter()
+        .filter(|(matched, _)| matched.matches(status.as_u16()))
+        .min_by_key(|(matched, _)| matches!(matched, crate::config::StatusMatch::Class(_)))
+        .map(|(_, path)| path);
+
+    let read = match template {
+        Some(path) => Some((path, tokio::fs::read_to_string(path).await)),
+        None => None,
+    };
+
+    let (content_type, content) = match read {
+        Some((path, Ok(template))) => (content_type(path), render(&template, status, request_id)),
+        Some((path, Err(err))) => {
+            error!(path = %path.display(), %err, "Failed to read error page");
+            (String::from("text/plain"), plain(status, request_id))
+        }
+        None => (String::from("text/plain"), plain(status, request_id)),
+    };
+
+    let mut response = LocalResponse::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(REQUEST_ID, request_id);
+    if let Some(retry_after) = original.get(header::RETRY_AFTER) {
+        response = response.header(header::RETRY_AFTER, retry_after);
+    }
+
+    response.body(body::full(content)).unwrap()
+}
+
+fn content_type(path: &Path) -> String {
+    mime_guess::from_path(path)
+        .first_or(mime_guess::mime::TEXT_HTML)
+        .to_string()
+}
+
+fn render(template: &str, status: StatusCode, request_id: &str) -> String {
+    template
+        .replace("{status}", &escape(status.as_str()))
+        .replace("{request_id}", &escape(request_id))
+}
+
+/// `value` safe to insert in HTML text and attributes.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for character in value.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+fn plain(status: StatusCode, request_id: &str) -> String {
+    let reason = status.canonical_reason().unwrap_or_default();
+    format!(
+        "HTTP {} {}\nRequest ID: {request_id}\n",
+        status.as_u16(),
+        reason.to_uppercase()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+    use crate::config::StatusMatch;
+
+    #[tokio::test]
+    async fn renders_most_specific_page() {
+        let directory = std::env::temp_dir().join(format!("xnav-errors-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let exact = directory.join("502.html");
+        let class = directory.join("5xx.html");
+        std::fs::write(&exact, "<p>{status} bad gateway, {request_id}</p>").unwrap();
+        std::fs::write(&class, "<p>{status} server error</p>").unwrap();
+
+        let mut pattern: Pattern = toml::from_str(r#"forward = ["127.0.0.1:8080"]"#).unwrap();
+        pattern.intercept_errors = true;
+        pattern.intercepted_statuses = vec![StatusMatch::Class(5)];
+        pattern.error_pages = [
+            (StatusMatch::Exact(502), exact),
+            (StatusMatch::Class(5), class),
+        ]
+        .into_iter()
+        .collect();
+
+        assert!(intercepts(&pattern, StatusCode::BAD_GATEWAY));
+        assert!(!intercepts(&pattern, StatusCode::NOT_FOUND));
+
+        let headers = HeaderMap::new();
+        let response = page(&pattern, StatusCode::BAD_GATEWAY, &headers, "abc").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(response.headers()[REQUEST_ID], "abc");
+        let content = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(content, "<p>502 bad gateway, abc</p>");
+
+        let response = page(&pattern, StatusCode::SERVICE_UNAVAILABLE, &headers, "abc").await;
+        let content = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(content, "<p>503 server error</p>");
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn refuses_unsafe_request_ids() {
+        let headers = |id: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(REQUEST_ID, id.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(request_id(&headers("a1.b_c-d")), "a1.b_c-d");
+        let injected = request_id(&headers("<script>alert(1)</script>"));
+        assert!(injected
+            .bytes()
+            .all(|byte| byte.is_ascii_hexdigit() || byte == b'-'));
+        assert_ne!(request_id(&headers(&"a".repeat(129))), "a".repeat(129));
+
+        assert_eq!(
+            render("<p>{request_id}</p>", StatusCode::BAD_GATEWAY, "<b>\"&'"),
+            "<p>&lt;b&gt;&quot;&amp;&#39;</p>"
+        );
+    }
+}
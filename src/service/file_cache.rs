@@ -0,0 +1,381 @@
+//! In-memory cache for small static files, invalidated by a filesystem
+//! watcher on the serve directory.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use bytes::Bytes;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::config::{Encoding, FileCache};
+
+/// Changes remembered by the watcher. Reads that started before more
+/// changes than this happened are not cached.
+const RECENT_CHANGES: usize = 256;
+
+type Entries = Arc<Mutex<HashMap<PathBuf, Entry>>>;
+
+/// Cached content of a single file.
+struct Entry {
+    content: Bytes,
+    inserted: Instant,
+    /// Content compressed with each encoding requested so far.
+    compressed: Vec<(Encoding, Bytes)>,
+}
+
+/// Changes seen by the watcher, numbered in order, so that files read while
+/// they changed are not cached.
+#[derive(Default)]
+struct Changes {
+    count: u64,
+    /// Most recent changes, `None` when anything might have changed.
+    recent: VecDeque<(u64, Option<PathBuf>)>,
+}
+
+/// Runtime storage behind a [`FileCache`] configuration.
+#[derive(Default)]
+pub struct FileStore {
+    /// Cached files indexed by their canonical path.
+    entries: Entries,
+    /// Changes seen by the watcher, only updated with `entries` locked.
+    changes: Arc<Mutex<Changes>>,
+    /// Watcher that evicts entries when files change on disk. Started when
+    /// the first file is read since the directory is not known before.
+    watcher: OnceLock<Option<RecommendedWatcher>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Outcome of reading a file through the cache, attached to the extensions
+/// of the response so that it can be reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup {
+    Hit,
+    Miss,
+    /// The file was cached but its TTL had expired.
+    Stale,
+}
+
+impl Lookup {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Lookup::Hit => "hit",
+            Lookup::Miss => "miss",
+            Lookup::Stale => "stale",
+        }
+    }
+}
+
+/// Totals of a [`FileStore`] since startup, along with its current size.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheUsage {
+    pub hits: u64,
+    /// Reads from disk, stale entries included.
+    pub misses: u64,
+    pub stale: u64,
+    /// Entries dropped to make room for others.
+    pub evictions: u64,
+    pub entries: usize,
+    /// Size of the cached files and their compressed variants.
+    pub bytes: u64,
+}
+
+impl std::fmt::Debug for FileStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileStore")
+            .field("entries", &self.entries.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl FileCache {
+    /// Reads `file` from memory if possible, otherwise from disk. Files not
+    /// larger than the configured limit are cached for subsequent reads.
+    pub async fn read(&self, directory: &Path, file: &Path) -> Result<(Bytes, Lookup), io::Error> {
+        let lookup = match self.get(file) {
+            Ok(content) => {
+                self.store.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok((content, Lookup::Hit));
+            }
+            Err(lookup) => lookup,
+        };
+
+        self.store.misses.fetch_add(1, Ordering::Relaxed);
+        if lookup == Lookup::Stale {
+            self.store.stale.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Changes made while the file is read are noticed by the watcher,
+        // which has to be running beforehand.
+        self.store.watch(directory);
+        let seen = self.store.changes.lock().unwrap().count;
+
+        let content = Bytes::from(tokio::fs::read(file).await?);
+
+        if content.len() as u64 <= self.max_file_size {
+            self.insert(file, content.clone(), seen);
+        }
+
+        Ok((content, lookup))
+    }
+
+    fn get(&self, file: &Path) -> Result<Bytes, Lookup> {
+        let mut entries = self.store.entries.lock().unwrap();
+
+        let entry = entries.get(file).ok_or(Lookup::Miss)?;
+
+        if entry.inserted.elapsed() > self.ttl {
+            entries.remove(file);
+            return Err(Lookup::Stale);
+        }
+
+        Ok(entry.content.clone())
+    }
+
+    /// Caches `content`, read once the watcher had seen `seen` changes,
+    /// unless `file` changed since.
+    fn insert(&self, file: &Path, content: Bytes, seen: u64) {
+        let mut entries = self.store.entries.lock().unwrap();
+
+        if self.store.changes.lock().unwrap().since(seen, file) {
+            return;
+        }
+
+        if entries.len() >= self.max_entries && !entries.contains_key(file) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.inserted)
+                .map(|(path, _)| path.clone());
+
+            match oldest {
+                Some(path) => entries.remove(&path),
+                None => return,
+            };
+            self.store.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let entry = Entry {
+            content,
+            inserted: Instant::now(),
+            compressed: Vec::new(),
+        };
+        entries.insert(file.to_path_buf(), entry);
+    }
+
+    /// Content of the cached `file` compressed with `encoding`, if it was
+    /// kept by [`FileCache::keep_compressed`].
+    pub fn compressed(&self, file: &Path, encoding: Encoding) -> Option<Bytes> {
+        let entries = self.store.entries.lock().unwrap();
+
+        entries
+            .get(file)?
+            .compressed
+            .iter()
+            .find(|(kept, _)| *kept == encoding)
+            .map(|(_, compressed)| compressed.clone())
+    }
+
+    /// Keeps `compressed`, the result of compressing `content` with
+    /// `encoding`, along with the cached `file`. Nothing is kept if the file
+    /// is no longer cached or changed meanwhile.
+    pub fn keep_compressed(
+        &self,
+        file: &Path,
+        content: &Bytes,
+        encoding: Encoding,
+        compressed: Bytes,
+    ) {
+        let mut entries = self.store.entries.lock().unwrap();
+
+        let Some(entry) = entries
+            .get_mut(file)
+            .filter(|entry| entry.content == content)
+        else {
+            return;
+        };
+
+        if !entry.compressed.iter().any(|(kept, _)| *kept == encoding) {
+            entry.compressed.push((encoding, compressed));
+        }
+    }
+}
+
+impl Entry {
+    /// Bytes held by the entry, compressed variants included.
+    fn size(&self) -> u64 {
+        let compressed: usize = self.compressed.iter().map(|(_, bytes)| bytes.len()).sum();
+
+        (self.content.len() + compressed) as u64
+    }
+}
+
+impl FileStore {
+    /// Totals of the cache, for the status endpoint.
+    pub fn usage(&self) -> CacheUsage {
+        let entries = self.entries.lock().unwrap();
+
+        CacheUsage {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            stale: self.stale.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: entries.len(),
+            bytes: entries.values().map(Entry::size).sum(),
+        }
+    }
+
+    /// Evicts the cached files for which `purged` is true, returning how
+    /// many there were.
+    pub fn purge(&self, purged: impl Fn(&Path) -> bool) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|file, _| !purged(file));
+
+        before - entries.len()
+    }
+
+    /// Starts watching `directory` unless a watcher was already started.
+    fn watch(&self, directory: &Path) {
+        self.watcher.get_or_init(|| {
+            let entries = Arc::clone(&self.entries);
+            let changes = Arc::clone(&self.changes);
+
+            let result =
+                notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                    let mut entries = entries.lock().unwrap();
+                    let mut changes = changes.lock().unwrap();
+                    match event {
+                        // Reading files, including our own reads, changes nothing.
+                        Ok(event) if event.kind.is_access() => {}
+                        Ok(event) if !event.need_rescan() => {
+                            for path in &event.paths {
+                                entries.retain(|file, _| !file.starts_with(path));
+                                changes.record(Some(path));
+                            }
+                        }
+                        // Events might have been lost, nothing cached can be trusted.
+                        _ => {
+                            entries.clear();
+                            changes.record(None);
+                        }
+                    }
+                })
+                .and_then(|mut watcher| {
+                    watcher.watch(directory, RecursiveMode::Recursive)?;
+                    Ok(watcher)
+                });
+
+            match result {
+                Ok(watcher) => Some(watcher),
+                Err(err) => {
+                    warn!(?directory, %err, "Failed to watch directory, relying on cache TTL only");
+                    None
+                }
+            }
+        });
+    }
+}
+
+impl Changes {
+    fn record(&mut self, path: Option<&Path>) {
+        self.count += 1;
+
+        if self.recent.len() >= RECENT_CHANGES {
+            self.recent.pop_front();
+        }
+        self.recent
+            .push_back((self.count, path.map(Path::to_owned)));
+    }
+
+    /// Whether `file` might have changed after the first `seen` changes.
+    /// Changes no longer remembered are assumed to concern it.
+    fn since(&self, seen: u64, file: &Path) -> bool {
+        let forgotten = self
+            .recent
+            .front()
+            .is_some_and(|(first, _)| *first > seen + 1);
+
+        forgotten
+            || self
+                .recent
+                .iter()
+                .filter(|(number, _)| *number > seen)
+                .any(|(_, path)| path.as_ref().is_none_or(|path| file.starts_with(path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_compressed_variants_with_their_entry() {
+        let root = std::env::temp_dir().join(format!("xnav-file-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("app.js");
+        std::fs::write(&file, "console.log(1)").unwrap();
+
+        let cache = toml::from_str::<FileCache>("").unwrap();
+        let (content, lookup) = cache.read(&root, &file).await.unwrap();
+        assert_eq!(lookup, Lookup::Miss);
+
+        let gzipped = Bytes::from_static(b"gzipped");
+        cache.keep_compressed(&file, &content, Encoding::Gzip, gzipped.clone());
+        assert_eq!(cache.compressed(&file, Encoding::Gzip), Some(gzipped));
+        assert_eq!(cache.compressed(&file, Encoding::Brotli), None);
+        assert_eq!(
+            cache.store.usage().bytes,
+            (content.len() + "gzipped".len()) as u64
+        );
+
+        // Variants of content that changed meanwhile are not kept.
+        let old = Bytes::from_static(b"console.log(0)");
+        cache.keep_compressed(&file, &old, Encoding::Brotli, Bytes::from_static(b"br"));
+        assert_eq!(cache.compressed(&file, Encoding::Brotli), None);
+
+        // Variants go away with their entry, and aren't back once it is.
+        assert_eq!(cache.store.purge(|purged| purged == file), 1);
+        assert_eq!(cache.compressed(&file, Encoding::Gzip), None);
+        let (_, lookup) = cache.read(&root, &file).await.unwrap();
+        assert_eq!(lookup, Lookup::Miss);
+        assert_eq!(cache.compressed(&file, Encoding::Gzip), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn changes_during_reads() {
+        let mut changes = Changes::default();
+        let (file, other) = (Path::new("/srv/app.js"), Path::new("/srv/index.html"));
+
+        let seen = changes.count;
+        assert!(!changes.since(seen, file));
+        changes.record(Some(other));
+        assert!(!changes.since(seen, file));
+        changes.record(Some(Path::new("/srv")));
+        assert!(changes.since(seen, file));
+
+        let seen = changes.count;
+        changes.record(None);
+        assert!(changes.since(seen, other));
+
+        let seen = changes.count;
+        for _ in 0..=RECENT_CHANGES {
+            changes.record(Some(other));
+        }
+        assert!(changes.since(seen, file));
+        assert!(!changes.since(changes.count, file));
+    }
+}
@@ -1,37 +1,741 @@
 //! Static files server sub-service.
 
-use crate::service::{BoxBodyResponse, LocalResponse};
-use hyper::header;
-use std::path::Path;
+use crate::{
+    config::{Download, Encoding, Pattern, Writable},
+    service::{
+        buffer::{Buffered, Spilled},
+        compression,
+        range::{self, Ranges},
+        secret_matches,
+        traffic::Counted,
+        BoxBodyResponse, LocalResponse,
+    },
+};
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::{body::Incoming, header, Method, Request, StatusCode};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{io::AsyncWriteExt, sync::OnceCell};
+use tracing::error;
+
+/// Compressed copies kept of the files too large for memory.
+const COMPRESSED_COPIES: usize = 64;
+
+/// Compressed copies of the files too large for memory, indexed by path,
+/// modification time and encoding. Each version of a file is compressed
+/// once per encoding rather than on every request.
+static COMPRESSED: OnceLock<Mutex<HashMap<CopyKey, Arc<OnceCell<Buffered>>>>> = OnceLock::new();
+
+type CopyKey = (PathBuf, SystemTime, Encoding);
 
 /// Returns an HTTP response whose body is the content of a file.
-pub async fn transfer(path: &str, root: &str) -> Result<BoxBodyResponse, hyper::Error> {
+pub async fn transfer<T>(
+    request: &Request<T>,
+    path: &str,
+    root: &str,
+    pattern: &Pattern,
+) -> Result<BoxBodyResponse, hyper::Error> {
     let Ok(directory) = Path::new(root).canonicalize() else {
         return Ok(LocalResponse::not_found());
     };
 
-    let Ok(file) = directory.join(path).canonicalize() else {
+    if !pattern.serve_hidden && is_hidden(path) {
+        return Ok(LocalResponse::not_found());
+    }
+
+    let maybe_file = resolve(&directory, path, pattern).or_else(|| {
+        pattern
+            .fallback
+            .as_ref()
+            .and_then(|fallback| resolve(&directory, fallback, pattern))
+    });
+
+    let Some(file) = maybe_file else {
         return Ok(LocalResponse::not_found());
     };
 
-    if !file.starts_with(&directory) || !file.is_file() {
+    let content_type = content_type(&file, pattern);
+
+    let Ok(metadata) = tokio::fs::metadata(&file).await else {
         return Ok(LocalResponse::not_found());
+    };
+
+    let modified = metadata.modified().ok();
+    let limits = &pattern.response_buffering;
+
+    let mut lookup = None;
+
+    // Files past the memory allowance are streamed from disk.
+    let mut content = if metadata.len() > limits.memory {
+        Buffered::File(Arc::new(Spilled::existing(file.clone(), metadata.len())))
+    } else {
+        let content = match &pattern.file_cache {
+            Some(cache) => cache.read(&directory, &file).await.map(|(content, found)| {
+                lookup = Some(found);
+                content
+            }),
+            None => tokio::fs::read(&file).await.map(Bytes::from),
+        };
+
+        let Ok(content) = content else {
+            return Ok(LocalResponse::not_found());
+        };
+
+        Buffered::Memory(content)
+    };
+
+    let length = content.len();
+    let tag = entity_tag(length, modified);
+
+    let mut response = LocalResponse::builder().header(header::ACCEPT_RANGES, "bytes");
+
+    if let Some(lookup) = lookup {
+        response = response.extension(lookup);
+    }
+
+    if let Some(modified) = modified {
+        response = response.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+
+    let download = pattern.download.as_ref();
+
+    if download.is_some_and(|download| is_download(download, path, &file)) {
+        response = response.header(header::CONTENT_DISPOSITION, content_disposition(&file));
     }
 
-    let content_type = match file.extension().and_then(|e| e.to_str()).unwrap_or("txt") {
-        "html" => "text/html",
-        "css" => "text/css",
-        "js" => "application/javascript",
-        "png" => "image/png",
-        "jpeg" => "image/jpeg",
-        _ => "text/plain",
+    let if_range = request.headers().get(header::IF_RANGE);
+
+    if request.method() == Method::GET && range::is_current(if_range, &tag, modified) {
+        match range::parse(request.headers().get(header::RANGE), length) {
+            Ranges::Satisfiable(ranges) => match (&content, ranges.as_slice()) {
+                (Buffered::Memory(bytes), _) => {
+                    response = response.header(header::ETAG, format!("\"{tag}\""));
+                    return Ok(range::partial(response, bytes, &content_type, &ranges));
+                }
+                (Buffered::File(_), [range]) => {
+                    response = response.header(header::ETAG, format!("\"{tag}\""));
+                    let body = content.response_body(Some(range));
+                    return Ok(range::single(response, body, &content_type, range, length));
+                }
+                // Multipart bodies are not assembled from disk, the whole
+                // file is sent instead.
+                (Buffered::File(_), _) => {}
+            },
+            Ranges::Unsatisfiable => return Ok(range::unsatisfiable(response, length as usize)),
+            Ranges::Ignore => {}
+        }
+    }
+
+    let mut etag = format!("\"{tag}\"");
+
+    response = response.header(header::CONTENT_TYPE, &content_type);
+
+    let compression = pattern
+        .compression
+        .as_ref()
+        .filter(|config| compression::is_compressible(&content_type, config));
+
+    if let Some(config) = compression {
+        response = response.header(header::VARY, header::ACCEPT_ENCODING.as_str());
+
+        let encoding = compression::negotiate(
+            request.headers().get(header::ACCEPT_ENCODING),
+            &config.algorithms,
+        )
+        .filter(|_| length >= config.min_size as u64);
+
+        if let Some(encoding) = encoding {
+            // HEAD responses don't pay for the compression, the compressed
+            // length is left out instead.
+            if request.method() == Method::HEAD {
+                return Ok(response
+                    .header(header::CONTENT_ENCODING, encoding.as_str())
+                    .header(header::ETAG, format!("\"{tag}-{}\"", encoding.as_str()))
+                    .body(crate::service::body::empty())
+                    .unwrap());
+            }
+
+            // Files read through the cache are only compressed once per
+            // encoding, the result is kept along with the cached content.
+            let cache = pattern.file_cache.as_ref().filter(|_| lookup.is_some());
+
+            let compressed = match &content {
+                Buffered::Memory(bytes) => {
+                    match cache.and_then(|c| c.compressed(&file, encoding)) {
+                        Some(compressed) => Ok(Buffered::Memory(compressed)),
+                        None => {
+                            let input = bytes.clone();
+                            let compressed = tokio::task::spawn_blocking(move || {
+                                compression::compress(encoding, &input).map(Bytes::from)
+                            })
+                            .await
+                            .unwrap_or_else(|err| Err(io::Error::other(err)));
+
+                            if let (Some(cache), Ok(compressed)) = (cache, &compressed) {
+                                cache.keep_compressed(&file, bytes, encoding, compressed.clone());
+                            }
+
+                            compressed.map(Buffered::Memory)
+                        }
+                    }
+                }
+                Buffered::File(_) => {
+                    compressed_copy(&file, modified, encoding, limits.directory.as_deref()).await
+                }
+            };
+
+            // Fall back to the uncompressed file if the encoder fails.
+            if let Ok(compressed) = compressed {
+                response = response.header(header::CONTENT_ENCODING, encoding.as_str());
+                content = compressed;
+                // Each encoding is a different representation.
+                etag = format!("\"{tag}-{}\"", encoding.as_str());
+            }
+        }
+    }
+
+    response = response
+        .header(header::ETAG, etag)
+        .header(header::CONTENT_LENGTH, content.len());
+
+    // HEAD responses carry the same headers as GET, but never a body.
+    if request.method() == Method::HEAD {
+        return Ok(response.body(crate::service::body::empty()).unwrap());
+    }
+
+    Ok(response.body(content.response_body(None)).unwrap())
+}
+
+/// Compresses a file too large for memory into a spilled copy, or reuses the
+/// copy made for the same version of the file and encoding.
+async fn compressed_copy(
+    file: &Path,
+    modified: Option<SystemTime>,
+    encoding: Encoding,
+    directory: Option<&Path>,
+) -> io::Result<Buffered> {
+    let compress = || {
+        let input = file.to_owned();
+        Buffered::spill(directory, move |output| {
+            compression::compress_file(encoding, &input, output)
+        })
     };
 
-    match tokio::fs::read(file).await {
-        Ok(content) => Ok(LocalResponse::builder()
-            .header(header::CONTENT_TYPE, content_type)
-            .body(crate::service::body::full(content))
-            .unwrap()),
-        Err(_) => Ok(LocalResponse::not_found()),
+    let Some(modified) = modified else {
+        return compress().await;
+    };
+
+    let copy = {
+        let mut copies = COMPRESSED.get_or_init(Default::default).lock().unwrap();
+
+        // Copies of previous versions are never served again.
+        copies.retain(|(path, version, _), _| path != file || *version == modified);
+
+        let key = (file.to_owned(), modified, encoding);
+
+        let full = copies.len() >= COMPRESSED_COPIES && !copies.contains_key(&key);
+
+        if let Some(evicted) = copies.keys().next().filter(|_| full).cloned() {
+            copies.remove(&evicted);
+        }
+
+        copies.entry(key).or_default().clone()
+    };
+
+    copy.get_or_try_init(compress).await.cloned()
+}
+
+/// Creates or overwrites a file with the body of a PUT request. The content
+/// is written to a temporary file of its own first so that readers never
+/// observe a partial upload, nor concurrent uploads mixed together.
+pub async fn upload(
+    request: Request<Counted<Incoming>>,
+    path: &str,
+    root: &str,
+    pattern: &Pattern,
+    writable: &Writable,
+) -> Result<BoxBodyResponse, hyper::Error> {
+    if !is_authorized(&request, writable) {
+        return Ok(LocalResponse::unauthorized());
+    }
+
+    let Ok(directory) = Path::new(root).canonicalize() else {
+        return Ok(LocalResponse::not_found());
+    };
+
+    let Some(file) = writable_path(&directory, path, pattern).await else {
+        return Ok(LocalResponse::forbidden());
+    };
+
+    let exists = file.exists();
+    let (temporary, output) = match temporary(&file).await {
+        Ok(created) => created,
+        Err(err) => {
+            error!(?file, %err, "Failed to store upload");
+            return Ok(LocalResponse::internal_server_error());
+        }
+    };
+
+    let max_size = pattern
+        .max_body_size
+        .map_or(writable.max_size, |limit| limit.min(writable.max_size));
+    let result = write_body(request.into_body(), output, max_size).await;
+
+    let result = match result {
+        Ok(()) => tokio::fs::rename(&temporary, &file)
+            .await
+            .map_err(Upload::Io),
+        Err(err) => Err(err),
+    };
+
+    if let Err(err) = result {
+        let _ = tokio::fs::remove_file(&temporary).await;
+        return match err {
+            Upload::TooLarge => Ok(LocalResponse::payload_too_large()),
+            Upload::Body(err) => Err(err),
+            Upload::Io(err) => {
+                error!(?file, %err, "Failed to store upload");
+                Ok(LocalResponse::internal_server_error())
+            }
+        };
+    }
+
+    let status = if exists {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+
+    Ok(LocalResponse::builder()
+        .status(status)
+        .body(crate::service::body::empty())
+        .unwrap())
+}
+
+/// Deletes the file targeted by a DELETE request.
+pub async fn remove<T>(
+    request: &Request<T>,
+    path: &str,
+    root: &str,
+    pattern: &Pattern,
+    writable: &Writable,
+) -> Result<BoxBodyResponse, hyper::Error> {
+    if !is_authorized(request, writable) {
+        return Ok(LocalResponse::unauthorized());
+    }
+
+    let Ok(directory) = Path::new(root).canonicalize() else {
+        return Ok(LocalResponse::not_found());
+    };
+
+    if !pattern.serve_hidden && is_hidden(path) {
+        return Ok(LocalResponse::not_found());
+    }
+
+    let Some(file) = resolve(&directory, path, pattern) else {
+        return Ok(LocalResponse::not_found());
+    };
+
+    if tokio::fs::remove_file(file).await.is_err() {
+        return Ok(LocalResponse::internal_server_error());
+    }
+
+    Ok(LocalResponse::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(crate::service::body::empty())
+        .unwrap())
+}
+
+/// Reasons why an upload can fail.
+enum Upload {
+    TooLarge,
+    Body(hyper::Error),
+    Io(io::Error),
+}
+
+/// Streams `body` into `file`, giving up once more than `max_size` bytes
+/// have been received.
+async fn write_body(
+    mut body: Counted<Incoming>,
+    mut output: tokio::fs::File,
+    max_size: u64,
+) -> Result<(), Upload> {
+    let mut written = 0;
+
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(Upload::Body)?;
+        if let Ok(chunk) = frame.into_data() {
+            written += chunk.len() as u64;
+            if written > max_size {
+                return Err(Upload::TooLarge);
+            }
+            output.write_all(&chunk).await.map_err(Upload::Io)?;
+        }
+    }
+
+    output.flush().await.map_err(Upload::Io)
+}
+
+/// Creates a file next to `file` to write an upload to, with a random name
+/// and never one that already exists.
+async fn temporary(file: &Path) -> io::Result<(PathBuf, tokio::fs::File)> {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+
+    loop {
+        let mut random = [0; 8];
+        getrandom::getrandom(&mut random).expect("no source of randomness");
+        let suffix: String = random.iter().map(|byte| format!("{byte:02x}")).collect();
+        let path = file.with_file_name(format!(".{name}.{suffix}.xnav-upload"));
+
+        match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(output) => return Ok((path, output)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Checks the bearer token required for modifications, if any. Patterns
+/// without one authenticate their clients before getting here.
+fn is_authorized<T>(request: &Request<T>, writable: &Writable) -> bool {
+    let Some(token) = &writable.token else {
+        return true;
+    };
+
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| secret_matches(token.as_bytes(), provided.as_bytes()))
+}
+
+/// Returns the location where an upload to `path` should be written, making
+/// sure that it lands inside `directory` and creating missing parents.
+async fn writable_path(directory: &Path, path: &str, pattern: &Pattern) -> Option<PathBuf> {
+    let relative = Path::new(path);
+
+    let only_normal = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+
+    if path.is_empty() || !only_normal || (!pattern.serve_hidden && is_hidden(path)) {
+        return None;
+    }
+
+    let file = directory.join(relative);
+
+    // Existing files must pass the same checks as reads.
+    if file.symlink_metadata().is_ok() {
+        return resolve(directory, path, pattern);
+    }
+
+    // The deepest parent that exists is checked before anything is created,
+    // so that links can't lead outside of `directory`.
+    let missing = relative.parent()?;
+    let existing = missing
+        .ancestors()
+        .find(|ancestor| directory.join(ancestor).symlink_metadata().is_ok())?;
+    let mut parent = if existing.as_os_str().is_empty() {
+        directory.to_path_buf()
+    } else if pattern.follow_symlinks {
+        directory.join(existing).canonicalize().ok()?
+    } else {
+        resolve_without_symlinks(directory, existing.to_str()?)?
+    };
+
+    if !parent.starts_with(directory) || !parent.is_dir() {
+        return None;
+    }
+
+    for component in missing.strip_prefix(existing).ok()?.components() {
+        parent.push(component);
+        match tokio::fs::create_dir(&parent).await {
+            Ok(()) => {}
+            // Created meanwhile, only a real directory will do.
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                if !tokio::fs::symlink_metadata(&parent).await.ok()?.is_dir() {
+                    return None;
+                }
+            }
+            Err(_) => return None,
+        }
+    }
+
+    Some(parent.join(file.file_name()?))
+}
+
+/// Returns the canonical path of `path` if it's a regular file located inside
+/// `directory`.
+fn resolve(directory: &Path, path: &str, pattern: &Pattern) -> Option<PathBuf> {
+    let file = if pattern.follow_symlinks {
+        directory.join(path).canonicalize().ok()?
+    } else {
+        resolve_without_symlinks(directory, path)?
+    };
+
+    let relative = file.strip_prefix(directory).ok()?;
+
+    if !pattern.serve_hidden && is_hidden(relative.to_str()?) {
+        return None;
+    }
+
+    if !file.is_file() {
+        return None;
+    }
+
+    Some(file)
+}
+
+/// Joins `path` to `directory` one component at a time, refusing to traverse
+/// any symbolic link and to step above `directory`.
+fn resolve_without_symlinks(directory: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = directory.to_path_buf();
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(segment) => {
+                file.push(segment);
+                if file.symlink_metadata().ok()?.file_type().is_symlink() {
+                    return None;
+                }
+            }
+            Component::ParentDir => {
+                if file == directory {
+                    return None;
+                }
+                file.pop();
+            }
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(file)
+}
+
+/// Returns `true` if any segment of `path` is a dot-segment or a dotfile such
+/// as `.env` or `.git`.
+fn is_hidden(path: &str) -> bool {
+    path.split(['/', '\\'])
+        .any(|segment| segment.starts_with('.'))
+}
+
+/// Returns `true` if `file` requested as `path` must be sent as an attachment.
+/// Without any extensions or paths configured every file is an attachment.
+fn is_download(download: &Download, path: &str, file: &Path) -> bool {
+    if download.extensions.is_empty() && download.paths.is_empty() {
+        return true;
+    }
+
+    let name = file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let matches_extension = download.extensions.iter().any(|extension| {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        name.strip_suffix(extension.as_str())
+            .is_some_and(|stem| stem.ends_with('.'))
+    });
+
+    matches_extension
+        || download
+            .paths
+            .iter()
+            .any(|prefix| path.starts_with(prefix.trim_start_matches('/')))
+}
+
+/// Builds an attachment `Content-Disposition` value for `file`, using the
+/// RFC 6266 extended parameter when the name is not plain ASCII.
+fn content_disposition(file: &Path) -> String {
+    let name = file
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    let is_plain = name
+        .chars()
+        .all(|c| c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ');
+
+    if is_plain {
+        return format!("attachment; filename=\"{name}\"");
+    }
+
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' ' => ' ',
+            c if c.is_ascii_graphic() && c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let encoded: String = name
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect();
+
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// Strong validator for a file derived from its size and modification time,
+/// without the surrounding quotes.
+fn entity_tag(length: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_nanos());
+
+    format!("{length:x}-{modified:x}")
+}
+
+/// Determines the MIME type of a file from its extension. Overrides from the
+/// pattern configuration take precedence over the built-in mapping.
+fn content_type(file: &Path, pattern: &Pattern) -> String {
+    let Some(extension) = file.extension().and_then(|e| e.to_str()) else {
+        return String::from("text/plain");
+    };
+
+    let extension = extension.to_ascii_lowercase();
+
+    if let Some(content_type) = pattern.content_types.get(&extension) {
+        return content_type.clone();
+    }
+
+    mime_guess::from_ext(&extension)
+        .first_raw()
+        .unwrap_or("application/octet-stream")
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_paths() {
+        assert!(is_hidden(".env"));
+        assert!(is_hidden(".git/config"));
+        assert!(is_hidden("assets/../.env"));
+        assert!(is_hidden("./index.html"));
+        assert!(!is_hidden("index.html"));
+        assert!(!is_hidden("assets/app.min.js"));
+        assert!(!is_hidden(""));
+    }
+
+    #[test]
+    fn attachment_names() {
+        assert_eq!(
+            content_disposition(Path::new("/srv/build 1.zip")),
+            "attachment; filename=\"build 1.zip\""
+        );
+        assert_eq!(
+            content_disposition(Path::new("/srv/résumé.pdf")),
+            "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"
+        );
+    }
+
+    #[tokio::test]
+    async fn uploads_get_their_own_file() {
+        let root = std::env::temp_dir().join(format!("xnav-uploads-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("report.csv");
+
+        let (first, _) = temporary(&file).await.unwrap();
+        let (second, _) = temporary(&file).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), Some(root.as_path()));
+        assert!(first
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".report.csv."));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_not_traversed() {
+        let root = std::env::temp_dir().join(format!("xnav-symlinks-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("assets/app.js"), "").unwrap();
+        std::os::unix::fs::symlink(root.join("assets"), root.join("linked")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        assert!(resolve_without_symlinks(&root, "assets/app.js").is_some());
+        assert!(resolve_without_symlinks(&root, "assets/../assets/app.js").is_some());
+        assert!(resolve_without_symlinks(&root, "linked/app.js").is_none());
+        assert!(resolve_without_symlinks(&root, "../etc/passwd").is_none());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn uploads_stay_inside_the_root() {
+        let base = std::env::temp_dir().join(format!("xnav-writable-{}", std::process::id()));
+        let (root, outside) = (base.join("root"), base.join("outside"));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("linked")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        for follow_symlinks in [false, true] {
+            let pattern = toml::from_str::<Pattern>(&format!(
+                "serve = {:?}\nfollow_symlinks = {follow_symlinks}",
+                root.to_str().unwrap()
+            ))
+            .unwrap();
+
+            assert!(writable_path(&root, "linked/x/y/file", &pattern)
+                .await
+                .is_none());
+            assert!(!outside.join("x").exists());
+
+            let file = writable_path(&root, "a/b/file", &pattern).await.unwrap();
+            assert_eq!(file, root.join("a/b/file"));
+            assert!(root.join("a/b").is_dir());
+        }
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn large_files_are_compressed_once() {
+        let root = std::env::temp_dir().join(format!("xnav-compressed-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("app.js");
+        std::fs::write(&file, "let x = 1;\n".repeat(1000)).unwrap();
+        let modified = std::fs::metadata(&file).unwrap().modified().ok();
+
+        let copy = |encoding| compressed_copy(&file, modified, encoding, Some(&root));
+        let (Buffered::File(first), Buffered::File(second), Buffered::File(other)) = (
+            copy(Encoding::Gzip).await.unwrap(),
+            copy(Encoding::Gzip).await.unwrap(),
+            copy(Encoding::Zstd).await.unwrap(),
+        ) else {
+            panic!("compressed copies are spilled");
+        };
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -0,0 +1,40 @@
+//! Countries of client addresses, see [`GeoIp`].
+//!
+//! [`GeoIp`]: crate::config::GeoIp
+
+use std::{net::IpAddr, path::Path};
+
+use maxminddb::{geoip2, Reader};
+
+/// MaxMind database mapping addresses to countries, empty until opened.
+#[derive(Default)]
+pub struct Countries {
+    reader: Option<Reader<Vec<u8>>>,
+}
+
+impl std::fmt::Debug for Countries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Countries")
+            .field("opened", &self.reader.is_some())
+            .finish()
+    }
+}
+
+impl Countries {
+    /// Reads the GeoLite2 or GeoIP2 Country database at `path` into memory.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|err| format!("failed to open GeoIP database {path:?}: {err}"))?;
+
+        Ok(Self {
+            reader: Some(reader),
+        })
+    }
+
+    /// ISO code of the country of `ip`, as in `"FR"`, if it's known.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let country: geoip2::Country = self.reader.as_ref()?.lookup(ip).ok()?;
+
+        country.country?.iso_code.map(str::to_owned)
+    }
+}
@@ -0,0 +1,37 @@
+//! Extension point for embedders that need to observe requests without
+//! modifying the service.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use http::{response, HeaderMap, Method, Uri};
+
+/// Callbacks invoked at each stage of a request. All the methods do nothing
+/// by default, implementors only override what they need. Hooks run on the
+/// request task, so they should return quickly.
+pub trait RequestHook: Send + Sync + 'static {
+    /// The request was received, before any pattern is matched.
+    fn on_request(&self, _request: &RequestInfo) {}
+
+    /// The request is about to be forwarded to `backend`. Called for every
+    /// attempt, retries and hedges included.
+    fn on_upstream_selected(&self, _request: &RequestInfo, _backend: SocketAddr) {}
+
+    /// Response headers are ready to be sent to the client.
+    fn on_response(&self, _request: &RequestInfo, _response: &response::Parts) {}
+
+    /// The request failed without a response, the connection is closed.
+    fn on_error(&self, _request: &RequestInfo, _error: &hyper::Error) {}
+}
+
+/// Details of the request given to [`RequestHook`] callbacks.
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    pub client_addr: SocketAddr,
+    pub server_addr: SocketAddr,
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+}
+
+/// Hooks registered on a server, shared by all its connections.
+pub type Hooks = Arc<[Arc<dyn RequestHook>]>;
@@ -0,0 +1,87 @@
+//! Latency distribution of the requests handled by a pattern.
+
+use std::{sync::Mutex, time::Duration};
+
+use hdrhistogram::Histogram;
+use serde::Serialize;
+
+/// Slowest latency tracked precisely, anything above is clamped.
+const MAX_MICROS: u64 = 60 * 60 * 1_000_000;
+
+/// Histogram of request latencies in microseconds, shared by all the
+/// replicas of a server.
+pub struct Latency {
+    histogram: Mutex<Histogram<u64>>,
+}
+
+/// Summary of a [`Latency`] histogram, all the values in microseconds.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self {
+            histogram: Mutex::new(Histogram::new_with_bounds(1, MAX_MICROS, 3).unwrap()),
+        }
+    }
+}
+
+impl std::fmt::Debug for Latency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Latency")
+            .field("count", &self.histogram.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Latency {
+    /// Adds the latency of one request.
+    pub fn record(&self, elapsed: Duration) {
+        let micros = (elapsed.as_micros() as u64).clamp(1, MAX_MICROS);
+        self.histogram.lock().unwrap().saturating_record(micros);
+    }
+
+    /// Percentiles of all the latencies recorded so far.
+    pub fn percentiles(&self) -> Percentiles {
+        let histogram = self.histogram.lock().unwrap();
+
+        Percentiles {
+            count: histogram.len(),
+            p50: histogram.value_at_quantile(0.5),
+            p90: histogram.value_at_quantile(0.9),
+            p99: histogram.value_at_quantile(0.99),
+            max: histogram.max(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let latency = Latency::default();
+
+        assert_eq!(latency.percentiles().count, 0);
+
+        for millis in 1..=100 {
+            latency.record(Duration::from_millis(millis));
+        }
+
+        let percentiles = latency.percentiles();
+        let approx = |value: u64, millis: u64| value.abs_diff(millis * 1000) <= millis;
+
+        assert_eq!(percentiles.count, 100);
+        assert!(approx(percentiles.p50, 50), "{percentiles:?}");
+        assert!(approx(percentiles.p90, 90), "{percentiles:?}");
+        assert!(approx(percentiles.p99, 99), "{percentiles:?}");
+        assert!(approx(percentiles.max, 100), "{percentiles:?}");
+    }
+}
@@ -1,28 +1,110 @@
 //! Proxy server module, handling HTTP requests, serving static files, and proxying to backend servers.
 
+mod access;
+mod auth;
+mod ban;
 mod body;
+mod buffer;
+mod capture;
+mod challenge;
+mod compression;
+mod errors;
+mod file_cache;
 mod files;
+mod geoip;
+mod hook;
+mod latency;
+mod oidc;
+mod pace;
 mod proxy;
+mod range;
+mod rate;
+mod rules;
+mod security;
+mod signature;
+mod strict;
+mod substitute;
+mod traffic;
+mod uri;
+mod via;
+mod warm;
 
 pub mod request;
 pub mod response;
 
+pub use access::{allows, secret_matches};
+pub use auth::{ApiKeys, Users};
+pub use ban::Offenders;
 pub use body::{empty, full};
+pub use capture::{Capturer, Record};
+pub use file_cache::{CacheUsage, FileStore, Lookup};
 pub use files::transfer;
+pub use geoip::Countries;
+pub use hook::{Hooks, RequestHook, RequestInfo};
+pub use latency::{Latency, Percentiles};
+pub use oidc::{validate as validate_oidc, Provider};
 pub use proxy::forward;
+pub use proxy::Tunnels;
+pub use rate::{ClientLimiters, RateLimiter};
 pub use request::ProxyRequest;
 pub use response::{BoxBodyResponse, LocalResponse, ProxyResponse};
+pub use rules::RuleCounters;
+pub use traffic::Traffic;
+pub use warm::Warm;
 
-use crate::config::{self, Action, Forward};
-use hyper::{body::Incoming, service::Service, Request};
-use tokio::time::Instant;
+use crate::{
+    config::{self, Action, Bandwidth, Forward, LogLevel, Priority},
+    logging, metrics,
+    threading::{Decision, Tier},
+};
+use http_body_util::BodyExt;
+use hyper::{
+    body::{Body, Incoming},
+    header::{HeaderName, HeaderValue},
+    service::Service,
+    Method, Request, Response,
+};
+use tokio::{sync::watch, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, event, info_span, Instrument, Level, Span};
 
-use std::{future::Future, net::SocketAddr, pin::Pin};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{atomic::Ordering, Arc},
+};
+
+use auth::Verdict;
+use body::{Deadline, Throttled};
+use pace::Pace;
+use traffic::{Counted, Direction, Exchange};
+
+/// Emits an access log event at a level chosen by the configuration, which
+/// `tracing` macros only accept as a constant.
+macro_rules! access_event {
+    ($level:expr, $($fields:tt)*) => {
+        match $level {
+            LogLevel::Trace => event!(target: logging::ACCESS, Level::TRACE, $($fields)*),
+            LogLevel::Debug => event!(target: logging::ACCESS, Level::DEBUG, $($fields)*),
+            LogLevel::Info => event!(target: logging::ACCESS, Level::INFO, $($fields)*),
+            LogLevel::Warn => event!(target: logging::ACCESS, Level::WARN, $($fields)*),
+            LogLevel::Error => event!(target: logging::ACCESS, Level::ERROR, $($fields)*),
+        }
+    };
+}
 
 pub struct Xnav {
     config: &'static config::Server,
     client_addr: SocketAddr,
     server_addr: SocketAddr,
+    hooks: Hooks,
+    capturer: Option<Arc<Capturer>>,
+    requests: Option<watch::Sender<usize>>,
+    tunnels: Tunnels,
+    /// Bandwidth of this connection.
+    bandwidth: Option<Bandwidth>,
+    starved: CancellationToken,
 }
 
 impl Xnav {
@@ -36,8 +118,46 @@ impl Xnav {
             config,
             client_addr,
             server_addr,
+            hooks: Arc::new([]),
+            capturer: None,
+            requests: None,
+            tunnels: Tunnels::default(),
+            bandwidth: config.bandwidth.as_ref().map(Bandwidth::unshared),
+            starved: CancellationToken::new(),
         }
     }
+
+    /// Runs `hooks` at every stage of each request.
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Records sampled requests into `capturer`.
+    pub fn with_capturer(mut self, capturer: Option<Arc<Capturer>>) -> Self {
+        self.capturer = capturer;
+        self
+    }
+
+    /// Keeps `requests` up to date with the number of requests in flight,
+    /// which only go down once their bodies are done.
+    pub fn with_requests(mut self, requests: watch::Sender<usize>) -> Self {
+        self.requests = Some(requests);
+        self
+    }
+
+    /// Spawns upgraded connections into `tunnels`.
+    pub fn with_tunnels(mut self, tunnels: Tunnels) -> Self {
+        self.tunnels = tunnels;
+        self
+    }
+
+    /// Cancels `starved` once the client sends a request body slower than
+    /// the `min_data_rate` of the server.
+    pub fn with_starved(mut self, starved: CancellationToken) -> Self {
+        self.starved = starved;
+        self
+    }
 }
 
 impl Service<Request<Incoming>> for Xnav {
@@ -52,48 +172,533 @@ impl Service<Request<Incoming>> for Xnav {
             client_addr,
             server_addr,
             config,
+            ref hooks,
+            ref capturer,
+            ref requests,
+            ref tunnels,
+            ref bandwidth,
+            ref starved,
         } = *self;
 
+        let hooks = hooks.clone();
+        let tunnels = tunnels.clone();
+        let bandwidth = bandwidth.clone();
+        let starved = starved.clone();
+
+        let client_ip = access::client_ip(
+            client_addr.ip(),
+            request.headers(),
+            config.client_ip_header,
+            &config.trusted_proxies,
+        );
+
+        // API keys sent in the query are kept out of logs and captures.
+        let redacted = auth::redact(&config.patterns, request.uri());
+
+        let exchange = match capturer {
+            Some(capturer) if capturer.should_capture(request.headers()) => {
+                let record = Record::new(
+                    client_addr,
+                    server_addr,
+                    request.method().to_string(),
+                    redacted.to_string(),
+                    request.headers(),
+                );
+                Exchange::capturing(capturer.clone(), record)
+            }
+            _ => Exchange::default(),
+        };
+
+        if let Some(requests) = requests.clone() {
+            requests.send_modify(|requests| *requests += 1);
+            exchange.on_close(move |_| requests.send_modify(|requests| *requests -= 1));
+        }
+
         let instant = Instant::now();
 
-        Box::pin(async move {
-            let uri = request.uri().to_string();
-            let method = request.method().to_string();
+        let span = info_span!(
+            "request",
+            client = %client_addr,
+            server = %config.log_name,
+            method = %request.method(),
+            uri = %redacted,
+            status = tracing::field::Empty,
+        );
 
-            let maybe_pattern = config
-                .patterns
-                .iter()
-                .find(|pattern| uri.starts_with(pattern.uri.as_str()));
+        logging::set_parent(&span, request.headers());
 
-            let Some(pattern) = maybe_pattern else {
-                return Ok(LocalResponse::not_found());
-            };
+        let exclusions = logging::exclusions(request.uri().path(), request.headers());
 
-            let response = match &pattern.action {
-                Action::Forward(Forward { scheduler, .. }) => {
-                    let by = config.name.as_ref().map(|name| name.clone());
-                    let request = ProxyRequest::new(request, client_addr, server_addr, by);
-                    proxy::forward(request, scheduler.next_server()).await
-                }
+        // Hooks get their own copy of the request details, which is only
+        // worth making if there are hooks at all.
+        let info = (!hooks.is_empty()).then(|| RequestInfo {
+            client_addr,
+            server_addr,
+            method: request.method().clone(),
+            uri: redacted,
+            headers: request.headers().clone(),
+        });
+
+        run_hooks(&hooks, &info, |hook, info| hook.on_request(info));
+
+        Box::pin(
+            async move {
+                let mut response = async {
+                    if config.ban.as_ref().is_some_and(|ban| ban.bans(client_ip)) {
+                        debug!("Client is banned");
+                        return Ok(LocalResponse::forbidden());
+                    }
+
+                    let mut request = request;
+                    if config.strict_http {
+                        if let Some(violation) = strict::violation(&request) {
+                            debug!(violation, "Refusing ambiguous request");
+                            return Ok(LocalResponse::bad_request());
+                        }
+                        strict::normalize(request.headers_mut());
+                    }
+
+                    if let Some(refused) = rules::refuse(&config.rules, &request, client_ip) {
+                        return Ok(refused);
+                    }
+
+                    *request.uri_mut() = uri::normalize(request.uri());
+
+                    let uri = auth::redact(&config.patterns, request.uri()).to_string();
+                    let method = request.method().to_string();
+
+                    let exchange = Arc::new(exchange);
+                    let pace = config
+                        .min_data_rate
+                        .as_ref()
+                        .map(|rate| Pace::new(rate, starved.clone()));
+                    let mut request = request.map(|body| {
+                        Counted::new(body, exchange.clone(), Direction::Received).paced(pace)
+                    });
+
+                    let maybe_pattern = config
+                        .patterns
+                        .iter()
+                        .find(|pattern| uri.starts_with(pattern.uri.as_str()));
+
+                    let Some(pattern) = maybe_pattern else {
+                        return Ok(LocalResponse::not_found());
+                    };
+
+                    let refused = pattern
+                        .allowed_methods
+                        .as_ref()
+                        .filter(|allowed| !access::allows_method(allowed, request.method()));
+                    if let Some(allowed) = refused {
+                        debug!(method = %request.method(), "Method is not allowed");
+                        let allow = access::allow_header(allowed);
+                        return Ok(LocalResponse::method_not_allowed(&allow));
+                    }
+
+                    if !access::allows(&pattern.allow, &pattern.deny, client_ip) {
+                        debug!(%client_ip, "Client address is not allowed");
+                        return Ok(LocalResponse::forbidden());
+                    }
+
+                    let country = config
+                        .geoip
+                        .as_ref()
+                        .and_then(|geoip| geoip.countries.country(client_ip));
+                    let (allowed, denied) = (&pattern.allow_countries, &pattern.deny_countries);
+                    if !access::allows_country(allowed, denied, country.as_deref()) {
+                        debug!(%client_ip, country, "Client country is not allowed");
+                        return Ok(LocalResponse::forbidden());
+                    }
+                    let same_site = pattern.csrf.as_ref().is_none_or(|csrf| {
+                        access::allows_origin(csrf, request.method(), request.headers())
+                    });
+                    if !same_site {
+                        debug!("Cross-site request refused");
+                        return Ok(LocalResponse::forbidden());
+                    }
+                    let challenged = pattern.challenge.as_ref().and_then(|settings| {
+                        let https = access::is_https(
+                            client_addr.ip(),
+                            request.headers(),
+                            &config.trusted_proxies,
+                        );
+                        challenge::challenge(settings, &request, client_ip, https)
+                    });
+                    if let Some(challenged) = challenged {
+                        return Ok(challenged);
+                    }
+                    if let Some(geoip) = &config.geoip {
+                        let headers = request.headers_mut();
+                        headers.remove(geoip.header.as_str());
+                        let name = HeaderName::try_from(geoip.header.as_str());
+                        let value = country.as_deref().map(HeaderValue::from_str);
+                        if let (Ok(name), Some(Ok(value))) = (name, value) {
+                            headers.insert(name, value);
+                        }
+                    }
+
+                    let label = match auth::authenticate(pattern, &mut request, client_addr).await {
+                        Verdict::Allowed(label) => label,
+                        Verdict::Rejected(response) => return Ok(response),
+                    };
 
-                Action::Serve(directory) => {
-                    let path = if request.uri().path().starts_with("/") {
-                        &request.uri().path()[1..]
-                    } else {
-                        request.uri().path()
+                    // Only error pages need an ID for now, they show it to the
+                    // client so that the request can be found in the logs.
+                    let request_id = match &pattern.action {
+                        Action::Forward(_) if pattern.intercept_errors => {
+                            Some(errors::request_id(request.headers()))
+                        }
+                        _ => None,
                     };
-                    files::transfer(path, directory).await
+
+                    let mut backend = None;
+                    let deadline = pattern.deadline.map(|deadline| instant + deadline);
+
+                    let handled = async {
+                        // Before taking a place among the requests in flight,
+                        // which tarpitted requests would only waste.
+                        tarpit(pattern).await;
+
+                        if !admit(config, &exchange) {
+                            return Ok(LocalResponse::service_unavailable());
+                        }
+
+                        let limits = [&config.rate_limit, &pattern.rate_limit];
+                        if !limits.into_iter().flatten().all(|limit| limit.allows()) {
+                            debug!("Rate limit exceeded");
+                            return Ok(LocalResponse::too_many_requests());
+                        }
+
+                        if pattern.priority == Priority::Low && crate::server::overloaded() {
+                            debug!("Shedding low priority request");
+                            return Ok(LocalResponse::service_unavailable());
+                        }
+
+                        let declared = request.body().size_hint().lower();
+                        if pattern.max_body_size.is_some_and(|limit| declared > limit) {
+                            return Ok(LocalResponse::payload_too_large());
+                        }
+
+                        match &pattern.action {
+                            Action::Forward(Forward {
+                                scheduler,
+                                backup_scheduler,
+                                algorithm,
+                                picks,
+                                backends,
+                                ..
+                            }) => {
+                                let by = config.name.as_ref().map(|name| name.clone());
+                                let mut request =
+                                    ProxyRequest::new(request, client_addr, server_addr, by);
+                                if config.x_forwarded {
+                                    let trusted = &config.trusted_proxies;
+                                    request.add_x_forwarded(access::is_trusted(
+                                        trusted,
+                                        client_addr.ip(),
+                                    ));
+                                }
+                                if config.real_ip {
+                                    request.set_real_ip(client_ip);
+                                }
+                                if let Some(via) = &config.via {
+                                    request.add_via(via);
+                                }
+                                request.rewrite_headers(&pattern.request.headers, client_ip);
+                                // A whole cycle of the scheduler goes through
+                                // every backend, skipping those at their limit.
+                                let cycle = backends.iter().map(|backend| backend.weight).sum();
+                                let group = pattern
+                                    .split
+                                    .as_ref()
+                                    .and_then(|split| split.splitter.pick());
+                                let next = |tier, excluded: &[SocketAddr]| {
+                                    // Backups are shared by all the groups.
+                                    let (scheduler, group) = match tier {
+                                        Tier::Primary => (scheduler, group.as_deref()),
+                                        Tier::Backup => (backup_scheduler.as_ref()?, None),
+                                    };
+                                    let in_group = |server| {
+                                        backends.iter().any(|backend| {
+                                            backend.address == server
+                                                && backend.group.as_deref() == group
+                                        })
+                                    };
+                                    let (lease, weight) = (0..cycle).find_map(|_| {
+                                        let Decision { server, weight } = scheduler.schedule();
+                                        if excluded.contains(&server)
+                                            || group.is_some() && !in_group(server)
+                                        {
+                                            return None;
+                                        }
+                                        Some((picks.lease(server)?, weight))
+                                    })?;
+                                    let server = lease.server();
+                                    debug!(
+                                        backend = %server,
+                                        ?algorithm,
+                                        weight,
+                                        group,
+                                        "Scheduled backend"
+                                    );
+                                    backend = Some(server);
+                                    run_hooks(&hooks, &info, |hook, info| {
+                                        hook.on_upstream_selected(info, server)
+                                    });
+                                    Some(lease)
+                                };
+                                let exchange = exchange.clone();
+                                let via = config.via.as_ref();
+                                proxy::forward(
+                                    request, next, picks, pattern, via, exchange, &tunnels,
+                                )
+                                .await
+                            }
+
+                            Action::Serve(directory) => {
+                                let mut path = request.uri().path();
+                                if pattern.alias {
+                                    path = path.strip_prefix(pattern.uri.as_str()).unwrap_or(path);
+                                }
+                                let path = path.strip_prefix('/').unwrap_or(path);
+                                match (request.method(), &pattern.serve_writable) {
+                                    (&Method::PUT, Some(writable)) => {
+                                        let path = path.to_owned();
+                                        files::upload(request, &path, directory, pattern, writable)
+                                            .await
+                                    }
+                                    (&Method::DELETE, Some(writable)) => {
+                                        files::remove(&request, path, directory, pattern, writable)
+                                            .await
+                                    }
+                                    _ => files::transfer(&request, path, directory, pattern).await,
+                                }
+                            }
+                        }
+                    };
+
+                    // Dropping the unfinished future cancels the backend
+                    // request along with it.
+                    let response = match deadline {
+                        Some(deadline) => tokio::time::timeout_at(deadline, handled)
+                            .await
+                            .unwrap_or_else(|_| {
+                                error!("Request deadline exceeded");
+                                Ok(LocalResponse::gateway_timeout())
+                            }),
+                        None => handled.await,
+                    };
+
+                    let latency = instant.elapsed();
+                    pattern.latency.record(latency);
+
+                    let response = match (response?, request_id) {
+                        (response, Some(id)) if errors::intercepts(pattern, response.status()) => {
+                            errors::page(pattern, response.status(), response.headers(), &id).await
+                        }
+                        (response, _) => response,
+                    };
+                    let status = response.status().as_u16();
+                    Span::current().record("status", status);
+                    exchange.capture_response(status, response.headers());
+                    let cache = response.extensions().get::<Lookup>().copied();
+
+                    // The access log waits for the bodies to be fully transferred
+                    // so that it can report their size.
+                    let span = Span::current();
+                    exchange.on_close(move |exchange| {
+                        let traffic = exchange.traffic();
+                        let upstream = exchange.upstream();
+                        let connect = upstream.map(|upstream| upstream.connect);
+                        let first_byte = upstream.map(|upstream| upstream.first_byte);
+                        let total = upstream.map(|upstream| upstream.started.elapsed());
+                        // The last one scheduled unless a response came back,
+                        // hedges are scheduled even if they lose.
+                        let backend = upstream.map(|upstream| upstream.backend).or(backend);
+
+                        pattern.traffic.add_received(traffic.received());
+                        pattern.traffic.add_sent(traffic.sent());
+
+                        metrics::record(&metrics::Sample {
+                            server: config.name.as_deref().unwrap_or(&config.log_name),
+                            route: &pattern.uri,
+                            method: &method,
+                            status,
+                            latency,
+                            received: traffic.received(),
+                            sent: traffic.sent(),
+                            backend,
+                            cache,
+                        });
+
+                        if !pattern.access_log || exclusions.excludes(status) {
+                            return;
+                        }
+
+                        span.in_scope(|| {
+                            access_event!(
+                                pattern.access_log_level,
+                                client = %client_addr,
+                                server = %config.log_name,
+                                route = %pattern.uri,
+                                backend = backend.map(tracing::field::display),
+                                %method,
+                                %uri,
+                                status,
+                                api_key = label.as_deref().map(tracing::field::display),
+                                country = country.as_deref().map(tracing::field::display),
+                                ?latency,
+                                received = traffic.received(),
+                                sent = traffic.sent(),
+                                upstream_connect = connect.map(tracing::field::debug),
+                                upstream_first_byte = first_byte.map(tracing::field::debug),
+                                upstream_total = total.map(tracing::field::debug),
+                            );
+                        });
+                    });
+
+                    let span = Span::current();
+                    let bandwidths: Vec<_> = bandwidth
+                        .into_iter()
+                        .chain(pattern.bandwidth.clone())
+                        .collect();
+                    Ok(response.map(|body| {
+                        let body = Counted::new(body, exchange, Direction::Sent);
+                        let body = match deadline {
+                            Some(deadline) => Deadline::new(body, deadline, span).boxed(),
+                            None => body.boxed(),
+                        };
+                        if bandwidths.is_empty() {
+                            body
+                        } else {
+                            Throttled::new(body, bandwidths).boxed()
+                        }
+                    }))
+                }
+                .await;
+
+                if let (Some(ban), Ok(response)) = (&config.ban, &response) {
+                    ban.record(client_ip, response.status().as_u16());
                 }
-            };
 
-            if let Ok(response) = &response {
-                let status = response.status();
-                let log_name = &config.log_name;
-                let elapsed = instant.elapsed();
-                println!("{client_addr} -> {log_name} {method} {uri} HTTP {status} {elapsed:?}");
+                if let (Some(security), Ok(response)) = (&config.security_headers, &mut response) {
+                    security::secure(response, security);
+                }
+
+                match response {
+                    Ok(response) if info.is_some() => {
+                        let (parts, body) = response.into_parts();
+                        run_hooks(&hooks, &info, |hook, info| hook.on_response(info, &parts));
+                        Ok(Response::from_parts(parts, body))
+                    }
+                    Err(err) => {
+                        run_hooks(&hooks, &info, |hook, info| hook.on_error(info, &err));
+                        Err(err)
+                    }
+                    response => response,
+                }
             }
+            .instrument(span),
+        )
+    }
+}
+
+/// Holds the requests of `pattern` for its `tarpit`, if any.
+async fn tarpit(pattern: &config::Pattern) {
+    if let Some(tarpit) = pattern.tarpit {
+        debug!(?tarpit, "Holding request in the tarpit");
+        tokio::time::sleep(tarpit).await;
+    }
+}
+
+/// Takes a place among the requests in flight of `config`, given back once
+/// `exchange` closes. Fails when all `max_requests` places are taken.
+fn admit(config: &config::Server, exchange: &Exchange) -> bool {
+    let Some(max_requests) = config.max_requests else {
+        return true;
+    };
+
+    let admitted = config
+        .requests
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |requests| {
+            (requests < max_requests).then_some(requests + 1)
+        });
+    if admitted.is_err() {
+        debug!(max_requests, "Too many requests in flight");
+        return false;
+    }
+
+    let requests = config.requests.clone();
+    exchange.on_close(move |_| {
+        requests.fetch_sub(1, Ordering::AcqRel);
+    });
+
+    true
+}
+
+/// Calls `callback` with every registered hook.
+fn run_hooks(
+    hooks: &Hooks,
+    info: &Option<RequestInfo>,
+    callback: impl Fn(&dyn RequestHook, &RequestInfo),
+) {
+    if let Some(info) = info {
+        for hook in hooks.iter() {
+            callback(hook.as_ref(), info);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn caps_requests_in_flight() {
+        let config = toml::from_str::<config::Server>(
+            r#"
+                listen = ["127.0.0.1:8080"]
+                forward = "127.0.0.1:8081"
+                max_requests = 2
+            "#,
+        )
+        .unwrap();
+
+        let first = Exchange::default();
+        let second = Exchange::default();
+        assert!(admit(&config, &first));
+        assert!(admit(&config, &second));
+        assert!(!admit(&config, &Exchange::default()));
+
+        // Places are given back as requests finish.
+        drop(first);
+        let third = Exchange::default();
+        assert!(admit(&config, &third));
+        assert!(!admit(&config, &Exchange::default()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_tarpitted_requests() {
+        let pattern = |extra: &str| {
+            toml::from_str::<config::Pattern>(&format!(
+                r#"
+                    forward = "127.0.0.1:8081"
+                    {extra}
+                "#
+            ))
+            .unwrap()
+        };
+        let held = pattern(r#"tarpit = "10s""#);
+
+        let started = Instant::now();
+        tarpit(&pattern("")).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
 
-            response
-        })
+        let holding = tokio::time::timeout(Duration::from_secs(9), tarpit(&held));
+        assert!(holding.await.is_err());
+        tarpit(&held).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(19));
     }
 }
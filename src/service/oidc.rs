@@ -0,0 +1,714 @@
+//! OpenID Connect relying party logging users in with an identity provider
+//! before their requests get through, see [`Oidc`].
+//!
+//! Users without a session are redirected to the provider with the
+//! authorization code flow. Its callback is exchanged for an ID token at the
+//! token endpoint, and the claims of the token are kept in an encrypted
+//! session cookie. The ID token comes straight from the provider over TLS,
+//! which authenticates it in place of its signature as allowed by
+//! OpenID Connect Core 3.1.3.7. That's why providers and their endpoints
+//! must use `https`.
+//!
+//! [`Oidc`]: crate::config::Oidc
+
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http_body_util::{BodyExt, Limited};
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    Method, Request, StatusCode, Uri,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tracing::{debug, error, warn};
+
+use crate::{
+    config::Oidc,
+    service::{
+        body, challenge, proxy,
+        response::{BoxBodyResponse, LocalResponse},
+    },
+};
+
+/// Largest response accepted from the provider.
+const MAX_RESPONSE_SIZE: usize = 1 << 20;
+
+/// Users get this long to log in with the provider.
+const LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Endpoints of the provider, fetched from its discovery document when
+/// they are first needed and again after failures.
+#[derive(Debug, Default)]
+pub struct Provider {
+    endpoints: OnceCell<Endpoints>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Endpoints {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// Content of the session cookie.
+#[derive(Serialize, Deserialize)]
+struct Session {
+    expires: u64,
+    claims: HashMap<String, String>,
+}
+
+/// Content of the cookie kept while users log in with the provider.
+#[derive(Serialize, Deserialize)]
+struct Login {
+    expires: u64,
+    state: String,
+    nonce: String,
+    /// Where the user goes back to once logged in.
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct Tokens {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdToken {
+    iss: String,
+    aud: Audience,
+    exp: u64,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(flatten)]
+    claims: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Self::One(audience) => audience == client_id,
+            Self::Many(audiences) => audiences.iter().any(|audience| audience == client_id),
+        }
+    }
+}
+
+/// Checks that the URLs of `oidc` are absolute, and that the provider is
+/// reached over TLS.
+pub fn validate(oidc: &Oidc) -> Result<(), String> {
+    for url in [&oidc.issuer, &oidc.redirect_uri] {
+        let uri: Uri = url
+            .parse()
+            .map_err(|err| format!("invalid OIDC URL {url:?}: {err}"))?;
+        if uri.scheme().is_none() || uri.host().is_none() {
+            return Err(format!("OIDC URL {url:?} is not absolute"));
+        }
+    }
+
+    if !is_https(&oidc.issuer) {
+        return Err(format!("OIDC issuer {:?} is not https", oidc.issuer));
+    }
+
+    for name in oidc.claims.values() {
+        HeaderName::try_from(name.as_str())
+            .map_err(|_| format!("invalid OIDC claim header {name:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Lets `request` through when it belongs to a session, with the claims of
+/// the user in their headers. Otherwise responds with a redirect to the
+/// provider, or back from it once the user logged in.
+pub async fn authenticate<T>(oidc: &Oidc, request: &mut Request<T>) -> Option<BoxBodyResponse> {
+    // Claims only ever come from the session, never from the client.
+    for name in oidc.claims.values() {
+        request.headers_mut().remove(name.as_str());
+    }
+
+    let now = unix_time();
+    let session = challenge::cookie(request.headers(), &oidc.cookie)
+        .and_then(|cookie| open::<Session>(oidc, cookie))
+        .filter(|session| session.expires > now);
+
+    if let Some(session) = session {
+        for (claim, name) in &oidc.claims {
+            let header = session.claims.get(claim).and_then(|value| {
+                Some((
+                    HeaderName::try_from(name.as_str()).ok()?,
+                    HeaderValue::from_str(value).ok()?,
+                ))
+            });
+            if let Some((name, value)) = header {
+                request.headers_mut().insert(name, value);
+            }
+        }
+        return None;
+    }
+
+    let endpoints = match discover(oidc).await {
+        Some(endpoints) => endpoints,
+        None => return Some(LocalResponse::bad_gateway()),
+    };
+
+    let callback = oidc.redirect_uri.parse::<Uri>().unwrap();
+    if request.uri().path() == callback.path() {
+        return Some(callback_response(oidc, endpoints, request, now).await);
+    }
+
+    // Only navigations can go through the provider and back.
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return Some(LocalResponse::unauthorized());
+    }
+
+    Some(login(oidc, endpoints, request.uri(), now))
+}
+
+/// Redirect to the authorization endpoint of the provider.
+fn login(oidc: &Oidc, endpoints: &Endpoints, target: &Uri, now: u64) -> BoxBodyResponse {
+    let login = Login {
+        expires: now + LOGIN_TTL.as_secs(),
+        state: random(),
+        nonce: random(),
+        target: target
+            .path_and_query()
+            .map_or_else(|| String::from("/"), ToString::to_string),
+    };
+
+    let separator = if endpoints.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let location = format!(
+        "{}{separator}{}",
+        endpoints.authorization_endpoint,
+        form(&[
+            ("response_type", "code"),
+            ("client_id", &oidc.client_id),
+            ("redirect_uri", &oidc.redirect_uri),
+            ("scope", &oidc.scopes.join(" ")),
+            ("state", &login.state),
+            ("nonce", &login.nonce),
+        ])
+    );
+
+    debug!("Redirecting to OIDC provider");
+
+    LocalResponse::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location)
+        .header(
+            header::SET_COOKIE,
+            set_cookie(
+                oidc,
+                &login_cookie(oidc),
+                &seal(oidc, &login),
+                LOGIN_TTL.as_secs(),
+            ),
+        )
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(body::empty())
+        .unwrap()
+}
+
+/// Exchanges the code of the callback for an ID token and opens a session
+/// with its claims.
+async fn callback_response<T>(
+    oidc: &Oidc,
+    endpoints: &Endpoints,
+    request: &Request<T>,
+    now: u64,
+) -> BoxBodyResponse {
+    let login = challenge::cookie(request.headers(), &login_cookie(oidc))
+        .and_then(|cookie| open::<Login>(oidc, cookie))
+        .filter(|login| login.expires > now);
+    let params = query(request.uri());
+
+    let (Some(login), Some(code), Some(state)) = (login, params.get("code"), params.get("state"))
+    else {
+        warn!(
+            error = params.get("error").map(String::as_str),
+            "Invalid OIDC callback"
+        );
+        return LocalResponse::bad_request();
+    };
+    if *state != login.state {
+        warn!("OIDC callback state does not match");
+        return LocalResponse::bad_request();
+    }
+
+    let body = form(&[
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &oidc.redirect_uri),
+        ("client_id", &oidc.client_id),
+        ("client_secret", oidc.client_secret.expose()),
+    ]);
+    let Some(tokens) = fetch::<Tokens>(oidc, Method::POST, &endpoints.token_endpoint, body).await
+    else {
+        return LocalResponse::bad_gateway();
+    };
+
+    let Some(claims) = claims(oidc, &endpoints.issuer, &tokens.id_token, &login.nonce, now) else {
+        return LocalResponse::bad_gateway();
+    };
+    let session = Session {
+        expires: now + oidc.session_ttl.as_secs(),
+        claims,
+    };
+
+    LocalResponse::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, login.target)
+        .header(
+            header::SET_COOKIE,
+            set_cookie(
+                oidc,
+                &oidc.cookie,
+                &seal(oidc, &session),
+                oidc.session_ttl.as_secs(),
+            ),
+        )
+        .header(
+            header::SET_COOKIE,
+            set_cookie(oidc, &login_cookie(oidc), "", 0),
+        )
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(body::empty())
+        .unwrap()
+}
+
+/// Claims of `id_token` kept in the session, if the token was issued by
+/// `issuer` to this client for the login of `nonce` and hasn't expired.
+fn claims(
+    oidc: &Oidc,
+    issuer: &str,
+    id_token: &str,
+    nonce: &str,
+    now: u64,
+) -> Option<HashMap<String, String>> {
+    let Some(token) = decode(id_token) else {
+        error!("Invalid ID token");
+        return None;
+    };
+    let valid = token.iss == issuer
+        && token.aud.contains(&oidc.client_id)
+        && token.exp > now
+        && token.nonce.as_deref() == Some(nonce);
+    if !valid {
+        error!(issuer = token.iss, "ID token failed validation");
+        return None;
+    }
+
+    debug!(
+        subject = token.claims.get("sub").map(tracing::field::display),
+        "Logged in with OIDC"
+    );
+
+    let claims = oidc
+        .claims
+        .keys()
+        .filter_map(|claim| {
+            let value = match token.claims.get(claim)? {
+                serde_json::Value::String(value) => value.clone(),
+                serde_json::Value::Null => return None,
+                value => value.to_string(),
+            };
+            Some((claim.clone(), value))
+        })
+        .collect();
+
+    Some(claims)
+}
+
+async fn discover(oidc: &Oidc) -> Option<&Endpoints> {
+    oidc.provider
+        .endpoints
+        .get_or_try_init(|| async {
+            let url = format!(
+                "{}/.well-known/openid-configuration",
+                oidc.issuer.trim_end_matches('/')
+            );
+            let endpoints = fetch::<Endpoints>(oidc, Method::GET, &url, String::new())
+                .await
+                .ok_or(())?;
+            check_endpoints(oidc, &endpoints)
+                .inspect_err(|err| error!(url, err, "Invalid OIDC discovery document"))
+                .map_err(|_| ())?;
+            Ok::<_, ()>(endpoints)
+        })
+        .await
+        .ok()
+}
+
+/// Checks that discovered `endpoints` belong to the configured provider, as
+/// required by OpenID Connect Discovery 4.3, and are reached over TLS.
+fn check_endpoints(oidc: &Oidc, endpoints: &Endpoints) -> Result<(), String> {
+    if endpoints.issuer != oidc.issuer {
+        return Err(format!("issuer {:?} does not match", endpoints.issuer));
+    }
+
+    for url in [&endpoints.authorization_endpoint, &endpoints.token_endpoint] {
+        if !is_https(url) {
+            return Err(format!("endpoint {url:?} is not https"));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_https(url: &str) -> bool {
+    url.parse::<Uri>()
+        .is_ok_and(|uri| uri.scheme_str() == Some("https") && uri.host().is_some())
+}
+
+/// Sends a request to the provider and parses its JSON response. A
+/// non-empty `form` is sent as the body.
+async fn fetch<R: DeserializeOwned>(
+    oidc: &Oidc,
+    method: Method,
+    url: &str,
+    form: String,
+) -> Option<R> {
+    let uri: Uri = url
+        .parse()
+        .inspect_err(|err| error!(url, %err, "Invalid OIDC provider URL"))
+        .ok()?;
+    let host = uri.host()?.to_owned();
+    let tls = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(header::HOST, uri.authority()?.as_str())
+        .header(header::ACCEPT, "application/json");
+    if !form.is_empty() {
+        request = request.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+    }
+    let request = request
+        .body(body::full(form).map_err(Into::into).boxed())
+        .ok()?;
+
+    let fetched = async {
+        let mut sender = if tls {
+            proxy::connect_tls(&host, port).await?
+        } else {
+            let address = tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .ok()?
+                .next()?;
+            proxy::connect(address).await?.0
+        };
+        let response = sender
+            .send_request(request)
+            .await
+            .inspect_err(|err| error!(url, %err, "OIDC provider failed to respond"))
+            .ok()?;
+
+        let status = response.status();
+        let content = Limited::new(response.into_body(), MAX_RESPONSE_SIZE)
+            .collect()
+            .await
+            .ok()?
+            .to_bytes();
+        if !status.is_success() {
+            error!(url, %status, "OIDC provider refused request");
+            return None;
+        }
+
+        serde_json::from_slice(&content)
+            .inspect_err(|err| error!(url, %err, "Invalid OIDC provider response"))
+            .ok()
+    };
+
+    match tokio::time::timeout(oidc.timeout, fetched).await {
+        Ok(response) => response,
+        Err(_) => {
+            error!(url, timeout = ?oidc.timeout, "OIDC provider timed out");
+            None
+        }
+    }
+}
+
+/// Payload of a JWT, whose signature is not checked.
+fn decode(token: &str) -> Option<IdToken> {
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+
+    serde_json::from_slice(&payload).ok()
+}
+
+/// Encrypts `content` into a cookie value.
+fn seal<C: Serialize>(oidc: &Oidc, content: &C) -> String {
+    let nonce: [u8; 12] = random_bytes();
+    let plaintext = serde_json::to_vec(content).unwrap();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        cipher(oidc)
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .unwrap(),
+    );
+
+    URL_SAFE_NO_PAD.encode(sealed)
+}
+
+/// Decrypts a cookie value made by [`seal`].
+fn open<C: DeserializeOwned>(oidc: &Oidc, cookie: &str) -> Option<C> {
+    let sealed = URL_SAFE_NO_PAD.decode(cookie).ok()?;
+    if sealed.len() < 12 {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(12);
+    let plaintext = cipher(oidc)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .ok()?;
+
+    serde_json::from_slice(&plaintext).ok()
+}
+
+/// Cipher of the cookies, keyed by the secret of `oidc` or by one generated
+/// at startup when it has none, which logs users out on restarts.
+fn cipher(oidc: &Oidc) -> Aes256Gcm {
+    static GENERATED: OnceLock<[u8; 32]> = OnceLock::new();
+
+    let key = match &oidc.secret {
+        Some(secret) => Sha256::digest(secret.expose()).into(),
+        None => *GENERATED.get_or_init(random_bytes),
+    };
+
+    Aes256Gcm::new(&key.into())
+}
+
+fn login_cookie(oidc: &Oidc) -> String {
+    format!("{}_login", oidc.cookie)
+}
+
+/// Cookies are only sent over HTTPS when the callback is, which is the
+/// case unless testing, since xnav sits behind a TLS terminating balancer.
+fn set_cookie(oidc: &Oidc, name: &str, value: &str, max_age: u64) -> String {
+    let secure = if oidc.redirect_uri.starts_with("https:") {
+        "; Secure"
+    } else {
+        ""
+    };
+
+    format!("{name}={value}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}")
+}
+
+fn query(uri: &Uri) -> HashMap<String, String> {
+    uri.query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            Some((decode_component(name)?, decode_component(value)?))
+        })
+        .collect()
+}
+
+/// URL-encoded form of `pairs`.
+fn form(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{}={}", encode_component(name), encode_component(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn encode_component(component: &str) -> String {
+    component
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn decode_component(component: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(component.len());
+    let mut rest = component.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'%' => {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            }
+            b'+' => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+fn random() -> String {
+    URL_SAFE_NO_PAD.encode(random_bytes::<16>())
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).expect("no source of randomness");
+    bytes
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seals_sessions() {
+        let settings = |secret: &str| {
+            toml::from_str::<Oidc>(&format!(
+                r#"
+                    issuer = "https://idp.example.com"
+                    client_id = "xnav"
+                    client_secret = "secret"
+                    redirect_uri = "https://app.example.com/oauth2/callback"
+                    secret = {secret}
+                "#
+            ))
+            .unwrap()
+        };
+        let oidc = settings(r#""cookie secret""#);
+        assert!(validate(&oidc).is_ok());
+
+        let session = Session {
+            expires: 1,
+            claims: HashMap::from([(String::from("sub"), String::from("alice"))]),
+        };
+        let sealed = seal(&oidc, &session);
+        let opened: Session = open(&oidc, &sealed).unwrap();
+        assert_eq!(opened.claims["sub"], "alice");
+
+        let mut tampered = URL_SAFE_NO_PAD.decode(&sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open::<Session>(&oidc, &URL_SAFE_NO_PAD.encode(tampered)).is_none());
+
+        let file = std::env::temp_dir().join(format!("xnav-oidc-secret-{}", std::process::id()));
+        std::fs::write(&file, "cookie secret\n").unwrap();
+        let from_file = settings(&format!("{{ file = {:?} }}", file.to_str().unwrap()));
+        std::fs::remove_file(&file).unwrap();
+        assert!(open::<Session>(&from_file, &sealed).is_some());
+        assert!(open::<Session>(&settings(r#""other""#), &sealed).is_none());
+
+        let query = query(&Uri::from_static("/cb?code=a%2Fb+c&state=xyz"));
+        assert_eq!(query["code"], "a/b c");
+        assert_eq!(form(&[("scope", "openid email")]), "scope=openid%20email");
+    }
+
+    #[tokio::test]
+    async fn validates_callbacks() {
+        let oidc = toml::from_str::<Oidc>(
+            r#"
+                issuer = "https://idp.example.com"
+                client_id = "xnav"
+                client_secret = "secret"
+                redirect_uri = "https://app.example.com/oauth2/callback"
+            "#,
+        )
+        .unwrap();
+        let mut plain = toml::from_str::<Oidc>(
+            r#"
+                issuer = "http://idp.example.com"
+                client_id = "xnav"
+                client_secret = "secret"
+                redirect_uri = "https://app.example.com/oauth2/callback"
+            "#,
+        )
+        .unwrap();
+        assert!(validate(&plain).is_err());
+        plain.issuer = oidc.issuer.clone();
+        assert!(validate(&plain).is_ok());
+
+        let mut endpoints = Endpoints {
+            issuer: oidc.issuer.clone(),
+            authorization_endpoint: String::from("https://idp.example.com/authorize"),
+            token_endpoint: String::from("http://idp.example.com/token"),
+        };
+        assert!(check_endpoints(&oidc, &endpoints).is_err());
+        endpoints.token_endpoint = String::from("https://idp.example.com/token");
+        assert!(check_endpoints(&oidc, &endpoints).is_ok());
+        endpoints.issuer = String::from("https://evil.example.com");
+        assert!(check_endpoints(&oidc, &endpoints).is_err());
+
+        // Callbacks without the login of the user never reach the provider.
+        let login = Login {
+            expires: 100,
+            state: String::from("state"),
+            nonce: String::from("nonce"),
+            target: String::from("/"),
+        };
+        let cookie = format!("{}={}", login_cookie(&oidc), seal(&oidc, &login));
+        for (uri, cookie) in [
+            ("/oauth2/callback?code=c&state=state", None),
+            ("/oauth2/callback?code=c&state=other", Some(&cookie)),
+            ("/oauth2/callback?error=access_denied", Some(&cookie)),
+        ] {
+            let mut request = Request::builder().uri(uri);
+            if let Some(cookie) = cookie {
+                request = request.header(header::COOKIE, cookie);
+            }
+            let request = request.body(()).unwrap();
+            let response = callback_response(&oidc, &endpoints, &request, 10).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+
+        let token = |payload: serde_json::Value| {
+            format!(
+                "e30.{}.signature",
+                URL_SAFE_NO_PAD.encode(payload.to_string())
+            )
+        };
+        let payload = serde_json::json!({
+            "iss": "https://idp.example.com",
+            "aud": ["other", "xnav"],
+            "exp": 100,
+            "nonce": "nonce",
+            "sub": "alice",
+            "email": null,
+        });
+        let kept = claims(&oidc, &oidc.issuer, &token(payload.clone()), "nonce", 10).unwrap();
+        assert_eq!(
+            kept,
+            HashMap::from([(String::from("sub"), String::from("alice"))])
+        );
+
+        assert!(claims(&oidc, &oidc.issuer, &token(payload.clone()), "other", 10).is_none());
+        assert!(claims(&oidc, &oidc.issuer, &token(payload.clone()), "nonce", 100).is_none());
+        let mut foreign = payload;
+        foreign["aud"] = serde_json::json!("other");
+        assert!(claims(&oidc, &oidc.issuer, &token(foreign), "nonce", 10).is_none());
+    }
+}
@@ -0,0 +1,178 @@
+//! Minimum data rate of request bodies, see [`MinDataRate`].
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Wake, Waker},
+    time::Duration,
+};
+
+use tokio::time::{Instant, Sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::config::MinDataRate;
+
+/// Time spent waiting for a client along with what it sent meanwhile.
+pub struct Pace {
+    rate: &'static MinDataRate,
+    received: u64,
+    /// Time spent waiting for data, past waits only.
+    waited: Duration,
+    /// Start of the current wait, if any.
+    waiting: Option<Instant>,
+    /// When the client made the body ready before the current poll.
+    ready: Option<Instant>,
+    /// Fires when the client falls behind if it keeps sending nothing.
+    behind: Option<Pin<Box<Sleep>>>,
+    /// Cancelled once the client is too slow, which closes its connection.
+    starved: CancellationToken,
+    woken: Arc<Woken>,
+}
+
+/// Waker of the body that notes when the client made it ready, since the
+/// body may be polled much later when the backend applies backpressure.
+#[derive(Default)]
+struct Woken {
+    at: Mutex<Option<Instant>>,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        *self.at.lock().unwrap() = Some(Instant::now());
+        if let Some(waker) = &*self.waker.lock().unwrap() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+impl Pace {
+    pub fn new(rate: &'static MinDataRate, starved: CancellationToken) -> Self {
+        Self {
+            rate,
+            received: 0,
+            waited: Duration::ZERO,
+            waiting: None,
+            ready: None,
+            behind: None,
+            starved,
+            woken: Arc::default(),
+        }
+    }
+
+    /// Waker to poll the body with instead of the one of `cx`.
+    pub fn waker(&mut self, cx: &Context<'_>) -> Waker {
+        *self.woken.waker.lock().unwrap() = Some(cx.waker().clone());
+        self.ready = self.woken.at.lock().unwrap().take();
+
+        Waker::from(self.woken.clone())
+    }
+
+    /// Takes note of a poll of the body that got `bytes` of data, or
+    /// nothing if it's still pending. The body must be polled with
+    /// [`Pace::waker`].
+    pub fn track(&mut self, bytes: Option<u64>, cx: &mut Context<'_>) {
+        let Some(bytes) = bytes else {
+            let since = *self.waiting.get_or_insert_with(Instant::now);
+            let deadline = since + self.allowance();
+            let behind = self
+                .behind
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            if behind.deadline() != deadline {
+                behind.as_mut().reset(deadline);
+            }
+
+            if behind.as_mut().poll(cx).is_ready() && !self.starved.is_cancelled() {
+                warn!(
+                    received = self.received,
+                    waited = ?self.waited + since.elapsed(),
+                    "Client sends its body below the minimum data rate"
+                );
+                self.starved.cancel();
+            }
+            return;
+        };
+
+        // The wait ends once the client makes the body ready, not when it
+        // gets polled again.
+        if let Some(since) = self.waiting.take() {
+            let until = self.ready.take().unwrap_or_else(Instant::now);
+            self.waited += until.saturating_duration_since(since);
+        }
+        self.received += bytes;
+    }
+
+    /// Time the client may go on waiting in the current wait without
+    /// sending anything.
+    fn allowance(&self) -> Duration {
+        let earned =
+            Duration::from_millis(self.received * 1000 / self.rate.bytes_per_second.max(1));
+
+        earned.max(self.rate.grace).saturating_sub(self.waited)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Waker;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn starves_slow_clients() {
+        let rate = Box::leak(Box::new(MinDataRate {
+            bytes_per_second: 100,
+            grace: Duration::from_secs(2),
+        }));
+        let starved = CancellationToken::new();
+        let mut pace = Pace::new(rate, starved.clone());
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // 300 bytes buy three seconds of waiting.
+        pace.track(Some(300), &mut cx);
+        pace.track(None, &mut cx);
+        tokio::time::advance(Duration::from_millis(2900)).await;
+        pace.track(None, &mut cx);
+        assert!(!starved.is_cancelled());
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+        pace.track(None, &mut cx);
+        assert!(starved.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_backpressure() {
+        let rate = Box::leak(Box::new(MinDataRate {
+            bytes_per_second: 100,
+            grace: Duration::from_secs(2),
+        }));
+        let starved = CancellationToken::new();
+        let mut pace = Pace::new(rate, starved.clone());
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // The client is ready after a second, the body polled ten later.
+        let waker = pace.waker(&cx);
+        pace.track(None, &mut cx);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        waker.wake();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        pace.waker(&cx);
+        pace.track(Some(0), &mut cx);
+
+        pace.waker(&cx);
+        pace.track(None, &mut cx);
+        tokio::time::advance(Duration::from_millis(900)).await;
+        pace.track(None, &mut cx);
+        assert!(!starved.is_cancelled());
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+        pace.track(None, &mut cx);
+        assert!(starved.is_cancelled());
+    }
+}
@@ -1,73 +1,1155 @@
-use std::net::SocketAddr;
+use std::{
+    error::Error,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
+};
 
-use http_body_util::BodyExt;
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, LengthLimitError, Limited};
 use hyper::{
     body::{Body, Incoming},
-    client::conn::http1::Builder,
-    header,
-    upgrade::{OnUpgrade, Upgraded},
+    client::conn::http1::{Builder, SendRequest},
+    header::{self, HeaderValue},
+    upgrade::OnUpgrade,
+    HeaderMap, Method, Request, Response, StatusCode,
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::TcpStream,
+    time::Instant,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
 };
 use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, field::Empty, info_span, warn, Instrument, Span};
 
-use crate::service::{
-    request::ProxyRequest,
-    response::{BoxBodyResponse, LocalResponse, ProxyResponse},
+use crate::{
+    config::{Action, Pattern, RetryOn, Via},
+    service::{
+        body::Deadline,
+        buffer::{Buffered, Buffering},
+        compression,
+        request::ProxyRequest,
+        response::{BoxBodyResponse, LocalResponse, ProxyResponse},
+        signature, substitute,
+        traffic::{Counted, Exchange, Upstream},
+    },
+    threading::{Lease, Picks, Tier},
 };
 
+pub(super) type ProxyBody = BoxBody<Bytes, Box<dyn Error + Send + Sync>>;
+
+/// Sends `request` to a backend leased by `next`, and again to the next one
+/// when `pattern` allows retrying the failure. Once out of retries, the
+/// request fails over to a backup backend if there is one. The lease is held
+/// until the exchange closes, `next` returns `None` when all backends are
+/// full and the request has to wait in the queue of `picks`. Each attempt
+/// gets its own `proxy` span, the last one covers the hop until both bodies
+/// are done. Backends too slow to answer get a `504 Gateway Timeout` instead,
+/// unless a hedge sent to another backend answers first. Upgraded
+/// connections are handed over to `tunnels`. Responses of backends get the
+/// entry of `via`, if any.
 pub(super) async fn forward(
-    mut request: ProxyRequest<Incoming>,
+    mut request: ProxyRequest<Counted<Incoming>>,
+    mut next: impl FnMut(Tier, &[SocketAddr]) -> Option<Lease>,
+    picks: &Picks,
+    pattern: &Pattern,
+    via: Option<&Via>,
+    exchange: Arc<Exchange>,
+    tunnels: &Tunnels,
+) -> Result<BoxBodyResponse, hyper::Error> {
+    let mut maybe_client_upgrade = None;
+
+    if request.headers().contains_key(header::UPGRADE) {
+        let upgrade = request.extensions_mut().remove::<OnUpgrade>().unwrap();
+        maybe_client_upgrade = Some(upgrade);
+    }
+
+    let is_head = request.method() == Method::HEAD;
+
+    let (mut head, body) = request.into_forwarded().into_parts();
+
+    let retry_method = pattern.retries_method(head.method.as_str());
+
+    let content_length = head
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+
+    // Once sent, a streamed body is gone. Small bodies are buffered up front
+    // if the request may be retried, and requests without a body can be
+    // rebuilt from the head for every attempt.
+    let empty = body.is_end_stream();
+
+    let retried = pattern.retries() > 0 || picks.has_backups() || pattern.hedge_after.is_some();
+
+    let (buffered, trailers, mut body) = match (&pattern.request_buffering, content_length) {
+        (Some(buffering), _) => {
+            match Buffered::read(body, pattern.max_body_size, buffering).await {
+                Ok((buffered, trailers)) => (Some(buffered), trailers, None),
+                Err(Buffering::TooLarge) => return Ok(LocalResponse::payload_too_large()),
+                Err(Buffering::Body(err)) => return Err(err),
+                Err(Buffering::Io(err)) => {
+                    error!(%err, "Failed to buffer request body");
+                    return Ok(LocalResponse::internal_server_error());
+                }
+            }
+        }
+        (None, Some(length)) if retried && retry_method && length <= pattern.retry_buffer() => {
+            let collected = body.collect().await?;
+            let trailers = collected.trailers().cloned();
+            (Some(Buffered::Memory(collected.to_bytes())), trailers, None)
+        }
+        // Signatures cover the whole body, it has to arrive before sending.
+        (None, _) if pattern.signature.is_some() => {
+            match limited(body, pattern.max_body_size).collect().await {
+                Ok(collected) => {
+                    let trailers = collected.trailers().cloned();
+                    (Some(Buffered::Memory(collected.to_bytes())), trailers, None)
+                }
+                Err(err) if err.is::<LengthLimitError>() => {
+                    return Ok(LocalResponse::payload_too_large());
+                }
+                Err(err) => match err.downcast::<hyper::Error>() {
+                    Ok(err) => return Err(*err),
+                    Err(err) => {
+                        error!(%err, "Failed to read request body");
+                        return Ok(LocalResponse::bad_request());
+                    }
+                },
+            }
+        }
+        // Declared lengths were checked before routing, only chunked bodies
+        // can grow past the limit while streaming.
+        (None, None) => (None, None, Some(limited(body, pattern.max_body_size))),
+        (None, Some(_)) => (None, None, Some(body.map_err(Into::into).boxed())),
+    };
+
+    let verified = match (&pattern.signature, &buffered) {
+        (Some(settings), Some(buffered)) => signature::verify(settings, &head, buffered).await,
+        // Signed bodies are always buffered above, fail closed otherwise.
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+    if !verified {
+        return Ok(LocalResponse::unauthorized());
+    }
+
+    if let Some(buffered) = &buffered {
+        delimit(&mut head.headers, buffered, trailers.is_some());
+    }
+
+    // Time spent reading the body counts against the client, not the
+    // backend.
+    let started = Instant::now();
+    let deadline = pattern.response_timeout.map(|timeout| started + timeout);
+
+    let replayable = maybe_client_upgrade.is_none()
+        && retry_safe(pattern, retry_method, empty)
+        && (buffered.is_some() || empty);
+
+    let mut attempts = Attempts {
+        pattern,
+        picks,
+        started,
+        number: 1,
+        leased: None,
+        failed_over: false,
+        failed: Vec::new(),
+    };
+
+    loop {
+        let lease = match attempts.leased.take() {
+            Some(lease) => Some(lease),
+            None => match next(Tier::Primary, &attempts.failed) {
+                Some(lease) => Some(lease),
+                None => queue(&mut next, picks, pattern, &attempts.failed).await,
+            },
+        };
+
+        let Some(lease) = lease else {
+            if picks.all_down() {
+                warn!("All backends are down");
+                return unavailable(head, pattern, LocalResponse::service_unavailable).await;
+            }
+            warn!("All backends are at their request limit");
+            return Ok(LocalResponse::service_unavailable());
+        };
+        let to = lease.server();
+        let span = info_span!(
+            "proxy",
+            backend = %to,
+            attempt = attempts.number,
+            status = Empty,
+            received = Empty,
+            sent = Empty,
+        );
+
+        let first = attempt(to, pattern, deadline, || {
+            let body = match (&buffered, body.take()) {
+                (Some(buffered), _) => replay(buffered, trailers.as_ref()),
+                (None, Some(body)) => body,
+                (None, None) => crate::service::body::empty().map_err(Into::into).boxed(),
+            };
+            rebuild(&head, body)
+        })
+        .instrument(span.clone());
+
+        let (outcome, hedged) = match pattern.hedge_after {
+            Some(after) if replayable => {
+                let second = || {
+                    let excluded: Vec<_> = attempts.failed.iter().copied().chain([to]).collect();
+                    let lease = next(Tier::Primary, &excluded)?;
+                    let span = info_span!(
+                        "proxy",
+                        backend = %lease.server(),
+                        attempt = attempts.number,
+                        hedge = true,
+                        status = Empty,
+                        received = Empty,
+                        sent = Empty,
+                    );
+                    span.in_scope(|| debug!(?after, "Hedging request"));
+                    let body = match &buffered {
+                        Some(buffered) => replay(buffered, trailers.as_ref()),
+                        None => crate::service::body::empty().map_err(Into::into).boxed(),
+                    };
+                    let hedge = attempt(lease.server(), pattern, deadline, || rebuild(&head, body))
+                        .instrument(span.clone());
+                    Some((hedge, (lease, span)))
+                };
+                hedge(first, after, second).await
+            }
+            _ => (first.await, None),
+        };
+
+        // The attempt that lost is cancelled, releasing its backend.
+        let (lease, span) = hedged.unwrap_or((lease, span));
+        let to = lease.server();
+
+        picks.reached(to, !matches!(outcome, Err(Failure::Connect)));
+
+        let Answer {
+            mut response,
+            connect,
+            first_byte,
+        } = match outcome {
+            Ok(answer) => answer,
+            Err(Failure::Connect)
+                if span.in_scope(|| attempts.retry(RetryOn::ConnectFailure, to, &mut next)) =>
+            {
+                continue;
+            }
+            Err(Failure::Connect) if picks.all_down() => {
+                return unavailable(head, pattern, LocalResponse::bad_gateway).await;
+            }
+            Err(Failure::Connect) => return Ok(LocalResponse::bad_gateway()),
+            Err(Failure::TimedOut) => return Ok(timed_out(&span)),
+            Err(Failure::Backend(err)) if is_too_large(&err) => {
+                return Ok(LocalResponse::payload_too_large())
+            }
+            Err(Failure::Backend(err)) => return Err(err),
+        };
+
+        if let Some(pause) = pattern
+            .max_backoff
+            .and_then(|limit| Some(backoff(&response)?.min(limit)))
+        {
+            span.in_scope(|| warn!(?pause, "Backend asked to back off"));
+            picks.pause(to, pause);
+        }
+
+        let status = response.status().as_u16();
+        if replayable && span.in_scope(|| attempts.retry(RetryOn::Status(status), to, &mut next)) {
+            continue;
+        }
+
+        span.record("status", status);
+        exchange.set_span(span.clone());
+        exchange.on_close(move |_| drop(lease));
+        exchange.set_upstream(Upstream {
+            backend: to,
+            started,
+            connect,
+            first_byte,
+        });
+
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            if let Some(client_upgrade) = maybe_client_upgrade {
+                let server_upgrade = response.extensions_mut().remove::<OnUpgrade>().unwrap();
+                let tunnel_span = span.in_scope(|| {
+                    info_span!("tunnel", backend = %to, client_bytes = Empty, server_bytes = Empty)
+                });
+                tunnels.spawn(
+                    tunnel(client_upgrade, server_upgrade, exchange).instrument(tunnel_span),
+                );
+            } else {
+                return Ok(LocalResponse::bad_gateway());
+            }
+        }
+
+        // Responses to HEAD keep Content-Length from the backend but must not
+        // carry a body, regardless of what the backend sends.
+        let response = match deadline {
+            _ if is_head => response.map(|_| crate::service::body::empty()),
+            Some(deadline) => response.map(|body| {
+                Deadline::new(body, deadline, span)
+                    .map_err(Into::into)
+                    .boxed()
+            }),
+            None => response.map(|body| body.map_err(Into::into).boxed()),
+        };
+
+        let accept_encoding = head.headers.get(header::ACCEPT_ENCODING);
+        let response = match pattern.decompress {
+            true => {
+                let plaintext = pattern
+                    .response
+                    .substitute
+                    .as_ref()
+                    .is_some_and(|substitution| substitute::reads(substitution, &response));
+                compression::decompress(accept_encoding, plaintext, response)
+            }
+            false => response,
+        };
+        let response = match &pattern.response.substitute {
+            Some(substitution) => substitute::filter(substitution, response),
+            None => response,
+        };
+        let response = match &pattern.compression {
+            Some(compression) => compression::filter(compression, accept_encoding, response),
+            None => response,
+        };
+
+        let mut response = ProxyResponse::new(response);
+        if let Some(via) = via {
+            response.add_via(via);
+        }
+        response.rewrite_cookies(&pattern.response);
+        response.rewrite_headers(&pattern.response.headers);
+        return Ok(response.into_forwarded());
+    }
+}
+
+/// Response headers received from a backend.
+struct Answer {
+    response: Response<Incoming>,
+    connect: Duration,
+    first_byte: Duration,
+}
+
+/// Why an attempt got no response.
+enum Failure {
+    /// The backend could not be connected to, the request was not sent.
+    Connect,
+    TimedOut,
+    Backend(hyper::Error),
+}
+
+/// Connects to `to` and sends it the request built by `request`, waiting for
+/// the response headers until the deadlines of `pattern` pass.
+async fn attempt(
     to: SocketAddr,
+    pattern: &Pattern,
+    deadline: Option<Instant>,
+    request: impl FnOnce() -> Request<ProxyBody>,
+) -> Result<Answer, Failure> {
+    let warm = match &pattern.action {
+        Action::Forward(forward) => forward.warm.take(to),
+        Action::Serve(_) => None,
+    };
+
+    let (sender, connect) = match warm {
+        Some(sender) => (sender, Duration::ZERO),
+        None => within(deadline, connect(to))
+            .await
+            .ok_or(Failure::TimedOut)?
+            .ok_or(Failure::Connect)?,
+    };
+
+    let mut request = request();
+    if let Some(host) = upstream_host(pattern, to) {
+        request.headers_mut().insert(header::HOST, host);
+    }
+    crate::logging::inject(&Span::current(), request.headers_mut());
+
+    let first_byte_deadline = pattern
+        .first_byte_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let limit = first_byte_deadline.into_iter().chain(deadline).min();
+
+    let (response, first_byte) = within(limit, send(sender, request, connect))
+        .await
+        .ok_or(Failure::TimedOut)?
+        .map_err(Failure::Backend)?;
+
+    Ok(Answer {
+        response,
+        connect,
+        first_byte,
+    })
+}
+
+/// Waits for `first`, starting the attempt made by `second` when there's no
+/// response after `after`. Whichever responds first wins and the other one
+/// is dropped, along with the `T` of `second` if it lost.
+async fn hedge<A, B, T, R>(
+    first: A,
+    after: Duration,
+    second: impl FnOnce() -> Option<(B, T)>,
+) -> (Result<R, Failure>, Option<T>)
+where
+    A: Future<Output = Result<R, Failure>>,
+    B: Future<Output = Result<R, Failure>>,
+{
+    tokio::pin!(first);
+
+    if let Ok(outcome) = tokio::time::timeout(after, &mut first).await {
+        return (outcome, None);
+    }
+
+    let Some((second, extra)) = second() else {
+        return (first.await, None);
+    };
+    tokio::pin!(second);
+
+    tokio::select! {
+        outcome = &mut first => match outcome {
+            Ok(_) => (outcome, None),
+            Err(_) => (second.await, Some(extra)),
+        },
+        outcome = &mut second => match outcome {
+            Ok(_) => (outcome, Some(extra)),
+            Err(_) => (first.await, None),
+        },
+    }
+}
+
+/// Time during which a backend overwhelmed by requests asks not to get any
+/// more, in seconds or as a date.
+fn backoff<T>(response: &Response<T>) -> Option<Duration> {
+    let status = response.status();
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+
+    let retry_after = response.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+
+    match retry_after.trim().parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(retry_after)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok(),
+    }
+}
+
+/// Request to send to a backend, with the forwarded `head` and `body`.
+fn rebuild(head: &http::request::Parts, body: ProxyBody) -> Request<ProxyBody> {
+    let mut request = Request::new(body);
+    *request.method_mut() = head.method.clone();
+    *request.uri_mut() = head.uri.clone();
+    *request.version_mut() = head.version;
+    *request.headers_mut() = head.headers.clone();
+    *request.extensions_mut() = head.extensions.clone();
+    request
+}
+
+/// Sets how the body of a `buffered` request ends. Chunked bodies are sent
+/// with their length once it's known, unless they have trailers, which only
+/// chunked bodies carry.
+fn delimit(headers: &mut HeaderMap, buffered: &Buffered, trailers: bool) {
+    if trailers {
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+    } else {
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.insert(header::CONTENT_LENGTH, buffered.len().into());
+    }
+}
+
+/// Body of a `buffered` request, sent again with its `trailers`.
+fn replay(buffered: &Buffered, trailers: Option<&HeaderMap>) -> ProxyBody {
+    match trailers {
+        Some(trailers) => {
+            let trailers = std::future::ready(Some(Ok(trailers.clone())));
+            buffered.body().with_trailers(trailers).boxed()
+        }
+        None => buffered.body(),
+    }
+}
+
+/// `Host` sent to the backend at `to`, when `pattern` replaces the one of
+/// the client.
+fn upstream_host(pattern: &Pattern, to: SocketAddr) -> Option<HeaderValue> {
+    let host = match pattern.upstream_host.as_deref()? {
+        "$backend" => to.to_string(),
+        host => String::from(host),
+    };
+
+    // Literal hosts are refused when the config is loaded, and addresses
+    // are always valid.
+    HeaderValue::try_from(host).ok()
+}
+
+/// Boxes a request body, failing it once more than `limit` bytes went
+/// through.
+fn limited(body: Counted<Incoming>, limit: Option<u64>) -> ProxyBody {
+    match limit {
+        Some(limit) => Limited::new(body, limit.try_into().unwrap_or(usize::MAX)).boxed(),
+        None => body.map_err(Into::into).boxed(),
+    }
+}
+
+/// Tells whether sending failed because the request body went past its
+/// limit.
+fn is_too_large(err: &hyper::Error) -> bool {
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        if err.is::<LengthLimitError>() {
+            return true;
+        }
+        source = err.source();
+    }
+
+    false
+}
+
+/// Attempts made to send a request.
+struct Attempts<'a> {
+    pattern: &'a Pattern,
+    picks: &'a Picks,
+    started: Instant,
+    number: u32,
+    /// Backend leased for the next attempt.
+    leased: Option<Lease>,
+    failed_over: bool,
+    /// Backends that failed the request, which aren't tried again.
+    failed: Vec<SocketAddr>,
+}
+
+impl Attempts<'_> {
+    /// Decides whether a request that failed with `failure` on `backend` is
+    /// sent again: to another primary while retries are left, then once to
+    /// a backup. Without a lease for the next attempt, the request waits in
+    /// the queue for primaries that are only busy.
+    fn retry(
+        &mut self,
+        failure: RetryOn,
+        backend: SocketAddr,
+        next: &mut impl FnMut(Tier, &[SocketAddr]) -> Option<Lease>,
+    ) -> bool {
+        let pattern = self.pattern;
+
+        let retryable = pattern.retry_on().contains(&failure)
+            && pattern
+                .retry_budget
+                .is_none_or(|budget| self.started.elapsed() < budget);
+
+        if !retryable {
+            return false;
+        }
+
+        self.failed.push(backend);
+
+        // Primaries that all failed already leave the backups.
+        let retries_left = self.number <= pattern.retries();
+        let primary = if retries_left {
+            next(Tier::Primary, &self.failed)
+        } else {
+            None
+        };
+
+        if let Some(lease) = primary {
+            warn!(?failure, next = %lease.server(), "Retrying request on the next backend");
+            self.leased = Some(lease);
+        } else if retries_left && pattern.queue.is_some() && self.picks.busy(&self.failed) {
+            warn!(?failure, "Retrying request once a busy backend is free");
+        } else if self.failed_over {
+            return false;
+        } else {
+            let Some(lease) = next(Tier::Backup, &self.failed) else {
+                return false;
+            };
+            warn!(?failure, backup = %lease.server(), "Failing over to a backup backend");
+            self.leased = Some(lease);
+            self.failed_over = true;
+        }
+
+        self.number += 1;
+        true
+    }
+}
+
+/// Whether a request that reached a backend may be sent again: its method is
+/// retried, or it has no body, or its body was buffered in full.
+fn retry_safe(pattern: &Pattern, retry_method: bool, empty: bool) -> bool {
+    retry_method || empty || pattern.request_buffering.is_some()
+}
+
+/// Waits in the queue of the pattern until `next` leases a backend other than
+/// those `excluded`, giving up when the queue is full or the timeout passes.
+async fn queue(
+    next: &mut impl FnMut(Tier, &[SocketAddr]) -> Option<Lease>,
+    picks: &Picks,
+    pattern: &Pattern,
+    excluded: &[SocketAddr],
+) -> Option<Lease> {
+    let queue = pattern.queue.as_ref()?;
+    let _place = picks.enqueue(queue.depth)?;
+    let deadline = Instant::now() + queue.timeout;
+
+    loop {
+        // Registered before trying so that releases in between aren't lost.
+        let released = picks.released();
+        tokio::pin!(released);
+        released.as_mut().enable();
+
+        if let Some(lease) = next(Tier::Primary, excluded) {
+            return Some(lease);
+        }
+
+        tokio::time::timeout_at(deadline, released).await.ok()?;
+    }
+}
+
+/// Answer to the request with this `head` when all the backends are down,
+/// see `unavailable` in [`Pattern`]. Patterns without it get `otherwise`.
+async fn unavailable(
+    head: http::request::Parts,
+    pattern: &Pattern,
+    otherwise: fn() -> BoxBodyResponse,
 ) -> Result<BoxBodyResponse, hyper::Error> {
-    let Ok(stream) = TcpStream::connect(to).await else {
-        return Ok(LocalResponse::bad_gateway());
+    let Some(unavailable) = &pattern.unavailable else {
+        return Ok(otherwise());
+    };
+
+    let Some(directory) = &unavailable.serve else {
+        return Ok(LocalResponse::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::CONTENT_TYPE, &unavailable.content_type)
+            .body(crate::service::body::full(unavailable.body.clone()))
+            .unwrap());
+    };
+
+    let request = Request::from_parts(head, ());
+    let mut path = request.uri().path();
+    if pattern.alias {
+        path = path.strip_prefix(pattern.uri.as_str()).unwrap_or(path);
+    }
+    let path = path.strip_prefix('/').unwrap_or(path);
+
+    // The status keeps the pages out of caches and search engines.
+    let mut response = super::files::transfer(&request, path, directory, pattern).await?;
+    if response.status() == StatusCode::OK {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    Ok(response)
+}
+
+/// Runs `future` until `deadline`, returning `None` if it passes first.
+async fn within<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+fn timed_out(span: &Span) -> BoxBodyResponse {
+    span.in_scope(|| error!("Backend response timed out"));
+    span.record("status", 504);
+    LocalResponse::gateway_timeout()
+}
+
+/// Opens an HTTP connection to `to`, returning the time it took. Failures
+/// are logged here, the request has not been sent yet.
+pub(super) async fn connect(to: SocketAddr) -> Option<(SendRequest<ProxyBody>, Duration)> {
+    let started = Instant::now();
+
+    let stream = match TcpStream::connect(to).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            let connect = started.elapsed();
+            error!(%err, ?connect, "Failed to connect to backend");
+            return None;
+        }
+    };
+
+    handshake(stream, to, started).await
+}
+
+/// Opens an HTTPS connection to `host`, verifying its certificate against
+/// the roots of the web PKI. Used to talk to services outside the proxied
+/// network, such as identity providers.
+pub(super) async fn connect_tls(host: &str, port: u16) -> Option<SendRequest<ProxyBody>> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    let started = Instant::now();
+    let config = CONFIG.get_or_init(|| {
+        let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Arc::new(config)
+    });
+
+    let Ok(name) = ServerName::try_from(host.to_owned()) else {
+        error!(host, "Invalid TLS server name");
+        return None;
+    };
+
+    let stream = match TcpStream::connect((host, port)).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!(host, %err, "Failed to connect");
+            return None;
+        }
+    };
+    let to = stream.peer_addr().ok()?;
+
+    let stream = match TlsConnector::from(config.clone())
+        .connect(name, stream)
+        .await
+    {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!(host, %err, "TLS handshake failed");
+            return None;
+        }
     };
 
+    handshake(stream, to, started)
+        .await
+        .map(|(sender, _)| sender)
+}
+
+async fn handshake<S>(
+    stream: S,
+    to: SocketAddr,
+    started: Instant,
+) -> Option<(SendRequest<ProxyBody>, Duration)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     let stream = stream.compat(); // Convert into a compatible type
 
-    let (mut sender, conn) = Builder::new()
+    let (sender, conn) = match Builder::new()
         .preserve_header_case(true)
         .title_case_headers(true)
         .handshake(stream)
-        .await?;
+        .await
+    {
+        Ok(connection) => connection,
+        Err(err) => {
+            let connect = started.elapsed();
+            error!(%err, ?connect, "Handshake with backend failed");
+            return None;
+        }
+    };
 
     tokio::task::spawn(async move {
         if let Err(err) = conn.await {
-            println!("Connection failed: {:?}", err);
+            error!(backend = %to, %err, "Connection to backend failed");
         }
     });
 
-    let mut maybe_client_upgrade = None;
+    Some((sender, started.elapsed()))
+}
 
-    if request.headers().contains_key(header::UPGRADE) {
-        let upgrade = request.extensions_mut().remove::<OnUpgrade>().unwrap();
-        maybe_client_upgrade = Some(upgrade);
-    }
+/// Sends `request` and waits for the response headers, returning the time
+/// it took for them to arrive.
+async fn send(
+    mut sender: SendRequest<ProxyBody>,
+    request: Request<ProxyBody>,
+    connect: Duration,
+) -> Result<(Response<Incoming>, Duration), hyper::Error> {
+    let sent = Instant::now();
 
-    let mut response = sender.send_request(request.into_forwarded()).await?;
+    let response = sender.send_request(request).await.inspect_err(|err| {
+        let waited = sent.elapsed();
+        if !is_too_large(err) {
+            error!(%err, ?connect, ?waited, "Backend failed to respond");
+        }
+    })?;
 
-    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
-        if let Some(client_upgrade) = maybe_client_upgrade {
-            let server_upgrade = response.extensions_mut().remove::<OnUpgrade>().unwrap();
-            tokio::task::spawn(tunnel(client_upgrade, server_upgrade));
-        } else {
-            return Ok(LocalResponse::bad_gateway());
+    let first_byte = sent.elapsed();
+    debug!(?connect, ?first_byte, "Received response headers");
+
+    Ok((response, first_byte))
+}
+
+/// Upgraded connections of a server, which outlive the requests that opened
+/// them.
+#[derive(Debug, Clone, Default)]
+pub struct Tunnels {
+    tracker: TaskTracker,
+    abort: CancellationToken,
+}
+
+impl Tunnels {
+    /// Tunnels that are closed once `abort` is cancelled.
+    pub fn new(abort: CancellationToken) -> Self {
+        Self {
+            tracker: TaskTracker::new(),
+            abort,
         }
     }
 
-    Ok(ProxyResponse::new(response.map(|body| body.boxed())).into_forwarded())
+    fn spawn(&self, tunnel: impl Future<Output = ()> + Send + 'static) {
+        let abort = self.abort.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                () = tunnel => {}
+                () = abort.cancelled() => debug!("Tunnel aborted"),
+            }
+        });
+    }
+
+    /// Number of tunnels still open.
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracker.is_empty()
+    }
+
+    /// Completes once all the tunnels are closed, no more can be opened
+    /// after this is called.
+    pub async fn closed(&self) {
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
 }
 
-async fn tunnel(client: OnUpgrade, server: OnUpgrade) {
+/// Copies data between both upgraded connections. The bytes are accounted
+/// to the exchange, which is kept alive until the tunnel is closed.
+async fn tunnel(client: OnUpgrade, server: OnUpgrade, exchange: Arc<Exchange>) {
     let (mut upgraded_client, mut upgraded_server) = tokio::try_join!(client, server).unwrap();
 
     match tokio::io::copy_bidirectional(&mut upgraded_client, &mut upgraded_server).await {
         Ok((client_bytes, server_bytes)) => {
-            println!("Client wrote {client_bytes} bytes, server wrote {server_bytes} bytes")
+            exchange.traffic().add_received(client_bytes);
+            exchange.traffic().add_sent(server_bytes);
+            let span = Span::current();
+            span.record("client_bytes", client_bytes);
+            span.record("server_bytes", server_bytes);
+            debug!(client_bytes, server_bytes, "Tunnel closed")
         }
-        Err(err) => eprintln!("Tunnel error: {err}"),
+        Err(err) => error!(%err, "Tunnel error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Backend;
+
+    #[tokio::test(start_paused = true)]
+    async fn hedges_slow_attempts() {
+        let after = Duration::from_secs(1);
+        let answer = |name: &'static str, secs: u64| async move {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            Ok::<_, Failure>(name)
+        };
+        let failure = || async { Err::<&str, _>(Failure::Connect) };
+
+        let mut hedged_fast = false;
+        let (outcome, hedged) = hedge(answer("first", 0), after, || {
+            hedged_fast = true;
+            Some((failure(), ()))
+        })
+        .await;
+        assert!(!hedged_fast);
+        assert!(matches!((outcome, hedged), (Ok("first"), None)));
+
+        let (outcome, hedged) = hedge(answer("first", 3), after, || {
+            Some((answer("hedge", 1), "lease"))
+        })
+        .await;
+        assert!(matches!((outcome, hedged), (Ok("hedge"), Some("lease"))));
+
+        // The original attempt keeps its backend when it wins.
+        let (outcome, hedged) = hedge(answer("first", 2), after, || {
+            Some((answer("hedge", 5), "lease"))
+        })
+        .await;
+        assert!(matches!((outcome, hedged), (Ok("first"), None)));
+
+        let (outcome, hedged) =
+            hedge(answer("first", 3), after, || Some((failure(), "lease"))).await;
+        assert!(matches!((outcome, hedged), (Ok("first"), None)));
+
+        let (outcome, hedged) = hedge(answer("first", 3), after, || {
+            None::<(std::future::Ready<_>, ())>
+        })
+        .await;
+        assert!(matches!((outcome, hedged), (Ok("first"), None)));
+    }
+
+    #[test]
+    fn retries_on_next_backends() {
+        let backends: Vec<_> = (9000..9003)
+            .map(|port| Backend {
+                address: SocketAddr::from(([127, 0, 0, 1], port)),
+                weight: 1,
+                max_requests: None,
+                backup: false,
+                group: None,
+            })
+            .collect();
+        let picks = Arc::new(Picks::new(&backends));
+        let pattern = toml::from_str::<Pattern>(
+            r#"
+                forward = ["127.0.0.1:9000", "127.0.0.1:9001", "127.0.0.1:9002"]
+                retries = 1
+                retry_budget = "1s"
+                retry_on = ["connect-failure", "503"]
+            "#,
+        )
+        .unwrap();
+
+        let attempts = |started| Attempts {
+            pattern: &pattern,
+            picks: &picks,
+            started,
+            number: 1,
+            leased: None,
+            failed_over: false,
+            failed: Vec::new(),
+        };
+        let mut next = |tier: Tier, excluded: &[SocketAddr]| {
+            backends
+                .iter()
+                .filter(|_| tier == Tier::Primary)
+                .filter(|backend| !excluded.contains(&backend.address))
+                .find_map(|backend| picks.lease(backend.address))
+        };
+        let [first, second, _] = [0, 1, 2].map(|i| backends[i].address);
+
+        let mut retried = attempts(Instant::now());
+        assert!(retried.retry(RetryOn::Status(503), first, &mut next));
+        let lease = retried.leased.take().unwrap();
+        assert_eq!(lease.server(), second);
+        assert!(!retried.retry(RetryOn::ConnectFailure, second, &mut next));
+
+        let mut other = attempts(Instant::now());
+        assert!(!other.retry(RetryOn::Status(502), first, &mut next));
+
+        let mut late = attempts(Instant::now() - Duration::from_secs(2));
+        assert!(!late.retry(RetryOn::ConnectFailure, first, &mut next));
+    }
+
+    #[test]
+    fn retries_safe_requests() {
+        let pattern = |extra: &str| {
+            toml::from_str::<Pattern>(&format!(
+                r#"
+                    forward = ["127.0.0.1:9000"]
+                    {extra}
+                "#
+            ))
+            .unwrap()
+        };
+        let default = pattern("");
+        let post = |pattern: &Pattern| pattern.retries_method("POST");
+
+        assert!(retry_safe(&default, default.retries_method("GET"), false));
+        assert!(retry_safe(&default, post(&default), true));
+        assert!(!retry_safe(&default, post(&default), false));
+
+        let buffering = pattern("request_buffering = {}");
+        assert!(retry_safe(&buffering, post(&buffering), false));
+
+        let methods = pattern(r#"retry_methods = ["POST"]"#);
+        assert!(retry_safe(&methods, post(&methods), false));
+        assert!(!retry_safe(&methods, methods.retries_method("GET"), false));
+    }
+
+    #[test]
+    fn queues_retries_for_busy_backends() {
+        let backend = |port, backup| Backend {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            weight: 1,
+            max_requests: Some(1),
+            backup,
+            group: None,
+        };
+        let backends = [
+            backend(9000, false),
+            backend(9001, false),
+            backend(9002, true),
+        ];
+        let picks = Arc::new(Picks::new(&backends));
+        let _held = picks.lease(backends[1].address).unwrap();
+
+        let retry = |queue: &str| {
+            let pattern = toml::from_str::<Pattern>(&format!(
+                r#"
+                    forward = ["127.0.0.1:9000", "127.0.0.1:9001"]
+                    retries = 2
+                    {queue}
+                "#
+            ))
+            .unwrap();
+            let mut attempts = Attempts {
+                pattern: &pattern,
+                picks: &picks,
+                started: Instant::now(),
+                number: 1,
+                leased: None,
+                failed_over: false,
+                failed: Vec::new(),
+            };
+            let mut next = |tier: Tier, excluded: &[SocketAddr]| {
+                backends
+                    .iter()
+                    .filter(|backend| backend.backup == (tier == Tier::Backup))
+                    .filter(|backend| !excluded.contains(&backend.address))
+                    .find_map(|backend| picks.lease(backend.address))
+            };
+            let retried = attempts.retry(RetryOn::ConnectFailure, backends[0].address, &mut next);
+            (retried, attempts.leased.map(|lease| lease.server()))
+        };
+
+        // The busy primary is waited for rather than failed over.
+        assert_eq!(retry("queue = { depth = 10 }"), (true, None));
+        assert_eq!(retry(""), (true, Some(backends[2].address)));
+    }
+
+    #[test]
+    fn backs_off_after_retry_after() {
+        let response = |status: StatusCode, retry_after: &str| {
+            Response::builder()
+                .status(status)
+                .header(header::RETRY_AFTER, retry_after)
+                .body(())
+                .unwrap()
+        };
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        let earlier = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(120));
+
+        assert_eq!(
+            backoff(&response(StatusCode::SERVICE_UNAVAILABLE, " 30 ")),
+            Some(Duration::from_secs(30))
+        );
+        let date = backoff(&response(StatusCode::TOO_MANY_REQUESTS, &later)).unwrap();
+        assert!(date > Duration::from_secs(110) && date <= Duration::from_secs(120));
+        assert_eq!(
+            backoff(&response(StatusCode::TOO_MANY_REQUESTS, &earlier)),
+            None
+        );
+        assert_eq!(
+            backoff(&response(StatusCode::TOO_MANY_REQUESTS, "soon")),
+            None
+        );
+        assert_eq!(backoff(&response(StatusCode::BAD_GATEWAY, "30")), None);
+    }
+
+    #[tokio::test]
+    async fn replays_trailers() {
+        let buffered = Buffered::Memory(Bytes::from_static(b"payload"));
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+
+        // Every attempt gets the whole body and its trailers again.
+        for _ in 0..2 {
+            let collected = replay(&buffered, Some(&trailers)).collect().await.unwrap();
+            assert_eq!(collected.trailers(), Some(&trailers));
+            assert_eq!(collected.to_bytes(), "payload");
+        }
+        let collected = replay(&buffered, None).collect().await.unwrap();
+        assert_eq!(collected.trailers(), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        delimit(&mut headers, &buffered, false);
+        assert_eq!(headers[header::CONTENT_LENGTH], "7");
+        assert!(!headers.contains_key(header::TRANSFER_ENCODING));
+
+        delimit(&mut headers, &buffered, true);
+        assert_eq!(headers[header::TRANSFER_ENCODING], "chunked");
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn answers_when_unavailable() {
+        let root = std::env::temp_dir().join(format!("xnav-unavailable-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "maintenance").unwrap();
+
+        let answer = |extra: String| async move {
+            let pattern = toml::from_str::<Pattern>(&format!(
+                r#"
+                    forward = ["127.0.0.1:9000"]
+                    {extra}
+                "#
+            ))
+            .unwrap();
+            let (head, ()) = Request::get("/index.html").body(()).unwrap().into_parts();
+            let response = unavailable(head, &pattern, LocalResponse::bad_gateway)
+                .await
+                .unwrap();
+            let status = response.status();
+            let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, content_type, body)
+        };
+
+        let (status, _, _) = answer(String::new()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        let page = r#"unavailable = { body = "<h1>Back soon</h1>" }"#;
+        let (status, content_type, body) = answer(page.to_owned()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(content_type.unwrap(), "text/html");
+        assert_eq!(body, "<h1>Back soon</h1>");
+
+        let served = format!("unavailable = {{ serve = {:?} }}", root.to_str().unwrap());
+        let (status, _, body) = answer(served).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "maintenance");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn replaces_upstream_host() {
+        let pattern = |extra: &str| {
+            toml::from_str::<Pattern>(&format!(
+                r#"
+                    forward = ["127.0.0.1:9000"]
+                    {extra}
+                "#
+            ))
+        };
+        let to = SocketAddr::from(([127, 0, 0, 1], 9000));
+        let host = |extra| upstream_host(&pattern(extra).unwrap(), to);
+
+        assert_eq!(host(""), None);
+        assert_eq!(
+            host(r#"upstream_host = "$backend""#).unwrap(),
+            "127.0.0.1:9000"
+        );
+        assert_eq!(
+            host(r#"upstream_host = "internal.svc""#).unwrap(),
+            "internal.svc"
+        );
+        assert!(pattern(r#"upstream_host = "internal.svc\r\n""#).is_err());
     }
 }
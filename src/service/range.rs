@@ -0,0 +1,270 @@
+//! Byte range requests as described in RFC 9110 section 14.
+
+use std::{ops::RangeInclusive, time::SystemTime};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::header::{self, HeaderValue};
+
+use crate::service::{response::ResponseBody, BoxBodyResponse};
+
+/// Upper limit of ranges in a single request, anything above is answered
+/// with the complete representation to avoid amplification.
+const MAX_RANGES: usize = 32;
+
+/// Result of evaluating a `Range` header against a representation.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// Header missing, malformed or not worth honoring, send everything.
+    Ignore,
+    /// None of the ranges overlap the representation.
+    Unsatisfiable,
+    /// Sorted and coalesced ranges to send.
+    Satisfiable(Vec<RangeInclusive<u64>>),
+}
+
+/// Parses a `Range` header for a representation of `length` bytes.
+pub fn parse(range: Option<&HeaderValue>, length: u64) -> Ranges {
+    let Some(specs) = range
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    else {
+        return Ranges::Ignore;
+    };
+
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',').map(str::trim) {
+        let Some((start, end)) = spec.split_once('-') else {
+            return Ranges::Ignore;
+        };
+
+        let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => start..=end.min(length.saturating_sub(1)),
+            (Ok(start), Err(_)) if end.is_empty() => start..=length.saturating_sub(1),
+            (Err(_), Ok(suffix)) if start.is_empty() => {
+                if suffix == 0 {
+                    continue;
+                }
+                length.saturating_sub(suffix)..=length.saturating_sub(1)
+            }
+            _ => return Ranges::Ignore,
+        };
+
+        if *range.start() < length {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return Ranges::Ignore;
+    }
+
+    ranges.sort_by_key(|range| *range.start());
+
+    let mut coalesced: Vec<RangeInclusive<u64>> = Vec::with_capacity(ranges.len());
+
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                *last = *last.start()..=*range.end().max(last.end());
+            }
+            _ => coalesced.push(range),
+        }
+    }
+
+    Ranges::Satisfiable(coalesced)
+}
+
+/// Evaluates an `If-Range` precondition. Ranges must only be sent if the
+/// validator still matches the current representation, otherwise the client
+/// gets the complete file. Weak entity tags never match.
+pub fn is_current(if_range: Option<&HeaderValue>, tag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(if_range) = if_range.and_then(|value| value.to_str().ok()) else {
+        return if_range.is_none();
+    };
+
+    let if_range = if_range.trim();
+
+    if let Some(quoted) = if_range.strip_prefix('"') {
+        return quoted.strip_suffix('"') == Some(tag);
+    }
+
+    if if_range.starts_with("W/") {
+        return false;
+    }
+
+    match (httpdate::parse_http_date(if_range), modified) {
+        (Ok(date), Some(modified)) => {
+            httpdate::fmt_http_date(modified) == httpdate::fmt_http_date(date)
+        }
+        _ => false,
+    }
+}
+
+/// Builds a 206 response for the given ranges of `content`. A single range is
+/// sent as is, multiple ranges are sent as `multipart/byteranges`.
+pub fn partial(
+    response: http::response::Builder,
+    content: &Bytes,
+    content_type: &str,
+    ranges: &[RangeInclusive<u64>],
+) -> BoxBodyResponse {
+    let length = content.len();
+    let slice = |range: &RangeInclusive<u64>| {
+        content.slice(*range.start() as usize..=*range.end() as usize)
+    };
+
+    if let [range] = ranges {
+        let body = crate::service::body::full(slice(range));
+        return single(response, body, content_type, range, length as u64);
+    }
+
+    let response = response.status(http::StatusCode::PARTIAL_CONTENT);
+
+    let boundary = boundary();
+    let mut body = BytesMut::new();
+
+    for range in ranges {
+        body.put(
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {}-{}/{length}\r\n\r\n",
+                range.start(),
+                range.end()
+            )
+            .as_bytes(),
+        );
+        body.put(slice(range));
+    }
+
+    body.put(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    response
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={boundary}"),
+        )
+        .body(crate::service::body::full(body.freeze()))
+        .unwrap()
+}
+
+/// Builds a 206 response whose `body` is the given `range` of a
+/// representation of `length` bytes.
+pub fn single(
+    response: http::response::Builder,
+    body: ResponseBody,
+    content_type: &str,
+    range: &RangeInclusive<u64>,
+    length: u64,
+) -> BoxBodyResponse {
+    response
+        .status(http::StatusCode::PARTIAL_CONTENT)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{length}", range.start(), range.end()),
+        )
+        .body(body)
+        .unwrap()
+}
+
+/// Builds the 416 response sent when no range can be satisfied.
+pub fn unsatisfiable(response: http::response::Builder, length: usize) -> BoxBodyResponse {
+    response
+        .status(http::StatusCode::RANGE_NOT_SATISFIABLE)
+        .header(header::CONTENT_RANGE, format!("bytes */{length}"))
+        .body(crate::service::body::empty())
+        .unwrap()
+}
+
+/// Generates a multipart boundary unlikely to be found in the content.
+fn boundary() -> String {
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+
+    format!(
+        "xnav-{nanos:08x}{:08x}",
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(range: &str, length: u64) -> Ranges {
+        parse(Some(&HeaderValue::from_str(range).unwrap()), length)
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse(None, 100), Ranges::Ignore);
+        assert_eq!(
+            parse_str("bytes=0-9", 100),
+            Ranges::Satisfiable(vec![0..=9])
+        );
+        assert_eq!(
+            parse_str("bytes=90-", 100),
+            Ranges::Satisfiable(vec![90..=99])
+        );
+        assert_eq!(
+            parse_str("bytes=-10", 100),
+            Ranges::Satisfiable(vec![90..=99])
+        );
+        assert_eq!(
+            parse_str("bytes=50-500", 100),
+            Ranges::Satisfiable(vec![50..=99])
+        );
+        assert_eq!(parse_str("bytes=100-", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse_str("bytes=9-0", 100), Ranges::Ignore);
+        assert_eq!(parse_str("items=0-9", 100), Ranges::Ignore);
+    }
+
+    #[test]
+    fn if_range() {
+        let modified = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        let header = |value| HeaderValue::from_static(value);
+
+        assert!(is_current(None, "abc", Some(modified)));
+        assert!(is_current(Some(&header("\"abc\"")), "abc", None));
+        assert!(!is_current(Some(&header("\"xyz\"")), "abc", None));
+        assert!(!is_current(Some(&header("W/\"abc\"")), "abc", None));
+        assert!(is_current(
+            Some(&header("Wed, 21 Oct 2015 07:28:00 GMT")),
+            "abc",
+            Some(modified)
+        ));
+        assert!(!is_current(
+            Some(&header("Tue, 20 Oct 2015 07:28:00 GMT")),
+            "abc",
+            Some(modified)
+        ));
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(
+            parse_str("bytes=50-59, 0-9", 100),
+            Ranges::Satisfiable(vec![0..=9, 50..=59])
+        );
+        assert_eq!(
+            parse_str("bytes=0-9, 5-19, 20-29, -5", 100),
+            Ranges::Satisfiable(vec![0..=29, 95..=99])
+        );
+        assert_eq!(
+            parse_str("bytes=0-9, 200-300", 100),
+            Ranges::Satisfiable(vec![0..=9])
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Request and bandwidth ceilings shared by every client of a server or
+//! pattern, or kept for each client.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::ban;
+
+/// Clients with a bucket of their own at once. Past it, full buckets are
+/// dropped, and new clients go through unlimited while none is.
+const MAX_CLIENTS: usize = 100_000;
+
+/// Token bucket, filled up to its burst size when first used.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    bucket: Mutex<Option<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Takes a token out of a bucket refilled with `rate` tokens per second
+    /// and holding at most `burst` of them. Returns `false` if it's empty.
+    pub fn acquire(&self, rate: f64, burst: f64) -> bool {
+        self.acquire_at(Instant::now(), rate, burst)
+    }
+
+    fn acquire_at(&self, now: Instant, rate: f64, burst: f64) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        let bucket = bucket.get_or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        bucket.refill(now, rate, burst);
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Takes `amount` tokens even if the bucket holds fewer, returning how
+    /// long it takes to refill the missing ones.
+    pub fn borrow(&self, amount: f64, rate: f64, burst: f64) -> Duration {
+        self.borrow_at(Instant::now(), amount, rate, burst)
+    }
+
+    fn borrow_at(&self, now: Instant, amount: f64, rate: f64, burst: f64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let bucket = bucket.get_or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        bucket.refill(now, rate, burst);
+        bucket.tokens -= amount;
+
+        if bucket.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-bucket.tokens / rate)
+    }
+}
+
+/// Token buckets of each client, IPv6 ones per /64 as for bans.
+#[derive(Debug, Default)]
+pub struct ClientLimiters {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl ClientLimiters {
+    /// Same as [`RateLimiter::acquire`] with the bucket of `client`.
+    pub fn acquire(&self, client: IpAddr, rate: f64, burst: f64) -> bool {
+        self.acquire_at(Instant::now(), client, rate, burst)
+    }
+
+    fn acquire_at(&self, now: Instant, client: IpAddr, rate: f64, burst: f64) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let key = ban::key(client);
+
+        if !buckets.contains_key(&key) && buckets.len() >= MAX_CLIENTS {
+            // Full buckets are the same as new ones.
+            buckets.retain(|_, bucket| {
+                bucket.refill(now, rate, burst);
+                bucket.tokens < burst
+            });
+            if buckets.len() >= MAX_CLIENTS {
+                return true;
+            }
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        bucket.refill(now, rate, burst);
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn refills_at_rate() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        let allowed = (0..5)
+            .filter(|_| limiter.acquire_at(start, 2.0, 3.0))
+            .count();
+        assert_eq!(allowed, 3);
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.acquire_at(later, 2.0, 3.0));
+        assert!(!limiter.acquire_at(later, 2.0, 3.0));
+
+        // Idle time never fills the bucket past the burst size.
+        let much_later = start + Duration::from_secs(60);
+        let allowed = (0..5)
+            .filter(|_| limiter.acquire_at(much_later, 2.0, 3.0))
+            .count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[test]
+    fn borrows_ahead() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        assert_eq!(
+            limiter.borrow_at(start, 600.0, 1000.0, 1000.0),
+            Duration::ZERO
+        );
+        assert_eq!(
+            limiter.borrow_at(start, 900.0, 1000.0, 1000.0),
+            Duration::from_millis(500)
+        );
+
+        // The debt is paid back before anything else goes through.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(
+            limiter.borrow_at(later, 0.0, 1000.0, 1000.0),
+            Duration::ZERO
+        );
+        assert!(!limiter.acquire_at(later, 1000.0, 1000.0));
+    }
+
+    #[test]
+    fn limits_clients_apart() {
+        let limiters = ClientLimiters::default();
+        let start = Instant::now();
+        let (first, second) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+
+        assert!(limiters.acquire_at(start, first, 1.0, 2.0));
+        assert!(limiters.acquire_at(start, first, 1.0, 2.0));
+        assert!(!limiters.acquire_at(start, first, 1.0, 2.0));
+        assert!(limiters.acquire_at(start, second, 1.0, 2.0));
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiters.acquire_at(later, first, 1.0, 2.0));
+        assert!(!limiters.acquire_at(later, first, 1.0, 2.0));
+    }
+}
@@ -1,6 +1,16 @@
-use http::{Extensions, HeaderMap, Uri};
+use http::{header::HeaderValue, Extensions, HeaderMap, Method, Uri};
 use hyper::{header, upgrade::OnUpgrade, Request};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{
+    config::{HeaderRules, Via},
+    service::via,
+};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_REAL_IP: &str = "x-real-ip";
 
 /// Request received by this proxy from a client.
 pub struct ProxyRequest<T> {
@@ -25,6 +35,10 @@ impl<T> ProxyRequest<T> {
         }
     }
 
+    pub fn method(&self) -> &Method {
+        self.request.method()
+    }
+
     pub fn headers(&self) -> &HeaderMap {
         self.request.headers()
     }
@@ -64,6 +78,88 @@ impl<T> ProxyRequest<T> {
     pub fn uri(&self) -> &Uri {
         self.request.uri()
     }
+
+    /// Sets `X-Real-IP` to `client_ip`, the address of the client traced
+    /// through trusted proxies.
+    pub fn set_real_ip(&mut self, client_ip: IpAddr) {
+        let value = HeaderValue::from_str(&client_ip.to_string()).unwrap();
+        self.request.headers_mut().insert(X_REAL_IP, value);
+    }
+
+    /// Adds the `Via` entry of this proxy.
+    pub fn add_via(&mut self, settings: &Via) {
+        let version = self.request.version();
+        via::add(settings, self.request.headers_mut(), version);
+    }
+
+    /// Appends the client to the `X-Forwarded-For` of a `trusted_peer`, or
+    /// replaces it for others, and sets `X-Forwarded-Proto` and
+    /// `X-Forwarded-Host` unless a `trusted_peer` already sent them.
+    pub fn add_x_forwarded(&mut self, trusted_peer: bool) {
+        let headers = self.request.headers_mut();
+
+        // Hops sent by anyone else could name any address.
+        let mut hops: Vec<_> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter(|_| trusted_peer)
+            .filter_map(|value| value.to_str().ok())
+            .map(str::to_owned)
+            .collect();
+        hops.push(self.client_addr.ip().to_string());
+        if let Ok(value) = HeaderValue::from_str(&hops.join(", ")) {
+            headers.insert(X_FORWARDED_FOR, value);
+        }
+
+        if !trusted_peer || !headers.contains_key(X_FORWARDED_PROTO) {
+            // TLS is terminated in front of xnav, if at all.
+            headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("http"));
+        }
+
+        if !trusted_peer || !headers.contains_key(X_FORWARDED_HOST) {
+            match headers.get(header::HOST).cloned() {
+                Some(host) => headers.insert(X_FORWARDED_HOST, host),
+                None => headers.remove(X_FORWARDED_HOST),
+            };
+        }
+    }
+
+    /// Applies the header `rules` of a pattern, `client_ip` being the
+    /// address of the client traced through trusted proxies.
+    pub fn rewrite_headers(&mut self, rules: &HeaderRules, client_ip: IpAddr) {
+        let variables = self.variables(client_ip);
+        let expand = |template: &str| {
+            variables
+                .iter()
+                .fold(template.to_owned(), |value, (variable, replacement)| {
+                    value.replace(variable, replacement)
+                })
+        };
+
+        rules.apply(self.request.headers_mut(), expand);
+    }
+
+    /// Values of the variables of header rules for this request.
+    fn variables(&self, client_ip: IpAddr) -> [(&'static str, String); 7] {
+        let uri = self.request.uri();
+        let host = self
+            .request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| uri.authority().map(|authority| authority.as_str()))
+            .unwrap_or_default();
+
+        [
+            ("$server_addr", self.server_addr.to_string()),
+            ("$client_port", self.client_addr.port().to_string()),
+            ("$client_ip", client_ip.to_string()),
+            ("$method", self.request.method().to_string()),
+            ("$query", uri.query().unwrap_or_default().to_owned()),
+            ("$path", uri.path().to_owned()),
+            ("$host", host.to_owned()),
+        ]
+    }
 }
 
 #[cfg(test)]
@@ -82,6 +178,70 @@ mod tests {
         String::from(forwarded)
     }
 
+    #[test]
+    fn rewrites_headers() {
+        let client = "192.0.2.1:8000".parse().unwrap();
+        let proxy = "127.0.0.1:9000".parse().unwrap();
+        let rules: HeaderRules = toml::from_str(
+            r#"
+                add = { x-trace = "from $client_ip:$client_port" }
+                set = { x-original-uri = "$method $path?$query", host = "backend" }
+                remove = ["cookie"]
+            "#,
+        )
+        .unwrap();
+
+        let mut request = ProxyRequest::new(
+            Request::get("/a?b=c")
+                .header("host", "example.com")
+                .header("x-trace", "upstream")
+                .header("x-original-uri", "forged")
+                .header("cookie", "session=1")
+                .body(())
+                .unwrap(),
+            client,
+            proxy,
+            None,
+        );
+        request.rewrite_headers(&rules, "203.0.113.9".parse().unwrap());
+
+        let headers = request.headers();
+        let traces: Vec<_> = headers.get_all("x-trace").iter().collect();
+        assert_eq!(traces, ["upstream", "from 203.0.113.9:8000"]);
+        assert_eq!(headers["x-original-uri"], "GET /a?b=c");
+        assert_eq!(headers["host"], "backend");
+        assert!(!headers.contains_key("cookie"));
+    }
+
+    #[test]
+    fn x_forwarded_headers() {
+        let client = "192.0.2.1:8000".parse().unwrap();
+        let proxy = "127.0.0.1:9000".parse().unwrap();
+        let request = || {
+            Request::get("/")
+                .header("host", "example.com")
+                .header("x-forwarded-for", "203.0.113.9")
+                .header("x-forwarded-proto", "https")
+                .body(())
+                .unwrap()
+        };
+
+        let mut trusted = ProxyRequest::new(request(), client, proxy, None);
+        trusted.add_x_forwarded(true);
+        let headers = trusted.headers();
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.9, 192.0.2.1");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+
+        let mut untrusted = ProxyRequest::new(request(), client, proxy, None);
+        untrusted.add_x_forwarded(false);
+        assert_eq!(untrusted.headers()["x-forwarded-for"], "192.0.2.1");
+        assert_eq!(untrusted.headers()["x-forwarded-proto"], "http");
+
+        untrusted.set_real_ip("203.0.113.9".parse().unwrap());
+        assert_eq!(untrusted.headers()["x-real-ip"], "203.0.113.9");
+    }
+
     #[test]
     fn forwarded_request() {
         let client = "127.0.0.1:8000".parse().unwrap();
@@ -1,13 +1,24 @@
 //! Types and abstractions for HTTP responses.
 
+use std::error::Error;
+
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
 use hyper::{
     header::{self, HeaderValue},
-    Response,
+    HeaderMap, Response,
+};
+
+use crate::{
+    config::{HeaderRules, ResponseRewrite, Via},
+    service::via,
 };
 
-pub type BoxBodyResponse = Response<BoxBody<Bytes, hyper::Error>>;
+/// Body of responses. Its errors abort the connection, so that clients
+/// can tell a response was cut short.
+pub type ResponseBody = BoxBody<Bytes, Box<dyn Error + Send + Sync>>;
+
+pub type BoxBodyResponse = Response<ResponseBody>;
 
 /// Response sent back to the client at the end of the proxying process.
 pub struct ProxyResponse<T> {
@@ -19,6 +30,42 @@ impl<T> ProxyResponse<T> {
         Self { response }
     }
 
+    /// Applies the header `rules` of a pattern to the response of the
+    /// backend.
+    pub fn rewrite_headers(&mut self, rules: &HeaderRules) {
+        rules.apply(self.response.headers_mut(), str::to_owned);
+    }
+
+    /// Adds the `Via` entry of this proxy to the response of the backend.
+    pub fn add_via(&mut self, settings: &Via) {
+        let version = self.response.version();
+        via::add(settings, self.response.headers_mut(), version);
+    }
+
+    /// Rewrites the `Domain` and `Path` of the cookies set by the backend
+    /// according to the `cookie_domains` and `cookie_paths` of `rewrite`.
+    pub fn rewrite_cookies(&mut self, rewrite: &ResponseRewrite) {
+        if rewrite.cookie_domains.is_empty() && rewrite.cookie_paths.is_empty() {
+            return;
+        }
+
+        let headers = self.response.headers_mut();
+        let cookies: Vec<_> = headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|cookie| match cookie.to_str() {
+                Ok(value) => HeaderValue::from_str(&rewrite_cookie(value, rewrite))
+                    .unwrap_or_else(|_| cookie.clone()),
+                Err(_) => cookie.clone(),
+            })
+            .collect();
+
+        headers.remove(header::SET_COOKIE);
+        for cookie in cookies {
+            headers.append(header::SET_COOKIE, cookie);
+        }
+    }
+
     pub fn into_forwarded(mut self) -> Response<T> {
         self.response.headers_mut().insert(
             header::SERVER,
@@ -28,6 +75,75 @@ impl<T> ProxyResponse<T> {
     }
 }
 
+/// `Set-Cookie` value with its `Domain` and `Path` attributes rewritten.
+/// Other attributes are kept as they are.
+fn rewrite_cookie(cookie: &str, rewrite: &ResponseRewrite) -> String {
+    let mut attributes = cookie.split(';');
+    let pair = attributes.next().unwrap_or_default();
+
+    let attributes = attributes.map(|attribute| {
+        let Some((name, value)) = attribute.split_once('=') else {
+            return attribute.to_owned();
+        };
+
+        let replaced = match name.trim().to_ascii_lowercase().as_str() {
+            "domain" => {
+                let domain = value.trim();
+                let bare = domain.strip_prefix('.').unwrap_or(domain);
+                rewrite
+                    .cookie_domains
+                    .iter()
+                    .find(|(from, _)| {
+                        let from = from.strip_prefix('.').unwrap_or(from);
+                        from.eq_ignore_ascii_case(bare)
+                    })
+                    .map(|(_, to)| to.clone())
+            }
+            "path" => {
+                let path = value.trim();
+                rewrite
+                    .cookie_paths
+                    .iter()
+                    .filter(|(from, _)| within(path, from))
+                    .max_by_key(|(from, _)| from.len())
+                    .map(|(from, to)| format!("{to}{}", &path[from.len()..]))
+            }
+            _ => None,
+        };
+
+        match replaced {
+            Some(value) => format!("{name}={value}"),
+            None => attribute.to_owned(),
+        }
+    });
+
+    std::iter::once(pair.to_owned())
+        .chain(attributes)
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Tells whether `path` is `prefix` or below it, so that `/api` covers
+/// `/api/users` but not `/apiv2`.
+fn within(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Makes the `ETag` of a backend response weak, once its body is no longer
+/// byte for byte what the backend tagged.
+pub fn weaken_etag(headers: &mut HeaderMap) {
+    if let Some(etag) = headers.get(header::ETAG) {
+        let etag = etag.as_bytes();
+        if !etag.starts_with(b"W/") {
+            let weak = HeaderValue::from_bytes(&[b"W/", etag].concat()).unwrap();
+            headers.insert(header::ETAG, weak);
+        }
+    }
+}
+
 /// HTTP response originated on this server.
 pub struct LocalResponse;
 
@@ -36,6 +152,14 @@ impl LocalResponse {
         Response::builder().header(header::SERVER, xnav_server_header())
     }
 
+    pub fn bad_request() -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::BAD_REQUEST)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(crate::service::body::full("HTTP 400 BAD REQUEST"))
+            .unwrap()
+    }
+
     pub fn not_found() -> BoxBodyResponse {
         Self::builder()
             .status(http::StatusCode::NOT_FOUND)
@@ -44,6 +168,58 @@ impl LocalResponse {
             .unwrap()
     }
 
+    pub fn unauthorized() -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::UNAUTHORIZED)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .body(crate::service::body::full("HTTP 401 UNAUTHORIZED"))
+            .unwrap()
+    }
+
+    pub fn forbidden() -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(crate::service::body::full("HTTP 403 FORBIDDEN"))
+            .unwrap()
+    }
+
+    /// Response refusing a method, `allow` listing those that aren't.
+    pub fn method_not_allowed(allow: &str) -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::ALLOW, allow)
+            .body(crate::service::body::full("HTTP 405 METHOD NOT ALLOWED"))
+            .unwrap()
+    }
+
+    pub fn payload_too_large() -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::PAYLOAD_TOO_LARGE)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(crate::service::body::full("HTTP 413 PAYLOAD TOO LARGE"))
+            .unwrap()
+    }
+
+    pub fn too_many_requests() -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::TOO_MANY_REQUESTS)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::RETRY_AFTER, "1")
+            .body(crate::service::body::full("HTTP 429 TOO MANY REQUESTS"))
+            .unwrap()
+    }
+
+    pub fn internal_server_error() -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(crate::service::body::full("HTTP 500 INTERNAL SERVER ERROR"))
+            .unwrap()
+    }
+
     pub fn bad_gateway() -> BoxBodyResponse {
         Self::builder()
             .status(http::StatusCode::BAD_GATEWAY)
@@ -51,8 +227,112 @@ impl LocalResponse {
             .body(crate::service::body::full("HTTP 502 BAD GATEWAY"))
             .unwrap()
     }
+
+    pub fn service_unavailable() -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(crate::service::body::full("HTTP 503 SERVICE UNAVAILABLE"))
+            .unwrap()
+    }
+
+    pub fn gateway_timeout() -> BoxBodyResponse {
+        Self::builder()
+            .status(http::StatusCode::GATEWAY_TIMEOUT)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(crate::service::body::full("HTTP 504 GATEWAY TIMEOUT"))
+            .unwrap()
+    }
 }
 
 pub fn xnav_server_header() -> String {
     format!("xnav/{}", crate::VERSION)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_backend_headers() {
+        let rules: HeaderRules = toml::from_str(
+            r#"
+                set = { cache-control = "no-store" }
+                remove = ["x-powered-by"]
+            "#,
+        )
+        .unwrap();
+
+        let mut response = ProxyResponse::new(
+            Response::builder()
+                .header("x-powered-by", "PHP/5.6")
+                .header("cache-control", "public")
+                .body(())
+                .unwrap(),
+        );
+        response.rewrite_headers(&rules);
+        let response = response.into_forwarded();
+
+        let headers = response.headers();
+        assert!(!headers.contains_key("x-powered-by"));
+        assert_eq!(headers[header::CACHE_CONTROL], "no-store");
+        assert_eq!(headers[header::SERVER], xnav_server_header());
+    }
+
+    #[test]
+    fn rejects_server_rules() {
+        let rewrite = |headers: &str| toml::from_str::<ResponseRewrite>(headers);
+
+        assert!(rewrite(r#"headers = { set = { server = "backend" } }"#).is_err());
+        assert!(rewrite(r#"headers = { add = { Server = "backend" } }"#).is_err());
+        assert!(rewrite(r#"headers = { remove = ["server"] }"#).is_err());
+        assert!(rewrite(r#"headers = { remove = ["x-powered-by"] }"#).is_ok());
+    }
+
+    #[test]
+    fn rewrites_cookies() {
+        let rewrite: ResponseRewrite = toml::from_str(
+            r#"
+                cookie_domains = { "internal.svc" = "example.com" }
+                cookie_paths = { "/" = "/app/", "/api/" = "/app/v1/" }
+            "#,
+        )
+        .unwrap();
+
+        let mut response = ProxyResponse::new(
+            Response::builder()
+                .header("set-cookie", "id=1; Domain=.Internal.svc; Path=/; HttpOnly")
+                .header("set-cookie", "token=a=b; path=/api/users; Secure")
+                .header("set-cookie", "theme=dark; Domain=other.org")
+                .body(())
+                .unwrap(),
+        );
+        response.rewrite_cookies(&rewrite);
+
+        let cookies: Vec<_> = response
+            .response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .collect();
+        assert_eq!(
+            cookies,
+            [
+                "id=1; Domain=example.com; Path=/app/; HttpOnly",
+                "token=a=b; path=/app/v1/users; Secure",
+                "theme=dark; Domain=other.org",
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrites_cookie_paths_at_segments() {
+        let rewrite: ResponseRewrite =
+            toml::from_str(r#"cookie_paths = { "/api" = "/v1" }"#).unwrap();
+        let path = |cookie| rewrite_cookie(cookie, &rewrite);
+
+        assert_eq!(path("id=1; Path=/api"), "id=1; Path=/v1");
+        assert_eq!(path("id=1; Path=/api/users"), "id=1; Path=/v1/users");
+        assert_eq!(path("id=1; Path=/apiv2"), "id=1; Path=/apiv2");
+    }
+}
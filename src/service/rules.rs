@@ -0,0 +1,165 @@
+//! Requests refused by the rules of a server before routing, see [`Rule`].
+//!
+//! [`Rule`]: crate::config::Rule
+
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use hyper::{body::Body, Request};
+use tracing::debug;
+
+use crate::{
+    config::{Rule, RuleAction},
+    service::{
+        response::{BoxBodyResponse, LocalResponse},
+        uri,
+    },
+};
+
+/// Totals of a rule since startup.
+#[derive(Debug, Default)]
+pub struct RuleCounters {
+    matched: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl RuleCounters {
+    /// Requests matching the conditions of the rule.
+    pub fn matched(&self) -> u64 {
+        self.matched.load(Ordering::Relaxed)
+    }
+
+    /// Matching requests that got an error response because of the rule.
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+}
+
+/// Response refusing `request` of `client` according to the first rule it
+/// matches, if that rule refuses it.
+pub fn refuse<T: Body>(
+    rules: &[Rule],
+    request: &Request<T>,
+    client: IpAddr,
+) -> Option<BoxBodyResponse> {
+    if rules.is_empty() {
+        return None;
+    }
+
+    let normalized = uri::normalize(request.uri());
+    let rule = rules
+        .iter()
+        .find(|rule| matches(rule, request, normalized.path()))?;
+    rule.counters.matched.fetch_add(1, Ordering::Relaxed);
+
+    let response = match &rule.action {
+        RuleAction::Block => LocalResponse::forbidden(),
+        RuleAction::RateLimit(limit) if !limit.allows_client(&rule.clients, client) => {
+            LocalResponse::too_many_requests()
+        }
+        RuleAction::RateLimit(_) => return None,
+    };
+
+    debug!(rule = %rule.name, "Request refused by rule");
+    rule.counters.refused.fetch_add(1, Ordering::Relaxed);
+
+    Some(response)
+}
+
+/// Whether `request`, whose normalized path is `path`, meets all the
+/// conditions of `rule`.
+fn matches<T: Body>(rule: &Rule, request: &Request<T>, path: &str) -> bool {
+    let contains = |haystack: &str, needle: &str| {
+        haystack
+            .to_ascii_lowercase()
+            .contains(&needle.to_ascii_lowercase())
+    };
+    let uri = request.uri();
+
+    let path = rule
+        .path_contains
+        .as_deref()
+        .is_none_or(|needle| contains(path, needle));
+    let query = rule
+        .query_contains
+        .as_deref()
+        .is_none_or(|needle| contains(uri.query().unwrap_or_default(), needle));
+    let headers = rule.headers.iter().all(|(name, needle)| {
+        request
+            .headers()
+            .get_all(name.as_str())
+            .iter()
+            .any(|value| contains(&String::from_utf8_lossy(value.as_bytes()), needle))
+    });
+    let uri_length = rule
+        .uri_longer_than
+        .is_none_or(|limit| uri.to_string().len() > limit);
+    let body_size = rule
+        .body_larger_than
+        .is_none_or(|limit| request.body().size_hint().lower() > limit);
+
+    path && query && headers && uri_length && body_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_matching_requests() {
+        #[derive(serde::Deserialize)]
+        struct Rules {
+            rule: Vec<Rule>,
+        }
+
+        let Rules { rule: rules } = toml::from_str(
+            r#"
+                [[rule]]
+                name = "traversal"
+                path_contains = "..%2F"
+                action = "block"
+
+                [[rule]]
+                name = "scanners"
+                headers = { user-agent = "sqlmap" }
+                action.rate_limit = { requests_per_second = 1 }
+
+                [[rule]]
+                name = "long"
+                uri_longer_than = 20
+                action = "block"
+            "#,
+        )
+        .unwrap();
+
+        let request = |uri: &str, agent: &str| {
+            Request::builder()
+                .uri(uri)
+                .header("user-agent", agent)
+                .body(http_body_util::Empty::<bytes::Bytes>::new())
+                .unwrap()
+        };
+
+        let client = IpAddr::from([10, 0, 0, 1]);
+        let refused =
+            |uri: &str, agent: &str| refuse(&rules, &request(uri, agent), client).is_some();
+
+        assert!(refused("/a/..%2f..%2fetc", "curl"));
+        assert!(refused("/a/%2e%2e%2Fetc", "curl"));
+        assert!(!refused("/", "curl"));
+        assert!(!refused("/", "SQLMap/1.0"));
+        assert!(refused("/", "sqlmap/1.0"));
+        assert!(refused("/?query=0123456789abcdef", "curl"));
+
+        // Other clients keep their own limit.
+        let other = IpAddr::from([10, 0, 0, 2]);
+        assert!(refuse(&rules, &request("/", "sqlmap/1.0"), other).is_none());
+
+        let counts = |rule: &Rule| (rule.counters.matched(), rule.counters.refused());
+        assert_eq!(counts(&rules[0]), (2, 2));
+        assert_eq!(counts(&rules[1]), (3, 1));
+        assert_eq!(counts(&rules[2]), (1, 1));
+    }
+}
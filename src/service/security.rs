@@ -0,0 +1,112 @@
+//! Security headers added to the responses of a server, see
+//! [`SecurityHeaders`].
+
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    Response,
+};
+
+use crate::config::{SecurityHeaders, SecurityPreset};
+
+/// Adds the headers of `security` to `response`, leaving alone those the
+/// response already has, such as a stricter policy set by a backend.
+pub fn secure<T>(response: &mut Response<T>, security: &SecurityHeaders) {
+    for (name, value) in resolve(security) {
+        // Overrides are refused when the config is loaded.
+        let Ok(value) = HeaderValue::from_str(value) else {
+            continue;
+        };
+        if !response.headers().contains_key(&name) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+}
+
+/// Headers of the preset with the overrides applied, empty overrides
+/// leaving the header out.
+fn resolve(security: &SecurityHeaders) -> Vec<(HeaderName, &str)> {
+    let (hsts, content_type_options, frame_options, referrer_policy, csp) = match security.preset {
+        SecurityPreset::None => ("", "", "", "", ""),
+        SecurityPreset::Basic => (
+            "",
+            "nosniff",
+            "SAMEORIGIN",
+            "strict-origin-when-cross-origin",
+            "",
+        ),
+        SecurityPreset::Strict => (
+            "max-age=31536000; includeSubDomains",
+            "nosniff",
+            "DENY",
+            "no-referrer",
+            "default-src 'self'",
+        ),
+    };
+
+    let headers = [
+        (
+            header::STRICT_TRANSPORT_SECURITY,
+            &security.strict_transport_security,
+            hsts,
+        ),
+        (
+            header::X_CONTENT_TYPE_OPTIONS,
+            &security.content_type_options,
+            content_type_options,
+        ),
+        (
+            header::X_FRAME_OPTIONS,
+            &security.frame_options,
+            frame_options,
+        ),
+        (
+            header::REFERRER_POLICY,
+            &security.referrer_policy,
+            referrer_policy,
+        ),
+        (
+            header::CONTENT_SECURITY_POLICY,
+            &security.content_security_policy,
+            csp,
+        ),
+    ];
+
+    headers
+        .into_iter()
+        .map(|(name, value, preset)| (name, value.as_deref().unwrap_or(preset)))
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_preset() {
+        let security: SecurityHeaders = toml::from_str(
+            r#"
+                preset = "strict"
+                frame_options = "SAMEORIGIN"
+                content_security_policy = ""
+            "#,
+        )
+        .unwrap();
+
+        let mut response = Response::builder()
+            .header(header::REFERRER_POLICY, "origin")
+            .body(())
+            .unwrap();
+        secure(&mut response, &security);
+
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::STRICT_TRANSPORT_SECURITY],
+            "max-age=31536000; includeSubDomains"
+        );
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(headers[header::REFERRER_POLICY], "origin");
+        assert!(!headers.contains_key(header::CONTENT_SECURITY_POLICY));
+    }
+}
@@ -0,0 +1,189 @@
+//! HMAC signatures of incoming requests, such as those of webhooks, see
+//! [`Signature`].
+//!
+//! [`Signature`]: crate::config::Signature
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{digest::KeyInit, Hmac, Mac};
+use http::request::Parts;
+use http_body_util::BodyExt;
+use sha1::Sha1;
+use sha2::Sha256;
+use tracing::debug;
+
+use crate::{
+    config::{Signature, SignatureAlgorithm, SignatureEncoding},
+    service::buffer::Buffered,
+};
+
+/// Whether the request made of `head` and `body` carries a valid signature
+/// and, when required, a recent timestamp.
+pub async fn verify(signature: &Signature, head: &Parts, body: &Buffered) -> bool {
+    let Some(expected) = expected(signature, head) else {
+        debug!(header = signature.header, "Missing or malformed signature");
+        return false;
+    };
+
+    let timestamp = match &signature.timestamp_header {
+        Some(name) => {
+            let timestamp = head
+                .headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok()?.trim().parse::<u64>().ok());
+            match timestamp {
+                Some(timestamp) if is_recent(timestamp, signature.tolerance) => {
+                    timestamp.to_string()
+                }
+                _ => {
+                    debug!(header = name, "Missing or stale signature timestamp");
+                    return false;
+                }
+            }
+        }
+        None => String::new(),
+    };
+
+    let verified = match signature.algorithm {
+        SignatureAlgorithm::Sha256 => {
+            check::<Hmac<Sha256>>(signature, head, &timestamp, body, &expected).await
+        }
+        SignatureAlgorithm::Sha1 => {
+            check::<Hmac<Sha1>>(signature, head, &timestamp, body, &expected).await
+        }
+    };
+    if !verified {
+        debug!("Invalid request signature");
+    }
+
+    verified
+}
+
+/// Signature sent by the client, decoded.
+fn expected(signature: &Signature, head: &Parts) -> Option<Vec<u8>> {
+    let value = head.headers.get(signature.header.as_str())?.to_str().ok()?;
+    let value = value.trim().strip_prefix(signature.prefix.as_str())?;
+
+    match signature.encoding {
+        SignatureEncoding::Hex => decode_hex(value),
+        SignatureEncoding::Base64 => STANDARD.decode(value).ok(),
+    }
+}
+
+/// Computes the MAC of the canonical form of the request, streaming the
+/// body in place of its `{body}` placeholder.
+async fn check<M: Mac + KeyInit>(
+    signature: &Signature,
+    head: &Parts,
+    timestamp: &str,
+    body: &Buffered,
+    expected: &[u8],
+) -> bool {
+    let fill = |part: &str| {
+        part.replace("{method}", head.method.as_str())
+            .replace(
+                "{path}",
+                head.uri.path_and_query().map_or("/", |path| path.as_str()),
+            )
+            .replace("{timestamp}", timestamp)
+    };
+    let (before, after) = signature
+        .canonical
+        .split_once("{body}")
+        .unwrap_or((signature.canonical.as_str(), ""));
+
+    let mut mac = <M as KeyInit>::new_from_slice(signature.secret.expose().as_bytes()).unwrap();
+    mac.update(fill(before).as_bytes());
+
+    let mut content = body.body();
+    while let Some(frame) = content.frame().await {
+        let Ok(frame) = frame else {
+            return false;
+        };
+        if let Some(data) = frame.data_ref() {
+            mac.update(data);
+        }
+    }
+
+    mac.update(fill(after).as_bytes());
+    mac.verify_slice(expected).is_ok()
+}
+
+fn is_recent(timestamp: u64, tolerance: Duration) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    now.abs_diff(timestamp) <= tolerance.as_secs()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use hyper::Request;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn verifies_webhook_signatures() {
+        let github: Signature = toml::from_str(
+            r#"
+                secret = "It's a Secret to Everybody"
+                header = "x-hub-signature-256"
+                prefix = "sha256="
+            "#,
+        )
+        .unwrap();
+        let body = Buffered::Memory(Bytes::from_static(b"Hello, World!"));
+        let head = |name: &str, value: &str| {
+            Request::post("/hooks")
+                .header(name, value)
+                .body(())
+                .unwrap()
+                .into_parts()
+                .0
+        };
+
+        let signed = head(
+            "x-hub-signature-256",
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+        );
+        assert!(verify(&github, &signed, &body).await);
+        let forged = head(
+            "x-hub-signature-256",
+            "sha256=857107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+        );
+        assert!(!verify(&github, &forged, &body).await);
+        assert!(!verify(&github, &head("x-other", "1"), &body).await);
+
+        let timestamped: Signature = toml::from_str(
+            r#"
+                secret = "s"
+                header = "x-signature"
+                timestamp_header = "x-timestamp"
+                canonical = "v0:{timestamp}:{body}"
+            "#,
+        )
+        .unwrap();
+        let stale = Request::post("/hooks")
+            .header("x-timestamp", "1")
+            .header("x-signature", "00")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        assert!(!verify(&timestamped, &stale, &body).await);
+    }
+}
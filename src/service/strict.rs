@@ -0,0 +1,170 @@
+//! Checks of the `strict_http` mode of servers, refusing the ambiguous
+//! requests that front and back ends could frame differently, which is how
+//! requests get smuggled through proxies.
+
+use hyper::{
+    header::{self, HeaderValue},
+    HeaderMap, Request, Version,
+};
+
+/// Headers that only concern the connection they were sent on. `Trailer`
+/// isn't one of them, it announces the trailers that go all the way.
+const HOP_BY_HOP: [&str; 4] = [
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "proxy-authorization",
+];
+
+/// Headers that framing or backends depend on, which clients can't get rid
+/// of by listing them in `Connection`.
+const END_TO_END: [&str; 7] = [
+    "content-length",
+    "transfer-encoding",
+    "trailer",
+    "host",
+    "authorization",
+    "cookie",
+    "content-type",
+];
+
+/// Reason to refuse `request`, if it's ambiguous. Obsolete line folding
+/// never gets this far, the parser always refuses it.
+pub fn violation<T>(request: &Request<T>) -> Option<&'static str> {
+    let headers = request.headers();
+    let lengths = headers.get_all(header::CONTENT_LENGTH).iter().count();
+    let encodings: Vec<_> = headers.get_all(header::TRANSFER_ENCODING).iter().collect();
+
+    if lengths > 0 && !encodings.is_empty() {
+        return Some("both Content-Length and Transfer-Encoding");
+    }
+    if lengths > 1 {
+        return Some("repeated Content-Length");
+    }
+    if headers.get_all(header::HOST).iter().count() > 1 {
+        return Some("repeated Host");
+    }
+    match encodings.as_slice() {
+        [] => {}
+        [_] if request.version() < Version::HTTP_11 => {
+            return Some("Transfer-Encoding in HTTP/1.0");
+        }
+        [encoding] if encoding.as_bytes().eq_ignore_ascii_case(b"chunked") => {}
+        _ => return Some("Transfer-Encoding other than chunked"),
+    }
+
+    // Visible ASCII, spaces and tabs only, no obs-text.
+    let invalid = headers.values().any(|value| {
+        value
+            .as_bytes()
+            .iter()
+            .any(|&byte| byte != b'\t' && !(b' '..=b'~').contains(&byte))
+    });
+    if invalid {
+        return Some("invalid characters in header value");
+    }
+
+    None
+}
+
+/// Removes the hop-by-hop headers of the client connection, along with the
+/// headers it lists in `Connection` other than end-to-end ones, so that
+/// they don't reach backends.
+/// Upgrades keep their `Connection` and `Upgrade` headers, and clients
+/// accepting trailers still get them from backends through `TE: trailers`.
+pub fn normalize(headers: &mut HeaderMap) {
+    let accepts_trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let coding = coding.split(';').next().unwrap_or_default();
+            coding.trim().eq_ignore_ascii_case("trailers")
+        });
+
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty() && name != "upgrade")
+        .filter(|name| !END_TO_END.contains(&name.as_str()))
+        .collect();
+
+    for name in HOP_BY_HOP
+        .iter()
+        .copied()
+        .chain(listed.iter().map(String::as_str))
+    {
+        headers.remove(name);
+    }
+
+    if !headers.contains_key(header::UPGRADE) {
+        headers.remove(header::CONNECTION);
+    }
+
+    if accepts_trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_ambiguous_framing() {
+        let request = |headers: &[(&str, &[u8])]| {
+            let mut request = Request::builder();
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            request.body(()).unwrap()
+        };
+
+        let conflicting = request(&[("content-length", b"5"), ("transfer-encoding", b"chunked")]);
+        assert!(violation(&conflicting).is_some());
+        let repeated = request(&[("content-length", b"5"), ("content-length", b"5")]);
+        assert!(violation(&repeated).is_some());
+        let unknown = request(&[("transfer-encoding", b"gzip, chunked")]);
+        assert!(violation(&unknown).is_some());
+        let obs_text = request(&[("x-name", b"caf\xe9")]);
+        assert!(violation(&obs_text).is_some());
+
+        let chunked = request(&[("transfer-encoding", b"Chunked"), ("x-name", b"a\tb")]);
+        assert_eq!(violation(&chunked), None);
+
+        let mut headers = request(&[
+            (
+                "connection",
+                b"keep-alive, x-secret, Content-Length, authorization",
+            ),
+            ("keep-alive", b"timeout=5"),
+            ("x-secret", b"1"),
+            ("x-kept", b"1"),
+            ("content-length", b"5"),
+            ("authorization", b"Bearer token"),
+        ])
+        .into_parts()
+        .0
+        .headers;
+        normalize(&mut headers);
+        let mut names: Vec<_> = headers.keys().map(|name| name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["authorization", "content-length", "x-kept"]);
+
+        let mut headers = request(&[
+            ("connection", b"te"),
+            ("te", b"gzip, trailers"),
+            ("trailer", b"x-checksum"),
+        ])
+        .into_parts()
+        .0
+        .headers;
+        normalize(&mut headers);
+        assert_eq!(headers["te"], "trailers");
+        assert_eq!(headers["trailer"], "x-checksum");
+    }
+}
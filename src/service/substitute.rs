@@ -0,0 +1,228 @@
+//! Find and replace in the bodies of backend responses, see
+//! [`Substitution`].
+//!
+//! [`Substitution`]: crate::config::Substitution
+
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    header, HeaderMap, StatusCode,
+};
+
+use crate::{
+    config::Substitution,
+    service::{
+        compression,
+        response::{self, BoxBodyResponse},
+    },
+};
+
+/// `response` with its body going through the replacements of
+/// `substitution`, unless its content type is left alone or its body is
+/// encoded or partial.
+pub fn filter(substitution: &Substitution, response: BoxBodyResponse) -> BoxBodyResponse {
+    if !applies(substitution, &response) {
+        return response;
+    }
+
+    let (mut head, body) = response.into_parts();
+    head.headers.remove(header::CONTENT_LENGTH);
+    response::weaken_etag(&mut head.headers);
+
+    let body = Substituted::new(body, substitution).boxed();
+
+    BoxBodyResponse::from_parts(head, body)
+}
+
+fn applies(substitution: &Substitution, response: &BoxBodyResponse) -> bool {
+    let identity = response
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .is_none_or(|encoding| encoding.as_bytes().eq_ignore_ascii_case(b"identity"));
+
+    identity && reads(substitution, response)
+}
+
+/// Whether `substitution` rewrites the body of `response` once it's
+/// decompressed.
+pub fn reads(substitution: &Substitution, response: &BoxBodyResponse) -> bool {
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+
+    response.status() != StatusCode::PARTIAL_CONTENT
+        && compression::matches_mime(content_type, &substitution.mime_types)
+}
+
+/// Body with the strings of a [`Substitution`] replaced as data goes
+/// through. The end of each frame that could be the start of a match is
+/// held back until the next one arrives.
+pub struct Substituted<B> {
+    inner: B,
+    /// Strings to find and their replacements, longest first, so that the
+    /// longest of the matches starting at the same position wins.
+    replacements: Vec<(Bytes, Bytes)>,
+    longest: usize,
+    pending: Vec<u8>,
+    trailers: Option<HeaderMap>,
+    done: bool,
+}
+
+impl<B> Substituted<B> {
+    pub fn new(inner: B, substitution: &Substitution) -> Self {
+        let mut replacements: Vec<_> = substitution
+            .replace
+            .iter()
+            .filter(|(find, _)| !find.is_empty())
+            .map(|(find, replacement)| {
+                (
+                    Bytes::copy_from_slice(find.as_bytes()),
+                    Bytes::copy_from_slice(replacement.as_bytes()),
+                )
+            })
+            .collect();
+        replacements.sort_by_key(|(find, _)| std::cmp::Reverse(find.len()));
+        let longest = replacements.first().map_or(0, |(find, _)| find.len());
+
+        Self {
+            inner,
+            replacements,
+            longest,
+            pending: Vec::new(),
+            trailers: None,
+            done: false,
+        }
+    }
+
+    /// Replaces the matches in the pending data followed by `data`,
+    /// returning what can be sent. Everything is sent once `last`.
+    fn feed(&mut self, data: &[u8], last: bool) -> Bytes {
+        self.pending.extend_from_slice(data);
+
+        let limit = match last {
+            true => self.pending.len(),
+            false => self
+                .pending
+                .len()
+                .saturating_sub(self.longest.saturating_sub(1)),
+        };
+
+        let mut output = Vec::with_capacity(self.pending.len());
+        let mut at = 0;
+        while let Some((position, (find, replacement))) = self.find(at, limit) {
+            output.extend_from_slice(&self.pending[at..position]);
+            output.extend_from_slice(replacement);
+            at = position + find.len();
+        }
+
+        let end = limit.max(at);
+        output.extend_from_slice(&self.pending[at..end]);
+        self.pending.drain(..end);
+
+        Bytes::from(output)
+    }
+
+    /// First match starting between `from` and `limit`. Matches starting
+    /// before `limit` are complete, unless the pending data is shorter than
+    /// the longest string.
+    fn find(&self, from: usize, limit: usize) -> Option<(usize, &(Bytes, Bytes))> {
+        (from..limit).find_map(|position| {
+            let rest = &self.pending[position..];
+            self.replacements
+                .iter()
+                .find(|(find, _)| rest.starts_with(find))
+                .map(|replacement| (position, replacement))
+        })
+    }
+}
+
+impl<B: Body<Data = Bytes> + Unpin> Body for Substituted<B> {
+    type Data = Bytes;
+
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            if let Some(trailers) = self.trailers.take() {
+                return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+            }
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            let data = match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => self.feed(&data, false),
+                    Err(frame) => {
+                        self.done = true;
+                        self.trailers = frame.into_trailers().ok();
+                        self.feed(&[], true)
+                    }
+                },
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => {
+                    self.done = true;
+                    self.feed(&[], true)
+                }
+            };
+
+            if !data.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(data))));
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        let drained = self.pending.is_empty() && self.inner.is_end_stream();
+        self.trailers.is_none() && (self.done || drained)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_across_frames() {
+        let substitution: Substitution = toml::from_str(
+            r#"
+                replace = { "http://internal.svc" = "https://example.com", "http://internal" = "-" }
+            "#,
+        )
+        .unwrap();
+        let mut body = Substituted::new(crate::service::body::empty(), &substitution);
+
+        let frames = [
+            "<a href=\"http://inter",
+            "nal.svc/a\">",
+            "http://internal.",
+            "svc",
+            " http://internal",
+        ];
+        let mut content: Vec<u8> = frames
+            .iter()
+            .flat_map(|data| body.feed(data.as_bytes(), false))
+            .collect();
+        content.extend(body.feed(&[], true));
+
+        assert_eq!(
+            String::from_utf8(content).unwrap(),
+            "<a href=\"https://example.com/a\">https://example.com -"
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Accounting of the body bytes received from clients and sent back to them,
+//! along with the time spent waiting on backends.
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Buf;
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    HeaderMap,
+};
+use tokio::time::Instant;
+use tracing::Span;
+
+use super::capture::{Capturer, Record};
+use super::pace::Pace;
+
+/// Byte counters, either for a single request or accumulated over all the
+/// requests matched by a pattern.
+#[derive(Default, Debug)]
+pub struct Traffic {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Traffic {
+    /// Bytes received from the client.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent to the client.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn add_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Timings of a request forwarded to a backend.
+#[derive(Debug, Clone, Copy)]
+pub struct Upstream {
+    /// Backend whose response was sent, that of the hedge if it won.
+    pub backend: SocketAddr,
+    /// When the connection to the backend was started.
+    pub started: Instant,
+    /// Time to establish the connection, including the HTTP handshake.
+    pub connect: Duration,
+    /// Time from sending the request until the response headers arrived.
+    pub first_byte: Duration,
+}
+
+type OnClose = Box<dyn FnOnce(&Exchange) + Send>;
+
+/// Request being recorded for debugging, stored when the exchange closes.
+struct Capturing {
+    capturer: Arc<Capturer>,
+    record: Mutex<Record>,
+}
+
+/// Traffic of a single request. The callbacks given to [`Exchange::on_close`]
+/// run once the bodies and any upgraded connection are done with it, which
+/// is when the totals are known.
+#[derive(Default)]
+pub struct Exchange {
+    traffic: Traffic,
+    upstream: Mutex<Option<Upstream>>,
+    on_close: Mutex<Vec<OnClose>>,
+    capture: Option<Capturing>,
+    span: Mutex<Option<Span>>,
+}
+
+impl Exchange {
+    /// Creates an exchange whose bodies are also copied into `record`.
+    pub fn capturing(capturer: Arc<Capturer>, record: Record) -> Self {
+        Self {
+            traffic: Traffic::default(),
+            upstream: Mutex::default(),
+            on_close: Mutex::default(),
+            span: Mutex::default(),
+            capture: Some(Capturing {
+                capturer,
+                record: Mutex::new(record),
+            }),
+        }
+    }
+
+    /// Keeps `span` open until the exchange is closed and records the body
+    /// bytes on it, so that it covers the whole transfer.
+    pub fn set_span(&self, span: Span) {
+        *self.span.lock().unwrap() = Some(span);
+    }
+
+    /// Adds the response head to the captured record, if any.
+    pub fn capture_response(&self, status: u16, headers: &HeaderMap) {
+        if let Some(capture) = &self.capture {
+            capture.record.lock().unwrap().set_response(status, headers);
+        }
+    }
+
+    fn capture_body(&self, direction: Direction, data: &[u8]) {
+        if let Some(capture) = &self.capture {
+            let limit = capture.capturer.body_bytes();
+            let response = matches!(direction, Direction::Sent);
+            capture
+                .record
+                .lock()
+                .unwrap()
+                .append_body(response, data, limit);
+        }
+    }
+
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Backend timings, if the request was forwarded.
+    pub fn upstream(&self) -> Option<Upstream> {
+        *self.upstream.lock().unwrap()
+    }
+
+    pub fn set_upstream(&self, upstream: Upstream) {
+        *self.upstream.lock().unwrap() = Some(upstream);
+    }
+
+    /// Adds a function called with the final counters, after the ones
+    /// added before it.
+    pub fn on_close(&self, callback: impl FnOnce(&Exchange) + Send + 'static) {
+        self.on_close.lock().unwrap().push(Box::new(callback));
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        for callback in std::mem::take(self.on_close.get_mut().unwrap()) {
+            callback(self);
+        }
+
+        if let Some(span) = self.span.get_mut().unwrap().take() {
+            span.record("received", self.traffic.received());
+            span.record("sent", self.traffic.sent());
+        }
+
+        if let Some(Capturing { capturer, record }) = self.capture.take() {
+            capturer.push(record.into_inner().unwrap());
+        }
+    }
+}
+
+/// Which counter of the [`Exchange`] a [`Counted`] body adds to.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// Body wrapper that counts the bytes of every data frame going through.
+pub struct Counted<B> {
+    inner: B,
+    exchange: Arc<Exchange>,
+    direction: Direction,
+    pace: Option<Pace>,
+}
+
+impl<B> Counted<B> {
+    pub fn new(inner: B, exchange: Arc<Exchange>, direction: Direction) -> Self {
+        Self {
+            inner,
+            exchange,
+            direction,
+            pace: None,
+        }
+    }
+
+    /// Holds the peer to a minimum data rate.
+    pub fn paced(mut self, pace: Option<Pace>) -> Self {
+        self.pace = pace;
+        self
+    }
+}
+
+impl<B: Body + Unpin> Body for Counted<B> {
+    type Data = B::Data;
+
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let poll = match &mut this.pace {
+            Some(pace) => {
+                let waker = pace.waker(cx);
+                Pin::new(&mut this.inner).poll_frame(&mut Context::from_waker(&waker))
+            }
+            None => Pin::new(&mut this.inner).poll_frame(cx),
+        };
+
+        let data = match &poll {
+            Poll::Ready(Some(Ok(frame))) => frame.data_ref(),
+            _ => None,
+        };
+
+        if let Some(pace) = &mut self.pace {
+            match &poll {
+                Poll::Pending => pace.track(None, cx),
+                _ => pace.track(Some(data.map_or(0, |data| data.remaining() as u64)), cx),
+            }
+        }
+
+        if let Some(data) = data {
+            let bytes = data.remaining() as u64;
+            let traffic = self.exchange.traffic();
+            match self.direction {
+                Direction::Received => traffic.add_received(bytes),
+                Direction::Sent => traffic.add_sent(bytes),
+            }
+            self.exchange.capture_body(self.direction, data.chunk());
+        }
+
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_until_closed() {
+        let totals = Arc::new(Traffic::default());
+        let exchange = Arc::new(Exchange::default());
+
+        let callback_totals = totals.clone();
+        exchange.on_close(move |exchange| {
+            callback_totals.add_received(exchange.traffic().received());
+            callback_totals.add_sent(exchange.traffic().sent());
+        });
+
+        let request = Counted::new(
+            Full::new(Bytes::from("hello")),
+            exchange.clone(),
+            Direction::Received,
+        );
+        let response = Counted::new(
+            Full::new(Bytes::from("hello world")),
+            exchange,
+            Direction::Sent,
+        );
+
+        request.collect().await.unwrap();
+        assert_eq!(totals.received(), 0);
+
+        response.collect().await.unwrap();
+        assert_eq!((totals.received(), totals.sent()), (5, 11));
+    }
+}
@@ -0,0 +1,124 @@
+//! Normal form of request paths, so that routing by prefix can't be
+//! bypassed with paths like `/./api/../admin` or `//admin`.
+
+use hyper::Uri;
+
+/// `uri` with its path normalized as described in RFC 3986 section 6.2.2.
+/// Unreserved characters are decoded while other percent-encodings are
+/// kept in uppercase, repeated slashes are merged and dot-segments are
+/// removed. Encoded slashes stay encoded, they are not path separators.
+pub fn normalize(uri: &Uri) -> Uri {
+    let path = uri.path();
+    // `*` of `OPTIONS *` has nothing to normalize.
+    if !path.starts_with('/') {
+        return uri.clone();
+    }
+
+    let normalized = remove_dot_segments(&merge_slashes(&decode_unreserved(path)));
+    if normalized == path {
+        return uri.clone();
+    }
+
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{normalized}?{query}"),
+        None => normalized,
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+
+    Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+}
+
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut at = 0;
+
+    while at < bytes.len() {
+        let escaped = (bytes[at] == b'%')
+            .then(|| path.get(at + 1..at + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                decoded.push(byte);
+                at += 3;
+            }
+            Some(byte) => {
+                decoded.extend_from_slice(format!("%{byte:02X}").as_bytes());
+                at += 3;
+            }
+            None => {
+                decoded.push(bytes[at]);
+                at += 1;
+            }
+        }
+    }
+
+    // Only ASCII is decoded, whatever else the path holds is left as is.
+    String::from_utf8(decoded).unwrap_or_else(|_| path.to_owned())
+}
+
+fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+
+    for character in path.chars() {
+        if character != '/' || !merged.ends_with('/') {
+            merged.push(character);
+        }
+    }
+
+    merged
+}
+
+/// Resolves `.` and `..` segments of an absolute path, never going above
+/// the root.
+fn remove_dot_segments(path: &str) -> String {
+    let segments: Vec<&str> = path[1..].split('/').collect();
+    let mut output = Vec::with_capacity(segments.len());
+
+    for (index, segment) in segments.iter().enumerate() {
+        let last = index == segments.len() - 1;
+        match *segment {
+            "." => {}
+            ".." => {
+                output.pop();
+            }
+            segment => {
+                output.push(segment);
+                continue;
+            }
+        }
+        // `/a/b/..` is the directory `/a/`.
+        if last {
+            output.push("");
+        }
+    }
+
+    format!("/{}", output.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_paths() {
+        let normalized = |uri: &str| normalize(&uri.parse().unwrap()).to_string();
+
+        assert_eq!(normalized("/./api/../admin"), "/admin");
+        assert_eq!(normalized("//admin///users/"), "/admin/users/");
+        assert_eq!(normalized("/%2e%2E/admin?next=/../x"), "/admin?next=/../x");
+        assert_eq!(normalized("/api/%7euser/%2f%c3%a9"), "/api/~user/%2F%C3%A9");
+        assert_eq!(normalized("/a/b/.."), "/a/");
+        assert_eq!(normalized("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalized("/100%"), "/100%");
+        assert_eq!(
+            normalized("http://example.com//a/./b"),
+            "http://example.com/a/b"
+        );
+        assert_eq!(normalized("*"), "*");
+        assert_eq!(decode_unreserved("/café/%7emenu"), "/café/~menu");
+    }
+}
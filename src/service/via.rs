@@ -0,0 +1,61 @@
+//! `Via` entries of forwarded messages, see [`Via`].
+//!
+//! [`Via`]: crate::config::Via
+
+use hyper::{
+    header::{self, HeaderValue},
+    HeaderMap, Version,
+};
+use tracing::warn;
+
+/// Adds the entry of this proxy to the `Via` of a message received with
+/// `version`, after those of previous proxies unless they are replaced.
+pub fn add(via: &crate::config::Via, headers: &mut HeaderMap, version: Version) {
+    // The protocol name is left out when it's HTTP, RFC 9110 section 7.6.3.
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+
+    let Ok(entry) = HeaderValue::from_str(&format!("{protocol} {}", via.pseudonym)) else {
+        warn!(pseudonym = via.pseudonym, "Invalid Via pseudonym");
+        return;
+    };
+
+    if via.replace {
+        headers.insert(header::VIA, entry);
+    } else {
+        headers.append(header::VIA, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Via;
+
+    #[test]
+    fn adds_entries() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::VIA, HeaderValue::from_static("1.0 fred"));
+
+        let extend: Via = toml::from_str("").unwrap();
+        add(&extend, &mut headers, Version::HTTP_11);
+        let entries: Vec<_> = headers.get_all(header::VIA).iter().collect();
+        assert_eq!(entries, ["1.0 fred", "1.1 xnav"]);
+
+        let replace: Via = toml::from_str(
+            r#"
+                pseudonym = "edge"
+                replace = true
+            "#,
+        )
+        .unwrap();
+        add(&replace, &mut headers, Version::HTTP_2);
+        let entries: Vec<_> = headers.get_all(header::VIA).iter().collect();
+        assert_eq!(entries, ["2 edge"]);
+    }
+}
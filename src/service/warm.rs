@@ -0,0 +1,172 @@
+//! Connections to backends opened ahead of the requests that need them, so
+//! that the first requests after startup don't wait for them.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hyper::client::conn::http1::SendRequest;
+use tokio::{sync::Notify, task::JoinSet, time::Instant};
+use tracing::debug;
+
+use crate::config::Backend;
+
+use super::proxy::{self, ProxyBody};
+
+/// Longest wait before connecting again to a backend that is down.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Time allowed to open a warm connection before the backend counts as down.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Idle connections to each backend of a forward action, shared by all the
+/// replicas of a server.
+pub struct Warm {
+    pools: Vec<Pool>,
+}
+
+struct Pool {
+    backend: SocketAddr,
+    idle: Mutex<Vec<SendRequest<ProxyBody>>>,
+    /// Notified when a connection is taken, so that it gets replaced.
+    taken: Notify,
+}
+
+impl Warm {
+    pub fn new(backends: &[Backend]) -> Self {
+        Self {
+            pools: backends
+                .iter()
+                .map(|backend| Pool {
+                    backend: backend.address,
+                    idle: Mutex::new(Vec::new()),
+                    taken: Notify::new(),
+                })
+                .collect(),
+        }
+    }
+
+    /// An idle connection to `backend` ready to send a request, if any.
+    pub(super) fn take(&self, backend: SocketAddr) -> Option<SendRequest<ProxyBody>> {
+        let pool = self.pools.iter().find(|pool| pool.backend == backend)?;
+
+        let mut idle = pool.idle.lock().unwrap();
+        let sender = std::iter::from_fn(|| idle.pop()).find(SendRequest::is_ready);
+        drop(idle);
+
+        if sender.is_some() {
+            pool.taken.notify_one();
+        }
+
+        sender
+    }
+
+    /// Keeps `connections` idle connections open to each backend, until the
+    /// task is aborted. Backends are connected to apart from each other, and
+    /// those that can't be reached are tried again less and less often.
+    pub async fn maintain(self: Arc<Self>, connections: usize) {
+        let mut pools = JoinSet::new();
+        for index in 0..self.pools.len() {
+            let warm = self.clone();
+            pools.spawn(async move { warm.pools[index].maintain(connections).await });
+        }
+
+        while pools.join_next().await.is_some() {}
+    }
+}
+
+impl Pool {
+    async fn maintain(&self, connections: usize) {
+        let mut retry = Retry::default();
+
+        loop {
+            if let Some(at) = retry.at {
+                tokio::time::sleep_until(at).await;
+            }
+
+            // Backends close connections that stay idle for too long.
+            self.idle
+                .lock()
+                .unwrap()
+                .retain(|sender| !sender.is_closed());
+
+            while self.idle.lock().unwrap().len() < connections {
+                let connected = tokio::time::timeout(CONNECT_TIMEOUT, proxy::connect(self.backend));
+                let Ok(Some((sender, _))) = connected.await else {
+                    retry.failed();
+                    debug!(backend = %self.backend, backoff = ?retry.backoff, "Backend not warmed up");
+                    break;
+                };
+                retry.succeeded();
+                self.idle.lock().unwrap().push(sender);
+            }
+
+            if retry.at.is_none() {
+                let _ = tokio::time::timeout(Duration::from_secs(1), self.taken.notified()).await;
+            }
+        }
+    }
+}
+
+/// When to connect again to a backend after failing to.
+#[derive(Default)]
+struct Retry {
+    at: Option<Instant>,
+    backoff: Duration,
+}
+
+impl Retry {
+    fn failed(&mut self) {
+        self.backoff = (self.backoff * 2).clamp(Duration::from_secs(1), MAX_BACKOFF);
+        self.at = Some(Instant::now() + self.backoff);
+    }
+
+    fn succeeded(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn warms_backends_apart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            while let Ok(connection) = listener.accept().await {
+                accepted.push(connection);
+            }
+        });
+
+        let backend = |address| Backend {
+            address,
+            weight: 1,
+            max_requests: None,
+            backup: false,
+            group: None,
+        };
+        // Nothing answers there, connecting waits or fails.
+        let unreachable = "192.0.2.1:80".parse().unwrap();
+        let warm = Arc::new(Warm::new(&[backend(unreachable), backend(live)]));
+        let maintained = tokio::spawn(warm.clone().maintain(2));
+
+        let warmed = async {
+            while warm.pools[1].idle.lock().unwrap().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), warmed)
+            .await
+            .unwrap();
+        assert!(warm.pools[0].idle.lock().unwrap().is_empty());
+
+        maintained.abort();
+    }
+}
@@ -1,15 +1,282 @@
 //! Load balancing and scheduler implementations.
+mod split;
 mod wrr;
 
+pub use split::Splitter;
 pub use wrr::WeightedRoundRobin;
 
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{futures::Notified, Notify},
+    time::Instant,
+};
+
 use crate::config::{Algorithm, Backend};
 
 /// A scheduler provides an algorithm for load balancing between multiple
 /// backend servers.
 pub trait Scheduler {
+    /// Picks the server that should process the next request.
+    fn schedule(&self) -> Decision;
+
     /// Returns the address of the server that should process the next request.
-    fn next_server(&self) -> std::net::SocketAddr;
+    fn next_server(&self) -> SocketAddr {
+        self.schedule().server
+    }
+}
+
+/// Server picked by a [`Scheduler`] along with what made it pick it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub server: SocketAddr,
+    /// Weight of the server, which determines its share of the requests.
+    pub weight: usize,
+}
+
+/// Group of backends a request is scheduled among.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Primary,
+    /// Backends that only get requests the primaries failed.
+    Backup,
+}
+
+/// Number of requests routed to each backend and still in flight, shared by
+/// all the replicas of a server so that uneven distributions can be spotted
+/// and `max_requests` holds across them.
+#[derive(Debug)]
+pub struct Picks {
+    backends: Vec<Picked>,
+    /// Requests waiting for a backend to free up.
+    queued: AtomicUsize,
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct Picked {
+    backend: Backend,
+    requests: AtomicU64,
+    in_flight: AtomicUsize,
+    /// Gets no requests until then, see [`Picks::pause`].
+    paused_until: Mutex<Option<Instant>>,
+    /// Whether the last connection attempt to the backend failed.
+    unreachable: AtomicBool,
+}
+
+/// Totals of a backend in [`Picks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub requests: u64,
+    pub in_flight: usize,
+    /// Time left before the backend gets requests again.
+    pub paused: Option<Duration>,
+}
+
+/// A place in the queue of requests waiting for a backend, see
+/// [`Picks::enqueue`].
+#[derive(Debug)]
+pub struct Place<'a> {
+    picks: &'a Picks,
+}
+
+/// A request in flight to a backend, which stops counting when dropped.
+#[derive(Debug)]
+pub struct Lease {
+    server: SocketAddr,
+    picks: Arc<Picks>,
+    index: Option<usize>,
+}
+
+impl Picks {
+    pub fn new(backends: &[Backend]) -> Self {
+        Self {
+            backends: backends
+                .iter()
+                .map(|backend| Picked {
+                    backend: backend.clone(),
+                    requests: AtomicU64::new(0),
+                    in_flight: AtomicUsize::new(0),
+                    paused_until: Mutex::new(None),
+                    unreachable: AtomicBool::new(false),
+                })
+                .collect(),
+            queued: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    /// Routes one more request to `server`, unless it already has its
+    /// `max_requests` in flight or it's paused.
+    pub fn lease(self: &Arc<Self>, server: SocketAddr) -> Option<Lease> {
+        let index = self
+            .backends
+            .iter()
+            .position(|picked| picked.backend.address == server);
+
+        if let Some(picked) = index.map(|index| &self.backends[index]) {
+            if picked.paused().is_some() {
+                return None;
+            }
+            let limit = picked.backend.max_requests.unwrap_or(usize::MAX);
+            picked
+                .in_flight
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_flight| {
+                    (in_flight < limit).then_some(in_flight + 1)
+                })
+                .ok()?;
+            picked.requests.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Some(Lease {
+            server,
+            picks: self.clone(),
+            index,
+        })
+    }
+
+    /// Sends no requests to `server` for the given `time`, as asked by
+    /// backends that are overwhelmed.
+    pub fn pause(&self, server: SocketAddr, time: Duration) {
+        let picked = self
+            .backends
+            .iter()
+            .find(|picked| picked.backend.address == server);
+
+        if let Some(picked) = picked {
+            *picked.paused_until.lock().unwrap() = Some(Instant::now() + time);
+        }
+    }
+
+    /// Takes note of whether `server` could be connected to. Unreachable
+    /// backends still get requests, the first one that connects marks the
+    /// backend as reachable again.
+    pub fn reached(&self, server: SocketAddr, reachable: bool) {
+        let picked = self
+            .backends
+            .iter()
+            .find(|picked| picked.backend.address == server);
+
+        if let Some(picked) = picked {
+            picked.unreachable.store(!reachable, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether none of the backends, backups included, can take requests
+    /// because they are all unreachable or paused.
+    pub fn all_down(&self) -> bool {
+        self.backends
+            .iter()
+            .all(|picked| picked.unreachable.load(Ordering::Relaxed) || picked.paused().is_some())
+    }
+
+    /// Whether a primary backend other than those `excluded` is up but has
+    /// its `max_requests` in flight, so that requests can wait for it.
+    pub fn busy(&self, excluded: &[SocketAddr]) -> bool {
+        self.backends.iter().any(|picked| {
+            let limit = picked.backend.max_requests.unwrap_or(usize::MAX);
+            !picked.backend.backup
+                && !excluded.contains(&picked.backend.address)
+                && !picked.unreachable.load(Ordering::Relaxed)
+                && picked.paused().is_none()
+                && picked.in_flight.load(Ordering::Acquire) >= limit
+        })
+    }
+
+    /// Whether any of the backends is a backup.
+    pub fn has_backups(&self) -> bool {
+        self.backends.iter().any(|picked| picked.backend.backup)
+    }
+
+    /// Takes a place in the queue unless `depth` requests are waiting
+    /// already. The request leaves the queue when the place is dropped.
+    pub fn enqueue(&self, depth: usize) -> Option<Place<'_>> {
+        self.queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < depth).then_some(queued + 1)
+            })
+            .ok()?;
+
+        Some(Place { picks: self })
+    }
+
+    /// Completes when any lease is dropped after this is called.
+    pub fn released(&self) -> Notified<'_> {
+        self.released.notified()
+    }
+
+    /// Backends with their totals.
+    pub fn counts(&self) -> Vec<(&Backend, Usage)> {
+        self.backends
+            .iter()
+            .map(|picked| {
+                let usage = Usage {
+                    requests: picked.requests.load(Ordering::Relaxed),
+                    in_flight: picked.in_flight.load(Ordering::Acquire),
+                    paused: picked.paused(),
+                };
+                (&picked.backend, usage)
+            })
+            .collect()
+    }
+}
+
+impl Picked {
+    fn paused(&self) -> Option<Duration> {
+        let until = (*self.paused_until.lock().unwrap())?;
+        until
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+    }
+}
+
+impl Lease {
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(index) = self.index {
+            self.picks.backends[index]
+                .in_flight
+                .fetch_sub(1, Ordering::AcqRel);
+            self.picks.released.notify_waiters();
+        }
+    }
+}
+
+impl Drop for Place<'_> {
+    fn drop(&mut self) {
+        self.picks.queued.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Builds the schedulers of the primary and backup backends. Backups are
+/// only told apart when there are primaries.
+pub fn make_tiers(
+    algorithm: Algorithm,
+    backends: &[Backend],
+) -> (
+    Box<dyn Scheduler + Send + Sync>,
+    Option<Box<dyn Scheduler + Send + Sync>>,
+) {
+    let (backups, primaries): (Vec<_>, Vec<_>) =
+        backends.iter().cloned().partition(|backend| backend.backup);
+
+    if primaries.is_empty() || backups.is_empty() {
+        return (make(algorithm, &backends.to_vec()), None);
+    }
+
+    (make(algorithm, &primaries), Some(make(algorithm, &backups)))
 }
 
 /// [`Scheduler`] factory.
@@ -18,3 +285,76 @@ pub fn make(algorithm: Algorithm, backends: &Vec<Backend>) -> Box<dyn Scheduler
         Algorithm::Wrr => WeightedRoundRobin::new(backends),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queue_until_released() {
+        let address = "127.0.0.1:9000".parse().unwrap();
+        let picks = Arc::new(Picks::new(&[Backend {
+            address,
+            weight: 1,
+            max_requests: Some(1),
+            backup: false,
+            group: None,
+        }]));
+
+        let lease = picks.lease(address).unwrap();
+        assert!(picks.lease(address).is_none());
+
+        let place = picks.enqueue(1).unwrap();
+        assert!(picks.enqueue(1).is_none());
+
+        let released = picks.released();
+        drop(lease);
+        released.await;
+        assert!(picks.lease(address).is_some());
+
+        drop(place);
+        assert!(picks.enqueue(1).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_backends() {
+        let address = "127.0.0.1:9000".parse().unwrap();
+        let picks = Arc::new(Picks::new(&[Backend {
+            address,
+            weight: 1,
+            max_requests: None,
+            backup: false,
+            group: None,
+        }]));
+
+        picks.pause(address, Duration::from_secs(5));
+        assert!(picks.lease(address).is_none());
+        assert_eq!(picks.counts()[0].1.paused, Some(Duration::from_secs(5)));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(picks.lease(address).is_some());
+        assert_eq!(picks.counts()[0].1.paused, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn down_backends() {
+        let backend = |port, backup| Backend {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            weight: 1,
+            max_requests: None,
+            backup,
+            group: None,
+        };
+        let (primary, backup) = (backend(9000, false), backend(9001, true));
+        let picks = Picks::new(&[primary.clone(), backup.clone()]);
+
+        picks.reached(primary.address, false);
+        assert!(!picks.all_down());
+
+        picks.pause(backup.address, Duration::from_secs(5));
+        assert!(picks.all_down());
+
+        picks.reached(primary.address, true);
+        assert!(!picks.all_down());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Shares requests between named groups of backends according to their
+/// weights, interleaving them as evenly as possible. Used for canary
+/// releases, where a small share of the traffic goes to the new version.
+#[derive(Debug)]
+pub struct Splitter {
+    groups: Mutex<Vec<Group>>,
+}
+
+#[derive(Debug)]
+struct Group {
+    name: String,
+    weight: u32,
+    /// Smooth weighted round robin state, the group with the highest one is
+    /// picked next.
+    current: i64,
+}
+
+impl Splitter {
+    pub fn new(weights: &BTreeMap<String, u32>) -> Self {
+        Self {
+            groups: Mutex::new(groups(weights)),
+        }
+    }
+
+    /// Replaces the weights of all groups.
+    pub fn set(&self, weights: &BTreeMap<String, u32>) {
+        *self.groups.lock().unwrap() = groups(weights);
+    }
+
+    pub fn weights(&self) -> BTreeMap<String, u32> {
+        self.groups
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|group| (group.name.clone(), group.weight))
+            .collect()
+    }
+
+    /// Group that should receive the next request, `None` if all weights are
+    /// zero.
+    pub fn pick(&self) -> Option<String> {
+        let mut groups = self.groups.lock().unwrap();
+        let total: i64 = groups.iter().map(|group| i64::from(group.weight)).sum();
+
+        if total == 0 {
+            return None;
+        }
+
+        for group in groups.iter_mut() {
+            group.current += i64::from(group.weight);
+        }
+
+        let picked = groups.iter_mut().max_by_key(|group| group.current)?;
+        picked.current -= total;
+
+        Some(picked.name.clone())
+    }
+}
+
+fn groups(weights: &BTreeMap<String, u32>) -> Vec<Group> {
+    weights
+        .iter()
+        .map(|(name, weight)| Group {
+            name: name.clone(),
+            weight: *weight,
+            current: 0,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_by_weight() {
+        let weights = BTreeMap::from([(String::from("stable"), 95), (String::from("canary"), 5)]);
+        let splitter = Splitter::new(&weights);
+
+        let picks: Vec<_> = (0..100).filter_map(|_| splitter.pick()).collect();
+        let canary = picks.iter().filter(|group| *group == "canary").count();
+        assert_eq!(canary, 5);
+
+        // Canary requests are spread out instead of sent in a burst.
+        let first = picks.iter().position(|group| group == "canary").unwrap();
+        assert!(first < 20);
+
+        splitter.set(&BTreeMap::from([
+            (String::from("stable"), 0),
+            (String::from("canary"), 1),
+        ]));
+        assert_eq!(splitter.pick().as_deref(), Some("canary"));
+
+        splitter.set(&BTreeMap::from([(String::from("stable"), 0)]));
+        assert_eq!(splitter.pick(), None);
+    }
+}
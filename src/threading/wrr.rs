@@ -1,13 +1,11 @@
-use std::net::SocketAddr;
-
-use super::Scheduler;
+use super::{Decision, Scheduler};
 use crate::{config::Backend, sync::Ring};
 
 /// Classical Weighted Round Robin (WRR) algorithm.
 #[derive(Debug)]
 pub struct WeightedRoundRobin {
     /// Pre-computed complete cycle of requests.
-    cycle: Ring<SocketAddr>,
+    cycle: Ring<Decision>,
 }
 
 impl WeightedRoundRobin {
@@ -19,7 +17,10 @@ impl WeightedRoundRobin {
         for backend in backends {
             let mut weight = backend.weight;
             while weight > 0 {
-                cycle.push(backend.address);
+                cycle.push(Decision {
+                    server: backend.address,
+                    weight: backend.weight,
+                });
                 weight -= 1;
             }
         }
@@ -31,7 +32,7 @@ impl WeightedRoundRobin {
 }
 
 impl Scheduler for WeightedRoundRobin {
-    fn next_server(&self) -> SocketAddr {
+    fn schedule(&self) -> Decision {
         self.cycle.next_as_owned()
     }
 }
@@ -63,6 +64,9 @@ mod tests {
                 .map(|(addr, weight)| Backend {
                     address: addr.parse().unwrap(),
                     weight: *weight,
+                    max_requests: None,
+                    backup: false,
+                    group: None,
                 })
                 .collect(),
         );